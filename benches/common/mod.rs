@@ -0,0 +1,56 @@
+//! Servers shared by the benchmarks
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Duration;
+
+use gatekeeper::client::Socks5Client;
+use gatekeeper::{Server, ServerCommand, ServerConfig};
+
+/// Proxy listening on a free port of localhost, which is terminated when dropped
+pub struct Proxy {
+    pub addr: SocketAddr,
+    tx: std::sync::mpsc::Sender<ServerCommand<std::net::TcpStream>>,
+    th: Option<thread::JoinHandle<()>>,
+}
+
+impl Proxy {
+    pub fn spawn() -> Self {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = ServerConfig::default();
+        config
+            .set_server_addr(addr)
+            .set_accept_timeout(Some(Duration::from_millis(100)));
+        let (mut server, tx) = Server::new(config);
+        let th = thread::spawn(move || server.serve().unwrap());
+        let proxy = Self {
+            addr,
+            tx,
+            th: Some(th),
+        };
+        // wait for the server listening
+        let client = proxy.client();
+        let probe = TcpListener::bind("127.0.0.1:0").unwrap();
+        while client.connect(probe.local_addr().unwrap().into()).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        proxy
+    }
+
+    pub fn client(&self) -> Socks5Client {
+        let mut client = Socks5Client::new(self.addr);
+        client.set_rw_timeout(Some(Duration::from_secs(5)));
+        client
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.tx.send(ServerCommand::Terminate).unwrap();
+        if let Some(th) = self.th.take() {
+            th.join().unwrap();
+        }
+    }
+}
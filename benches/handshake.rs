@@ -0,0 +1,71 @@
+//! Latency of handshakes from the method selection to the reply of `CONNECT`
+//!
+//! `in_memory` runs sessions of clients and destinations in memory (`test-util` feature),
+//! and `loopback_tcp` connects through a proxy listening on localhost.
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use gatekeeper::acceptor::Binder;
+use gatekeeper::model::Error;
+use gatekeeper::test_util::{BufferConnector, BufferStream};
+use gatekeeper::{Address, Server, ServerCommand, ServerConfig};
+
+mod common;
+
+/// NoAuth, then CONNECT 192.0.2.1:80
+const CONNECT_REQUEST: [u8; 13] = [5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 0, 80];
+
+/// Binder accepting the clients sending `CONNECT_REQUEST`
+struct Clients(u64);
+
+impl Binder for Clients {
+    type Stream = BufferStream;
+    type Iter = Box<dyn Iterator<Item = (BufferStream, SocketAddr)> + Send>;
+    fn bind(&self, _addr: SocketAddr) -> Result<Self::Iter, Error> {
+        let client_addr = "192.0.2.2:5000".parse().unwrap();
+        Ok(Box::new((0..self.0).map(move |_| {
+            let client = BufferStream::with_buffer(CONNECT_REQUEST[..].into(), vec![].into());
+            (client, client_addr)
+        })))
+    }
+}
+
+fn in_memory(c: &mut Criterion) {
+    let dst: Address = "192.0.2.1:80".parse().unwrap();
+    c.bench_function("handshake/in_memory", |b| {
+        b.iter_custom(|iters| {
+            let connector: BufferConnector<BufferStream> = [(dst.clone(), Ok(BufferStream::new()))]
+                .into_iter()
+                .collect();
+            let (tx_done, _rx_done) = std::sync::mpsc::sync_channel(1);
+            let (mut server, tx) =
+                Server::with_binder(ServerConfig::default(), Clients(iters), tx_done, connector);
+            let start = Instant::now();
+            while server.metrics().finished < iters {
+                server.run_once(Some(Duration::from_millis(1))).unwrap();
+            }
+            let elapsed = start.elapsed();
+            tx.send(ServerCommand::Terminate).unwrap();
+            server.serve().unwrap();
+            elapsed
+        })
+    });
+}
+
+fn loopback_tcp(c: &mut Criterion) {
+    // destination closing connections as soon as accepted
+    let dst = TcpListener::bind("127.0.0.1:0").unwrap();
+    let dst_addr = dst.local_addr().unwrap();
+    thread::spawn(move || for _ in dst.incoming() {});
+    let proxy = common::Proxy::spawn();
+    let client = proxy.client();
+    c.bench_function("handshake/loopback_tcp", |b| {
+        b.iter(|| client.connect(dst_addr.into()).unwrap())
+    });
+}
+
+criterion_group!(benches, in_memory, loopback_tcp);
+criterion_main!(benches);
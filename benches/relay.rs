@@ -0,0 +1,68 @@
+//! Throughput of relays
+//!
+//! `in_memory` relays between in-memory streams by `test_util::measure_relay` for some buffer sizes,
+//! and `loopback_tcp` sends bytes through a proxy listening on localhost.
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use gatekeeper::relay::Relay;
+use gatekeeper::test_util::measure_relay;
+
+mod common;
+
+/// bytes relayed in each direction by an iteration
+const SIZE: usize = 1 << 20;
+
+fn in_memory(c: &mut Criterion) {
+    let mut group = c.benchmark_group("relay/in_memory");
+    group.throughput(Throughput::Bytes(2 * SIZE as u64));
+    for buffer_size in [4 << 10, 64 << 10] {
+        let mut relay = Relay::new();
+        relay.set_buffer_size(buffer_size);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_size),
+            &relay,
+            |b, relay| {
+                b.iter_custom(|iters| {
+                    (0..iters)
+                        .map(|_| measure_relay(relay, SIZE, SIZE).unwrap().elapsed)
+                        .sum::<Duration>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn loopback_tcp(c: &mut Criterion) {
+    // destination acknowledging each `SIZE` bytes by a byte
+    let dst = TcpListener::bind("127.0.0.1:0").unwrap();
+    let dst_addr = dst.local_addr().unwrap();
+    thread::spawn(move || {
+        for strm in dst.incoming() {
+            let mut strm = strm.unwrap();
+            let mut buf = vec![0; SIZE];
+            while strm.read_exact(&mut buf).is_ok() && strm.write_all(&[0]).is_ok() {}
+        }
+    });
+    let proxy = common::Proxy::spawn();
+    let (mut strm, _) = proxy.client().connect(dst_addr.into()).unwrap();
+    let data = vec![0; SIZE];
+
+    let mut group = c.benchmark_group("relay");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.bench_function("loopback_tcp", |b| {
+        b.iter(|| {
+            strm.write_all(&data).unwrap();
+            strm.read_exact(&mut [0]).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, in_memory, loopback_tcp);
+criterion_main!(benches);
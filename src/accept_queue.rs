@@ -0,0 +1,191 @@
+//! Backpressure of acceptors
+//!
+//! Acceptors send accepted connections to the server by `ServerCommand::Connect` through the
+//! command channel, which is unbounded to keep `ServerCommand`s from applications flowing.
+//! [`AcceptQueue`] bounds the number of connections sent but not taken by the server yet,
+//! as `ServerConfig::accept_queue_capacity`.
+//!
+//! Connections rejected without sessions (as the queue is full, `ServerConfig::max_sessions`
+//! sessions are running, or the server is shutting down) are replied by threads,
+//! up to `MAX_REJECT_REPLIES` at the same time.
+//! Others are closed without a reply, not to spend a thread for each of them under connection
+//! floods.
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::config::AcceptOverflow;
+
+/// Maximum number of rejected connections replied at the same time
+pub(crate) const MAX_REJECT_REPLIES: usize = 16;
+
+/// Whether an acceptor may send a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Admission {
+    Admit,
+    /// the queue is full and the connection should be rejected
+    Full,
+    /// the server is stopped
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    pending: usize,
+    /// connections rejected as the queue was full
+    overflowed: u64,
+    /// rejected connections being replied
+    replying: usize,
+    closed: bool,
+}
+
+/// Number of connections pending in the command channel
+#[derive(Debug)]
+pub(crate) struct AcceptQueue {
+    /// unbounded if `None`
+    capacity: Option<usize>,
+    overflow: AcceptOverflow,
+    state: Mutex<QueueState>,
+    /// notified when a connection is taken or the queue is closed
+    taken: Condvar,
+}
+
+impl AcceptQueue {
+    pub fn new(capacity: Option<usize>, overflow: AcceptOverflow) -> Self {
+        Self {
+            capacity,
+            overflow,
+            state: Mutex::default(),
+            taken: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Count a connection to be sent by an acceptor
+    ///
+    /// With `AcceptOverflow::Block`, this waits until the server takes a pending connection.
+    pub fn push(&self) -> Admission {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Admission::Closed;
+            }
+            match self.capacity {
+                Some(capacity) if state.pending >= capacity => {}
+                _ => break,
+            }
+            match self.overflow {
+                AcceptOverflow::Block => {
+                    state = self
+                        .taken
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }
+                AcceptOverflow::Reject => {
+                    state.overflowed += 1;
+                    return Admission::Full;
+                }
+            }
+        }
+        state.pending += 1;
+        Admission::Admit
+    }
+
+    /// The server has taken a connection
+    pub fn pop(&self) {
+        let mut state = self.lock();
+        state.pending = state.pending.saturating_sub(1);
+        self.taken.notify_one();
+    }
+
+    /// Stop acceptors waiting for the server
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.taken.notify_all();
+    }
+
+    /// number of connections rejected as the queue was full
+    pub fn overflowed(&self) -> u64 {
+        self.lock().overflowed
+    }
+
+    /// Slot to reply to a rejected connection, `None` if `MAX_REJECT_REPLIES` are being replied
+    pub fn reply_slot(self: &Arc<Self>) -> Option<ReplySlot> {
+        let mut state = self.lock();
+        if state.replying >= MAX_REJECT_REPLIES {
+            return None;
+        }
+        state.replying += 1;
+        Some(ReplySlot(self.clone()))
+    }
+}
+
+/// Reply to a rejected connection, released when dropped
+#[derive(Debug)]
+pub(crate) struct ReplySlot(Arc<AcceptQueue>);
+
+impl Drop for ReplySlot {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.replying = state.replying.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn reject_overflow() {
+        let queue = AcceptQueue::new(Some(2), AcceptOverflow::Reject);
+        assert_eq!(queue.push(), Admission::Admit);
+        assert_eq!(queue.push(), Admission::Admit);
+        assert_eq!(queue.push(), Admission::Full);
+        queue.pop();
+        assert_eq!(queue.push(), Admission::Admit);
+        assert_eq!(queue.overflowed(), 1);
+        queue.close();
+        assert_eq!(queue.push(), Admission::Closed);
+
+        let unbounded = AcceptQueue::new(None, AcceptOverflow::Reject);
+        assert!((0..100).all(|_| unbounded.push() == Admission::Admit));
+    }
+
+    #[test]
+    fn reply_slots() {
+        let queue = Arc::new(AcceptQueue::new(Some(1), AcceptOverflow::Reject));
+        let mut slots: Vec<_> = (0..MAX_REJECT_REPLIES)
+            .map(|_| queue.reply_slot().unwrap())
+            .collect();
+        assert!(queue.reply_slot().is_none());
+        slots.pop();
+        assert!(queue.reply_slot().is_some());
+        drop(slots);
+        assert_eq!(queue.lock().replying, 0);
+    }
+
+    #[test]
+    fn block_overflow() {
+        let queue = Arc::new(AcceptQueue::new(Some(1), AcceptOverflow::Block));
+        assert_eq!(queue.push(), Admission::Admit);
+        let acceptor = {
+            let queue = queue.clone();
+            thread::spawn(move || [queue.push(), queue.push()])
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!acceptor.is_finished());
+        queue.pop();
+        thread::sleep(Duration::from_millis(100));
+        // the second one is still waiting
+        assert!(!acceptor.is_finished());
+        queue.close();
+        assert_eq!(
+            acceptor.join().unwrap(),
+            [Admission::Admit, Admission::Closed]
+        );
+        assert_eq!(queue.overflowed(), 0);
+    }
+}
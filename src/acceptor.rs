@@ -1,5 +1,13 @@
 use std::io;
 use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::{
+    fs::FileTypeExt,
+    io::{AsRawFd, FromRawFd, RawFd},
+    net::{UnixListener, UnixStream},
+};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 use std::sync::{
     mpsc::{self, Receiver},
     Arc, Mutex,
@@ -10,6 +18,7 @@ use failure::Fail;
 use log::*;
 
 use crate::byte_stream::ByteStream;
+use crate::config::TcpKeepalive;
 use crate::model;
 use crate::model::{Error, ErrorKind};
 use crate::tcp_listener_ext::*;
@@ -21,31 +30,33 @@ pub struct TcpAcceptor {
     rx: Arc<Mutex<Receiver<()>>>,
     /// timeout for accept
     accept_timeout: Option<Duration>,
+    /// keepalive probes on accepted connections
+    keepalive: Option<TcpKeepalive>,
+    /// woken up with the termination message instead of polling it every `accept_timeout`
+    #[cfg(unix)]
+    wakeup: Option<Arc<Wakeup>>,
 }
 
 impl TcpAcceptor {
-    fn new(
-        listener: TcpListener,
-        rw_timeout: Option<Duration>,
-        rx: Arc<Mutex<Receiver<()>>>,
-        accept_timeout: Option<Duration>,
-    ) -> Self {
-        Self {
-            listener,
-            rw_timeout,
-            rx,
-            accept_timeout,
+    /// `None` if woken up to check the termination message
+    fn accept_timeout(&self) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        #[cfg(unix)]
+        let accepted = match &self.wakeup {
+            Some(wakeup) => accept_or_wake(&self.listener, wakeup, None)?,
+            None => Some(self.listener.accept_timeout(self.accept_timeout)?),
+        };
+        #[cfg(not(unix))]
+        let accepted = Some(self.listener.accept_timeout(self.accept_timeout)?);
+        let (tcp, addr) = match accepted {
+            Some(accepted) => accepted,
+            None => return Ok(None),
+        };
+        tcp.set_read_timeout(self.rw_timeout)?;
+        tcp.set_write_timeout(self.rw_timeout)?;
+        if let Some(keepalive) = self.keepalive {
+            keepalive.apply(&tcp)?;
         }
-    }
-
-    fn accept_timeout(&self) -> io::Result<(TcpStream, SocketAddr)> {
-        self.listener
-            .accept_timeout(self.accept_timeout)
-            .and_then(|(tcp, addr)| {
-                tcp.set_read_timeout(self.rw_timeout)?;
-                tcp.set_write_timeout(self.rw_timeout)?;
-                Ok((tcp, addr))
-            })
+        Ok(Some((tcp, addr)))
     }
 }
 
@@ -74,7 +85,8 @@ impl Iterator for TcpAcceptor {
         loop {
             check_done!(&self.rx);
             match self.accept_timeout() {
-                Ok(x) => return Some(x),
+                Ok(Some(x)) => return Some(x),
+                Ok(None) => {}
                 Err(err) if err.kind() == io::ErrorKind::TimedOut => {
                     // trace!("accept timeout: {}", err);
                 }
@@ -92,13 +104,37 @@ pub trait Binder {
     type Stream: ByteStream + 'static;
     type Iter: Iterator<Item = (Self::Stream, SocketAddr)> + Send + 'static;
     fn bind(&self, addr: SocketAddr) -> Result<Self::Iter, Error>;
+
+    /// Wake up the acceptors waiting for connections, after termination messages are sent
+    ///
+    /// Acceptors not woken up check the message by polling it.
+    fn wake_acceptors(&self) {}
+}
+
+/// Self-pipe waking up acceptors, or `None` to poll termination messages every accept timeout
+#[cfg(unix)]
+fn acceptor_wakeup() -> Option<Arc<Wakeup>> {
+    match Wakeup::new() {
+        Ok(wakeup) => Some(Arc::new(wakeup)),
+        Err(err) => {
+            warn!("acceptors poll termination: {}", err);
+            None
+        }
+    }
 }
 
+/// Acceptors are woken up by the server to terminate on unix,
+/// so `accept_timeout` is the interval of polling the termination message only on other platforms.
 pub struct TcpBinder {
     rw_timeout: Option<Duration>,
     /// receiver for Acceptor termination message
     rx: Arc<Mutex<Receiver<()>>>,
     accept_timeout: Option<Duration>,
+    keepalive: Option<TcpKeepalive>,
+    /// listeners used instead of binding their local addresses
+    inherited: Mutex<Vec<TcpListener>>,
+    #[cfg(unix)]
+    wakeup: Option<Arc<Wakeup>>,
 }
 
 impl TcpBinder {
@@ -111,43 +147,402 @@ impl TcpBinder {
             rw_timeout,
             rx,
             accept_timeout,
+            keepalive: None,
+            inherited: Mutex::default(),
+            #[cfg(unix)]
+            wakeup: acceptor_wakeup(),
         }
     }
+
+    /// enable keepalive probes on connections from clients
+    pub fn set_tcp_keepalive(&mut self, keepalive: Option<TcpKeepalive>) -> &mut Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// accept clients on `listeners` (e.g. by [`listen_fds`]) instead of binding their local addresses
+    pub fn set_inherited_listeners(&mut self, listeners: Vec<TcpListener>) -> &mut Self {
+        self.inherited = Mutex::new(listeners);
+        self
+    }
+
+    /// take the inherited listener bound to `addr`
+    fn take_inherited(&self, addr: SocketAddr) -> Result<Option<TcpListener>, Error> {
+        let mut inherited = self.inherited.lock()?;
+        let pos = inherited
+            .iter()
+            .position(|listener| listener.local_addr().ok() == Some(addr));
+        Ok(pos.map(|pos| inherited.swap_remove(pos)))
+    }
 }
 
 impl Binder for TcpBinder {
     type Stream = TcpStream;
     type Iter = TcpAcceptor;
     fn bind(&self, addr: SocketAddr) -> Result<Self::Iter, Error> {
-        let tcp = socket2::Socket::new(
-            socket2::Domain::IPV4,
-            socket2::Type::STREAM,
-            Some(socket2::Protocol::TCP),
-        )?;
-        tcp.set_reuse_address(true)
-            .map_err(|err| addr_error(err, addr))?;
-        tcp.bind(&addr.into())
-            .map_err(|err| addr_error(err, addr))?;
-
-        // `backlog` parameter to `TcpBuilder::listen() is directly passed to `listen(2)` system call.
-        // If it is too small, clients may not `connect(2)` to the server.
-        // Here, `backlog` is intended to be as large as `net.core.somaxconn` kernel parameter,
-        tcp.listen(256)?;
-
-        Ok(TcpAcceptor::new(
-            tcp.into(),
-            self.rw_timeout,
-            self.rx.clone(),
-            self.accept_timeout,
-        ))
+        let listener = match self.take_inherited(addr)? {
+            Some(listener) => listener,
+            None => bind_listener(addr)?,
+        };
+        Ok(TcpAcceptor {
+            listener,
+            rw_timeout: self.rw_timeout,
+            rx: self.rx.clone(),
+            accept_timeout: self.accept_timeout,
+            keepalive: self.keepalive,
+            #[cfg(unix)]
+            wakeup: self.wakeup.clone(),
+        })
+    }
+
+    fn wake_acceptors(&self) {
+        #[cfg(unix)]
+        if let Some(wakeup) = &self.wakeup {
+            wakeup.wake();
+        }
+    }
+}
+
+/// Address of clients connected through a unix domain socket
+///
+/// Connect rules and rate limits see these clients as localhost.
+#[cfg(unix)]
+pub const UNIX_CLIENT_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+#[cfg(unix)]
+pub struct UnixAcceptor {
+    listener: UnixListener,
+    path: PathBuf,
+    rw_timeout: Option<Duration>,
+    /// receive termination message
+    rx: Arc<Mutex<Receiver<()>>>,
+    /// timeout for accept
+    accept_timeout: Option<Duration>,
+    /// woken up with the termination message instead of polling it every `accept_timeout`
+    wakeup: Option<Arc<Wakeup>>,
+}
+
+#[cfg(unix)]
+impl UnixAcceptor {
+    /// `None` if woken up to check the termination message
+    fn accept_timeout(&self) -> io::Result<Option<UnixStream>> {
+        let fd = self.listener.as_raw_fd();
+        let ready = match &self.wakeup {
+            Some(wakeup) => wait_acceptable(fd, Some(wakeup), None)?,
+            None => wait_acceptable(fd, None, self.accept_timeout)?,
+        };
+        if ready == Ready::Woken {
+            return Ok(None);
+        }
+        let (strm, _) = self.listener.accept()?;
+        strm.set_read_timeout(self.rw_timeout)?;
+        strm.set_write_timeout(self.rw_timeout)?;
+        Ok(Some(strm))
+    }
+}
+
+#[cfg(unix)]
+impl Iterator for UnixAcceptor {
+    type Item = (UnixStream, SocketAddr);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            check_done!(&self.rx);
+            match self.accept_timeout() {
+                Ok(Some(strm)) => return Some((strm, UNIX_CLIENT_ADDR)),
+                Ok(None) => {}
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                Err(err) => {
+                    error!("accept error: {}: {}", self.path.display(), err);
+                    return None;
+                }
+            }
+        }
     }
 }
 
+/// remove the socket file when the server stops listening
+#[cfg(unix)]
+impl Drop for UnixAcceptor {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!("remove socket: {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Listen on a unix domain socket instead of tcp addresses
+///
+/// The socket is bound only once, so the server should have a single listening address.
+#[cfg(unix)]
+pub struct UnixBinder {
+    path: PathBuf,
+    rw_timeout: Option<Duration>,
+    /// receiver for Acceptor termination message
+    rx: Arc<Mutex<Receiver<()>>>,
+    accept_timeout: Option<Duration>,
+    wakeup: Option<Arc<Wakeup>>,
+}
+
+#[cfg(unix)]
+impl UnixBinder {
+    pub fn new(
+        path: PathBuf,
+        rw_timeout: Option<Duration>,
+        rx: Arc<Mutex<Receiver<()>>>,
+        accept_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            path,
+            rw_timeout,
+            rx,
+            accept_timeout,
+            wakeup: acceptor_wakeup(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Binder for UnixBinder {
+    type Stream = UnixStream;
+    type Iter = UnixAcceptor;
+    /// `_addr` is ignored, and the socket is bound to the path
+    fn bind(&self, _addr: SocketAddr) -> Result<Self::Iter, Error> {
+        Ok(UnixAcceptor {
+            listener: bind_unix_listener(&self.path)?,
+            path: self.path.clone(),
+            rw_timeout: self.rw_timeout,
+            rx: self.rx.clone(),
+            accept_timeout: self.accept_timeout,
+            wakeup: self.wakeup.clone(),
+        })
+    }
+
+    fn wake_acceptors(&self) {
+        if let Some(wakeup) = &self.wakeup {
+            wakeup.wake();
+        }
+    }
+}
+
+/// create a unix domain socket listening on `path`
+///
+/// A socket file left by the previous run is replaced, but other files are not.
+#[cfg(unix)]
+pub(crate) fn bind_unix_listener(path: &Path) -> Result<UnixListener, Error> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path).map_err(|err| {
+        err.context(ErrorKind::message_fmt(format_args!(
+            "bind: {}",
+            path.display()
+        )))
+        .into()
+    })
+}
+
+/// Address of a client with IPv4 clients of dual-stack listeners as IPv4 (not `::ffff:a.b.c.d`),
+/// so that rules, rate limits and bans see the same address on any listener
+pub(crate) fn canonical_peer(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// create a listening socket bound to `addr`
+///
+/// A socket bound to an IPv6 address also accepts IPv4 clients (dual-stack) if the platform allows.
+pub(crate) fn bind_listener(addr: SocketAddr) -> Result<TcpListener, Error> {
+    let tcp = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    tcp.set_reuse_address(true)
+        .map_err(|err| addr_error(err, addr))?;
+    if addr.is_ipv6() {
+        if let Err(err) = tcp.set_only_v6(false) {
+            warn!("dual-stack is not available: {}: {}", addr, err);
+        }
+    }
+    tcp.bind(&addr.into())
+        .map_err(|err| addr_error(err, addr))?;
+
+    // `backlog` parameter to `TcpBuilder::listen() is directly passed to `listen(2)` system call.
+    // If it is too small, clients may not `connect(2)` to the server.
+    // Here, `backlog` is intended to be as large as `net.core.somaxconn` kernel parameter,
+    tcp.listen(256)?;
+    Ok(tcp.into())
+}
+
+/// the first file descriptor passed by socket activation (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed by systemd socket activation (see `sd_listen_fds(3)`)
+///
+/// Returns no listeners unless `LISTEN_PID` is the current process.
+/// `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` are removed not to be inherited by child processes,
+/// so this should be called once before spawning threads.
+/// Only TCP sockets are supported.
+#[cfg(unix)]
+pub fn listen_fds() -> Result<Vec<TcpListener>, Error> {
+    let pid = std::env::var("LISTEN_PID");
+    let fds = std::env::var("LISTEN_FDS");
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    let (pid, fds) = match (pid, fds) {
+        (Ok(pid), Ok(fds)) if pid.parse() == Ok(std::process::id()) => (pid, fds),
+        _ => return Ok(vec![]),
+    };
+    let count: RawFd = fds.parse().map_err(|_| {
+        ErrorKind::message_fmt(format_args!("LISTEN_FDS: {} (LISTEN_PID: {})", fds, pid))
+    })?;
+    (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count))
+        .map(inherited_listener)
+        .collect()
+}
+
+/// take the ownership of the listening socket `fd`
+#[cfg(unix)]
+fn inherited_listener(fd: RawFd) -> Result<TcpListener, Error> {
+    // safety: passed only to this process by the service manager, and not owned by others
+    let sock = unsafe { socket2::Socket::from_raw_fd(fd) };
+    sock.set_cloexec(true)?;
+    let is_tcp =
+        sock.r#type()? == socket2::Type::STREAM && sock.local_addr()?.as_socket().is_some();
+    if !is_tcp {
+        return Err(
+            ErrorKind::message_fmt(format_args!("LISTEN_FDS: {} is not a TCP socket", fd)).into(),
+        );
+    }
+    Ok(sock.into())
+}
+
 fn addr_error(io_err: io::Error, addr: SocketAddr) -> model::Error {
     match io_err.kind() {
         io::ErrorKind::AddrInUse => ErrorKind::AddressAlreadInUse { addr }.into(),
         io::ErrorKind::AddrNotAvailable => ErrorKind::AddressNotAvailable { addr }.into(),
-        _ => io_err.context(ErrorKind::Io),
+        _ => io_err.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn echo_once(listener: TcpListener) -> std::thread::JoinHandle<SocketAddr> {
+        std::thread::spawn(move || {
+            let (mut strm, peer) = listener.accept().unwrap();
+            let mut buf = [0; 5];
+            strm.read_exact(&mut buf).unwrap();
+            strm.write_all(&buf).unwrap();
+            peer
+        })
+    }
+
+    fn echo(addr: SocketAddr) {
+        let mut strm = TcpStream::connect(addr).unwrap();
+        strm.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        strm.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn ipv6_listener() {
+        let listener = bind_listener("[::1]:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.is_ipv6());
+        let handle = echo_once(listener);
+        echo(addr);
+        assert_eq!(handle.join().unwrap().ip(), Ipv6Addr::LOCALHOST);
+    }
+
+    #[test]
+    fn unix_listener() {
+        let path = std::env::temp_dir().join(format!("gatekeeper-{}.sock", std::process::id()));
+        let (_tx, rx) = mpsc::channel();
+        let binder = UnixBinder::new(path.clone(), None, Arc::new(Mutex::new(rx)), None);
+        // a stale socket is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        let mut acceptor = binder.bind(UNIX_CLIENT_ADDR).unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (mut strm, addr) = acceptor.next().unwrap();
+        assert_eq!(addr, UNIX_CLIENT_ADDR);
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        strm.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        drop(acceptor);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wake_acceptor() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let binder = TcpBinder::new(
+            None,
+            Arc::new(Mutex::new(rx)),
+            Some(Duration::from_secs(60)),
+        );
+        let mut acceptor = binder.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = acceptor.listener.local_addr().unwrap();
+        let th = std::thread::spawn(move || {
+            let accepted = acceptor.next().map(|(_, peer)| peer);
+            (accepted, acceptor.next().is_none())
+        });
+        let client = TcpStream::connect(addr).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        // terminated at once, not after the accept timeout
+        let start = std::time::Instant::now();
+        tx.send(()).unwrap();
+        binder.wake_acceptors();
+        let (accepted, terminated) = th.join().unwrap();
+        assert_eq!(accepted, Some(client.local_addr().unwrap()));
+        assert!(terminated);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn inherited_listeners() {
+        let (_tx, rx) = mpsc::sync_channel(1);
+        let mut binder = TcpBinder::new(None, Arc::new(Mutex::new(rx)), None);
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        binder.set_inherited_listeners(vec![listener]);
+
+        // the address is in use by the inherited listener
+        let mut acceptor = binder.bind(addr).unwrap();
+        let th = std::thread::spawn(move || (acceptor.next().map(|(_, peer)| peer), acceptor));
+        let client = TcpStream::connect(addr).unwrap();
+        let (peer, _acceptor) = th.join().unwrap();
+        assert_eq!(peer, Some(client.local_addr().unwrap()));
+        // taken by the first acceptor, which is still listening
+        assert!(binder.bind(addr).is_err());
+    }
+
+    #[test]
+    fn dual_stack_listener() {
+        let listener = bind_listener("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = echo_once(listener);
+        echo(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+        // IPv4 clients are seen as IPv4-mapped IPv6 addresses
+        let peer = handle.join().unwrap();
+        assert_eq!(
+            peer.ip(),
+            Ipv4Addr::LOCALHOST.to_ipv6_mapped(),
+            "peer: {}",
+            peer
+        );
     }
-    .into()
 }
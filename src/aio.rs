@@ -0,0 +1,35 @@
+//! Async proxy server on [tokio](https://tokio.rs)
+//!
+//! This module is available with `tokio` feature.
+//!
+//! The server in [`crate::server`] spawns OS threads for each session (and its relays).
+//! [`aio::Server`](Server) performs sessions as tokio tasks instead,
+//! so that many concurrent clients are served with a few threads.
+//!
+//! Only `CONNECT` command is supported.
+//! Sessions are reported to [`ServerConfig::session_logger`](crate::ServerConfig::session_logger)
+//! as well as the sync server.
+//!
+//! ```rust
+//! # use std::time::Duration;
+//! use gatekeeper::{aio, ServerCommand, ServerConfig};
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let mut config = ServerConfig::default();
+//! config.server_port = 1082; // conflict to other example
+//! let (mut server, tx) = aio::Server::new(config);
+//! let th = tokio::spawn(async move { server.serve().await });
+//! tokio::time::sleep(Duration::from_secs(1)).await;
+//! tx.send(ServerCommand::Terminate).unwrap();
+//! th.await.unwrap().unwrap();
+//! # }
+//! ```
+pub mod acceptor;
+pub mod byte_stream;
+pub mod connector;
+mod relay;
+pub mod server;
+mod session;
+mod socks_stream;
+
+pub use server::Server;
@@ -0,0 +1,76 @@
+use std::future::Future;
+use std::net::SocketAddr;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::acceptor::bind_listener;
+use crate::aio::byte_stream::ByteStream;
+use crate::config::TcpKeepalive;
+use crate::model::Error;
+
+/// Source of connections from clients
+pub trait Acceptor: Send {
+    type Stream: ByteStream + 'static;
+    fn accept(&mut self) -> impl Future<Output = Result<(Self::Stream, SocketAddr), Error>> + Send;
+}
+
+pub trait Binder {
+    type Stream: ByteStream + 'static;
+    type Acceptor: Acceptor<Stream = Self::Stream> + 'static;
+    fn bind(&self, addr: SocketAddr) -> impl Future<Output = Result<Self::Acceptor, Error>> + Send;
+}
+
+impl Acceptor for TcpListener {
+    type Stream = TcpStream;
+    async fn accept(&mut self) -> Result<(Self::Stream, SocketAddr), Error> {
+        Ok(TcpListener::accept(self).await?)
+    }
+}
+
+/// Listener enabling keepalive on accepted connections
+#[derive(Debug)]
+pub struct TcpAcceptor {
+    listener: TcpListener,
+    keepalive: Option<TcpKeepalive>,
+}
+
+impl Acceptor for TcpAcceptor {
+    type Stream = TcpStream;
+    async fn accept(&mut self) -> Result<(Self::Stream, SocketAddr), Error> {
+        let (strm, addr) = self.listener.accept().await?;
+        if let Some(keepalive) = self.keepalive {
+            keepalive.apply(&strm)?;
+        }
+        Ok((strm, addr))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TcpBinder {
+    keepalive: Option<TcpKeepalive>,
+}
+
+impl TcpBinder {
+    pub fn new() -> Self {
+        Self { keepalive: None }
+    }
+
+    /// enable keepalive probes on connections from clients
+    pub fn set_tcp_keepalive(&mut self, keepalive: Option<TcpKeepalive>) -> &mut Self {
+        self.keepalive = keepalive;
+        self
+    }
+}
+
+impl Binder for TcpBinder {
+    type Stream = TcpStream;
+    type Acceptor = TcpAcceptor;
+    async fn bind(&self, addr: SocketAddr) -> Result<Self::Acceptor, Error> {
+        let listener = bind_listener(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpAcceptor {
+            listener: TcpListener::from_std(listener)?,
+            keepalive: self.keepalive,
+        })
+    }
+}
@@ -0,0 +1,38 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// async read/write operations on byte stream
+pub trait ByteStream: fmt::Debug + AsyncRead + AsyncWrite + Unpin + Send {
+    /// Local address of the underlying connection, if any
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// byte stream on tcp connection
+impl ByteStream for TcpStream {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+/// Boxed stream
+impl<S: ByteStream + ?Sized> ByteStream for Box<S> {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+}
+
+pub type BoxedStream<'a> = Box<dyn ByteStream + 'a>;
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    /// in-memory byte stream
+    impl ByteStream for DuplexStream {}
+}
@@ -0,0 +1,215 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::*;
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::aio::byte_stream::ByteStream;
+use crate::config::TcpKeepalive;
+use crate::connector::conn_error;
+use crate::model::{Address, Error, L4Protocol, OutboundBind};
+
+pub trait Connector: Send + Sync {
+    type B: ByteStream + 'static;
+    fn connect_byte_stream(
+        &self,
+        addr: Address,
+    ) -> impl Future<Output = Result<(Self::B, SocketAddr), Error>> + Send;
+    /// connect to `addr` from the local address or device of `bind` (e.g. given by a rule)
+    ///
+    /// Connectors not binding outbound connections connect as `connect_byte_stream`.
+    fn connect_byte_stream_from(
+        &self,
+        addr: Address,
+        bind: &OutboundBind,
+    ) -> impl Future<Output = Result<(Self::B, SocketAddr), Error>> + Send {
+        debug!("outbound bind is ignored: {:?}", bind);
+        self.connect_byte_stream(addr)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TcpConnector {
+    /// timeout of connecting to a destination
+    connect_timeout: Option<Duration>,
+    /// keepalive probes on connections to destinations
+    keepalive: Option<TcpKeepalive>,
+    /// local address or device of connections to destinations
+    outbound_bind: Option<OutboundBind>,
+}
+
+impl TcpConnector {
+    pub fn new() -> Self {
+        Self {
+            connect_timeout: None,
+            keepalive: None,
+            outbound_bind: None,
+        }
+    }
+
+    /// enable keepalive probes on connections to destinations
+    pub fn set_tcp_keepalive(&mut self, keepalive: Option<TcpKeepalive>) -> &mut Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// give up connecting after `timeout` (`None` waits for the system)
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// bind connections to destinations to the local address or device of `bind`
+    pub fn set_outbound_bind(&mut self, bind: Option<OutboundBind>) -> &mut Self {
+        self.outbound_bind = bind;
+        self
+    }
+
+    /// connect to `addr` from `bind`, or the default local address if `None`
+    async fn connect_from(
+        &self,
+        addr: Address,
+        bind: Option<&OutboundBind>,
+    ) -> Result<(TcpStream, SocketAddr), Error> {
+        let connect = async {
+            match (&addr, bind) {
+                (Address::Domain(host, port), None) => {
+                    TcpStream::connect((host.as_str(), *port)).await
+                }
+                (addr, None) => TcpStream::connect(addr.socket_addr().expect("ip address")).await,
+                (addr, Some(bind)) => connect_bound(addr, bind).await,
+            }
+        };
+        let strm = match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => connect.await,
+        }
+        .map_err(|err| conn_error(err, addr, L4Protocol::Tcp))?;
+        if let Some(keepalive) = self.keepalive {
+            keepalive.apply(&strm)?;
+        }
+
+        let peer = strm.peer_addr()?;
+        Ok((strm, peer))
+    }
+}
+
+impl Connector for TcpConnector {
+    type B = TcpStream;
+    async fn connect_byte_stream(&self, addr: Address) -> Result<(Self::B, SocketAddr), Error> {
+        self.connect_from(addr, self.outbound_bind.as_ref()).await
+    }
+    async fn connect_byte_stream_from(
+        &self,
+        addr: Address,
+        bind: &OutboundBind,
+    ) -> Result<(Self::B, SocketAddr), Error> {
+        self.connect_from(addr, Some(bind)).await
+    }
+}
+
+/// connect to the first address of `addr` reachable from the local address or device of `bind`
+///
+/// The local address only connects to addresses of the same family.
+async fn connect_bound(addr: &Address, bind: &OutboundBind) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = match addr {
+        Address::Domain(host, port) => tokio::net::lookup_host((host.as_str(), *port))
+            .await?
+            .collect(),
+        addr => vec![addr.socket_addr().expect("ip address")],
+    };
+    let mut last_err = None;
+    for dst in addrs {
+        if matches!(bind.addr, Some(local) if local.is_ipv4() != dst.is_ipv4()) {
+            continue;
+        }
+        let sock = if dst.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(device) = &bind.device {
+            bind_device(&sock, device)?;
+        }
+        if let Some(local) = bind.addr {
+            sock.bind(SocketAddr::new(local, 0))?;
+        }
+        match sock.connect(dst).await {
+            Ok(strm) => return Ok(strm),
+            Err(err) => {
+                debug!("connect error: {}: {}", dst, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no addresses to connect from {:?}", bind),
+        )
+    }))
+}
+
+/// send packets of `sock` only through the network device `device` (`SO_BINDTODEVICE`)
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(sock: &TcpSocket, device: &str) -> io::Result<()> {
+    sock.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_sock: &TcpSocket, device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("binding to a device is not supported: {}", device),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{ErrorKind, IpAddr};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn outbound_bind() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connector = TcpConnector::new();
+        // the whole 127.0.0.0/8 is assigned to the loopback on linux
+        connector.set_outbound_bind(Some(OutboundBind::from_addr([127, 0, 0, 2].into())));
+        let (strm, _) = connector.connect_byte_stream(addr.into()).await.unwrap();
+        assert_eq!(
+            strm.local_addr().unwrap().ip(),
+            IpAddr::from([127, 0, 0, 2])
+        );
+        // overridden by a rule
+        let bind = OutboundBind::from_addr([127, 0, 0, 3].into());
+        let (strm, _) = connector
+            .connect_byte_stream_from(addr.into(), &bind)
+            .await
+            .unwrap();
+        assert_eq!(
+            strm.local_addr().unwrap().ip(),
+            IpAddr::from([127, 0, 0, 3])
+        );
+
+        // the local address can not connect to the other family
+        let bind = OutboundBind::from_addr("::1".parse().unwrap());
+        let err = connector
+            .connect_byte_stream_from(addr.into(), &bind)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Io { .. }));
+
+        let bind = OutboundBind::from_device("no-such-device0");
+        assert!(connector
+            .connect_byte_stream_from(addr.into(), &bind)
+            .await
+            .is_err());
+    }
+}
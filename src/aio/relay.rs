@@ -0,0 +1,274 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use log::*;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::aio::byte_stream::ByteStream;
+use crate::model::Error;
+use crate::relay::{Bandwidth, Lifetime, SharedBucket, Traffic, Watchdog};
+
+/// Interval to check the lifetime of relays at most
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stream counts read bytes
+struct Counted<S> {
+    inner: S,
+    count: Arc<AtomicU64>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let size = buf.filled().len() - filled;
+        self.count.fetch_add(size as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Stream throttles reading by token buckets
+struct Throttled<S> {
+    inner: S,
+    /// no buckets means unlimited
+    buckets: Vec<SharedBucket>,
+    /// waiting for tokens to be filled
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    fn new(inner: S, buckets: Vec<SharedBucket>) -> Self {
+        Self {
+            inner,
+            buckets,
+            sleep: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.buckets.is_empty() || buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        // a bucket is consistent even if another relay panicked while holding it
+        let allowed = loop {
+            if let Some(sleep) = this.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            let allowed = this
+                .buckets
+                .iter()
+                .map(|b| b.lock().unwrap_or_else(PoisonError::into_inner).available())
+                .min()
+                .unwrap_or(0);
+            if allowed > 0 {
+                break allowed.min(buf.remaining());
+            }
+            let wait = this
+                .buckets
+                .iter()
+                .map(|b| {
+                    b.lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .wait_time(1)
+                })
+                .max()
+                .unwrap_or_default();
+            this.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        };
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let size = limited.filled().len();
+        buf.advance(size);
+        for bucket in &this.buckets {
+            bucket
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .consume(size);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Complete when the relay exceeds `lifetime`
+async fn watch(traffic: Traffic, lifetime: Lifetime) -> &'static str {
+    let interval = [lifetime.idle_timeout, lifetime.max_duration]
+        .into_iter()
+        .flatten()
+        .fold(WATCH_INTERVAL, Duration::min);
+    let mut watchdog = Watchdog::new(traffic, lifetime);
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Some(reason) = watchdog.expired() {
+            return reason;
+        }
+    }
+}
+
+/// Relay bytes between `client_conn` and `server_conn`
+///
+/// This completes when both directions reach EOF, or the relay exceeds `lifetime`.
+/// Relayed bytes are counted by `traffic` and limited by `bandwidth`.
+pub async fn relay(
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+    client_conn: impl ByteStream,
+    server_conn: impl ByteStream,
+    traffic: Traffic,
+    lifetime: Lifetime,
+    bandwidth: Bandwidth,
+) -> Result<(), Error> {
+    info!("spawned relay: {} <=> {}", client_addr, server_addr);
+    let mut client_conn = Counted {
+        inner: Throttled::new(client_conn, bandwidth.upload),
+        count: traffic.upload.clone(),
+    };
+    let mut server_conn = Counted {
+        inner: Throttled::new(server_conn, bandwidth.download),
+        count: traffic.download.clone(),
+    };
+    tokio::select! {
+        res = tokio::io::copy_bidirectional(&mut client_conn, &mut server_conn) => {
+            let (outbound, incoming) = res?;
+            info!(
+                "relay has been finished: {} <=> {}: {} / {} bytes",
+                client_addr, server_addr, outbound, incoming
+            );
+        }
+        reason = watch(traffic, lifetime) => {
+            info!(
+                "relay is terminated: {} <=> {}: {}",
+                client_addr, server_addr, reason
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let (mut client, client_conn) = tokio::io::duplex(1024);
+        let (mut server, server_conn) = tokio::io::duplex(1024);
+        let traffic = Traffic::default();
+        let start = Instant::now();
+        let relay = tokio::spawn(relay(
+            "127.0.0.1:12345".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+            client_conn,
+            server_conn,
+            traffic.clone(),
+            Lifetime {
+                idle_timeout: Some(Duration::from_millis(200)),
+                max_duration: None,
+            },
+            Bandwidth::default(),
+        ));
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        relay.await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(traffic.upload(), 5);
+        // connections are closed by the relay
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn throttle() {
+        use crate::config::RateLimit;
+
+        let (mut client, client_conn) = tokio::io::duplex(4096);
+        let (mut server, server_conn) = tokio::io::duplex(4096);
+        let relay = tokio::spawn(relay(
+            "127.0.0.1:12345".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+            client_conn,
+            server_conn,
+            Traffic::default(),
+            Lifetime::default(),
+            Bandwidth::new(Some(RateLimit {
+                upload_bps: 1000,
+                download_bps: 0,
+            })),
+        ));
+
+        let start = Instant::now();
+        client.write_all(&[0u8; 2500]).await.unwrap();
+        let mut buf = [0u8; 2500];
+        server.read_exact(&mut buf).await.unwrap();
+        // the first 1000 bytes are sent without waiting
+        assert!(start.elapsed() >= Duration::from_millis(1400));
+
+        let start = Instant::now();
+        server.write_all(&[0u8; 2500]).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        drop(client);
+        drop(server);
+        relay.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,525 @@
+//! Async proxy server main process
+//!
+//! The workflow is the same as [`crate::server`],
+//! except that the acceptor and sessions are tokio tasks instead of threads.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::*;
+use rand::prelude::*;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::acceptor::canonical_peer;
+use crate::aio::acceptor::{Acceptor, Binder, TcpBinder};
+use crate::aio::byte_stream::ByteStream;
+use crate::aio::connector::{Connector, TcpConnector};
+use crate::aio::session::{reject_client, Session, SessionHandle};
+use crate::client_ban::{is_failed_attempt, ClientBans};
+use crate::config::ServerConfig;
+use crate::connection_limiter::ConnectionLimiter;
+use crate::destination_limiter::{DestinationLimiter, DestinationSlot};
+use crate::error::Error;
+use crate::event::SessionFinishedEvent;
+use crate::metrics::{Counters, Metrics, Outcome, ServerStatus};
+use crate::model::{ConnectError, ErrorKind, ProtocolVersion, SocketAddr};
+use crate::policy::ConnectPolicy;
+use crate::relay::{Bandwidth, Lifetime};
+use crate::server_command::ServerCommand;
+use crate::session::{SessionId, SessionInfo, SessionStats};
+
+pub struct Server<S, T, C> {
+    config: ServerConfig,
+    tx_cmd: mpsc::UnboundedSender<ServerCommand<S>>,
+    rx_cmd: mpsc::UnboundedReceiver<ServerCommand<S>>,
+    /// bind server address
+    binder: T,
+    /// make connection to service host
+    connector: C,
+    protocol_version: ProtocolVersion,
+    session: HashMap<SessionId, SessionHandle>,
+    /// cumulative counters for metrics
+    counters: Counters,
+    /// bandwidth shared by all sessions
+    bandwidth: Bandwidth,
+    /// rate limit of connections from each client
+    connection_limiter: Option<ConnectionLimiter>,
+    /// clients failing repeatedly
+    client_bans: Option<ClientBans>,
+    /// sessions through rules with `max_sessions`, released on `Disconnect`
+    destination_limiter: Arc<DestinationLimiter>,
+    /// policy shared by sessions, built again when the rule is reloaded
+    policy: Arc<dyn ConnectPolicy>,
+    /// random context for generating SessionIds
+    id_rng: StdRng,
+}
+
+/// spawn a task send accepted stream to `tx`
+///
+/// The task is stopped when a message is sent to the returned `Sender`.
+fn spawn_acceptor<A>(
+    mut acceptor: A,
+    tx: mpsc::UnboundedSender<ServerCommand<A::Stream>>,
+) -> (JoinHandle<()>, oneshot::Sender<()>)
+where
+    A: Acceptor + 'static,
+{
+    use ServerCommand::*;
+    let (tx_done, mut rx_done) = oneshot::channel();
+    let handle = tokio::spawn(async move {
+        loop {
+            let (strm, addr) = tokio::select! {
+                _ = &mut rx_done => break,
+                accepted = acceptor.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        error!("accept error: {}", err);
+                        break;
+                    }
+                },
+            };
+            if tx.send(Connect(strm, canonical_peer(addr))).is_err() {
+                info!("disconnected ServerCommand chan");
+                break;
+            }
+        }
+    });
+    (handle, tx_done)
+}
+
+/// spawn a task perform `Session.start`
+///
+/// `Disconnect` is sent to `tx_cmd` when the session is finished.
+fn spawn_session<S, D>(
+    session: Session<D>,
+    addr: SocketAddr,
+    strm: S,
+    tx_cmd: mpsc::UnboundedSender<ServerCommand<S>>,
+) -> SessionHandle
+where
+    S: ByteStream + 'static,
+    D: Connector + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let id = session.id;
+    let traffic = session.traffic();
+    let destination = session.destination();
+    let handle = tokio::spawn(async move {
+        let res = tokio::select! {
+            res = session.start(addr, strm) => res,
+            _ = rx => Ok(()),
+        };
+        tx_cmd.send(ServerCommand::Disconnect(id)).ok();
+        res
+    });
+    SessionHandle::new(id, addr, handle, tx, traffic, destination)
+}
+
+impl Server<TcpStream, TcpBinder, TcpConnector> {
+    pub fn new(config: ServerConfig) -> (Self, mpsc::UnboundedSender<ServerCommand<TcpStream>>) {
+        let mut connector = TcpConnector::new();
+        connector
+            .set_connect_timeout(config.connect_timeout)
+            .set_tcp_keepalive(config.tcp_keepalive)
+            .set_outbound_bind(config.outbound_bind_addr.clone());
+        let mut binder = TcpBinder::new();
+        binder.set_tcp_keepalive(config.tcp_keepalive);
+        Server::with_binder(config, binder, connector)
+    }
+}
+
+impl<S, T, C> Server<S, T, C>
+where
+    S: ByteStream + 'static,
+    T: Binder<Stream = S>,
+    C: Connector + Clone + 'static,
+{
+    pub fn with_binder(
+        config: ServerConfig,
+        binder: T,
+        connector: C,
+    ) -> (Self, mpsc::UnboundedSender<ServerCommand<S>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                bandwidth: Bandwidth::new(config.global_rate_limit),
+                connection_limiter: config.connection_rate_limit.map(ConnectionLimiter::new),
+                client_bans: config.client_ban.map(ClientBans::new),
+                destination_limiter: Arc::default(),
+                policy: config.connect_policy(),
+                config,
+                tx_cmd: tx.clone(),
+                rx_cmd: rx,
+                binder,
+                connector,
+                protocol_version: ProtocolVersion::from(5),
+                session: HashMap::new(),
+                counters: Counters::default(),
+                id_rng: StdRng::from_entropy(),
+            },
+            tx,
+        )
+    }
+
+    fn next_session_id(&mut self) -> SessionId {
+        loop {
+            let next_candidate = self.id_rng.next_u32().into();
+            if self.session.contains_key(&next_candidate) {
+                continue;
+            }
+            debug!("next session id is issued: {}", next_candidate);
+            return next_candidate;
+        }
+    }
+
+    /// `ServerConfig::max_sessions` sessions are running
+    fn is_full(&self) -> bool {
+        matches!(self.config.max_sessions, Some(max) if self.session.len() >= max)
+    }
+
+    /// `false` if the client exceeds `ServerConfig::connection_rate_limit`
+    fn check_connection_rate(&mut self, addr: SocketAddr) -> bool {
+        match &mut self.connection_limiter {
+            Some(limiter) => limiter.check(addr.ip()),
+            None => true,
+        }
+    }
+
+    /// the client is banned by `ServerConfig::client_ban`
+    fn is_banned(&mut self, addr: SocketAddr) -> bool {
+        match &mut self.client_bans {
+            Some(bans) => bans.is_banned(addr.ip()),
+            None => false,
+        }
+    }
+
+    /// Timeout of reading requests of clients
+    ///
+    /// Streams of tasks have no read timeouts,
+    /// so `ServerConfig::client_rw_timeout` bounds the handshake if `handshake_timeout` is not set.
+    fn handshake_timeout(&self) -> Option<Duration> {
+        self.config
+            .handshake_timeout
+            .or(self.config.client_rw_timeout)
+    }
+
+    /// reply `cerr` to the client without starting a session
+    fn reject(&mut self, stream: S, addr: SocketAddr, cerr: ConnectError) {
+        self.counters.accept();
+        self.counters.reject();
+        let version = self.protocol_version;
+        let server_addr = self.config.server_addr();
+        let timeout = self.handshake_timeout();
+        tokio::spawn(async move {
+            let reject = reject_client(version, server_addr, stream, cerr);
+            let res = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, reject)
+                    .await
+                    .unwrap_or_else(|_| Err(ErrorKind::HandshakeTimedOut.into())),
+                None => reject.await,
+            };
+            if let Err(err) = res {
+                debug!("reject error: {}: {}", addr, err);
+            }
+        });
+    }
+
+    /// Statistics of running sessions
+    pub fn session_stats(&self) -> HashMap<SessionId, SessionStats> {
+        self.session
+            .iter()
+            .map(|(id, ss)| (*id, ss.stats()))
+            .collect()
+    }
+
+    /// Running sessions in order of start time
+    pub fn list_sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<_> = self
+            .session
+            .iter()
+            .map(|(id, ss)| SessionInfo {
+                id: *id,
+                state: ss.state(),
+                stats: ss.stats(),
+            })
+            .collect();
+        sessions.sort_by_key(|info| (info.stats.started_at, info.id));
+        sessions
+    }
+
+    /// Snapshot of server metrics
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.counters.snapshot(self.session_stats());
+        if let Some(bans) = &self.client_bans {
+            metrics.banned_clients = bans.banned_clients();
+        }
+        metrics
+    }
+
+    /// Snapshot of server metrics and running sessions
+    pub fn status(&self) -> ServerStatus {
+        ServerStatus {
+            metrics: self.metrics(),
+            sessions: self.list_sessions(),
+        }
+    }
+
+    /// Server main loop
+    ///
+    /// An acceptor task is spawned for each of `ServerConfig::listen_addrs`.
+    pub async fn serve(&mut self) -> Result<(), Error> {
+        let mut accept_task = vec![];
+        let mut tx_done = vec![];
+        for addr in self.config.listen_addrs() {
+            let acceptor = self.binder.bind(addr).await?;
+            let (task, tx) = spawn_acceptor(acceptor, self.tx_cmd.clone());
+            accept_task.push(task);
+            tx_done.push(tx);
+        }
+        // taken by `Shutdown` to stop the acceptors
+        let mut tx_acceptor_done = Some(tx_done);
+
+        while let Some(cmd) = self.rx_cmd.recv().await {
+            use ServerCommand::*;
+            info!("cmd: {:?}", cmd);
+            match cmd {
+                Terminate => {
+                    for tx in tx_acceptor_done.take().into_iter().flatten() {
+                        tx.send(()).ok();
+                    }
+                    for (_, ss) in self.session.drain() {
+                        ss.stop().await.1.ok();
+                    }
+                    debug!("join accept task");
+                    for task in accept_task {
+                        task.await.ok();
+                    }
+                    break;
+                }
+                Shutdown { grace } => {
+                    if let Some(tx_done) = tx_acceptor_done.take() {
+                        for tx in tx_done {
+                            tx.send(()).ok();
+                        }
+                        let tx = self.tx_cmd.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(grace).await;
+                            tx.send(Terminate).ok();
+                        });
+                    }
+                }
+                // `ServerConfig::http_connect_addr` is not listened on
+                ConnectHttp(_, addr) => {
+                    warn!("http connect is not supported, reject connection: {}", addr);
+                }
+                // nor `ServerConfig::forwards`
+                ConnectForward(_, addr, _) => {
+                    warn!("forwarding is not supported, reject connection: {}", addr);
+                }
+                Connect(stream, addr) if tx_acceptor_done.is_none() => {
+                    info!("reject connection in shutdown: {}", addr);
+                    self.reject(stream, addr, ConnectError::ServerFailure);
+                }
+                Connect(_, addr) if self.is_banned(addr) => {
+                    debug!("banned client, close connection: {}", addr);
+                    self.counters.accept();
+                    self.counters.reject();
+                }
+                Connect(_, addr) if !self.check_connection_rate(addr) => {
+                    warn!("connection rate limit exceeded, close connection: {}", addr);
+                    self.counters.accept();
+                    self.counters.reject();
+                }
+                Connect(stream, addr) if self.is_full() => {
+                    warn!("too many sessions, reject connection: {}", addr);
+                    let cerr = self.config.max_sessions_reply.clone();
+                    self.reject(stream, addr, cerr);
+                }
+                Connect(stream, addr) => {
+                    self.counters.accept();
+                    let mut session = Session::new(
+                        self.next_session_id(),
+                        self.protocol_version,
+                        self.connector.clone(),
+                        self.config.credentials.clone(),
+                        self.config.server_addr(),
+                        self.policy.clone(),
+                    );
+                    session.logger = self.config.audit_logger();
+                    session.events = self.config.event_handler.clone();
+                    session.reply_addr = self.config.reply_addr;
+                    session.bandwidth = Bandwidth::new(self.config.rate_limit).and(&self.bandwidth);
+                    session.handshake_timeout = self.handshake_timeout();
+                    session.lenient_version = self.config.lenient_version;
+                    session.allowed_methods = self.config.allowed_methods.clone();
+                    session.rewriter = self.config.rewriter.clone();
+                    session.destination_slot = Some(DestinationSlot::new(
+                        self.destination_limiter.clone(),
+                        session.id,
+                    ));
+                    session.lifetime = Lifetime {
+                        idle_timeout: self.config.idle_timeout,
+                        max_duration: self.config.max_session_duration,
+                    };
+                    let id = session.id;
+                    self.session.insert(
+                        id,
+                        spawn_session(session, addr, stream, self.tx_cmd.clone()),
+                    );
+                }
+                QueryStats(tx) => {
+                    tx.send(self.session_stats()).ok();
+                }
+                ListSessions(tx) => {
+                    tx.send(self.list_sessions()).ok();
+                }
+                Kill(id) => match self.session.get_mut(&id) {
+                    // `Disconnect` is sent when the session is stopped
+                    Some(session) => session.kill(),
+                    None => warn!("no such session: {}", id),
+                },
+                QueryMetrics(tx) => {
+                    tx.send(self.metrics()).ok();
+                }
+                QueryStatus(tx) => {
+                    tx.send(self.status()).ok();
+                }
+                ReloadRules(rule) => {
+                    if self.config.policy.is_some() {
+                        warn!("connect rule is reloaded, but not applied with a connect policy");
+                    }
+                    // running sessions keep the rule they started with
+                    self.config.set_connect_rule(rule);
+                    self.policy = self.config.connect_policy();
+                }
+                Disconnect(id) => {
+                    self.destination_limiter.release(id);
+                    if let Some(session) = self.session.remove(&id) {
+                        let addr = session.client_addr();
+                        let (stats, result) = session.stop().await;
+                        let outcome = match &result {
+                            Ok(Ok(())) => {
+                                info!("session is stopped: {}: {}", addr, id);
+                                Outcome::Success
+                            }
+                            Ok(Err(err)) => {
+                                error!("session error: {}", err);
+                                Outcome::Error(err)
+                            }
+                            Err(err) => {
+                                error!("session panic: {}: {}: {:?}", addr, id, err);
+                                Outcome::Panic
+                            }
+                        };
+                        self.counters.finish(&stats, &outcome);
+                        let failed =
+                            matches!(outcome, Outcome::Error(err) if is_failed_attempt(addr, err));
+                        if let (true, Some(bans)) = (failed, &mut self.client_bans) {
+                            if bans.fail(addr.ip()) {
+                                warn!("client is banned for failing repeatedly: {}", addr.ip());
+                            }
+                        }
+                        if let Some(events) = &self.config.event_handler {
+                            events.on_session_finished(&SessionFinishedEvent::new(
+                                id, stats, &outcome,
+                            ));
+                        }
+                    } else {
+                        error!("session has already been stopped: {}", id);
+                    }
+                }
+            }
+            if tx_acceptor_done.is_none() && self.session.is_empty() {
+                debug!("join accept task");
+                for task in accept_task {
+                    task.await.ok();
+                }
+                break;
+            }
+        }
+        info!("server shutdown");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn server_shutdown() {
+        let config = ServerConfig {
+            server_port: 0,
+            ..ServerConfig::default()
+        };
+        let (mut server, tx) = Server::new(config);
+
+        let req_shutdown = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let req_shutdown = Instant::now();
+            tx.send(ServerCommand::Terminate).unwrap();
+            req_shutdown
+        });
+
+        server.serve().await.unwrap();
+        let shutdown = Instant::now();
+        assert!(shutdown > req_shutdown.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn shutdown_without_sessions() {
+        let config = ServerConfig {
+            server_port: 0,
+            ..ServerConfig::default()
+        };
+        let (mut server, tx) = Server::new(config);
+        tx.send(ServerCommand::Shutdown {
+            grace: Duration::from_secs(60),
+        })
+        .unwrap();
+
+        // no need to wait for the grace period
+        tokio::time::timeout(Duration::from_secs(5), server.serve())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn idle_client() {
+        use tokio::io::AsyncReadExt;
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = ServerConfig {
+            server_ip: "127.0.0.1".parse().unwrap(),
+            server_port: port,
+            client_rw_timeout: Some(Duration::from_millis(200)),
+            handshake_timeout: None,
+            ..ServerConfig::default()
+        };
+        let (mut server, tx) = Server::new(config);
+        let server = tokio::spawn(async move { server.serve().await });
+
+        let mut client = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        };
+        // the client sending nothing is disconnected by the read timeout of clients
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut [0; 2]))
+            .await
+            .unwrap();
+        assert!(!matches!(read, Ok(n) if n > 0));
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,664 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::*;
+use tokio::sync::oneshot;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::aio::byte_stream::ByteStream;
+use crate::aio::connector::Connector;
+use crate::aio::relay;
+use crate::aio::socks_stream::AsyncSocksStream;
+use crate::audit::{ConnectEvent, DisconnectLog, RejectEvent, SessionLogger};
+use crate::auth_service::{verify_user_pass, ConfigAuthService, CredentialStore};
+use crate::config::ReplyAddr;
+use crate::destination_limiter::DestinationSlot;
+use crate::event::{AcceptEvent, AuthEvent, ServerEventHandler};
+use crate::model::model::*;
+use crate::model::{Error, ErrorKind, SessionContext};
+use crate::policy::{ConnectContext, ConnectPolicy, Decision};
+use crate::relay::{Bandwidth, Lifetime, Traffic};
+use crate::rewrite::ConnectRewriter;
+use crate::session::{
+    check_decision, decide_rule, expect_version, matched_rule, reject_reason, select_method,
+    Decided, Destination, SessionId, SessionState, SessionStats,
+};
+
+#[derive(Debug)]
+pub struct SessionHandle {
+    id: SessionId,
+    /// client address
+    addr: SocketAddr,
+    /// task performs the session
+    handle: JoinHandle<Result<(), Error>>,
+    /// Sender to send a termination message to the session task (`None` once sent)
+    tx: Option<oneshot::Sender<()>>,
+    /// bytes relayed by the session
+    traffic: Traffic,
+    /// destination requested to the session
+    destination: Destination,
+    /// when the session has been started
+    started: Instant,
+    started_at: SystemTime,
+}
+
+impl SessionHandle {
+    pub(crate) fn new(
+        id: SessionId,
+        addr: SocketAddr,
+        handle: JoinHandle<Result<(), Error>>,
+        tx: oneshot::Sender<()>,
+        traffic: Traffic,
+        destination: Destination,
+    ) -> Self {
+        Self {
+            id,
+            addr,
+            handle,
+            tx: Some(tx),
+            traffic,
+            destination,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        }
+    }
+
+    pub fn client_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// context attached to errors of the session
+    fn context(&self) -> SessionContext {
+        SessionContext {
+            session_id: self.id,
+            client_addr: self.addr,
+            dst_addr: self.destination.get(),
+        }
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        SessionStats {
+            client_addr: self.addr,
+            upload_bytes: self.traffic.upload(),
+            download_bytes: self.traffic.download(),
+            duration: self.started.elapsed(),
+            started_at: self.started_at,
+            dst_addr: self.destination.get(),
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.destination.state()
+    }
+
+    /// request the session to stop without waiting for the task
+    pub fn kill(&mut self) {
+        trace!("kill session: {}", self.addr);
+        if let Some(tx) = self.tx.take() {
+            tx.send(()).ok();
+        }
+    }
+
+    /// Stop the session and wait for the task
+    ///
+    /// Statistics are taken after the task has finished, so all relayed bytes are counted.
+    pub async fn stop(mut self) -> (SessionStats, Result<Result<(), Error>, JoinError>) {
+        trace!("stop session: {}", self.addr);
+        // ignore disconnected error. if the receiver is deallocated,
+        // the session task should have been terminated.
+        if let Some(tx) = self.tx.take() {
+            tx.send(()).ok();
+        }
+        let result = (&mut self.handle).await;
+        let context = self.context();
+        (
+            self.stats(),
+            result.map(|res| res.map_err(|err| err.in_session(context))),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Session<D> {
+    pub id: SessionId,
+    pub version: ProtocolVersion,
+    pub dst_connector: D,
+    pub credentials: Option<Arc<dyn CredentialStore>>,
+    pub server_addr: SocketAddr,
+    /// decides requests of the client
+    pub policy: Arc<dyn ConnectPolicy>,
+    /// audit logger of connect requests
+    pub logger: Option<Arc<dyn SessionLogger>>,
+    /// address replied to CONNECT requests
+    pub reply_addr: ReplyAddr,
+    /// receiver of lifecycle events
+    pub events: Option<Arc<dyn ServerEventHandler>>,
+    /// limits of the lifetime of the relay
+    pub lifetime: Lifetime,
+    /// bandwidth limit of the relay
+    pub bandwidth: Bandwidth,
+    /// timeout of reading the request from the connection
+    pub handshake_timeout: Option<Duration>,
+    /// accept messages of the client with a protocol version other than `version`
+    pub lenient_version: bool,
+    /// auth methods allowed to be selected (`None` for any)
+    pub allowed_methods: Option<Vec<Method>>,
+    /// rewrites destinations allowed by `policy` before connecting
+    pub rewriter: Option<Arc<dyn ConnectRewriter>>,
+    /// counter of sessions through rules with `max_sessions` (not limited if `None`)
+    pub(crate) destination_slot: Option<DestinationSlot>,
+    /// bytes relayed by this session
+    traffic: Traffic,
+    /// destination requested by the client
+    destination: Destination,
+}
+
+impl<D> Session<D>
+where
+    D: Connector,
+{
+    pub fn new(
+        id: SessionId,
+        version: ProtocolVersion,
+        dst_connector: D,
+        credentials: Option<Arc<dyn CredentialStore>>,
+        server_addr: SocketAddr,
+        policy: Arc<dyn ConnectPolicy>,
+    ) -> Self {
+        Self {
+            id,
+            version,
+            dst_connector,
+            credentials,
+            server_addr,
+            policy,
+            logger: None,
+            reply_addr: ReplyAddr::default(),
+            events: None,
+            lifetime: Lifetime::default(),
+            bandwidth: Bandwidth::default(),
+            handshake_timeout: None,
+            lenient_version: false,
+            allowed_methods: None,
+            rewriter: None,
+            destination_slot: None,
+            traffic: Traffic::default(),
+            destination: Destination::default(),
+        }
+    }
+
+    /// counter of bytes relayed by this session
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.clone()
+    }
+
+    /// destination requested to this session
+    pub(crate) fn destination(&self) -> Destination {
+        self.destination.clone()
+    }
+
+    fn connect_reply(&self, connect_result: Result<(), ConnectError>) -> ConnectReply {
+        ConnectReply {
+            version: self.version,
+            connect_result,
+            server_addr: self.server_addr.into(),
+        }
+    }
+
+    /// Reply to a successful CONNECT request
+    ///
+    /// * `local`
+    ///    The local address of the connection to the destination.
+    fn connected_reply(&self, local: Option<SocketAddr>) -> ConnectReply {
+        ConnectReply {
+            server_addr: self.reply_addr.select(self.server_addr, local).into(),
+            ..self.connect_reply(Ok(()))
+        }
+    }
+
+    async fn negotiate_auth_method<T: ByteStream>(
+        &self,
+        socks: &mut AsyncSocksStream<T>,
+    ) -> Result<MethodSelection, Error> {
+        let candidates = socks.recv_method_candidates().await?;
+        trace!("candidates: {:?}", candidates);
+        let selection = select_method(
+            self.version,
+            self.lenient_version,
+            &ConfigAuthService::new(self.credentials.clone()),
+            self.allowed_methods.as_deref(),
+            &candidates,
+        );
+        trace!("selection: {:?}", selection);
+        match selection {
+            Ok(method) => {
+                let method_sel = MethodSelection {
+                    version: self.version,
+                    method,
+                };
+                socks.send_method_selection(method_sel).await?;
+                Ok(method_sel)
+            }
+            Err(err) => {
+                // the client waits for the selection even if its candidates are malformed
+                socks
+                    .send_method_selection(MethodSelection {
+                        version: self.version,
+                        method: Method::NoMethods,
+                    })
+                    .await
+                    .ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// returns the name of the authenticated user
+    async fn authorize<T: ByteStream>(
+        &self,
+        method: Method,
+        socks: &mut AsyncSocksStream<T>,
+    ) -> Result<Option<String>, Error> {
+        match (method, &self.credentials) {
+            (Method::NoAuth, None) => Ok(None),
+            (Method::UserPass, Some(store)) => {
+                let req = socks.recv_user_pass_request().await?;
+                let (reply, user) = verify_user_pass(&**store, req);
+                // the client waits for the status of the sub-negotiation even if the request is rejected
+                let sent = socks.send_user_pass_reply(reply).await;
+                let user = user?;
+                sent?;
+                Ok(Some(user))
+            }
+            (method, _) => Err(ErrorKind::message_fmt(format_args!(
+                "unexpected auth method: {}",
+                method
+            ))
+            .into()),
+        }
+    }
+
+    async fn perform_command(
+        &self,
+        src_addr: SocketAddr,
+        user: Option<&str>,
+        req: &ConnectRequest,
+    ) -> Decided<(D::B, SocketAddr)> {
+        match req.command {
+            Command::Connect => {}
+            cmd @ Command::Bind | cmd @ Command::UdpAssociate => {
+                return (None, Err(ErrorKind::command_not_supported(cmd).into()));
+            }
+        };
+        // filter out request not sufficies the connection rule
+        let decision = decide_rule(
+            &*self.policy,
+            src_addr,
+            user,
+            &req.connect_to,
+            L4Protocol::Tcp,
+        );
+        let res = self.connect_decided(src_addr, user, req, &decision).await;
+        (Some(decision), res)
+    }
+
+    /// connect to the destination of `req` as decided by `decision`
+    async fn connect_decided(
+        &self,
+        src_addr: SocketAddr,
+        user: Option<&str>,
+        req: &ConnectRequest,
+        decision: &Decision,
+    ) -> Result<(D::B, SocketAddr), Error> {
+        check_decision(decision, &req.connect_to, L4Protocol::Tcp)?;
+        if let Some(slot) = &self.destination_slot {
+            slot.acquire(decision, &req.connect_to, L4Protocol::Tcp)?;
+        }
+        let ctx = ConnectContext::new(src_addr, user, &req.connect_to, L4Protocol::Tcp);
+        let connect_to = match self.rewriter.as_ref().and_then(|r| r.rewrite(&ctx)) {
+            Some(rewritten) => {
+                info!("rewrite destination: {} -> {}", req.connect_to, rewritten);
+                rewritten
+            }
+            None => req.connect_to.clone(),
+        };
+        match &decision.bind {
+            Some(bind) => {
+                self.dst_connector
+                    .connect_byte_stream_from(connect_to, bind)
+                    .await
+            }
+            None => self.dst_connector.connect_byte_stream(connect_to).await,
+        }
+    }
+
+    /// Negotiate the method, authenticate the client and read its request
+    async fn handshake<S: ByteStream>(
+        &self,
+        src_addr: SocketAddr,
+        socks: &mut AsyncSocksStream<S>,
+    ) -> Result<(Option<String>, ConnectRequest), Error> {
+        let select = self.negotiate_auth_method(socks).await?;
+        debug!("auth method: {:?}", select);
+        let user = self.authorize(select.method, socks).await?;
+        if let Some(events) = &self.events {
+            events.on_authenticated(&AuthEvent::new(
+                self.id,
+                src_addr,
+                select.method,
+                user.as_deref(),
+            ));
+        }
+
+        let req = socks.recv_connect_request().await.and_then(|req| {
+            if !self.lenient_version {
+                expect_version(self.version, req.version, "connect request")?;
+            }
+            Ok(req)
+        });
+        match req {
+            Ok(req) => Ok((user, req)),
+            Err(err) => {
+                if let ErrorKind::AddrTypeNotSupported { .. } | ErrorKind::MessageFormat { .. } =
+                    err.kind()
+                {
+                    socks
+                        .send_connect_reply(self.connect_reply(Err(err.cerr())))
+                        .await
+                        .ok();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    pub async fn start(self, src_addr: SocketAddr, src_conn: impl ByteStream) -> Result<(), Error> {
+        if let Some(events) = &self.events {
+            events.on_connect(&AcceptEvent::new(self.id, src_addr));
+        }
+        let mut socks = AsyncSocksStream::new(src_conn);
+
+        let handshake = self.handshake(src_addr, &mut socks);
+        let (user, req) = match self.handshake_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handshake)
+                .await
+                .map_err(|_| {
+                    info!("handshake timed out: {}", src_addr);
+                    Error::from(ErrorKind::HandshakeTimedOut)
+                })??,
+            None => handshake.await?,
+        };
+        let user = user.as_deref();
+        self.destination.set(req.connect_to.clone());
+        debug!("connect request: {:?}", req);
+
+        // other commands are rejected before the connect rule is applied
+        let (decision, res) = self.perform_command(src_addr, user, &req).await;
+        let (matched_rule, matched_rule_name) = matched_rule(decision.as_ref());
+        let (conn, dst_addr) = match res {
+            Ok((conn, dst_addr)) => {
+                info!("connected: {}: {}", req.connect_to, dst_addr);
+                socks
+                    .send_connect_reply(self.connected_reply(conn.local_addr()))
+                    .await?;
+                (conn, dst_addr)
+            }
+            Err(err) => {
+                error!("command error: {}", err);
+                trace!("command error: {:?}", err);
+                if let Some(logger) = &self.logger {
+                    logger.on_reject(&RejectEvent {
+                        time: SystemTime::now(),
+                        session_id: self.id,
+                        client_addr: src_addr,
+                        command: req.command,
+                        dst_addr: req.connect_to.clone(),
+                        reason: reject_reason(&err),
+                        matched_rule,
+                        matched_rule_name,
+                        reply: err.cerr(),
+                    });
+                }
+                // reply error
+                socks
+                    .send_connect_reply(self.connect_reply(Err(err.cerr())))
+                    .await?;
+                return Err(err);
+            }
+        };
+
+        self.destination.set_relaying();
+        let log = self.logger.map(|logger| {
+            let event = ConnectEvent {
+                time: SystemTime::now(),
+                session_id: self.id,
+                client_addr: src_addr,
+                command: req.command,
+                matched_rule,
+                matched_rule_name,
+                dst_addr: req.connect_to,
+                peer_addr: dst_addr,
+            };
+            logger.on_connect(&event);
+            DisconnectLog::new(logger, event, self.traffic.clone())
+        });
+        let result = relay::relay(
+            src_addr,
+            dst_addr,
+            socks.into_inner(),
+            conn,
+            self.traffic,
+            self.lifetime,
+            self.bandwidth,
+        )
+        .await;
+        if let Some(log) = log {
+            log.emit();
+        }
+        result
+    }
+}
+
+/// reply `cerr` to the connect request of a client without starting a session
+pub(crate) async fn reject_client(
+    version: ProtocolVersion,
+    server_addr: SocketAddr,
+    strm: impl ByteStream,
+    cerr: ConnectError,
+) -> Result<(), Error> {
+    let mut socks = AsyncSocksStream::new(strm);
+    let candidates = socks.recv_method_candidates().await?;
+    let method = if candidates.method.contains(&Method::NoAuth) {
+        Method::NoAuth
+    } else {
+        Method::NoMethods
+    };
+    socks
+        .send_method_selection(MethodSelection { version, method })
+        .await?;
+    if method == Method::NoMethods {
+        return Ok(());
+    }
+    socks.recv_connect_request().await?;
+    socks
+        .send_connect_reply(ConnectReply {
+            version,
+            connect_result: Err(cerr),
+            server_addr: server_addr.into(),
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aio::connector::TcpConnector;
+    use crate::auth_service::CredentialFn;
+    use crate::rw_socks_stream as socks;
+    use std::io;
+    use std::str::FromStr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// spawn a tcp server echoes a connection back
+    async fn spawn_echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut strm, _) = listener.accept().await.unwrap();
+            let (mut rd, mut wr) = strm.split();
+            tokio::io::copy(&mut rd, &mut wr).await.unwrap();
+        });
+        addr
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordLogger {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl SessionLogger for RecordLogger {
+        fn on_connect(&self, ev: &ConnectEvent) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!("connect {}", ev.dst_addr));
+        }
+        fn on_reject(&self, ev: &RejectEvent) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!("reject {}", ev.dst_addr));
+        }
+        fn on_disconnect(&self, ev: &crate::audit::DisconnectEvent) {
+            let mut events = self.events.lock().unwrap();
+            events.push(format!(
+                "disconnect {} {} {}",
+                ev.dst_addr, ev.upload_bytes, ev.download_bytes
+            ));
+        }
+    }
+
+    fn session(credentials: Option<Arc<dyn CredentialStore>>) -> Session<TcpConnector> {
+        Session::new(
+            0.into(),
+            5.into(),
+            TcpConnector::new(),
+            credentials,
+            "127.0.0.1:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+        )
+    }
+
+    #[tokio::test]
+    async fn connect_relay() {
+        let echo_addr = spawn_echo_server().await;
+        let (mut client, server) = tokio::io::duplex(1024);
+        let logger = Arc::new(RecordLogger::default());
+        let mut session = session(None);
+        session.logger = Some(logger.clone());
+        let traffic = session.traffic();
+        let session = tokio::spawn(session.start("127.0.0.1:12345".parse().unwrap(), server));
+
+        let req = {
+            let mut cursor = io::Cursor::new(vec![]);
+            socks::test::write_method_candidates(
+                &mut cursor,
+                MethodCandidates::new(&[Method::NoAuth]),
+            )
+            .unwrap();
+            socks::test::write_connect_request(&mut cursor, ConnectRequest::connect_to(echo_addr))
+                .unwrap();
+            cursor.into_inner()
+        };
+        client.write_all(&req).await.unwrap();
+
+        // method selection (2 bytes) and connect reply for ipv4 (10 bytes)
+        let mut rep = [0u8; 12];
+        client.read_exact(&mut rep).await.unwrap();
+        let mut cursor = io::Cursor::new(rep);
+        let sel = socks::test::read_method_selection(&mut cursor).unwrap();
+        assert_eq!(sel.method, Method::NoAuth);
+        let rep = socks::test::read_connect_reply(&mut cursor).unwrap();
+        assert_eq!(rep.connect_result, Ok(()));
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        client.shutdown().await.unwrap();
+        session.await.unwrap().unwrap();
+        assert_eq!(traffic.upload(), 5);
+        assert_eq!(traffic.download(), 5);
+        assert_eq!(
+            *logger.events.lock().unwrap(),
+            vec![
+                format!("connect {}", echo_addr),
+                format!("disconnect {} 5 5", echo_addr)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut session = session(None);
+        session.handshake_timeout = Some(Duration::from_millis(100));
+        let session = tokio::spawn(session.start("127.0.0.1:12345".parse().unwrap(), server));
+        // only the version
+        client.write_all(&[5]).await.unwrap();
+        let err = session.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::HandshakeTimedOut);
+    }
+
+    #[tokio::test]
+    async fn protocol_version() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let session = tokio::spawn(session(None).start("127.0.0.1:12345".parse().unwrap(), server));
+        // method candidates of an unknown version
+        client.write_all(&[6, 1, 0]).await.unwrap();
+        let err = session.await.unwrap().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::MessageFormat { .. }));
+    }
+
+    #[tokio::test]
+    async fn unrecognized_username_password() {
+        let store = Arc::new(CredentialFn(|user: &str, pass: &str| {
+            user == "user" && pass == "pass"
+        }));
+        let (mut client, server) = tokio::io::duplex(1024);
+        let session =
+            tokio::spawn(session(Some(store)).start("127.0.0.1:12345".parse().unwrap(), server));
+
+        let req = {
+            let mut cursor = io::Cursor::new(vec![]);
+            socks::test::write_method_candidates(
+                &mut cursor,
+                MethodCandidates::new(&[Method::UserPass]),
+            )
+            .unwrap();
+            socks::test::write_user_pass_request(
+                &mut cursor,
+                UserPassRequest::new("user", "wrong"),
+            )
+            .unwrap();
+            socks::test::write_connect_request(
+                &mut cursor,
+                ConnectRequest::connect_to(Address::from_str("192.168.0.1:5123").unwrap()),
+            )
+            .unwrap();
+            cursor.into_inner()
+        };
+        client.write_all(&req).await.unwrap();
+
+        let mut rep = [0u8; 4];
+        client.read_exact(&mut rep).await.unwrap();
+        let mut cursor = io::Cursor::new(rep);
+        let sel = socks::test::read_method_selection(&mut cursor).unwrap();
+        assert_eq!(sel.method, Method::UserPass);
+        let rep = socks::test::read_user_pass_reply(&mut cursor).unwrap();
+        assert!(!rep.success);
+
+        assert_eq!(
+            session.await.unwrap().unwrap_err().kind(),
+            &ErrorKind::UnrecognizedUsernamePassword
+        );
+    }
+}
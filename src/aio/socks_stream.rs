@@ -0,0 +1,141 @@
+//! SOCKS5 messages on async byte stream
+//!
+//! Messages are read field by field from the stream,
+//! and written at once after being serialized by [`ReadWriteStream`].
+use std::convert::{TryFrom, TryInto};
+use std::io;
+
+use failure::ResultExt;
+use log::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::model::dao::*;
+use crate::model::{self, Error, ErrorKind};
+use crate::raw_message::{self as raw, *};
+use crate::rw_socks_stream::{check_domain, parse_atyp, ReadWriteStream};
+
+pub struct AsyncSocksStream<T> {
+    strm: T,
+}
+
+impl<T> AsyncSocksStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(strm: T) -> Self {
+        Self { strm }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.strm
+    }
+
+    /// write a message serialized by `f`
+    async fn send<F>(&mut self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut ReadWriteStream<io::Cursor<Vec<u8>>>) -> Result<(), Error>,
+    {
+        let mut buf = ReadWriteStream::new(io::Cursor::new(vec![]));
+        f(&mut buf)?;
+        self.strm
+            .write_all(buf.into_inner().get_ref().as_slice())
+            .await?;
+        Ok(())
+    }
+
+    async fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0u8; len];
+        self.strm.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    async fn read_addr(&mut self, atyp: AddrType) -> Result<Addr, Error> {
+        use AddrType::*;
+        match atyp {
+            V4 => {
+                let mut buf = [0u8; 4];
+                self.strm.read_exact(&mut buf).await?;
+                Ok(Addr::IpAddr(Ipv4Addr::from(buf).into()))
+            }
+            Domain => {
+                let len = self.strm.read_u8().await? as usize;
+                let domain = self.read_bytes(len).await?;
+                check_domain(&domain)?;
+                Ok(Addr::Domain(domain))
+            }
+            V6 => {
+                let mut buf = [0u8; 16];
+                self.strm.read_exact(&mut buf).await?;
+                Ok(Addr::IpAddr(Ipv6Addr::from(buf).into()))
+            }
+        }
+    }
+
+    pub async fn recv_method_candidates(&mut self) -> Result<model::MethodCandidates, Error> {
+        trace!("recv_method_candidates");
+        let ver = self.strm.read_u8().await?.into();
+        let nmethods = self.strm.read_u8().await?;
+        let methods = self
+            .read_bytes(nmethods as usize)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        Ok(raw::MethodCandidates { ver, methods }.into())
+    }
+
+    pub async fn send_method_selection(
+        &mut self,
+        method_selection: model::MethodSelection,
+    ) -> Result<(), Error> {
+        self.send(|buf| buf.send_method_selection(method_selection))
+            .await
+    }
+
+    pub async fn recv_connect_request(&mut self) -> Result<model::ConnectRequest, Error> {
+        trace!("recv_connect_request");
+        let ver = self.strm.read_u8().await?.into();
+        let cmd = SockCommand::try_from(self.strm.read_u8().await?)
+            .context(ErrorKind::message_fmt(format_args!("ConnectRequest::cmd")))?;
+        let rsv = self.strm.read_u8().await?;
+        if rsv != RESERVED {
+            return Err(
+                ErrorKind::message_fmt(format_args!("value of rsv is not 0({})", rsv)).into(),
+            );
+        }
+        let atyp = parse_atyp(self.strm.read_u8().await?)?;
+        let dst_addr = self.read_addr(atyp).await?;
+        let dst_port = self.strm.read_u16().await?;
+        Ok(raw::ConnectRequest {
+            ver,
+            cmd,
+            rsv,
+            atyp,
+            dst_addr,
+            dst_port,
+        }
+        .try_into()
+        .map_err(|err| ErrorKind::message_fmt(format_args!("{}", err)))?)
+    }
+
+    pub async fn send_connect_reply(
+        &mut self,
+        connect_reply: model::ConnectReply,
+    ) -> Result<(), Error> {
+        self.send(|buf| buf.send_connect_reply(connect_reply)).await
+    }
+
+    pub async fn recv_user_pass_request(&mut self) -> Result<model::UserPassRequest, Error> {
+        trace!("recv_user_pass_request");
+        let ver = self.strm.read_u8().await?;
+        let ulen = self.strm.read_u8().await? as usize;
+        let uname = self.read_bytes(ulen).await?;
+        let plen = self.strm.read_u8().await? as usize;
+        let passwd = self.read_bytes(plen).await?;
+        Ok(raw::UserPassRequest { ver, uname, passwd }.into())
+    }
+
+    pub async fn send_user_pass_reply(&mut self, reply: model::UserPassReply) -> Result<(), Error> {
+        self.send(|buf| buf.send_user_pass_reply(reply)).await
+    }
+}
@@ -0,0 +1,330 @@
+//! Audit log of sessions
+//!
+//! [`SessionLogger`] is notified of connection requests a session performs.
+//! It is set by `ServerConfig::set_session_logger`,
+//! or registered in addition to others by `ServerConfig::add_session_logger`.
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! use gatekeeper::audit::JsonLinesLogger;
+//! use gatekeeper::ServerConfig;
+//! # fn main() -> Result<(), gatekeeper::error::Error> {
+//! let mut config = ServerConfig::default();
+//! config.set_session_logger(Some(Arc::new(JsonLinesLogger::new("audit.jsonl")?)));
+//! # Ok(())
+//! # }
+//! ```
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::*;
+use serde::{Serialize, Serializer};
+
+use crate::error::Error;
+use crate::model::{Address, Command, ConnectError, SocketAddr};
+use crate::relay::Traffic;
+use crate::session::SessionId;
+
+/// Receiver of session events
+pub trait SessionLogger: fmt::Debug + Send + Sync {
+    /// connection to the destination has been established
+    fn on_connect(&self, event: &ConnectEvent);
+    /// connection request has been rejected
+    fn on_reject(&self, event: &RejectEvent);
+    /// relay of the established connection has been finished
+    fn on_disconnect(&self, event: &DisconnectEvent);
+}
+
+/// Notifies every logger in order
+///
+/// This allows to register multiple loggers (e.g. a file and a metrics collector) on a server.
+impl SessionLogger for Vec<Arc<dyn SessionLogger>> {
+    fn on_connect(&self, event: &ConnectEvent) {
+        self.iter().for_each(|logger| logger.on_connect(event))
+    }
+
+    fn on_reject(&self, event: &RejectEvent) {
+        self.iter().for_each(|logger| logger.on_reject(event))
+    }
+
+    fn on_disconnect(&self, event: &DisconnectEvent) {
+        self.iter().for_each(|logger| logger.on_disconnect(event))
+    }
+}
+
+fn display<T: fmt::Display, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(value)
+}
+
+fn secs<S: Serializer>(dur: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(dur.as_secs_f64())
+}
+
+/// seconds since UNIX epoch
+fn unix_time<S: Serializer>(time: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+    secs(&time.duration_since(UNIX_EPOCH).unwrap_or_default(), s)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectEvent {
+    #[serde(serialize_with = "unix_time")]
+    pub time: SystemTime,
+    pub session_id: SessionId,
+    pub client_addr: SocketAddr,
+    pub command: Command,
+    /// requested destination
+    #[serde(serialize_with = "display")]
+    pub dst_addr: Address,
+    /// address actually connected to
+    pub peer_addr: SocketAddr,
+    /// index of the connect rule allowed the request (see `ConnectRule::matched_from`)
+    pub matched_rule: Option<usize>,
+    /// name of the matched rule, if it is named
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_rule_name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum RejectReason {
+    /// denied by the connect rule
+    NotAllowed,
+    /// failed to perform the command
+    Error(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectEvent {
+    #[serde(serialize_with = "unix_time")]
+    pub time: SystemTime,
+    pub session_id: SessionId,
+    pub client_addr: SocketAddr,
+    pub command: Command,
+    /// requested destination
+    #[serde(serialize_with = "display")]
+    pub dst_addr: Address,
+    pub reason: RejectReason,
+    /// index of the connect rule decided the request (see `ConnectRule::matched_from`)
+    pub matched_rule: Option<usize>,
+    /// name of the matched rule, if it is named
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_rule_name: Option<String>,
+    /// reply code sent to the client
+    #[serde(serialize_with = "display")]
+    pub reply: ConnectError,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DisconnectEvent {
+    #[serde(serialize_with = "unix_time")]
+    pub time: SystemTime,
+    pub session_id: SessionId,
+    pub client_addr: SocketAddr,
+    pub command: Command,
+    /// requested destination
+    #[serde(serialize_with = "display")]
+    pub dst_addr: Address,
+    /// bytes relayed from client to external network
+    pub upload_bytes: u64,
+    /// bytes relayed from external network to client
+    pub download_bytes: u64,
+    /// duration since the connection has been established
+    #[serde(serialize_with = "secs")]
+    pub duration: Duration,
+}
+
+/// Emits `DisconnectEvent` when relays are finished
+#[derive(Debug, Clone)]
+pub(crate) struct DisconnectLog {
+    logger: Arc<dyn SessionLogger>,
+    connect: ConnectEvent,
+    traffic: Traffic,
+    established: Instant,
+}
+
+impl DisconnectLog {
+    pub fn new(logger: Arc<dyn SessionLogger>, connect: ConnectEvent, traffic: Traffic) -> Self {
+        Self {
+            logger,
+            connect,
+            traffic,
+            established: Instant::now(),
+        }
+    }
+
+    pub fn emit(self) {
+        self.logger.on_disconnect(&DisconnectEvent {
+            time: SystemTime::now(),
+            session_id: self.connect.session_id,
+            client_addr: self.connect.client_addr,
+            command: self.connect.command,
+            dst_addr: self.connect.dst_addr,
+            upload_bytes: self.traffic.upload(),
+            download_bytes: self.traffic.download(),
+            duration: self.established.elapsed(),
+        })
+    }
+}
+
+/// Writes events to a file in [JSON Lines](https://jsonlines.org) format
+///
+/// Each line has `event` (`connect`, `reject` or `disconnect`) field in addition to the fields of the event.
+/// `time` is in seconds since UNIX epoch.
+#[derive(Debug)]
+pub struct JsonLinesLogger {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct Record<'a, E> {
+    event: &'static str,
+    #[serde(flatten)]
+    body: &'a E,
+}
+
+impl JsonLinesLogger {
+    /// Open `path` for appending events
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write<E: Serialize>(&self, event: &'static str, body: &E) {
+        let mut line = match serde_json::to_vec(&Record { event, body }) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("audit log serialization error: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(err) => err.into_inner(),
+        };
+        if let Err(err) = file.write_all(&line) {
+            error!("audit log write error: {}", err);
+        }
+    }
+}
+
+impl SessionLogger for JsonLinesLogger {
+    fn on_connect(&self, event: &ConnectEvent) {
+        self.write("connect", event)
+    }
+
+    fn on_reject(&self, event: &RejectEvent) {
+        self.write("reject", event)
+    }
+
+    fn on_disconnect(&self, event: &DisconnectEvent) {
+        self.write("disconnect", event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn json_lines() {
+        let path =
+            std::env::temp_dir().join(format!("gatekeeper-audit-{}.jsonl", std::process::id()));
+        let logger = JsonLinesLogger::new(&path).unwrap();
+        let connect = ConnectEvent {
+            time: SystemTime::now(),
+            session_id: 1.into(),
+            client_addr: "192.168.0.2:12345".parse().unwrap(),
+            command: Command::Connect,
+            dst_addr: Address::Domain("example.com".to_owned(), 80),
+            peer_addr: "93.184.216.34:80".parse().unwrap(),
+            matched_rule: Some(2),
+            matched_rule_name: None,
+        };
+        logger.on_connect(&connect);
+        logger.on_reject(&RejectEvent {
+            time: SystemTime::now(),
+            session_id: 2.into(),
+            client_addr: "192.168.0.2:12346".parse().unwrap(),
+            command: Command::Connect,
+            dst_addr: Address::Domain("example.org".to_owned(), 80),
+            reason: RejectReason::NotAllowed,
+            matched_rule: Some(0),
+            matched_rule_name: Some("default".to_owned()),
+            reply: ConnectError::ConnectionNotAllowed,
+        });
+        DisconnectLog::new(Arc::new(logger), connect, Traffic::default()).emit();
+
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["event"], "connect");
+        assert_eq!(lines[0]["session_id"], 1);
+        assert_eq!(lines[0]["dst_addr"], "example.com:80");
+        assert_eq!(lines[0]["peer_addr"], "93.184.216.34:80");
+        assert_eq!(lines[0]["matched_rule"], 2);
+        assert_eq!(lines[1]["event"], "reject");
+        assert_eq!(lines[1]["reason"], "NotAllowed");
+        assert_eq!(lines[1]["matched_rule"], 0);
+        assert_eq!(lines[1]["matched_rule_name"], "default");
+        assert!(lines[0].get("matched_rule_name").is_none());
+        assert_eq!(lines[1]["reply"], "ConnectionNotAllowed");
+        assert!(lines[1]["time"].as_f64().unwrap() > 0.0);
+        assert_eq!(lines[2]["event"], "disconnect");
+        assert_eq!(lines[2]["upload_bytes"], 0);
+    }
+
+    #[derive(Debug, Default)]
+    struct CountLogger(std::sync::atomic::AtomicUsize);
+
+    impl SessionLogger for CountLogger {
+        fn on_connect(&self, _: &ConnectEvent) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        fn on_reject(&self, _: &RejectEvent) {}
+        fn on_disconnect(&self, _: &DisconnectEvent) {
+            self.0.fetch_add(10, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn multiple_loggers() {
+        use crate::config::ServerConfig;
+        use std::sync::atomic::Ordering;
+
+        let first = Arc::new(CountLogger::default());
+        let second = Arc::new(CountLogger::default());
+        let mut config = ServerConfig::default();
+        config
+            .add_session_logger(first.clone())
+            .add_session_logger(second.clone());
+
+        let connect = ConnectEvent {
+            time: SystemTime::now(),
+            session_id: 1.into(),
+            client_addr: "192.168.0.2:12345".parse().unwrap(),
+            command: Command::Connect,
+            dst_addr: Address::Domain("example.com".to_owned(), 80),
+            peer_addr: "93.184.216.34:80".parse().unwrap(),
+            matched_rule: None,
+            matched_rule_name: None,
+        };
+        let logger = config.session_logger.unwrap();
+        logger.on_connect(&connect);
+        DisconnectLog::new(logger, connect, Traffic::default()).emit();
+
+        assert_eq!(first.0.load(Ordering::Relaxed), 11);
+        assert_eq!(second.0.load(Ordering::Relaxed), 11);
+    }
+}
@@ -1,7 +1,14 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
+use std::sync::Arc;
+
+use log::*;
 
 use crate::byte_stream::{BoxedStream, ByteStream};
-use crate::model::{Error, Method};
+use crate::model::dao::*;
+use crate::model::{Error, ErrorKind, Method, UserPassReply, UserPassRequest, USER_PASS_VERSION};
+use crate::rw_socks_stream::ReadWriteStream;
 
 pub trait AuthService: Send {
     /// decide auth method from candidates
@@ -14,10 +21,35 @@ pub trait AuthService: Send {
     fn authorize<'a, B>(&self, method: Method, conn: B) -> Result<BoxedStream<'a>, Error>
     where
         B: ByteStream + 'a;
+
+    /// authentication then return Wrapped stream and the name of the authenticated user
+    ///
+    /// The name is matched with `user` of connect rules.
+    /// By default, no user is authenticated.
+    fn authorize_user<'a, B>(
+        &self,
+        method: Method,
+        conn: B,
+    ) -> Result<(BoxedStream<'a>, Option<String>), Error>
+    where
+        B: ByteStream + 'a,
+    {
+        Ok((self.authorize(method, conn)?, None))
+    }
+
+    /// check the identity of the client authenticated by the underlying connection
+    /// (`ByteStream::peer_identity`, e.g. the common name of the certificate of a TLS client)
+    ///
+    /// This is called for clients of any protocol, with `None` if they are not identified.
+    /// By default, any client is accepted.
+    fn authorize_peer(&self, identity: Option<&str>) -> Result<(), Error> {
+        let _ = identity;
+        Ok(())
+    }
 }
 
 /// `NoAuth` method compeller
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct NoAuthService {}
 
 impl NoAuthService {
@@ -48,12 +80,184 @@ impl AuthService for NoAuthService {
     }
 }
 
+/// Verifier of username/password pairs
+pub trait CredentialStore: fmt::Debug + Send + Sync {
+    /// returns `true` if the pair of `username` and `password` is acceptable
+    fn verify(&self, username: &str, password: &str) -> bool;
+}
+
+/// static map from username to password
+impl CredentialStore for HashMap<String, String> {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        self.get(username).is_some_and(|pass| pass == password)
+    }
+}
+
+/// Credentials verified by a callback
+///
+/// ```
+/// # use gatekeeper::auth_service::{CredentialFn, CredentialStore};
+/// let store = CredentialFn(|user: &str, pass: &str| user == "alice" && pass == "secret");
+/// assert!(store.verify("alice", "secret"));
+/// assert!(!store.verify("alice", "wrong"));
+/// ```
+pub struct CredentialFn<F>(pub F);
+
+impl<F> fmt::Debug for CredentialFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CredentialFn")
+    }
+}
+
+impl<F> CredentialStore for CredentialFn<F>
+where
+    F: Fn(&str, &str) -> bool + Send + Sync,
+{
+    fn verify(&self, username: &str, password: &str) -> bool {
+        (self.0)(username, password)
+    }
+}
+
+/// Verify the username/password request `req` of a client by `store` (RFC1929)
+///
+/// Returns the reply to be sent to the client even if the request is rejected,
+/// and the name of the user if it is authenticated.
+/// This is shared by `UserPassService` and `aio::Session`.
+pub(crate) fn verify_user_pass(
+    store: &dyn CredentialStore,
+    req: UserPassRequest,
+) -> (UserPassReply, Result<String, Error>) {
+    debug!("user/pass request: {:?}", req);
+    let reply = |success| UserPassReply {
+        version: USER_PASS_VERSION,
+        success,
+    };
+    if req.version != USER_PASS_VERSION {
+        let err = ErrorKind::message_fmt(format_args!(
+            "unknown version of username/password sub-negotiation: {}",
+            req.version
+        ));
+        return (reply(false), Err(err.into()));
+    }
+    if !store.verify(&req.username, &req.password) {
+        info!("unrecognized username/password: {}", req.username);
+        return (
+            reply(false),
+            Err(ErrorKind::UnrecognizedUsernamePassword.into()),
+        );
+    }
+    (reply(true), Ok(req.username))
+}
+
+/// `UserPass` method compeller
+///
+/// Performs the username/password sub-negotiation described in RFC1929.
+#[derive(Debug, Clone)]
+pub struct UserPassService {
+    store: Arc<dyn CredentialStore>,
+}
+
+impl UserPassService {
+    pub fn new(store: Arc<dyn CredentialStore>) -> Self {
+        Self { store }
+    }
+}
+
+impl AuthService for UserPassService {
+    fn select(&self, candidates: &[Method]) -> Result<Option<Method>, Error> {
+        if candidates.contains(&Method::UserPass) {
+            Ok(Some(Method::UserPass))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn authorize<'a, B>(&self, method: Method, conn: B) -> Result<BoxedStream<'a>, Error>
+    where
+        B: ByteStream + 'a,
+    {
+        self.authorize_user(method, conn).map(|(conn, _)| conn)
+    }
+
+    fn authorize_user<'a, B>(
+        &self,
+        method: Method,
+        mut conn: B,
+    ) -> Result<(BoxedStream<'a>, Option<String>), Error>
+    where
+        B: ByteStream + 'a,
+    {
+        if method != Method::UserPass {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, method.to_string());
+            return Err(e.into());
+        }
+        let mut socks = ReadWriteStream::new(&mut conn);
+        let req = socks.recv_user_pass_request()?;
+        let (reply, user) = verify_user_pass(&*self.store, req);
+        // the client waits for the status of the sub-negotiation even if the request is rejected
+        let sent = socks.send_user_pass_reply(reply);
+        let user = user?;
+        sent?;
+        Ok((Box::new(conn), Some(user)))
+    }
+}
+
+/// AuthService selected by `ServerConfig`
+#[derive(Debug, Clone)]
+pub enum ConfigAuthService {
+    NoAuth(NoAuthService),
+    UserPass(UserPassService),
+}
+
+impl ConfigAuthService {
+    /// `UserPass` is required if `credentials` is given, `NoAuth` otherwise.
+    pub fn new(credentials: Option<Arc<dyn CredentialStore>>) -> Self {
+        match credentials {
+            Some(store) => ConfigAuthService::UserPass(UserPassService::new(store)),
+            None => ConfigAuthService::NoAuth(NoAuthService::new()),
+        }
+    }
+}
+
+impl AuthService for ConfigAuthService {
+    fn select(&self, candidates: &[Method]) -> Result<Option<Method>, Error> {
+        match self {
+            ConfigAuthService::NoAuth(auth) => auth.select(candidates),
+            ConfigAuthService::UserPass(auth) => auth.select(candidates),
+        }
+    }
+
+    fn authorize<'a, B>(&self, method: Method, conn: B) -> Result<BoxedStream<'a>, Error>
+    where
+        B: ByteStream + 'a,
+    {
+        match self {
+            ConfigAuthService::NoAuth(auth) => auth.authorize(method, conn),
+            ConfigAuthService::UserPass(auth) => auth.authorize(method, conn),
+        }
+    }
+
+    fn authorize_user<'a, B>(
+        &self,
+        method: Method,
+        conn: B,
+    ) -> Result<(BoxedStream<'a>, Option<String>), Error>
+    where
+        B: ByteStream + 'a,
+    {
+        match self {
+            ConfigAuthService::NoAuth(auth) => auth.authorize_user(method, conn),
+            ConfigAuthService::UserPass(auth) => auth.authorize_user(method, conn),
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
     use crate::model::ErrorKind;
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct RejectService;
 
     impl AuthService for RejectService {
@@ -69,4 +273,105 @@ pub mod test {
             Err(ErrorKind::Authentication.into())
         }
     }
+
+    fn user_pass_service() -> UserPassService {
+        let mut users = HashMap::new();
+        users.insert("alice".to_owned(), "secret".to_owned());
+        UserPassService::new(Arc::new(users))
+    }
+
+    #[test]
+    fn user_pass_select() {
+        let auth = user_pass_service();
+        assert_eq!(
+            auth.select(&[Method::NoAuth, Method::UserPass]).unwrap(),
+            Some(Method::UserPass)
+        );
+        assert_eq!(auth.select(&[Method::NoAuth]).unwrap(), None);
+    }
+
+    #[test]
+    fn user_pass_authorize() {
+        use crate::byte_stream::test::BufferStream;
+        use crate::model::UserPassRequest;
+        use crate::rw_socks_stream as socks;
+
+        let auth = user_pass_service();
+        let buff = {
+            let mut cursor = io::Cursor::new(vec![]);
+            socks::test::write_user_pass_request(
+                &mut cursor,
+                UserPassRequest::new("alice", "secret"),
+            )
+            .unwrap();
+            cursor.into_inner()
+        };
+        let src = BufferStream::with_buffer(buff.into(), vec![].into());
+        let (_, user) = auth.authorize_user(Method::UserPass, src.clone()).unwrap();
+        assert_eq!(user.as_deref(), Some("alice"));
+        src.wr_buff().set_position(0);
+        assert_eq!(
+            socks::test::read_user_pass_reply(&mut *src.wr_buff()).unwrap(),
+            UserPassReply {
+                version: USER_PASS_VERSION,
+                success: true
+            }
+        );
+    }
+
+    #[test]
+    fn user_pass_unknown_version() {
+        use crate::byte_stream::test::BufferStream;
+
+        let auth = user_pass_service();
+        let mut buff = vec![2, 5];
+        buff.extend_from_slice(b"alice");
+        buff.push(6);
+        buff.extend_from_slice(b"secret");
+        let src = BufferStream::with_buffer(buff.into(), vec![].into());
+        assert!(matches!(
+            auth.authorize(Method::UserPass, src.clone())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MessageFormat { .. }
+        ));
+        // replied failure
+        src.wr_buff().set_position(0);
+        let mut reply = vec![];
+        io::Read::read_to_end(&mut *src.wr_buff(), &mut reply).unwrap();
+        assert_eq!(reply, [USER_PASS_VERSION, 1]);
+    }
+
+    #[test]
+    fn user_pass_unrecognized() {
+        use crate::byte_stream::test::BufferStream;
+        use crate::model::UserPassRequest;
+        use crate::rw_socks_stream as socks;
+
+        let auth = user_pass_service();
+        let buff = {
+            let mut cursor = io::Cursor::new(vec![]);
+            socks::test::write_user_pass_request(
+                &mut cursor,
+                UserPassRequest::new("alice", "wrong"),
+            )
+            .unwrap();
+            cursor.into_inner()
+        };
+        let src = BufferStream::with_buffer(buff.into(), vec![].into());
+        assert_eq!(
+            auth.authorize(Method::UserPass, src.clone())
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::UnrecognizedUsernamePassword
+        );
+        src.wr_buff().set_position(0);
+        assert_eq!(
+            socks::test::read_user_pass_reply(&mut *src.wr_buff()).unwrap(),
+            UserPassReply {
+                version: USER_PASS_VERSION,
+                success: false
+            }
+        );
+    }
 }
@@ -1,7 +1,13 @@
 use std::fmt;
 use std::io;
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 use std::ops::Deref;
+#[cfg(unix)]
+use std::os::unix::{
+    io::{AsRawFd, RawFd},
+    net::UnixStream,
+};
+use std::time::Duration;
 
 use crate::model::Error;
 
@@ -9,6 +15,36 @@ use crate::model::Error;
 pub trait ByteStream: fmt::Debug + io::Read + io::Write + Send {
     #[allow(clippy::type_complexity)]
     fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error>;
+
+    /// Local address of the underlying connection, if any
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Read timeout of the underlying connection, if it is supported
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Set the read timeout of the underlying connection, if it is supported
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Identity of the client authenticated by the underlying connection
+    ///
+    /// e.g. the common name of the certificate of a TLS client
+    fn peer_identity(&self) -> Option<String> {
+        None
+    }
+
+    /// The socket underlying this stream, if data can be relayed on it directly
+    ///
+    /// Streams transforming data (e.g. TLS) must not return the socket.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
 }
 
 /// byte stream on tcp connection
@@ -19,6 +55,46 @@ impl ByteStream for TcpStream {
         let wr = self.try_clone()?;
         Ok((Box::new(rd), Box::new(wr)))
     }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// byte stream on unix domain socket
+#[cfg(unix)]
+impl ByteStream for UnixStream {
+    #[allow(clippy::type_complexity)]
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        let rd = self.try_clone()?;
+        let wr = self.try_clone()?;
+        Ok((Box::new(rd), Box::new(wr)))
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        UnixStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
 }
 
 /// Boxed stream
@@ -27,129 +103,36 @@ impl<S: ByteStream> ByteStream for Box<S> {
     fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
         self.deref().split()
     }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.deref().local_addr()
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.deref().read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.deref().set_read_timeout(timeout)
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.deref().peer_identity()
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.deref().raw_fd()
+    }
 }
 
 pub type BoxedStream<'a> = Box<dyn ByteStream + 'a>;
 
 #[cfg(test)]
 pub mod test {
-    use super::*;
-    use std::borrow::Cow;
-    use std::io::{self};
-    use std::sync::{Arc, Mutex, MutexGuard};
-
-    #[derive(Debug, Clone)]
-    pub struct BufferStream {
-        pub rd_buff: Arc<Mutex<io::Cursor<Vec<u8>>>>,
-        pub wr_buff: Arc<Mutex<io::Cursor<Vec<u8>>>>,
-    }
-
-    impl BufferStream {
-        pub fn new() -> Self {
-            BufferStream::with_buffer(vec![].into(), vec![].into())
-        }
-
-        pub fn with_buffer(rd: Cow<[u8]>, wr: Cow<[u8]>) -> Self {
-            Self {
-                rd_buff: Arc::new(Mutex::new(io::Cursor::new(rd.into_owned()))),
-                wr_buff: Arc::new(Mutex::new(io::Cursor::new(wr.into_owned()))),
-            }
-        }
-
-        pub fn rd_buff(&self) -> MutexGuard<'_, io::Cursor<Vec<u8>>> {
-            self.rd_buff.lock().unwrap()
-        }
-
-        pub fn wr_buff(&self) -> MutexGuard<'_, io::Cursor<Vec<u8>>> {
-            self.wr_buff.lock().unwrap()
-        }
-    }
-
-    impl io::Read for BufferStream {
-        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            self.rd_buff.lock().unwrap().read(buf)
-        }
-    }
-
-    impl io::Write for BufferStream {
-        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            self.wr_buff.lock().unwrap().write(buf)
-        }
-
-        fn flush(&mut self) -> io::Result<()> {
-            self.wr_buff.lock().unwrap().flush()
-        }
-    }
-
-    impl ByteStream for BufferStream {
-        fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
-            let rd = Self {
-                rd_buff: self.rd_buff.clone(),
-                wr_buff: self.wr_buff.clone(),
-            };
-            let wr = Self {
-                rd_buff: self.rd_buff.clone(),
-                wr_buff: self.wr_buff.clone(),
-            };
-            Ok((Box::new(rd), Box::new(wr)))
-        }
-    }
-
-    #[derive(Debug, Clone)]
-    pub struct IterBuffer<T> {
-        pub iter: T,
-        pub wr_buff: Arc<Mutex<io::Cursor<Vec<u8>>>>,
-    }
-
-    impl<T> IterBuffer<T>
-    where
-        T: Iterator<Item = Vec<u8>>,
-    {
-        pub fn new(iter: T, wr_buff: io::Cursor<Vec<u8>>) -> Self {
-            Self {
-                iter,
-                wr_buff: Arc::new(Mutex::new(wr_buff)),
-            }
-        }
-    }
-
-    impl<T> io::Read for IterBuffer<T>
-    where
-        T: Iterator<Item = Vec<u8>>,
-    {
-        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            if let Some(chunk) = self.iter.next() {
-                (&chunk[..]).read(buf)
-            } else {
-                Ok(0)
-            }
-        }
-    }
-
-    impl<T> io::Write for IterBuffer<T>
-    where
-        T: Iterator<Item = Vec<u8>>,
-    {
-        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            log::debug!("IterBuffer::write({})", String::from_utf8_lossy(buf));
-            self.wr_buff.lock().unwrap().write(buf)
-        }
-
-        fn flush(&mut self) -> io::Result<()> {
-            self.wr_buff.lock().unwrap().flush()
-        }
-    }
-
-    impl<T> ByteStream for IterBuffer<T>
-    where
-        T: fmt::Debug + Iterator<Item = Vec<u8>> + Clone + Send + 'static,
-    {
-        fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
-            let rd = Box::new(self.clone()) as Box<dyn io::Read + Send>;
-            let wr = Box::new(self.clone()) as Box<dyn io::Write + Send>;
-            Ok((rd, wr))
-        }
-    }
+    use std::io;
+
+    pub use crate::test_util::{BufferStream, IterBuffer};
 
     #[test]
     fn iter_buffer() {
@@ -0,0 +1,343 @@
+//! Client side of the SOCKS5 protocol
+//!
+//! [`Socks5Client`] connects to destinations through a SOCKS5 proxy (e.g. gatekeeper itself).
+//!
+//! ```no_run
+//! use std::io::Write;
+//! use gatekeeper::client::Socks5Client;
+//! use gatekeeper::Address;
+//!
+//! let mut client = Socks5Client::new("127.0.0.1:1080".parse().unwrap());
+//! client.set_credential("alice", "secret");
+//! let (mut strm, _bound) = client
+//!     .connect(Address::Domain("example.com".to_owned(), 80))
+//!     .unwrap();
+//! strm.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+//! ```
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use log::*;
+
+use crate::connector::conn_error;
+use crate::model::{
+    Address, Command, ConnectError, ConnectRequest, Error, ErrorKind, L4Protocol, Method,
+    MethodCandidates, UdpDatagram, UserPassRequest,
+};
+use crate::pkt_stream::MAX_PKT_SIZE;
+use crate::rw_socks_stream::{client, read_datagram, write_datagram};
+
+/// Client of a SOCKS5 proxy
+#[derive(Debug, Clone)]
+pub struct Socks5Client {
+    proxy: SocketAddr,
+    credential: Option<UserPassRequest>,
+    rw_timeout: Option<Duration>,
+}
+
+impl Socks5Client {
+    pub fn new(proxy: SocketAddr) -> Self {
+        Self {
+            proxy,
+            credential: None,
+            rw_timeout: None,
+        }
+    }
+
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.proxy
+    }
+
+    /// authenticate to the proxy with USERNAME/PASSWORD
+    pub fn set_credential<U, P>(&mut self, username: U, password: P) -> &mut Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.credential = Some(UserPassRequest::new(username, password));
+        self
+    }
+
+    /// read/write timeout of the connection to the proxy
+    pub fn set_rw_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.rw_timeout = timeout;
+        self
+    }
+
+    /// Connect to `dst` through the proxy (`CONNECT` command)
+    ///
+    /// Returns the stream relayed to `dst` and the address the proxy connected from.
+    pub fn connect(&self, dst: Address) -> Result<(TcpStream, Address), Error> {
+        let mut strm = self.open()?;
+        let bound = request(&mut strm, ConnectRequest::connect_to(dst))?;
+        Ok((strm, bound))
+    }
+
+    /// Relay datagrams through the proxy (`UDP ASSOCIATE` command)
+    ///
+    /// Datagrams are sent from `local`, e.g. `0.0.0.0:0` for an ephemeral port.
+    pub fn udp_associate(&self, local: SocketAddr) -> Result<Socks5Datagram, Error> {
+        let socket = UdpSocket::bind(local)?;
+        socket.set_read_timeout(self.rw_timeout)?;
+        socket.set_write_timeout(self.rw_timeout)?;
+        let mut control = self.open()?;
+        let relay = match request(
+            &mut control,
+            ConnectRequest::udp_associate(socket.local_addr()?),
+        )? {
+            // the proxy may reply an unspecified address meaning itself
+            Address::IpAddr(ip, port) if ip.is_unspecified() => {
+                SocketAddr::new(self.proxy.ip(), port)
+            }
+            Address::IpAddr(ip, port) => SocketAddr::new(ip, port),
+            Address::ScopedV6(addr) => addr.into(),
+            Address::Domain(domain, port) => {
+                return Err(ErrorKind::message_fmt(format_args!(
+                    "udp relay on a domain: {}:{}",
+                    domain, port
+                ))
+                .into())
+            }
+        };
+        Ok(Socks5Datagram {
+            control,
+            socket,
+            relay,
+        })
+    }
+
+    /// Connect to the proxy and authenticate
+    fn open(&self) -> Result<TcpStream, Error> {
+        let mut strm = TcpStream::connect(self.proxy)
+            .map_err(|err| conn_error(err, self.proxy.into(), L4Protocol::Tcp))?;
+        strm.set_read_timeout(self.rw_timeout)?;
+        strm.set_write_timeout(self.rw_timeout)?;
+        self.authenticate(&mut strm)?;
+        Ok(strm)
+    }
+
+    fn authenticate(&self, strm: &mut TcpStream) -> Result<(), Error> {
+        let methods: &[Method] = match self.credential {
+            Some(_) => &[Method::NoAuth, Method::UserPass],
+            None => &[Method::NoAuth],
+        };
+        client::write_method_candidates(&mut *strm, MethodCandidates::new(methods))?;
+        match (
+            client::read_method_selection(&mut *strm)?.method,
+            &self.credential,
+        ) {
+            (Method::NoAuth, _) => Ok(()),
+            (Method::UserPass, Some(credential)) => {
+                client::write_user_pass_request(&mut *strm, credential.clone())?;
+                if client::read_user_pass_reply(&mut *strm)?.success {
+                    Ok(())
+                } else {
+                    Err(ErrorKind::UnrecognizedUsernamePassword.into())
+                }
+            }
+            _ => Err(ErrorKind::NoAcceptableMethod.into()),
+        }
+    }
+}
+
+/// Send `req` and read its reply
+fn request(strm: &mut TcpStream, req: ConnectRequest) -> Result<Address, Error> {
+    let (command, dst) = (req.command, req.connect_to.clone());
+    client::write_connect_request(&mut *strm, req)?;
+    let reply = client::read_connect_reply(&mut *strm)?;
+    match reply.connect_result {
+        Ok(()) => Ok(reply.server_addr),
+        Err(cerr) => {
+            debug!("request is rejected: {:?}: {}: {}", command, dst, cerr);
+            Err(reply_error(cerr, dst, command))
+        }
+    }
+}
+
+/// Translate an error replied by a proxy to the request of `command` to `addr`
+pub(crate) fn reply_error(cerr: ConnectError, addr: Address, command: Command) -> Error {
+    use ConnectError::*;
+    let protocol = match command {
+        Command::UdpAssociate => L4Protocol::Udp,
+        Command::Connect | Command::Bind => L4Protocol::Tcp,
+    };
+    let port = addr.port();
+    match (cerr, addr) {
+        (NetworkUnreachable, Address::Domain(domain, _)) => {
+            ErrorKind::DomainNotResolved { domain, port }
+        }
+        (NetworkUnreachable, Address::IpAddr(ipaddr, _))
+        | (HostUnreachable, Address::IpAddr(ipaddr, _)) => ErrorKind::HostUnreachable {
+            host: ipaddr.to_string(),
+            port,
+        },
+        (NetworkUnreachable, Address::ScopedV6(addr))
+        | (HostUnreachable, Address::ScopedV6(addr)) => ErrorKind::HostUnreachable {
+            host: addr.ip().to_string(),
+            port,
+        },
+        (HostUnreachable, Address::Domain(domain, _)) => {
+            ErrorKind::HostUnreachable { host: domain, port }
+        }
+        (ConnectionNotAllowed, addr) => ErrorKind::connection_not_allowed(addr, protocol),
+        (ConnectionRefused, addr) => ErrorKind::connection_refused(addr, protocol),
+        (CommandNotSupported, _) => ErrorKind::command_not_supported(command),
+        (cerr, _) => ErrorKind::message_fmt(format_args!("upstream proxy: {}", cerr)),
+    }
+    .into()
+}
+
+/// UDP association with a SOCKS5 proxy
+///
+/// The association is terminated when this is dropped.
+#[derive(Debug)]
+pub struct Socks5Datagram {
+    /// the association lasts while this connection is open
+    control: TcpStream,
+    socket: UdpSocket,
+    /// address of the relay on the proxy
+    relay: SocketAddr,
+}
+
+impl Socks5Datagram {
+    /// address of the relay on the proxy
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /// Send `data` to `dst` through the proxy
+    pub fn send_to(&self, data: &[u8], dst: Address) -> Result<(), Error> {
+        let mut pkt = vec![];
+        write_datagram(
+            &mut pkt,
+            &UdpDatagram {
+                frag: 0,
+                dst_addr: dst,
+                data,
+            },
+        )?;
+        self.socket.send_to(&pkt, self.relay)?;
+        Ok(())
+    }
+
+    /// Receive a datagram into `buf`
+    ///
+    /// Returns the size of the data and the host sent it.
+    /// Datagrams not from the relay are ignored.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        let mut pkt = vec![0; MAX_PKT_SIZE];
+        loop {
+            let (size, src) = self.socket.recv_from(&mut pkt)?;
+            if src != self.relay {
+                debug!("drop datagram from unknown host: {}", src);
+                continue;
+            }
+            let datagram = read_datagram(&pkt[..size])?;
+            let size = datagram.data.len().min(buf.len());
+            buf[..size].copy_from_slice(&datagram.data[..size]);
+            return Ok((size, datagram.dst_addr));
+        }
+    }
+
+    /// The control connection to the proxy
+    pub fn control(&self) -> &TcpStream {
+        &self.control
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::server::Server;
+    use crate::server_command::ServerCommand;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// spawn a server listening on a free port of localhost
+    fn spawn_server(mut config: ServerConfig) -> (SocketAddr, impl FnOnce()) {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        config
+            .set_server_addr(addr)
+            .set_accept_timeout(Some(Duration::from_millis(100)));
+        let (mut server, tx) = Server::new(config);
+        let server_th = thread::spawn(move || server.serve().unwrap());
+        let stop = move || {
+            tx.send(ServerCommand::Terminate).unwrap();
+            server_th.join().unwrap();
+        };
+        (addr, stop)
+    }
+
+    #[test]
+    fn connect() {
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut strm, _) = echo.accept().unwrap();
+            let mut buf = [0; 5];
+            strm.read_exact(&mut buf).unwrap();
+            strm.write_all(&buf).unwrap();
+        });
+        let (proxy, stop) = spawn_server(ServerConfig::default());
+
+        let mut client = Socks5Client::new(proxy);
+        client.set_rw_timeout(Some(Duration::from_secs(5)));
+        // the server may not be listening yet
+        let (mut strm, _) = loop {
+            match client.connect(echo_addr.into()) {
+                Ok(conn) => break conn,
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        strm.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        strm.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let err = client
+            .connect(Address::Domain("unknown.invalid".into(), 80))
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::DomainNotResolved {
+                domain: "unknown.invalid".into(),
+                port: 80
+            }
+        );
+        stop();
+    }
+
+    #[test]
+    fn udp_associate() {
+        let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (size, peer) = echo.recv_from(&mut buf).unwrap();
+            echo.send_to(&buf[..size], peer).unwrap();
+        });
+        let (proxy, stop) = spawn_server(ServerConfig::default());
+
+        let mut client = Socks5Client::new(proxy);
+        client.set_rw_timeout(Some(Duration::from_secs(5)));
+        let local = "127.0.0.1:0".parse().unwrap();
+        let udp = loop {
+            match client.udp_associate(local) {
+                Ok(udp) => break udp,
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        udp.send_to(b"ping", echo_addr.into()).unwrap();
+        let mut buf = [0; 1024];
+        let (size, src) = udp.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"ping");
+        assert_eq!(src, echo_addr.into());
+        drop(udp);
+        stop();
+    }
+}
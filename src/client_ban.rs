@@ -0,0 +1,259 @@
+//! Temporary bans of clients failing repeatedly
+//!
+//! Clients repeating failed attempts (e.g. denied by rules, failing authentication, or sending
+//! malformed messages) occupy threads of the server for nothing.
+//! Failures of destinations (e.g. refused connections) are not counted against clients,
+//! and clients of unix sockets are never banned, as all of them share one address.
+//! With `ServerConfig::client_ban`, a client failing [`ClientBanConfig::threshold`] times within
+//! the window is banned for a while, and its connections are closed as soon as they are accepted.
+//! Banned clients are listed in `Metrics::banned_clients`.
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use crate::acceptor::UNIX_CLIENT_ADDR;
+use crate::model::{Error, ErrorKind, IpAddr, SocketAddr};
+
+/// Failures and bans of clients are kept up to this number each,
+/// the oldest failures and the bans lifted soonest are forgotten beyond it
+const MAX_CLIENTS: usize = 4096;
+
+/// Parameters of banning clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientBanConfig {
+    /// number of failed attempts to ban a client
+    pub threshold: usize,
+    /// failed attempts are counted within this duration
+    pub window: Duration,
+    /// duration of a ban
+    pub duration: Duration,
+}
+
+impl Default for ClientBanConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 10,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Client banned at a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BannedClient {
+    pub ip: IpAddr,
+    /// until the ban is lifted
+    pub remaining: Duration,
+}
+
+/// The session of the client at `addr` failed by the client, not by the server or the destination
+///
+/// Clients of unix sockets are not counted, since all of them are seen as `UNIX_CLIENT_ADDR`.
+pub(crate) fn is_failed_attempt(addr: SocketAddr, err: &Error) -> bool {
+    use ErrorKind as K;
+    #[cfg(unix)]
+    if addr == UNIX_CLIENT_ADDR {
+        return false;
+    }
+    matches!(
+        err.kind(),
+        K::MessageFormat { .. }
+            | K::Authentication
+            | K::NoAcceptableMethod
+            | K::UnrecognizedUsernamePassword
+            | K::ConnectionNotAllowed { .. }
+    )
+}
+
+/// Recent failures and bans keyed by client ip address
+#[derive(Debug)]
+pub(crate) struct ClientBans {
+    config: ClientBanConfig,
+    /// time of failed attempts within the window
+    failures: HashMap<IpAddr, VecDeque<Instant>>,
+    /// time each ban is lifted
+    banned: HashMap<IpAddr, Instant>,
+}
+
+impl ClientBans {
+    pub fn new(config: ClientBanConfig) -> Self {
+        Self {
+            config,
+            failures: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    pub fn is_banned(&mut self, ip: IpAddr) -> bool {
+        match self.banned.get(&ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.banned.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a failed attempt of `ip`
+    ///
+    /// returns `true` if `ip` is banned by this attempt.
+    pub fn fail(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.failures.len() >= MAX_CLIENTS && !self.failures.contains_key(&ip) {
+            self.purge(now);
+            evict_earliest(&mut self.failures, |failures| failures.back().copied());
+        }
+        let window = self.config.window;
+        let failures = self.failures.entry(ip).or_default();
+        while matches!(failures.front(), Some(t) if now.duration_since(*t) >= window) {
+            failures.pop_front();
+        }
+        failures.push_back(now);
+        if failures.len() < self.config.threshold {
+            return false;
+        }
+        self.failures.remove(&ip);
+        if self.banned.len() >= MAX_CLIENTS && !self.banned.contains_key(&ip) {
+            self.purge(now);
+            evict_earliest(&mut self.banned, |until| Some(*until));
+        }
+        self.banned.insert(ip, now + self.config.duration);
+        true
+    }
+
+    /// Clients banned now in order of ip address
+    pub fn banned_clients(&self) -> Vec<BannedClient> {
+        let now = Instant::now();
+        let mut banned: Vec<_> = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| BannedClient {
+                ip: *ip,
+                remaining: until.duration_since(now),
+            })
+            .collect();
+        banned.sort_by_key(|client| client.ip);
+        banned
+    }
+
+    /// Forget failures out of the window and lifted bans
+    fn purge(&mut self, now: Instant) {
+        let window = self.config.window;
+        self.failures.retain(
+            |_, failures| matches!(failures.back(), Some(t) if now.duration_since(*t) < window),
+        );
+        self.banned.retain(|_, until| *until > now);
+    }
+}
+
+/// Remove the client with the earliest `time` from `clients` if it has `MAX_CLIENTS` clients
+fn evict_earliest<T>(clients: &mut HashMap<IpAddr, T>, time: impl Fn(&T) -> Option<Instant>) {
+    if clients.len() < MAX_CLIENTS {
+        return;
+    }
+    let earliest = clients
+        .iter()
+        .min_by_key(|(_, value)| time(value))
+        .map(|(ip, _)| *ip);
+    if let Some(ip) = earliest {
+        clients.remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn ban_clients() {
+        let client1: IpAddr = "192.168.0.1".parse().unwrap();
+        let client2: IpAddr = "192.168.0.2".parse().unwrap();
+        let mut bans = ClientBans::new(ClientBanConfig {
+            threshold: 3,
+            window: Duration::from_millis(300),
+            duration: Duration::from_millis(300),
+        });
+        assert!(!bans.fail(client1));
+        assert!(!bans.fail(client1));
+        // the first failure is out of the window
+        thread::sleep(Duration::from_millis(350));
+        assert!(!bans.fail(client1));
+        assert!(!bans.fail(client1));
+        assert!(!bans.is_banned(client1));
+        assert!(bans.fail(client1));
+        assert!(bans.is_banned(client1));
+        // other clients are not affected
+        assert!(!bans.is_banned(client2));
+        assert!(!bans.fail(client2));
+
+        let banned = bans.banned_clients();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].ip, client1);
+        assert!(banned[0].remaining <= Duration::from_millis(300));
+
+        thread::sleep(Duration::from_millis(350));
+        assert!(!bans.is_banned(client1));
+        assert!(bans.banned_clients().is_empty());
+        bans.purge(Instant::now());
+        assert!(bans.failures.is_empty());
+    }
+
+    #[test]
+    fn limit_clients() {
+        use crate::model::Ipv4Addr;
+
+        let client = |n: usize| IpAddr::from(Ipv4Addr::from(0x0a00_0000 + n as u32));
+        let mut bans = ClientBans::new(ClientBanConfig {
+            threshold: 2,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(60),
+        });
+        assert!(!bans.fail(client(0)));
+        thread::sleep(Duration::from_millis(1));
+        for n in 1..=MAX_CLIENTS {
+            assert!(!bans.fail(client(n)));
+        }
+        // the client failed first is forgotten
+        assert_eq!(bans.failures.len(), MAX_CLIENTS);
+        assert!(!bans.failures.contains_key(&client(0)));
+        assert!(bans.failures.contains_key(&client(MAX_CLIENTS)));
+
+        assert!(bans.fail(client(1)));
+        thread::sleep(Duration::from_millis(1));
+        for n in 2..=MAX_CLIENTS {
+            assert!(bans.fail(client(n)));
+        }
+        assert!(!bans.fail(client(0)));
+        assert!(bans.fail(client(0)));
+        // the ban lifted first is forgotten
+        assert_eq!(bans.banned.len(), MAX_CLIENTS);
+        assert!(!bans.is_banned(client(1)));
+        assert!(bans.is_banned(client(0)));
+        assert!(bans.is_banned(client(MAX_CLIENTS)));
+    }
+
+    #[test]
+    fn failed_attempts() {
+        use crate::model::{Address, L4Protocol};
+
+        let client: SocketAddr = "192.168.0.1:5000".parse().unwrap();
+        let dst = Address::from(SocketAddr::from(([192, 0, 2, 1], 80)));
+        let not_allowed = ErrorKind::connection_not_allowed(dst.clone(), L4Protocol::Tcp).into();
+        assert!(is_failed_attempt(client, &not_allowed));
+        assert!(is_failed_attempt(client, &ErrorKind::Authentication.into()));
+        // failures of the destination
+        let refused = ErrorKind::connection_refused(dst, L4Protocol::Tcp).into();
+        assert!(!is_failed_attempt(client, &refused));
+        let unresolved = ErrorKind::DomainNotResolved {
+            domain: "example.test".to_owned(),
+            port: 80,
+        };
+        assert!(!is_failed_attempt(client, &unresolved.into()));
+        #[cfg(unix)]
+        assert!(!is_failed_attempt(UNIX_CLIENT_ADDR, &not_allowed));
+    }
+}
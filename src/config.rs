@@ -1,11 +1,203 @@
+#![allow(non_local_definitions)]
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::Path;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
+use crate::audit::SessionLogger;
+use crate::auth_service::CredentialStore;
+use crate::client_ban::ClientBanConfig;
+use crate::conn_pool::ConnectionPoolConfig;
+use crate::dns_cache::DnsCacheConfig;
 use crate::error::{Error, ErrorKind};
-use crate::model::{ConnectRule, IpAddr, Ipv4Addr, SocketAddr};
+use crate::event::{EventLogger, ServerEventHandler};
+use crate::geoip::GeoIpProvider;
+use crate::model::{
+    Address, ConnectError, ConnectRule, IpAddr, Ipv4Addr, Method, OutboundBind, SocketAddr,
+};
+use crate::policy::ConnectPolicy;
+use crate::relay::DEFAULT_BUFFER_SIZE;
+use crate::rewrite::ConnectRewriter;
 
-use failure::ResultExt;
+use failure::{Fail, ResultExt};
+use serde::Deserialize;
+
+/// Bandwidth limit
+///
+/// `0` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// bytes/sec relayed from client to external network
+    pub upload_bps: u64,
+    /// bytes/sec relayed from external network to client
+    pub download_bps: u64,
+}
+
+impl RateLimit {
+    /// same limit in both directions
+    pub fn symmetric(bps: u64) -> Self {
+        Self {
+            upload_bps: bps,
+            download_bps: bps,
+        }
+    }
+}
+
+/// TCP keepalive probes detecting half-open connections (e.g. dropped by a NAT)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// idle duration before the first probe
+    pub time: Duration,
+    /// interval between probes (`None` uses the system default)
+    pub interval: Option<Duration>,
+    /// number of unanswered probes before the connection is dropped
+    /// (`None` uses the system default, not supported on Windows)
+    pub retries: Option<u32>,
+}
+
+impl TcpKeepalive {
+    pub fn new(time: Duration) -> Self {
+        Self {
+            time,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    /// enable keepalive on `sock`
+    pub(crate) fn apply<'s>(&self, sock: impl Into<socket2::SockRef<'s>>) -> io::Result<()> {
+        let mut params = socket2::TcpKeepalive::new().with_time(self.time);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "windows"
+        ))]
+        if let Some(interval) = self.interval {
+            params = params.with_interval(interval);
+        }
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos"
+        ))]
+        if let Some(retries) = self.retries {
+            params = params.with_retries(retries);
+        }
+        sock.into().set_tcp_keepalive(&params)
+    }
+}
+
+/// Address replied to CONNECT requests as `BND.ADDR` and `BND.PORT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplyAddr {
+    /// `server_ip` and `server_port` of the configuration
+    #[default]
+    ServerAddr,
+    /// local address of the connection to the destination
+    LocalAddr,
+    /// specified address (e.g. the external address of a NAT)
+    Fixed(SocketAddr),
+}
+
+impl ReplyAddr {
+    /// `local` is the local address of the connection to the destination if it is known
+    pub(crate) fn select(&self, server_addr: SocketAddr, local: Option<SocketAddr>) -> SocketAddr {
+        match self {
+            ReplyAddr::ServerAddr => server_addr,
+            ReplyAddr::LocalAddr => local.unwrap_or(server_addr),
+            ReplyAddr::Fixed(addr) => *addr,
+        }
+    }
+}
+
+/// `server`, `local` or a socket address
+impl FromStr for ReplyAddr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "server" => Ok(ReplyAddr::ServerAddr),
+            "local" => Ok(ReplyAddr::LocalAddr),
+            addr => addr
+                .parse()
+                .map(ReplyAddr::Fixed)
+                .map_err(|err| format!("{}: {}", addr, err)),
+        }
+    }
+}
+
+/// What acceptors do with a connection while `ServerConfig::accept_queue_capacity` connections are pending
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AcceptOverflow {
+    /// stop accepting until the server takes a pending connection,
+    /// leaving new clients in the backlog of the listening socket
+    #[default]
+    Block,
+    /// reply `ServerFailure` to the client, or close the connection without a reply
+    /// while too many rejected clients are being replied
+    Reject,
+}
+
+/// `block` or `reject`
+impl FromStr for AcceptOverflow {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(AcceptOverflow::Block),
+            "reject" => Ok(AcceptOverflow::Reject),
+            s => Err(format!("{}: expected block or reject", s)),
+        }
+    }
+}
+
+/// Connections to `listen` forwarded to `to` without a SOCKS handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    pub listen: SocketAddr,
+    /// destination checked by the connect rule as CONNECT requests
+    pub to: Address,
+}
+
+/// `LISTEN=HOST:PORT` (e.g. `0.0.0.0:8443=internal:443`)
+impl FromStr for Forward {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (listen, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{}: expected LISTEN=HOST:PORT", s))?;
+        let listen = listen
+            .parse()
+            .map_err(|err| format!("{}: {}", listen, err))?;
+        let to = match to.parse::<SocketAddr>() {
+            Ok(addr) => addr.into(),
+            Err(_) => {
+                let (host, port) = to
+                    .rsplit_once(':')
+                    .ok_or_else(|| format!("{}: port is required", to))?;
+                let port = port.parse().map_err(|err| format!("{}: {}", to, err))?;
+                Address::Domain(host.to_owned(), port)
+            }
+        };
+        Ok(Forward { listen, to })
+    }
+}
+
+/// Account the server runs as after binding its listening sockets
+///
+/// If `group` is not given, the primary group of `user` is used.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunAs {
+    /// user name or uid
+    pub user: Option<String>,
+    /// group name or gid
+    pub group: Option<String>,
+}
 
 /// Server configuration
 #[derive(Debug, Clone)]
@@ -14,14 +206,124 @@ pub struct ServerConfig {
     pub server_ip: IpAddr,
     /// port number for listening connections. (default: 1080)
     pub server_port: u16,
+    /// addresses for listening connections in addition to `server_ip` and `server_port`. (default: empty)
+    pub additional_addrs: Vec<SocketAddr>,
+    /// unix domain socket listened on by `Server::with_unix_socket` instead of tcp addresses. (default: None)
+    pub unix_socket: Option<PathBuf>,
+    /// switch to this account by `Server::start` after binding the listening sockets (unix only). (default: None)
+    /// The rule file reloaded later and the directory of `unix_socket` should be accessible by it.
+    pub run_as: Option<RunAs>,
     /// rule set for filtering connection requests (default: allow any connection)
     pub conn_rule: ConnectRule,
+    /// policy deciding connect requests instead of `conn_rule`. (default: None)
+    pub policy: Option<Arc<dyn ConnectPolicy>>,
+    /// rewriter of destinations allowed by the rule or the policy. (default: None)
+    pub rewriter: Option<Arc<dyn ConnectRewriter>>,
     /// timeout of relaying data chunk from client to external network. (default: 2000ms)
     pub client_rw_timeout: Option<Duration>,
     /// timeout of relaying data chunk from external network to client. (default: 5000ms)
     pub server_rw_timeout: Option<Duration>,
-    /// timeout of accpet connection from client. (default 3s)
+    /// interval of acceptors checking the termination where they are not woken up
+    /// (other than unix platforms). (default 3s)
     pub accept_timeout: Option<Duration>,
+    /// timeout of the handshake from connection until the request of a client is read,
+    /// including the authentication. (default: None)
+    pub handshake_timeout: Option<Duration>,
+    /// credentials for username/password authentication. (default: None)
+    /// If this is set, clients are required to authenticate with `USERNAME/PASSWORD` method.
+    pub credentials: Option<Arc<dyn CredentialStore>>,
+    /// auth methods the server may select, whatever the auth service selects. (default: None)
+    /// If `NoAuth` is not included, clients are never served without authentication
+    /// even if credentials are missing by mistake.
+    pub allowed_methods: Option<Vec<Method>>,
+    /// bandwidth limit of each session. (default: None)
+    pub rate_limit: Option<RateLimit>,
+    /// bandwidth limit shared by all sessions. (default: None)
+    pub global_rate_limit: Option<RateLimit>,
+    /// receiver of audit events of sessions. (default: None)
+    pub session_logger: Option<Arc<dyn SessionLogger>>,
+    /// receiver of session lifecycle events. (default: None)
+    pub event_handler: Option<Arc<dyn ServerEventHandler>>,
+    /// timeout of waiting an incoming connection for BIND command. (default: 60s)
+    pub bind_timeout: Option<Duration>,
+    /// ports listened on for BIND command. (default: None, an ephemeral port is used)
+    pub bind_ports: Option<RangeInclusive<u16>>,
+    /// terminate sessions relaying no data for this duration. (default: None)
+    pub idle_timeout: Option<Duration>,
+    /// terminate sessions after relaying for this duration. (default: None)
+    pub max_session_duration: Option<Duration>,
+    /// apply ip address rules of `conn_rule` also to addresses resolved from requested domains,
+    /// and connect only to allowed ones. (default: false)
+    /// Not supported by `aio::Server`.
+    pub check_resolved: bool,
+    /// reject private, loopback and link-local addresses resolved from requested domains
+    /// unless a rule of the address allows them, against DNS rebinding. (default: false)
+    /// Not supported by `aio::Server`.
+    pub reject_private_resolved: bool,
+    /// accept SOCKS4/4a CONNECT requests, if authentication is not required. (default: false)
+    pub accept_socks4: bool,
+    /// accept method candidates and requests of SOCKS5 clients with another protocol version,
+    /// for buggy clients. (default: false)
+    pub lenient_version: bool,
+    /// address listened on for HTTP CONNECT requests, if authentication is not required. (default: None)
+    pub http_connect_addr: Option<SocketAddr>,
+    /// static forwardings of connections, checked by the connect rule but not authenticated. (default: empty)
+    pub forwards: Vec<Forward>,
+    /// for CONNECT requests to an ip address on port 443, read the TLS ClientHello
+    /// and close the session if its server name (SNI) is denied by domain rules. (default: false)
+    /// Not supported by `aio::Server`.
+    pub inspect_sni: bool,
+    /// reassemble fragmented UDP datagrams from clients within this duration. (default: None)
+    /// If `None`, fragmented datagrams are dropped.
+    pub udp_reassembly_timeout: Option<Duration>,
+    /// cache domains resolved for connections to destinations. (default: None)
+    pub dns_cache: Option<DnsCacheConfig>,
+    /// keep spare connections to recently connected destinations. (default: None)
+    /// Connections relayed for clients are never reused.
+    pub connection_pool: Option<ConnectionPoolConfig>,
+    /// keepalive probes on connections from clients and to destinations. (default: None)
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// local address or network device of connections to destinations. (default: None)
+    /// Rules allowing connections may bind them to another one by `ConnectRulePattern::bind`.
+    pub outbound_bind_addr: Option<OutboundBind>,
+    /// connections/sec accepted from each client ip address. (default: None)
+    /// Connections exceeding the limit are closed without a reply.
+    pub connection_rate_limit: Option<u64>,
+    /// ban clients failing repeatedly for a while. (default: None)
+    /// Connections of banned clients are closed without a reply.
+    pub client_ban: Option<ClientBanConfig>,
+    /// maximum number of accepted connections waiting for the server to start their sessions. (default: None)
+    /// Under connection floods, this bounds the memory held by connections the server cannot keep up with.
+    pub accept_queue_capacity: Option<usize>,
+    /// connections accepted while `accept_queue_capacity` connections are pending. (default: Block)
+    pub accept_overflow: AcceptOverflow,
+    /// maximum number of running sessions. (default: None)
+    pub max_sessions: Option<usize>,
+    /// reply to clients connected while `max_sessions` sessions are running. (default: ServerFailure)
+    ///
+    /// Clients are closed without the reply while too many rejected clients are being replied.
+    pub max_sessions_reply: ConnectError,
+    /// delay before connecting to the next address resolved from a domain
+    /// while the previous attempt is in progress. (default: 250ms)
+    pub connect_attempt_delay: Duration,
+    /// timeout of connecting to each address of a destination. (default: None)
+    /// Timed out requests are replied `TtlExpired`.
+    pub connect_timeout: Option<Duration>,
+    /// retries of connecting to a destination which refused or timed out. (default: 0)
+    /// The reply to the client is decided by the error of the last attempt.
+    pub connect_retries: u32,
+    /// delay before the first retry, doubled for each subsequent retry. (default: 100ms)
+    pub retry_backoff: Duration,
+    /// address replied to CONNECT requests. (default: ServerAddr)
+    pub reply_addr: ReplyAddr,
+    /// size of the buffer of each direction of TCP relays. (default: 8 KiB)
+    /// This is not applied to relays by `splice` and the async server.
+    pub relay_buffer_size: usize,
+    /// locates ip addresses for country patterns of `conn_rule`. (default: None)
+    pub geoip: Option<Arc<dyn GeoIpProvider>>,
+    /// number of recent connections the rule deciding them is cached for. (default: None)
+    /// This is shared by sessions of a server, and is cleared when the rule is reloaded.
+    pub rule_cache_capacity: Option<usize>,
 }
 
 impl ServerConfig {
@@ -79,8 +381,7 @@ impl ServerConfig {
     /// ```
     ///
     pub fn with_file(server_ip: IpAddr, server_port: u16, rulefile: &Path) -> Result<Self, Error> {
-        let path = File::open(rulefile)?;
-        let conn_rule = serde_yaml::from_reader(path).context(ErrorKind::Config)?;
+        let conn_rule = load_connect_rule(rulefile)?;
         Ok(ServerConfig {
             server_ip,
             server_port,
@@ -90,15 +391,208 @@ impl ServerConfig {
     }
 }
 
+/// Load filtering rules from a yaml file
+///
+/// See [`ServerConfig::with_file`] for the format.
+///
+/// The file may also be a mapping of `groups` of address patterns, `include` of other rule files
+/// and `rules`. Included files are found relative to the including file, and their groups and
+/// rules are merged; the included rules are placed before the rules of the including file,
+/// except the first (base) rule of `rulefile`.
+///
+/// ```yaml
+/// # rule.yml
+/// groups:
+///   internal:
+///     - IpAddr: { addr: 10.0.0.0, prefix: 8 }
+///     - Domain: { wildcard: "*.corp.example" }
+/// include:
+///   - blocked.yml
+/// rules:
+///   - Allow: { address: Any, port: Any, protocol: Any }
+///   - Deny: { address: { Specif: { Group: internal } }, port: Any, protocol: Any }
+/// ```
+pub fn load_connect_rule(rulefile: &Path) -> Result<ConnectRule, Error> {
+    let mut groups = serde_yaml::Mapping::new();
+    let rules = load_rule_file(rulefile, true, &mut vec![], &mut groups, &mut vec![])?;
+    let mut rule_set = serde_yaml::Mapping::new();
+    rule_set.insert("groups".into(), groups.into());
+    rule_set.insert("rules".into(), rules.into());
+    Ok(serde_yaml::from_value(rule_set.into()).context(ErrorKind::Config)?)
+}
+
+/// `rulefile` and the files included by it (canonicalized), read by [`load_connect_rule`]
+pub fn connect_rule_files(rulefile: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    load_rule_file(
+        rulefile,
+        true,
+        &mut vec![],
+        &mut serde_yaml::Mapping::new(),
+        &mut files,
+    )?;
+    Ok(files)
+}
+
+/// Rule file with groups and included files
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    groups: serde_yaml::Mapping,
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    rules: Vec<serde_yaml::Value>,
+}
+
+/// Rules of `path` with the rules of the included files
+///
+/// The groups are merged into `groups`, `stack` holds the files being loaded
+/// and `loaded` receives the files loaded.
+/// The first rule of the file is kept first if `base`.
+fn load_rule_file(
+    path: &Path,
+    base: bool,
+    stack: &mut Vec<PathBuf>,
+    groups: &mut serde_yaml::Mapping,
+    loaded: &mut Vec<PathBuf>,
+) -> Result<Vec<serde_yaml::Value>, Error> {
+    let canonical = path.canonicalize()?;
+    if stack.contains(&canonical) {
+        return Err(rule_file_error(path, "circular include"));
+    }
+    if !loaded.contains(&canonical) {
+        loaded.push(canonical.clone());
+    }
+    let value: serde_yaml::Value =
+        serde_yaml::from_reader(File::open(path)?).context(ErrorKind::Config)?;
+    let file = match value {
+        serde_yaml::Value::Sequence(rules) => RuleFile {
+            groups: serde_yaml::Mapping::new(),
+            include: vec![],
+            rules,
+        },
+        value => serde_yaml::from_value(value).context(ErrorKind::Config)?,
+    };
+    for (name, group) in file.groups {
+        if groups.contains_key(&name) {
+            let name = name.as_str().unwrap_or_default();
+            return Err(rule_file_error(
+                path,
+                format_args!("duplicate group: {}", name),
+            ));
+        }
+        groups.insert(name, group);
+    }
+
+    let mut rules = file.rules;
+    let own = if base && !rules.is_empty() {
+        rules.split_off(1)
+    } else {
+        std::mem::take(&mut rules)
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    stack.push(canonical);
+    for include in &file.include {
+        rules.extend(load_rule_file(
+            &dir.join(include),
+            false,
+            stack,
+            groups,
+            loaded,
+        )?);
+    }
+    stack.pop();
+    rules.extend(own);
+    Ok(rules)
+}
+
+fn rule_file_error(path: &Path, msg: impl std::fmt::Display) -> Error {
+    failure::err_msg(format!("{}: {}", path.display(), msg))
+        .context(ErrorKind::Config)
+        .into()
+}
+
+/// Load username/password pairs from a yaml file
+///
+/// The file is a mapping from username to password.
+///
+/// ```
+/// use std::fs;
+/// # use std::sync::Arc;
+/// # use gatekeeper::error::Error;
+/// use gatekeeper::config::{load_credentials, ServerConfig};
+/// # fn main() -> Result<(), Error> {
+/// let path = std::env::temp_dir().join("users.yml");
+/// fs::write(&path, r#"
+/// ---
+/// alice: secret
+/// bob: password
+/// "#.as_bytes())?;
+/// let users = load_credentials(&path)?;
+/// assert_eq!(users["alice"], "secret");
+/// let mut config = ServerConfig::default();
+/// config.set_credentials(Some(Arc::new(users)));
+/// # Ok(())
+/// # }
+/// ```
+pub fn load_credentials(path: &Path) -> Result<HashMap<String, String>, Error> {
+    let file = File::open(path)?;
+    Ok(serde_yaml::from_reader(file).context(ErrorKind::Config)?)
+}
+
 impl Default for ServerConfig {
     fn default() -> Self {
         ServerConfig {
             server_ip: Ipv4Addr::new(0, 0, 0, 0).into(),
             server_port: 1080,
+            additional_addrs: vec![],
+            unix_socket: None,
+            run_as: None,
             conn_rule: ConnectRule::any(),
+            policy: None,
+            rewriter: None,
             client_rw_timeout: Some(Duration::from_millis(2000)),
             server_rw_timeout: Some(Duration::from_millis(5000)),
             accept_timeout: Some(Duration::from_secs(3)),
+            handshake_timeout: None,
+            credentials: None,
+            allowed_methods: None,
+            rate_limit: None,
+            global_rate_limit: None,
+            session_logger: None,
+            event_handler: None,
+            bind_timeout: Some(Duration::from_secs(60)),
+            bind_ports: None,
+            idle_timeout: None,
+            max_session_duration: None,
+            check_resolved: false,
+            reject_private_resolved: false,
+            accept_socks4: false,
+            lenient_version: false,
+            http_connect_addr: None,
+            forwards: Vec::new(),
+            inspect_sni: false,
+            udp_reassembly_timeout: None,
+            dns_cache: None,
+            connection_pool: None,
+            tcp_keepalive: None,
+            outbound_bind_addr: None,
+            connection_rate_limit: None,
+            client_ban: None,
+            accept_queue_capacity: None,
+            accept_overflow: AcceptOverflow::Block,
+            max_sessions: None,
+            max_sessions_reply: ConnectError::ServerFailure,
+            connect_attempt_delay: Duration::from_millis(250),
+            connect_timeout: None,
+            connect_retries: 0,
+            retry_backoff: Duration::from_millis(100),
+            reply_addr: ReplyAddr::ServerAddr,
+            relay_buffer_size: DEFAULT_BUFFER_SIZE,
+            geoip: None,
+            rule_cache_capacity: None,
         }
     }
 }
@@ -108,8 +602,38 @@ impl ServerConfig {
         SocketAddr::new(self.server_ip, self.server_port)
     }
 
+    /// all addresses for listening connections
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = vec![self.server_addr()];
+        addrs.extend_from_slice(&self.additional_addrs);
+        addrs
+    }
+
+    /// number of acceptors of `listen_addrs`, `http_connect_addr` and `forwards`
+    pub(crate) fn acceptor_count(&self) -> usize {
+        self.listen_addrs().len()
+            + usize::from(self.http_connect_addr.is_some())
+            + self.forwards.len()
+    }
+
+    /// `conn_rule` locating ip addresses by `geoip`, and caching decisions by `rule_cache_capacity`
     pub fn connect_rule(&self) -> ConnectRule {
-        self.conn_rule.clone()
+        let mut rule = self.conn_rule.clone();
+        if self.geoip.is_some() {
+            rule.set_geoip_provider(self.geoip.clone());
+        }
+        if self.rule_cache_capacity.is_some() {
+            rule.set_decision_cache(self.rule_cache_capacity);
+        }
+        rule
+    }
+
+    /// `policy`, or `conn_rule` if it is not set
+    pub(crate) fn connect_policy(&self) -> Arc<dyn ConnectPolicy> {
+        match &self.policy {
+            Some(policy) => policy.clone(),
+            None => Arc::new(self.connect_rule()),
+        }
     }
 
     pub fn set_server_addr(&mut self, addr: SocketAddr) -> &mut Self {
@@ -118,11 +642,45 @@ impl ServerConfig {
         self
     }
 
+    /// listen on all of `addrs`, the first one is set to `server_ip` and `server_port`
+    pub fn set_listen_addrs(&mut self, addrs: &[SocketAddr]) -> &mut Self {
+        if let Some((first, rest)) = addrs.split_first() {
+            self.set_server_addr(*first);
+            self.additional_addrs = rest.to_vec();
+        }
+        self
+    }
+
+    pub fn set_unix_socket(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.unix_socket = path;
+        self
+    }
+
+    pub fn set_run_as(&mut self, run_as: Option<RunAs>) -> &mut Self {
+        self.run_as = run_as;
+        self
+    }
+
     pub fn set_connect_rule(&mut self, rule: ConnectRule) -> &mut Self {
         self.conn_rule = rule;
         self
     }
 
+    /// decide requests by `policy` instead of `conn_rule` (`None` applies `conn_rule`)
+    pub fn set_connect_policy(&mut self, policy: Option<Arc<dyn ConnectPolicy>>) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+
+    /// rewrite destinations allowed by the rule or the policy before connecting
+    pub fn set_connect_rewriter(
+        &mut self,
+        rewriter: Option<Arc<dyn ConnectRewriter>>,
+    ) -> &mut Self {
+        self.rewriter = rewriter;
+        self
+    }
+
     pub fn set_client_rw_timeout(&mut self, dur: Option<Duration>) -> &mut Self {
         self.client_rw_timeout = dur;
         self
@@ -137,4 +695,590 @@ impl ServerConfig {
         self.accept_timeout = dur;
         self
     }
+
+    pub fn set_credentials(&mut self, credentials: Option<Arc<dyn CredentialStore>>) -> &mut Self {
+        self.credentials = credentials;
+        self
+    }
+
+    pub fn set_allowed_methods(&mut self, methods: Option<Vec<Method>>) -> &mut Self {
+        self.allowed_methods = methods;
+        self
+    }
+
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) -> &mut Self {
+        self.rate_limit = limit;
+        self
+    }
+
+    pub fn set_global_rate_limit(&mut self, limit: Option<RateLimit>) -> &mut Self {
+        self.global_rate_limit = limit;
+        self
+    }
+
+    pub fn set_session_logger(&mut self, logger: Option<Arc<dyn SessionLogger>>) -> &mut Self {
+        self.session_logger = logger;
+        self
+    }
+
+    /// Register `logger` in addition to the logger already set
+    pub fn add_session_logger(&mut self, logger: Arc<dyn SessionLogger>) -> &mut Self {
+        self.session_logger = Some(match self.session_logger.take() {
+            None => logger,
+            Some(current) => Arc::new(vec![current, logger]),
+        });
+        self
+    }
+
+    pub fn set_event_handler(&mut self, handler: Option<Arc<dyn ServerEventHandler>>) -> &mut Self {
+        self.event_handler = handler;
+        self
+    }
+
+    /// Logger notifying both `session_logger` and `event_handler`
+    pub(crate) fn audit_logger(&self) -> Option<Arc<dyn SessionLogger>> {
+        let events = self
+            .event_handler
+            .clone()
+            .map(|handler| Arc::new(EventLogger(handler)) as Arc<dyn SessionLogger>);
+        match (self.session_logger.clone(), events) {
+            (Some(logger), Some(events)) => Some(Arc::new(vec![logger, events])),
+            (logger, events) => logger.or(events),
+        }
+    }
+
+    pub fn set_handshake_timeout(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.handshake_timeout = dur;
+        self
+    }
+
+    pub fn set_bind_timeout(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.bind_timeout = dur;
+        self
+    }
+
+    pub fn set_bind_ports(&mut self, ports: Option<RangeInclusive<u16>>) -> &mut Self {
+        self.bind_ports = ports;
+        self
+    }
+
+    pub fn set_idle_timeout(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.idle_timeout = dur;
+        self
+    }
+
+    pub fn set_max_session_duration(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.max_session_duration = dur;
+        self
+    }
+
+    pub fn set_check_resolved(&mut self, check: bool) -> &mut Self {
+        self.check_resolved = check;
+        self
+    }
+
+    pub fn set_reject_private_resolved(&mut self, reject: bool) -> &mut Self {
+        self.reject_private_resolved = reject;
+        self
+    }
+
+    pub fn set_accept_socks4(&mut self, accept: bool) -> &mut Self {
+        self.accept_socks4 = accept;
+        self
+    }
+
+    pub fn set_lenient_version(&mut self, lenient: bool) -> &mut Self {
+        self.lenient_version = lenient;
+        self
+    }
+
+    /// listen on `addr` for HTTP CONNECT requests, in addition to SOCKS
+    pub fn set_http_connect_addr(&mut self, addr: Option<SocketAddr>) -> &mut Self {
+        self.http_connect_addr = addr;
+        self
+    }
+
+    /// forward connections to the listen address of each of `forwards`, in addition to SOCKS
+    pub fn set_forwards(&mut self, forwards: Vec<Forward>) -> &mut Self {
+        self.forwards = forwards;
+        self
+    }
+
+    pub fn set_connection_rate_limit(&mut self, rate: Option<u64>) -> &mut Self {
+        self.connection_rate_limit = rate;
+        self
+    }
+
+    pub fn set_client_ban(&mut self, ban: Option<ClientBanConfig>) -> &mut Self {
+        self.client_ban = ban;
+        self
+    }
+
+    pub fn set_accept_queue_capacity(&mut self, capacity: Option<usize>) -> &mut Self {
+        self.accept_queue_capacity = capacity;
+        self
+    }
+
+    pub fn set_accept_overflow(&mut self, overflow: AcceptOverflow) -> &mut Self {
+        self.accept_overflow = overflow;
+        self
+    }
+
+    pub fn set_max_sessions(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_sessions = max;
+        self
+    }
+
+    pub fn set_max_sessions_reply(&mut self, reply: ConnectError) -> &mut Self {
+        self.max_sessions_reply = reply;
+        self
+    }
+
+    pub fn set_connect_attempt_delay(&mut self, delay: Duration) -> &mut Self {
+        self.connect_attempt_delay = delay;
+        self
+    }
+
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn set_connect_retries(&mut self, retries: u32) -> &mut Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    pub fn set_retry_backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    pub fn set_relay_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.relay_buffer_size = size;
+        self
+    }
+
+    pub fn set_inspect_sni(&mut self, inspect: bool) -> &mut Self {
+        self.inspect_sni = inspect;
+        self
+    }
+
+    pub fn set_udp_reassembly_timeout(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.udp_reassembly_timeout = dur;
+        self
+    }
+
+    pub fn set_dns_cache(&mut self, cache: Option<DnsCacheConfig>) -> &mut Self {
+        self.dns_cache = cache;
+        self
+    }
+
+    pub fn set_connection_pool(&mut self, pool: Option<ConnectionPoolConfig>) -> &mut Self {
+        self.connection_pool = pool;
+        self
+    }
+
+    pub fn set_tcp_keepalive(&mut self, keepalive: Option<TcpKeepalive>) -> &mut Self {
+        self.tcp_keepalive = keepalive;
+        self
+    }
+
+    pub fn set_outbound_bind_addr(&mut self, bind: Option<OutboundBind>) -> &mut Self {
+        self.outbound_bind_addr = bind;
+        self
+    }
+
+    pub fn set_reply_addr(&mut self, addr: ReplyAddr) -> &mut Self {
+        self.reply_addr = addr;
+        self
+    }
+
+    pub fn set_geoip_provider(&mut self, geoip: Option<Arc<dyn GeoIpProvider>>) -> &mut Self {
+        self.geoip = geoip;
+        self
+    }
+
+    pub fn set_rule_cache_capacity(&mut self, capacity: Option<usize>) -> &mut Self {
+        self.rule_cache_capacity = capacity;
+        self
+    }
+}
+
+/// Invalid combination of `ServerConfig` fields
+#[derive(Fail, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// a zero timeout, which is rejected by sockets. `None` should be used to disable it
+    #[fail(display = "zero timeout: {}", name)]
+    ZeroTimeout { name: &'static str },
+    /// a zero limit, with which no clients are served
+    #[fail(display = "zero limit: {}", name)]
+    ZeroLimit { name: &'static str },
+    #[fail(display = "duplicate listen address: {}", addr)]
+    DuplicateListenAddr { addr: SocketAddr },
+    /// additional addresses are not listened on with a unix domain socket
+    #[fail(display = "additional addresses with unix socket")]
+    UnixSocketWithAdditionalAddrs,
+    #[fail(display = "empty bind ports: {}-{}", start, end)]
+    EmptyBindPorts { start: u16, end: u16 },
+    /// `allowed_methods` is empty
+    #[fail(display = "no allowed auth method")]
+    NoAllowedMethod,
+    /// clients accepted without authentication while `NoAuth` is not allowed by `allowed_methods`
+    #[fail(display = "no-auth is not allowed: {}", name)]
+    NoAuthNotAllowed { name: &'static str },
+    /// SOCKS4 requests are accepted only without authentication
+    #[fail(display = "socks4 with credentials")]
+    Socks4WithCredentials,
+    /// HTTP CONNECT requests are accepted only without authentication
+    #[fail(display = "http connect with credentials")]
+    HttpConnectWithCredentials,
+}
+
+impl ServerConfig {
+    /// Builder of a validated configuration, starting from the default one
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
+    /// Check the combination of fields
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let timeouts = [
+            ("client_rw_timeout", self.client_rw_timeout),
+            ("server_rw_timeout", self.server_rw_timeout),
+            ("accept_timeout", self.accept_timeout),
+            ("handshake_timeout", self.handshake_timeout),
+            ("bind_timeout", self.bind_timeout),
+            ("idle_timeout", self.idle_timeout),
+            ("max_session_duration", self.max_session_duration),
+            ("connect_timeout", self.connect_timeout),
+            ("udp_reassembly_timeout", self.udp_reassembly_timeout),
+            ("tcp_keepalive.time", self.tcp_keepalive.map(|k| k.time)),
+            (
+                "connection_pool.idle_timeout",
+                self.connection_pool.map(|p| p.idle_timeout),
+            ),
+            ("client_ban.window", self.client_ban.map(|b| b.window)),
+            ("client_ban.duration", self.client_ban.map(|b| b.duration)),
+        ];
+        if let Some((name, _)) = timeouts
+            .iter()
+            .find(|(_, dur)| *dur == Some(Duration::ZERO))
+        {
+            return Err(ConfigError::ZeroTimeout { name });
+        }
+        if self.max_sessions == Some(0) {
+            return Err(ConfigError::ZeroLimit {
+                name: "max_sessions",
+            });
+        }
+        if self.relay_buffer_size == 0 {
+            return Err(ConfigError::ZeroLimit {
+                name: "relay_buffer_size",
+            });
+        }
+        if self.connection_rate_limit == Some(0) {
+            return Err(ConfigError::ZeroLimit {
+                name: "connection_rate_limit",
+            });
+        }
+        if self.accept_queue_capacity == Some(0) {
+            return Err(ConfigError::ZeroLimit {
+                name: "accept_queue_capacity",
+            });
+        }
+        if self.rule_cache_capacity == Some(0) {
+            return Err(ConfigError::ZeroLimit {
+                name: "rule_cache_capacity",
+            });
+        }
+        if matches!(self.dns_cache, Some(cache) if cache.capacity == 0) {
+            return Err(ConfigError::ZeroLimit {
+                name: "dns_cache.capacity",
+            });
+        }
+        if matches!(self.client_ban, Some(ban) if ban.threshold == 0) {
+            return Err(ConfigError::ZeroLimit {
+                name: "client_ban.threshold",
+            });
+        }
+        if let Some(pool) = self.connection_pool {
+            if pool.capacity == 0 {
+                return Err(ConfigError::ZeroLimit {
+                    name: "connection_pool.capacity",
+                });
+            }
+            if pool.max_idle_per_destination == 0 {
+                return Err(ConfigError::ZeroLimit {
+                    name: "connection_pool.max_idle_per_destination",
+                });
+            }
+        }
+        let mut addrs = self.listen_addrs();
+        addrs.extend(self.http_connect_addr);
+        addrs.extend(self.forwards.iter().map(|forward| forward.listen));
+        if let Some(addr) = addrs
+            .iter()
+            .enumerate()
+            .find(|(i, addr)| addrs[..*i].contains(addr))
+            .map(|(_, addr)| *addr)
+        {
+            return Err(ConfigError::DuplicateListenAddr { addr });
+        }
+        if self.unix_socket.is_some()
+            && (!self.additional_addrs.is_empty()
+                || self.http_connect_addr.is_some()
+                || !self.forwards.is_empty())
+        {
+            return Err(ConfigError::UnixSocketWithAdditionalAddrs);
+        }
+        if let Some(ports) = &self.bind_ports {
+            if ports.is_empty() {
+                return Err(ConfigError::EmptyBindPorts {
+                    start: *ports.start(),
+                    end: *ports.end(),
+                });
+            }
+        }
+        if self.accept_socks4 && self.credentials.is_some() {
+            return Err(ConfigError::Socks4WithCredentials);
+        }
+        if self.http_connect_addr.is_some() && self.credentials.is_some() {
+            return Err(ConfigError::HttpConnectWithCredentials);
+        }
+        if let Some(allowed) = &self.allowed_methods {
+            if allowed.is_empty() {
+                return Err(ConfigError::NoAllowedMethod);
+            }
+            let no_auth = [
+                ("accept_socks4", self.accept_socks4),
+                ("http_connect_addr", self.http_connect_addr.is_some()),
+                ("forwards", !self.forwards.is_empty()),
+            ];
+            if let Some((name, _)) = no_auth
+                .into_iter()
+                .find(|(_, enabled)| *enabled && !allowed.contains(&Method::NoAuth))
+            {
+                return Err(ConfigError::NoAuthNotAllowed { name });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// define builder methods delegating to the setters of `ServerConfig`
+macro_rules! builder_methods {
+    ($($name:ident => $setter:ident($ty:ty);)*) => {
+        $(
+            pub fn $name(mut self, value: $ty) -> Self {
+                self.config.$setter(value);
+                self
+            }
+        )*
+    };
+}
+
+/// Builder of `ServerConfig`
+///
+/// Unlike setting the fields directly, [`build`](Self::build) checks their combination.
+///
+/// ```
+/// use std::time::Duration;
+/// use gatekeeper::config::{ConfigError, ServerConfig};
+///
+/// let config = ServerConfig::builder()
+///     .server_addr("127.0.0.1:1080".parse().unwrap())
+///     .idle_timeout(Some(Duration::from_secs(600)))
+///     .build()
+///     .unwrap();
+/// assert_eq!(config.server_port, 1080);
+///
+/// let err = ServerConfig::builder()
+///     .connect_timeout(Some(Duration::ZERO))
+///     .build()
+///     .unwrap_err();
+/// assert_eq!(err, ConfigError::ZeroTimeout { name: "connect_timeout" });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    /// listen on all of `addrs`, the first one is the server address
+    pub fn listen_addrs(mut self, addrs: &[SocketAddr]) -> Self {
+        self.config.set_listen_addrs(addrs);
+        self
+    }
+
+    builder_methods! {
+        server_addr => set_server_addr(SocketAddr);
+        unix_socket => set_unix_socket(Option<PathBuf>);
+        run_as => set_run_as(Option<RunAs>);
+        connect_rule => set_connect_rule(ConnectRule);
+        connect_policy => set_connect_policy(Option<Arc<dyn ConnectPolicy>>);
+        connect_rewriter => set_connect_rewriter(Option<Arc<dyn ConnectRewriter>>);
+        client_rw_timeout => set_client_rw_timeout(Option<Duration>);
+        server_rw_timeout => set_server_rw_timeout(Option<Duration>);
+        accept_timeout => set_accept_timeout(Option<Duration>);
+        handshake_timeout => set_handshake_timeout(Option<Duration>);
+        credentials => set_credentials(Option<Arc<dyn CredentialStore>>);
+        allowed_methods => set_allowed_methods(Option<Vec<Method>>);
+        rate_limit => set_rate_limit(Option<RateLimit>);
+        global_rate_limit => set_global_rate_limit(Option<RateLimit>);
+        session_logger => set_session_logger(Option<Arc<dyn SessionLogger>>);
+        event_handler => set_event_handler(Option<Arc<dyn ServerEventHandler>>);
+        bind_timeout => set_bind_timeout(Option<Duration>);
+        bind_ports => set_bind_ports(Option<RangeInclusive<u16>>);
+        idle_timeout => set_idle_timeout(Option<Duration>);
+        max_session_duration => set_max_session_duration(Option<Duration>);
+        check_resolved => set_check_resolved(bool);
+        reject_private_resolved => set_reject_private_resolved(bool);
+        accept_socks4 => set_accept_socks4(bool);
+        lenient_version => set_lenient_version(bool);
+        http_connect_addr => set_http_connect_addr(Option<SocketAddr>);
+        forwards => set_forwards(Vec<Forward>);
+        inspect_sni => set_inspect_sni(bool);
+        udp_reassembly_timeout => set_udp_reassembly_timeout(Option<Duration>);
+        dns_cache => set_dns_cache(Option<DnsCacheConfig>);
+        connection_pool => set_connection_pool(Option<ConnectionPoolConfig>);
+        tcp_keepalive => set_tcp_keepalive(Option<TcpKeepalive>);
+        outbound_bind_addr => set_outbound_bind_addr(Option<OutboundBind>);
+        connection_rate_limit => set_connection_rate_limit(Option<u64>);
+        client_ban => set_client_ban(Option<ClientBanConfig>);
+        accept_queue_capacity => set_accept_queue_capacity(Option<usize>);
+        accept_overflow => set_accept_overflow(AcceptOverflow);
+        max_sessions => set_max_sessions(Option<usize>);
+        max_sessions_reply => set_max_sessions_reply(ConnectError);
+        connect_attempt_delay => set_connect_attempt_delay(Duration);
+        connect_timeout => set_connect_timeout(Option<Duration>);
+        connect_retries => set_connect_retries(u32);
+        retry_backoff => set_retry_backoff(Duration);
+        reply_addr => set_reply_addr(ReplyAddr);
+        relay_buffer_size => set_relay_buffer_size(usize);
+        geoip_provider => set_geoip_provider(Option<Arc<dyn GeoIpProvider>>);
+        rule_cache_capacity => set_rule_cache_capacity(Option<usize>);
+    }
+
+    /// validated configuration
+    pub fn build(self) -> Result<ServerConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Address, L4Protocol::Tcp};
+    use std::fs;
+
+    #[test]
+    fn include_rule_files() {
+        let dir = std::env::temp_dir().join(format!("gatekeeper-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(
+            dir.join("rule.yml"),
+            r#"
+include:
+  - sub/blocked.yml
+rules:
+  - Allow: { address: Any, port: Any, protocol: Any }
+  - Allow: { address: { Specif: { Domain: { pattern: www.blocked.test } } }, port: Any, protocol: Any }
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("sub/blocked.yml"),
+            r#"
+groups:
+  blocked:
+    - Domain: { wildcard: "*.blocked.test" }
+rules:
+  - Deny: { address: { Specif: { Group: blocked } }, port: Any, protocol: Any }
+"#,
+        )
+        .unwrap();
+        let rule = load_connect_rule(&dir.join("rule.yml")).unwrap();
+        let domain = |name: &str| Address::Domain(name.to_owned(), 80);
+        assert!(!rule.check(domain("mail.blocked.test"), Tcp));
+        // rules of the including file take precedence
+        assert!(rule.check(domain("www.blocked.test"), Tcp));
+        assert!(rule.check(domain("example.com"), Tcp));
+        assert_eq!(
+            connect_rule_files(&dir.join("rule.yml")).unwrap(),
+            [
+                dir.join("rule.yml").canonicalize().unwrap(),
+                dir.join("sub/blocked.yml").canonicalize().unwrap()
+            ]
+        );
+
+        fs::write(dir.join("sub/blocked.yml"), "include: [../rule.yml]").unwrap();
+        let err = load_connect_rule(&dir.join("rule.yml")).unwrap_err();
+        assert!(format!("{:?}", err).contains("circular include"));
+
+        fs::write(
+            dir.join("sub/blocked.yml"),
+            "groups: { blocked: [] }\ninclude: [dup.yml]",
+        )
+        .unwrap();
+        fs::write(dir.join("sub/dup.yml"), "groups: { blocked: [] }").unwrap();
+        let err = load_connect_rule(&dir.join("rule.yml")).unwrap_err();
+        assert!(format!("{:?}", err).contains("duplicate group: blocked"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_forward() {
+        let forward: Forward = "0.0.0.0:8443=internal:443".parse().unwrap();
+        assert_eq!(forward.listen, "0.0.0.0:8443".parse().unwrap());
+        assert_eq!(forward.to, Address::Domain("internal".into(), 443));
+        let forward: Forward = "[::1]:8080=[2001:db8::1]:80".parse().unwrap();
+        assert_eq!(forward.to, "[2001:db8::1]:80".parse().unwrap());
+        assert!("0.0.0.0:8443".parse::<Forward>().is_err());
+        assert!("0.0.0.0:8443=internal".parse::<Forward>().is_err());
+        assert!("localhost:8443=internal:443".parse::<Forward>().is_err());
+
+        let forward = "127.0.0.1:1080=internal:443".parse().unwrap();
+        let err = ServerConfig::builder()
+            .server_addr("127.0.0.1:1080".parse().unwrap())
+            .forwards(vec![forward])
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ConfigError::DuplicateListenAddr {
+                addr: "127.0.0.1:1080".parse().unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn validate_allowed_methods() {
+        assert_eq!("user-pass".parse(), Ok(Method::UserPass));
+        assert!("UserPass".parse::<Method>().is_err());
+
+        let err = ServerConfig::builder()
+            .allowed_methods(Some(vec![]))
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::NoAllowedMethod);
+        // SOCKS4 clients are not authenticated
+        let err = ServerConfig::builder()
+            .allowed_methods(Some(vec![Method::UserPass]))
+            .accept_socks4(true)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ConfigError::NoAuthNotAllowed {
+                name: "accept_socks4"
+            }
+        );
+        assert!(ServerConfig::builder()
+            .allowed_methods(Some(vec![Method::NoAuth]))
+            .accept_socks4(true)
+            .build()
+            .is_ok());
+    }
 }
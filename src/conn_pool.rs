@@ -0,0 +1,219 @@
+//! Pool of spare connections to destinations
+//!
+//! Bursty clients connecting to the same destinations (e.g. API endpoints) wait for the TCP
+//! handshake on every connection.
+//! [`ConnectionPool`] keeps spare connections to recently connected destinations, established in
+//! the background, and hands one to the next connection to the same destination.
+//! It is enabled for `TcpUdpConnector::from_config` by `ServerConfig::set_connection_pool`.
+//!
+//! Only connections never relayed are pooled.
+//! A connection closed by its client is not reused by another client even if it is still healthy,
+//! since the state of the stream (e.g. TLS sessions or authenticated HTTP connections)
+//! belongs to the client.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::model::Address;
+
+/// Parameters of [`ConnectionPool`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionPoolConfig {
+    /// maximum number of destinations to keep spare connections to
+    pub capacity: usize,
+    /// maximum number of spare connections to each destination
+    pub max_idle_per_destination: usize,
+    /// close spare connections not used in this duration
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            max_idle_per_destination: 1,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Spare {
+    strm: TcpStream,
+    since: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Idle {
+    spares: Vec<Spare>,
+    /// connections being established in the background
+    pending: usize,
+}
+
+/// Spare connections by requested destinations
+pub struct ConnectionPool {
+    config: ConnectionPoolConfig,
+    entries: Mutex<BTreeMap<Address, Idle>>,
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("config", &self.config)
+            .field("spares", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectionPool {
+    pub fn new(config: ConnectionPoolConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// number of spare connections
+    pub fn len(&self) -> usize {
+        self.lock().values().map(|idle| idle.spares.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<Address, Idle>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// close expired connections and forget destinations without any connection
+    fn expire(&self, entries: &mut BTreeMap<Address, Idle>) {
+        let timeout = self.config.idle_timeout;
+        entries.retain(|_, idle| {
+            idle.spares.retain(|spare| spare.since.elapsed() < timeout);
+            !idle.spares.is_empty() || idle.pending > 0
+        });
+    }
+
+    /// Take a healthy spare connection to `addr`
+    pub(crate) fn take(&self, addr: &Address) -> Option<TcpStream> {
+        let mut entries = self.lock();
+        self.expire(&mut entries);
+        let idle = entries.get_mut(addr)?;
+        while let Some(spare) = idle.spares.pop() {
+            if is_healthy(&spare.strm) {
+                return Some(spare.strm);
+            }
+        }
+        None
+    }
+
+    /// Count a spare connection to `addr` to be established
+    ///
+    /// returns `false` if the pool is already full for `addr`.
+    pub(crate) fn reserve(&self, addr: &Address) -> bool {
+        let mut entries = self.lock();
+        self.expire(&mut entries);
+        if !entries.contains_key(addr) && entries.len() >= self.config.capacity {
+            return false;
+        }
+        let idle = entries.entry(addr.clone()).or_default();
+        if idle.spares.len() + idle.pending >= self.config.max_idle_per_destination {
+            return false;
+        }
+        idle.pending += 1;
+        true
+    }
+
+    /// Keep a connection reserved by `reserve`, or forget the reservation if `None`
+    pub(crate) fn put(&self, addr: &Address, strm: Option<TcpStream>) {
+        let mut entries = self.lock();
+        if let Some(idle) = entries.get_mut(addr) {
+            idle.pending = idle.pending.saturating_sub(1);
+            idle.spares.extend(strm.map(|strm| Spare {
+                strm,
+                since: Instant::now(),
+            }));
+        }
+        self.expire(&mut entries);
+    }
+}
+
+/// the destination has not closed `strm` (it may have sent data, e.g. a banner)
+fn is_healthy(strm: &TcpStream) -> bool {
+    if strm.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let res = strm.peek(&mut [0]);
+    if strm.set_nonblocking(false).is_err() {
+        return false;
+    }
+    match res {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn spare_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: Address = listener.local_addr().unwrap().into();
+        let pool = ConnectionPool::new(ConnectionPoolConfig {
+            capacity: 1,
+            max_idle_per_destination: 2,
+            idle_timeout: Duration::from_secs(10),
+        });
+        assert!(pool.take(&addr).is_none());
+
+        assert!(pool.reserve(&addr));
+        assert!(pool.reserve(&addr));
+        assert!(!pool.reserve(&addr));
+        // no more destinations
+        assert!(!pool.reserve(&"192.0.2.1:80".parse().unwrap()));
+
+        let strm = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.put(&addr, Some(strm));
+        let strm = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.put(&addr, Some(strm));
+        assert_eq!(pool.len(), 2);
+
+        // the connection closed by the destination is dropped
+        let (_open, _) = listener.accept().unwrap();
+        let (closed, _) = listener.accept().unwrap();
+        drop(closed);
+        std::thread::sleep(Duration::from_millis(100));
+        let strm = pool.take(&addr).unwrap();
+        assert!(is_healthy(&strm));
+        assert!(pool.is_empty());
+
+        // a failed reservation
+        assert!(pool.reserve(&addr));
+        pool.put(&addr, None);
+        assert!(pool.reserve(&addr));
+    }
+
+    #[test]
+    fn expire_spare_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: Address = listener.local_addr().unwrap().into();
+        let pool = ConnectionPool::new(ConnectionPoolConfig {
+            idle_timeout: Duration::from_millis(50),
+            ..Default::default()
+        });
+        assert!(pool.reserve(&addr));
+        let strm = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.put(&addr, Some(strm));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(pool.take(&addr).is_none());
+        assert!(pool.is_empty());
+    }
+}
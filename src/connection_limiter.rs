@@ -0,0 +1,81 @@
+//! Rate limit of connections from each client
+//!
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::relay::TokenBucket;
+
+/// Buckets of clients connected recently are kept up to this number
+const MAX_CLIENTS: usize = 4096;
+
+/// Token buckets counting connections keyed by client ip address
+#[derive(Debug)]
+pub(crate) struct ConnectionLimiter {
+    /// connections/sec
+    rate: u64,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Count a connection from `ip`
+    ///
+    /// returns `false` if the connection exceeds the rate limit.
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        if self.buckets.len() >= MAX_CLIENTS {
+            self.purge();
+        }
+        let rate = self.rate;
+        let bucket = self
+            .buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(rate));
+        if bucket.available() == 0 {
+            return false;
+        }
+        bucket.consume(1);
+        true
+    }
+
+    /// Forget clients whose bucket is full, those are same as new clients
+    fn purge(&mut self) {
+        let rate = self.rate as usize;
+        self.buckets.retain(|_, bucket| bucket.available() < rate);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn connection_limiter() {
+        let client1: IpAddr = "192.168.0.1".parse().unwrap();
+        let client2: IpAddr = "192.168.0.2".parse().unwrap();
+        let mut limiter = ConnectionLimiter::new(2);
+        assert!(limiter.check(client1));
+        assert!(limiter.check(client1));
+        assert!(!limiter.check(client1));
+        // other clients are not affected
+        assert!(limiter.check(client2));
+
+        thread::sleep(Duration::from_millis(600));
+        assert!(limiter.check(client1));
+        assert!(!limiter.check(client1));
+
+        // the bucket of client2 has been refilled
+        limiter.purge();
+        assert_eq!(limiter.buckets.len(), 1);
+        thread::sleep(Duration::from_millis(1100));
+        limiter.purge();
+        assert!(limiter.buckets.is_empty());
+    }
+}
@@ -1,142 +1,936 @@
+use std::fmt;
 use std::io;
-use std::net::{SocketAddr, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::Duration;
 
+use crate::acceptor::bind_listener;
 use crate::byte_stream::ByteStream;
+use crate::client::Socks5Client;
+use crate::config::{ServerConfig, TcpKeepalive};
+use crate::conn_pool::ConnectionPool;
+use crate::dns_cache::CachingResolver;
 use crate::model;
 use crate::model::error::Error;
 use crate::model::model::*;
-use crate::pkt_stream::{PktStream, UdpPktStream};
+use crate::pkt_stream::{PktStream, UdpPktStream, MAX_PKT_SIZE};
+use crate::tcp_listener_ext::TcpListenerExt;
+use crate::thread::spawn_thread;
 
-use failure::Fail;
+use log::*;
+use socket2::{Domain, Protocol, Socket, Type};
 
 pub trait Connector: Send {
     type B: ByteStream;
-    type P: PktStream;
+    type P: PktStream + 'static;
+    type L: StreamListener;
     fn connect_byte_stream(&self, addr: Address) -> Result<(Self::B, SocketAddr), Error>;
-    fn connect_pkt_stream(&self, addr: Address) -> Result<(Self::P, SocketAddr), Error>;
+    /// connect to `addr` from the local address or device of `bind` (e.g. given by a rule)
+    ///
+    /// Connectors not binding outbound connections connect as `connect_byte_stream`.
+    fn connect_byte_stream_from(
+        &self,
+        addr: Address,
+        bind: &OutboundBind,
+    ) -> Result<(Self::B, SocketAddr), Error> {
+        debug!("outbound bind is ignored: {:?}", bind);
+        self.connect_byte_stream(addr)
+    }
+    /// bind a packet stream relaying datagrams on `addr`
+    fn bind_pkt_stream(&self, addr: SocketAddr) -> Result<Self::P, Error>;
+    /// listen on `addr` for an incoming connection (`BIND` command)
+    fn listen_byte_stream(&self, addr: SocketAddr) -> Result<Self::L, Error>;
+    /// resolver of domain names of destinations
+    fn resolver(&self) -> Arc<dyn Resolver> {
+        Arc::new(SystemResolver)
+    }
+    /// resolve `addr` into socket addresses
+    fn resolve(&self, addr: &Address) -> Result<Vec<SocketAddr>, Error> {
+        resolve_with(&*self.resolver(), addr)
+    }
 }
 
-#[derive(Debug, Clone)]
+/// Listening socket waits for a connection from an external host
+pub trait StreamListener: Send {
+    type B: ByteStream + 'static;
+    fn local_addr(&self) -> Result<SocketAddr, Error>;
+    /// accept a connection
+    ///
+    /// returns `None` if no connections arrived within `timeout`.
+    fn accept(&self, timeout: Duration) -> Result<Option<(Self::B, SocketAddr)>, Error>;
+}
+
+#[derive(Debug)]
+pub struct TcpStreamListener {
+    listener: TcpListener,
+    rw_timeout: Option<Duration>,
+    keepalive: Option<TcpKeepalive>,
+}
+
+impl StreamListener for TcpStreamListener {
+    type B = TcpStream;
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    fn accept(&self, timeout: Duration) -> Result<Option<(Self::B, SocketAddr)>, Error> {
+        match self.listener.accept_timeout(Some(timeout)) {
+            Ok((strm, peer)) => {
+                strm.set_read_timeout(self.rw_timeout)?;
+                strm.set_write_timeout(self.rw_timeout)?;
+                if let Some(keepalive) = self.keepalive {
+                    keepalive.apply(&strm)?;
+                }
+                Ok(Some((strm, peer)))
+            }
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Resolve domain names of destinations
+pub trait Resolver: Send + Sync {
+    /// resolve `domain` into socket addresses with `port`
+    ///
+    /// An empty list means the domain is not resolved.
+    fn resolve(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>, Error>;
+
+    /// resolve `domain` like `resolve`, with the TTL of the addresses if it is known
+    fn resolve_ttl(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> Result<(Vec<SocketAddr>, Option<Duration>), Error> {
+        Ok((self.resolve(domain, port)?, None))
+    }
+}
+
+/// Resolver by the system (`getaddrinfo(3)`)
+#[derive(Debug, Clone, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        match (domain, port).to_socket_addrs() {
+            Ok(addrs) => Ok(addrs.collect()),
+            Err(err) => {
+                debug!("resolve error: {}:{}: {}", domain, port, err);
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+pub(crate) fn resolve_with(
+    resolver: &dyn Resolver,
+    addr: &Address,
+) -> Result<Vec<SocketAddr>, Error> {
+    match addr {
+        Address::IpAddr(addr, port) => Ok(vec![SocketAddr::new(*addr, *port)]),
+        Address::ScopedV6(addr) => Ok(vec![(*addr).into()]),
+        Address::Domain(domain, port) => {
+            let addrs = resolver.resolve(domain, *port)?;
+            if addrs.is_empty() {
+                return Err(model::ErrorKind::DomainNotResolved {
+                    domain: domain.clone(),
+                    port: *port,
+                }
+                .into());
+            }
+            Ok(addrs)
+        }
+    }
+}
+
+/// Interval to check termination of UDP relays if no rw timeout is given
+const UDP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
 pub struct TcpUdpConnector {
     rw_timeout: Option<Duration>,
+    resolver: Arc<dyn Resolver>,
+    /// delay before starting a connection attempt to the next resolved address
+    attempt_delay: Duration,
+    /// timeout of each connection attempt
+    connect_timeout: Option<Duration>,
+    /// number of retries after a refused or timed out connection
+    connect_retries: u32,
+    /// delay before the first retry, doubled for each subsequent retry
+    retry_backoff: Duration,
+    /// keepalive probes on connections to destinations
+    keepalive: Option<TcpKeepalive>,
+    /// local address or device of connections to destinations
+    outbound_bind: Option<OutboundBind>,
+    /// spare connections to recent destinations
+    pool: Option<Arc<ConnectionPool>>,
+}
+
+impl fmt::Debug for TcpUdpConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TcpUdpConnector")
+            .field("rw_timeout", &self.rw_timeout)
+            .field("attempt_delay", &self.attempt_delay)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("connect_retries", &self.connect_retries)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("keepalive", &self.keepalive)
+            .field("outbound_bind", &self.outbound_bind)
+            .field("pool", &self.pool)
+            .finish_non_exhaustive()
+    }
 }
+
 impl TcpUdpConnector {
     pub fn new(rw_timeout: Option<Duration>) -> Self {
-        Self { rw_timeout }
+        Self {
+            rw_timeout,
+            resolver: Arc::new(SystemResolver),
+            // recommended by RFC8305
+            attempt_delay: Duration::from_millis(250),
+            connect_timeout: None,
+            connect_retries: 0,
+            retry_backoff: Duration::from_millis(100),
+            keepalive: None,
+            outbound_bind: None,
+            pool: None,
+        }
+    }
+
+    /// connector with timeouts, the dns cache and the connection pool of `config`
+    pub fn from_config(config: &ServerConfig) -> Self {
+        let mut connector = Self::new(config.server_rw_timeout);
+        connector
+            .set_attempt_delay(config.connect_attempt_delay)
+            .set_connect_timeout(config.connect_timeout)
+            .set_connect_retries(config.connect_retries)
+            .set_retry_backoff(config.retry_backoff)
+            .set_tcp_keepalive(config.tcp_keepalive)
+            .set_outbound_bind(config.outbound_bind_addr.clone());
+        if let Some(cache) = config.dns_cache {
+            connector.set_resolver(Arc::new(CachingResolver::new(
+                Arc::new(SystemResolver),
+                cache,
+            )));
+        }
+        if let Some(pool) = config.connection_pool {
+            connector.set_connection_pool(Some(Arc::new(ConnectionPool::new(pool))));
+        }
+        connector
+    }
+
+    /// start connecting to the next resolved address if the attempt does not complete in `delay`
+    pub fn set_attempt_delay(&mut self, delay: Duration) -> &mut Self {
+        self.attempt_delay = delay;
+        self
+    }
+
+    /// give up connecting to each resolved address after `timeout` (`None` waits for the system)
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// retry connecting up to `retries` times if the destination refused or timed out
+    pub fn set_connect_retries(&mut self, retries: u32) -> &mut Self {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// wait `backoff` before the first retry, and twice as long as the previous one after that
+    pub fn set_retry_backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// enable keepalive probes on connections to destinations
+    pub fn set_tcp_keepalive(&mut self, keepalive: Option<TcpKeepalive>) -> &mut Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// bind connections to destinations to the local address or device of `bind`
+    pub fn set_outbound_bind(&mut self, bind: Option<OutboundBind>) -> &mut Self {
+        self.outbound_bind = bind;
+        self
+    }
+
+    /// resolve domain names by `resolver` instead of the system
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolver>) -> &mut Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// keep spare connections to recent destinations in `pool`
+    ///
+    /// Connections from the default local address are taken from the pool if available.
+    /// The pool is shared by clones of the connector.
+    pub fn set_connection_pool(&mut self, pool: Option<Arc<ConnectionPool>>) -> &mut Self {
+        self.pool = pool;
+        self
+    }
+
+    /// connect to `addr` from the default local address, taking a spare connection if available
+    fn connect_pooled(&self, addr: Address) -> Result<(TcpStream, SocketAddr), Error> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return self.connect_from(addr, self.outbound_bind.as_ref()),
+        };
+        let conn = match pool.take(&addr) {
+            Some(strm) => {
+                debug!("spare connection: {}", addr);
+                let peer = strm.peer_addr()?;
+                (strm, peer)
+            }
+            None => self.connect_from(addr.clone(), self.outbound_bind.as_ref())?,
+        };
+        self.replenish(pool, addr);
+        Ok(conn)
+    }
+
+    /// establish a spare connection to `addr` in the background if the pool is not full
+    fn replenish(&self, pool: &Arc<ConnectionPool>, addr: Address) {
+        if !pool.reserve(&addr) {
+            return;
+        }
+        let connector = self.clone();
+        let pool = pool.clone();
+        let res = spawn_thread(&format!("spare: {}", addr), move || {
+            match connector.connect_from(addr.clone(), connector.outbound_bind.as_ref()) {
+                Ok((strm, _)) => pool.put(&addr, Some(strm)),
+                Err(err) => {
+                    debug!("spare connection error: {}: {}", addr, err);
+                    pool.put(&addr, None)
+                }
+            }
+        });
+        if let Err(err) = res {
+            error!("spawn spare connection: {}", err);
+        }
+    }
+
+    /// connect to `addr` from `bind`, or the default local address if `None`
+    fn connect_from(
+        &self,
+        addr: Address,
+        bind: Option<&OutboundBind>,
+    ) -> Result<(TcpStream, SocketAddr), Error> {
+        let mut addrs = self.resolve(&addr)?;
+        if let Some(local) = bind.and_then(|bind| bind.addr) {
+            // the local address can not connect to the other family
+            addrs.retain(|dst| dst.is_ipv4() == local.is_ipv4());
+            if addrs.is_empty() {
+                let err = io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("no addresses of the family of {}", local),
+                );
+                return Err(conn_error(err, addr, L4Protocol::Tcp));
+            }
+        }
+        let addrs = interleave_families(addrs);
+        let mut backoff = self.retry_backoff;
+        let mut retries = self.connect_retries;
+        let strm = loop {
+            match connect_racing(
+                addrs.clone(),
+                bind,
+                self.attempt_delay,
+                self.connect_timeout,
+            ) {
+                Ok(strm) => break strm,
+                Err(err) if retries > 0 && is_transient(&err) => {
+                    debug!("retry connecting to {} in {:?}: {}", addr, backoff, err);
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    retries -= 1;
+                }
+                Err(err) => return Err(conn_error(err, addr, L4Protocol::Tcp)),
+            }
+        };
+        strm.set_read_timeout(self.rw_timeout)?;
+        strm.set_write_timeout(self.rw_timeout)?;
+        if let Some(keepalive) = self.keepalive {
+            keepalive.apply(&strm)?;
+        }
+
+        let peer = strm.peer_addr()?;
+        Ok((strm, peer))
     }
 }
 
 impl Connector for TcpUdpConnector {
     type B = TcpStream;
     type P = UdpPktStream;
+    type L = TcpStreamListener;
     fn connect_byte_stream(&self, addr: Address) -> Result<(Self::B, SocketAddr), Error> {
-        let strm = match &addr {
-            Address::IpAddr(addr, port) => TcpStream::connect(SocketAddr::new(*addr, *port)),
-            Address::Domain(host, port) => TcpStream::connect((host.as_str(), *port)),
+        self.connect_pooled(addr)
+    }
+    fn connect_byte_stream_from(
+        &self,
+        addr: Address,
+        bind: &OutboundBind,
+    ) -> Result<(Self::B, SocketAddr), Error> {
+        self.connect_from(addr, Some(bind))
+    }
+    fn bind_pkt_stream(&self, addr: SocketAddr) -> Result<Self::P, Error> {
+        let sock = UdpSocket::bind(addr)?;
+        // the relay should wake up periodically to check termination of the session
+        sock.set_read_timeout(Some(self.rw_timeout.unwrap_or(UDP_POLL_INTERVAL)))?;
+        sock.set_write_timeout(self.rw_timeout)?;
+        Ok(UdpPktStream::new(MAX_PKT_SIZE, sock))
+    }
+    fn listen_byte_stream(&self, addr: SocketAddr) -> Result<Self::L, Error> {
+        Ok(TcpStreamListener {
+            listener: bind_listener(addr)?,
+            rw_timeout: self.rw_timeout,
+            keepalive: self.keepalive,
+        })
+    }
+    fn resolver(&self) -> Arc<dyn Resolver> {
+        self.resolver.clone()
+    }
+}
+
+/// Connector establishing outbound connections through an upstream socks5 proxy
+///
+/// Only `CONNECT` is relayed to the upstream.
+/// `BIND` and `UDP ASSOCIATE` are rejected as not supported.
+#[derive(Debug, Clone)]
+pub struct Socks5ProxyConnector {
+    client: Socks5Client,
+}
+
+impl Socks5ProxyConnector {
+    pub fn new(upstream: SocketAddr, rw_timeout: Option<Duration>) -> Self {
+        let mut client = Socks5Client::new(upstream);
+        client.set_rw_timeout(rw_timeout);
+        Self { client }
+    }
+
+    /// authenticate to the upstream with USERNAME/PASSWORD
+    pub fn set_credential<U, P>(&mut self, username: U, password: P) -> &mut Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.client.set_credential(username, password);
+        self
+    }
+}
+
+impl Connector for Socks5ProxyConnector {
+    type B = TcpStream;
+    type P = UdpPktStream;
+    type L = TcpStreamListener;
+    /// returns the stream to the upstream proxy and the address of the upstream
+    fn connect_byte_stream(&self, addr: Address) -> Result<(Self::B, SocketAddr), Error> {
+        let (strm, _) = self.client.connect(addr)?;
+        Ok((strm, self.client.proxy_addr()))
+    }
+    fn bind_pkt_stream(&self, _addr: SocketAddr) -> Result<Self::P, Error> {
+        Err(model::ErrorKind::command_not_supported(Command::UdpAssociate).into())
+    }
+    fn listen_byte_stream(&self, _addr: SocketAddr) -> Result<Self::L, Error> {
+        Err(model::ErrorKind::command_not_supported(Command::Bind).into())
+    }
+}
+
+/// Order addresses alternating IPv6 and IPv4, starting with the family of the first one
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    let mut ordered = vec![];
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
         }
-        .map_err(|err| conn_error(err, addr, L4Protocol::Tcp))?;
-        strm.set_read_timeout(self.rw_timeout)?;
-        strm.set_write_timeout(self.rw_timeout)?;
+    }
+}
 
-        let peer = strm.peer_addr()?;
-        Ok((strm, peer))
+/// Connect to one of `addrs` in the manner of Happy Eyeballs (RFC8305)
+///
+/// Attempts are started in order, each `delay` after the previous one or as soon as it fails,
+/// and the first established connection is returned.
+/// Connections established by the other attempts are closed.
+fn connect_racing(
+    addrs: Vec<SocketAddr>,
+    bind: Option<&OutboundBind>,
+    delay: Duration,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    if let [addr] = addrs[..] {
+        return connect_timeout(addr, bind, timeout);
+    }
+    let (tx, rx) = mpsc::channel();
+    let mut pending = 0;
+    let mut last_err = None;
+    let mut addrs = addrs.into_iter();
+    loop {
+        if let Some(addr) = addrs.next() {
+            let tx = tx.clone();
+            let bind = bind.cloned();
+            spawn_thread(&format!("connect: {}", addr), move || {
+                tx.send(connect_timeout(addr, bind.as_ref(), timeout)).ok();
+            })?;
+            pending += 1;
+        } else if pending == 0 {
+            return Err(last_err.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect")
+            }));
+        }
+        let res = if addrs.len() > 0 {
+            match rx.recv_timeout(delay) {
+                Ok(res) => res,
+                // start the next attempt
+                Err(_) => continue,
+            }
+        } else {
+            rx.recv().expect("connecting threads")
+        };
+        pending -= 1;
+        match res {
+            Ok(strm) => return Ok(strm),
+            Err(err) => {
+                debug!("connect error: {}", err);
+                last_err = Some(err);
+            }
+        }
+    }
+}
+
+/// the destination may accept a connection if retried later
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut
+    )
+}
+
+fn connect_timeout(
+    addr: SocketAddr,
+    bind: Option<&OutboundBind>,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let bind = match bind {
+        Some(bind) => bind,
+        None => {
+            return match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            }
+        }
+    };
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(device) = &bind.device {
+        bind_device(&sock, device)?;
     }
-    fn connect_pkt_stream(&self, _addr: Address) -> Result<(Self::P, SocketAddr), Error> {
-        unimplemented!("connect_pkt_stream")
-        /*
-        let sock_addr = self.resolve(addr)?;
-        UdpSocket::connect(sock_addr).map_err(Into::into)
-        */
+    if let Some(local) = bind.addr {
+        sock.bind(&SocketAddr::new(local, 0).into())?;
     }
+    match timeout {
+        Some(timeout) => sock.connect_timeout(&addr.into(), timeout)?,
+        None => sock.connect(&addr.into())?,
+    }
+    Ok(sock.into())
+}
+
+/// send packets of `sock` only through the network device `device` (`SO_BINDTODEVICE`)
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(sock: &Socket, device: &str) -> io::Result<()> {
+    sock.bind_device(Some(device.as_bytes()))
 }
 
-fn conn_error(io_err: io::Error, addr: Address, prot: L4Protocol) -> model::Error {
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_sock: &Socket, device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("binding to a device is not supported: {}", device),
+    ))
+}
+
+pub(crate) fn conn_error(io_err: io::Error, addr: Address, prot: L4Protocol) -> model::Error {
     use model::ErrorKind;
     match io_err.kind() {
         io::ErrorKind::ConnectionRefused => ErrorKind::connection_refused(addr, prot).into(),
-        _ => io_err.context(ErrorKind::Io),
+        io::ErrorKind::TimedOut => ErrorKind::connection_timed_out(addr, prot).into(),
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+            ErrorKind::HostUnreachable {
+                host: match &addr {
+                    Address::IpAddr(ip, _) => ip.to_string(),
+                    Address::ScopedV6(addr) => addr.ip().to_string(),
+                    Address::Domain(domain, _) => domain.clone(),
+                },
+                port: addr.port(),
+            }
+            .into()
+        }
+        _ => io_err.into(),
     }
-    .into()
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
-    use crate::byte_stream::ByteStream;
     use model::ErrorKind;
     use std::collections::BTreeMap;
-    use std::iter::FromIterator;
 
-    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-    pub struct BufferConnector<S> {
-        pub strms: BTreeMap<Address, Result<S, ConnectError>>,
-    }
+    pub use crate::test_util::BufferConnector;
 
-    impl<S> FromIterator<(Address, Result<S, ConnectError>)> for BufferConnector<S> {
-        fn from_iter<T>(iter: T) -> Self
-        where
-            T: IntoIterator<Item = (Address, Result<S, ConnectError>)>,
-        {
-            Self {
-                strms: iter.into_iter().collect(),
+    /// accept one connection and answer as an upstream socks5 proxy requiring USERNAME/PASSWORD
+    fn spawn_upstream(result: ConnectResult) -> (SocketAddr, std::thread::JoinHandle<Address>) {
+        use crate::model::SocksStream;
+        use crate::rw_socks_stream::ReadWriteStreamRef;
+        use std::io::{Read, Write};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut strm, _) = listener.accept().unwrap();
+            let mut socks = ReadWriteStreamRef::new(&mut strm);
+            let cand = socks.recv_method_candidates().unwrap();
+            assert_eq!(cand.method, vec![Method::NoAuth, Method::UserPass]);
+            socks
+                .send_method_selection(MethodSelection {
+                    version: DEFAULT_PROTOCOL_VERSION,
+                    method: Method::UserPass,
+                })
+                .unwrap();
+            let req = socks.recv_user_pass_request().unwrap();
+            assert_eq!(req, UserPassRequest::new("user", "pass"));
+            socks
+                .send_user_pass_reply(UserPassReply {
+                    version: USER_PASS_VERSION,
+                    success: true,
+                })
+                .unwrap();
+            let req = socks.recv_connect_request().unwrap();
+            socks
+                .send_connect_reply(ConnectReply {
+                    version: DEFAULT_PROTOCOL_VERSION,
+                    connect_result: result.clone(),
+                    server_addr: "0.0.0.0:0".parse::<SocketAddr>().unwrap().into(),
+                })
+                .unwrap();
+            if result.is_ok() {
+                let mut buf = [0; 5];
+                strm.read_exact(&mut buf).unwrap();
+                strm.write_all(&buf).unwrap();
             }
-        }
+            req.connect_to
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn socks5_proxy_connector() {
+        use std::io::{Read, Write};
+
+        let dst = Address::Domain("example.com".into(), 80);
+        let (upstream, handle) = spawn_upstream(Ok(()));
+        let mut connector = Socks5ProxyConnector::new(upstream, Some(Duration::from_secs(5)));
+        connector.set_credential("user", "pass");
+        let (mut strm, peer) = connector.connect_byte_stream(dst.clone()).unwrap();
+        assert_eq!(peer, upstream);
+        strm.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        strm.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(handle.join().unwrap(), dst);
+
+        let (upstream, handle) = spawn_upstream(Err(ConnectError::ConnectionRefused));
+        let mut connector = Socks5ProxyConnector::new(upstream, Some(Duration::from_secs(5)));
+        connector.set_credential("user", "pass");
+        let err = connector.connect_byte_stream(dst.clone()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::connection_refused(dst.clone(), L4Protocol::Tcp)
+        );
+        assert_eq!(handle.join().unwrap(), dst);
     }
 
-    impl<S> BufferConnector<S> {
-        pub fn stream(&self, addr: &Address) -> &S {
-            self.strms[addr].as_ref().unwrap()
+    /// resolve domains by the table
+    #[derive(Debug)]
+    pub struct MapResolver(pub BTreeMap<String, SocketAddr>);
+
+    impl Resolver for MapResolver {
+        fn resolve(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+            Ok(self
+                .0
+                .get(domain)
+                .map(|addr| {
+                    let mut addr = *addr;
+                    addr.set_port(port);
+                    addr
+                })
+                .into_iter()
+                .collect())
         }
     }
 
-    impl<S> Connector for BufferConnector<S>
-    where
-        S: ByteStream + Clone,
-    {
-        type B = S;
-        type P = UdpPktStream;
-        fn connect_byte_stream(&self, addr: Address) -> Result<(Self::B, SocketAddr), Error> {
-            println!("connect_byte_stream: {:?}", &addr);
-            match &self.strms[&addr] {
-                Ok(strm) => {
-                    use Address::*;
-                    let peer = match addr {
-                        IpAddr(peer, port) => SocketAddr::new(peer, port),
-                        Domain(_, port) => format!("192.168.1.1:{}", port).parse().unwrap(),
-                    };
-                    Ok((strm.clone(), peer))
-                }
-                Err(err) => {
-                    use Address::*;
-                    use ConnectError::*;
-                    use L4Protocol::*;
-                    let kind = match err {
-                        NetworkUnreachable => match addr {
-                            Domain(domain, port) => ErrorKind::DomainNotResolved { domain, port },
-                            IpAddr(ipaddr, port) => ErrorKind::HostUnreachable {
-                                host: ipaddr.to_string(),
-                                port,
-                            },
-                        },
-                        HostUnreachable => {
-                            let port = addr.port();
-                            let host = match addr {
-                                Domain(domain, _) => domain,
-                                IpAddr(ipaddr, _) => ipaddr.to_string(),
-                            };
-                            ErrorKind::HostUnreachable { host, port }
-                        }
-                        ConnectionNotAllowed => ErrorKind::connection_not_allowed(addr, Tcp),
-                        ConnectionRefused => ErrorKind::connection_refused(addr, Tcp),
-                        _ => ErrorKind::Io,
-                    };
-                    Err(kind.into())
-                }
+    /// a link-local address of an interface with its scope (`None` if no interface has one)
+    #[cfg(target_os = "linux")]
+    pub fn link_local_addr() -> Option<std::net::SocketAddrV6> {
+        // lines of "<address> <ifindex> <prefix length> <scope> <flags> <name>" in hex
+        let table = std::fs::read_to_string("/proc/net/if_inet6").ok()?;
+        table.lines().find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ip = std::net::Ipv6Addr::from(u128::from_str_radix(fields.first()?, 16).ok()?);
+            let index = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+            let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+            link_local.then(|| std::net::SocketAddrV6::new(ip, 0, 0, index))
+        })
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn link_local_destination() {
+        let addr = match link_local_addr() {
+            Some(addr) => addr,
+            None => return,
+        };
+        let listener = TcpListener::bind(addr).unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            SocketAddr::V6(addr) => addr,
+            addr => panic!("unexpected address: {}", addr),
+        };
+        assert_ne!(addr.scope_id(), 0);
+        let mut connector = TcpUdpConnector::new(None);
+        let (_strm, peer) = connector.connect_byte_stream(addr.into()).unwrap();
+        assert_eq!(peer, addr.into());
+
+        // the scope of the resolved address is kept
+        connector.set_resolver(Arc::new(MapResolver(
+            vec![("link-local.test".to_owned(), addr.into())]
+                .into_iter()
+                .collect(),
+        )));
+        let (_strm, peer) = connector
+            .connect_byte_stream(Address::Domain("link-local.test".into(), addr.port()))
+            .unwrap();
+        assert_eq!(peer, addr.into());
+    }
+
+    #[test]
+    fn tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connector = TcpUdpConnector::new(None);
+        let (strm, _) = connector.connect_byte_stream(addr.into()).unwrap();
+        assert!(!socket2::SockRef::from(&strm).keepalive().unwrap());
+
+        connector.set_tcp_keepalive(Some(TcpKeepalive::new(Duration::from_secs(30))));
+        let (strm, _) = connector.connect_byte_stream(addr.into()).unwrap();
+        let sock = socket2::SockRef::from(&strm);
+        assert!(sock.keepalive().unwrap());
+        assert_eq!(sock.keepalive_time().unwrap(), Duration::from_secs(30));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn outbound_bind() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connector = TcpUdpConnector::new(None);
+        // the whole 127.0.0.0/8 is assigned to the loopback on linux
+        connector.set_outbound_bind(Some(OutboundBind::from_addr([127, 0, 0, 2].into())));
+        let (strm, _) = connector.connect_byte_stream(addr.into()).unwrap();
+        assert_eq!(
+            strm.local_addr().unwrap().ip(),
+            IpAddr::from([127, 0, 0, 2])
+        );
+        // overridden by a rule
+        let bind = OutboundBind::from_addr([127, 0, 0, 3].into());
+        let (strm, _) = connector
+            .connect_byte_stream_from(addr.into(), &bind)
+            .unwrap();
+        assert_eq!(
+            strm.local_addr().unwrap().ip(),
+            IpAddr::from([127, 0, 0, 3])
+        );
+
+        // the local address can not connect to the other family
+        let bind = OutboundBind::from_addr("::1".parse().unwrap());
+        let err = connector
+            .connect_byte_stream_from(addr.into(), &bind)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Io { .. }));
+
+        let bind = OutboundBind::from_device("no-such-device0");
+        assert!(connector
+            .connect_byte_stream_from(addr.into(), &bind)
+            .is_err());
+    }
+
+    #[test]
+    fn custom_resolver() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connector = TcpUdpConnector::new(None);
+        connector.set_resolver(Arc::new(MapResolver(
+            vec![("gatekeeper.test".to_owned(), addr)]
+                .into_iter()
+                .collect(),
+        )));
+
+        let (_strm, peer) = connector
+            .connect_byte_stream(Address::Domain("gatekeeper.test".into(), addr.port()))
+            .unwrap();
+        assert_eq!(peer, addr);
+
+        let err = connector
+            .connect_byte_stream(Address::Domain("unknown.test".into(), 80))
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::DomainNotResolved {
+                domain: "unknown.test".into(),
+                port: 80
             }
+        );
+    }
+
+    #[test]
+    fn interleave_addresses() {
+        let addrs: Vec<SocketAddr> = [
+            "[::1]:80",
+            "[::2]:80",
+            "[::3]:80",
+            "10.0.0.1:80",
+            "10.0.0.2:80",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let ordered: Vec<SocketAddr> = [
+            "[::1]:80",
+            "10.0.0.1:80",
+            "[::2]:80",
+            "10.0.0.2:80",
+            "[::3]:80",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        assert_eq!(interleave_families(addrs), ordered);
+    }
+
+    #[test]
+    fn connect_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // refused
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut connector = TcpUdpConnector::new(None);
+        connector
+            .set_resolver(Arc::new(MultiResolver(vec![closed, addr])))
+            .set_attempt_delay(Duration::from_secs(10));
+        let (_strm, peer) = connector
+            .connect_byte_stream(Address::Domain("gatekeeper.test".into(), 0))
+            .unwrap();
+        assert_eq!(peer, addr);
+
+        connector.set_resolver(Arc::new(MultiResolver(vec![closed, closed])));
+        let err = connector
+            .connect_byte_stream(Address::Domain("gatekeeper.test".into(), 0))
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::connection_refused(
+                Address::Domain("gatekeeper.test".into(), 0),
+                L4Protocol::Tcp
+            )
+        );
+    }
+
+    #[test]
+    fn retry_refused_connection() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut connector = TcpUdpConnector::new(None);
+        connector
+            .set_connect_retries(2)
+            .set_retry_backoff(Duration::from_millis(50));
+        let start = std::time::Instant::now();
+        let err = connector.connect_byte_stream(closed.into()).unwrap_err();
+        // 50ms + 100ms
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::connection_refused(closed.into(), L4Protocol::Tcp)
+        );
+        assert_eq!(err.cerr(), ConnectError::ConnectionRefused);
+    }
+
+    #[test]
+    fn spare_connection() {
+        use crate::conn_pool::ConnectionPoolConfig;
+        use std::io::{Read, Write};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: Address = listener.local_addr().unwrap().into();
+        let pool = Arc::new(ConnectionPool::new(ConnectionPoolConfig::default()));
+        let mut connector = TcpUdpConnector::new(None);
+        connector.set_connection_pool(Some(pool.clone()));
+
+        let (first, _) = connector.connect_byte_stream(addr.clone()).unwrap();
+        let (_, _) = listener.accept().unwrap();
+        // a spare connection is established in the background
+        let (mut spare, _) = listener.accept().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.len(), 1);
+
+        let (mut second, _) = connector.connect_byte_stream(addr).unwrap();
+        assert!(pool.is_empty());
+        second.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        spare.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        // taking the spare one establishes another, while the used connections are not pooled
+        drop(first);
+        let (_, _) = listener.accept().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.len(), 1);
+    }
+
+    /// resolve any domain into the fixed addresses
+    #[derive(Debug)]
+    struct MultiResolver(Vec<SocketAddr>);
+
+    impl Resolver for MultiResolver {
+        fn resolve(&self, _domain: &str, _port: u16) -> Result<Vec<SocketAddr>, Error> {
+            Ok(self.0.clone())
         }
-        fn connect_pkt_stream(&self, _addr: Address) -> Result<(Self::P, SocketAddr), Error> {
-            unimplemented!("BufferConnector::connect_pkt_stream")
-        }
+    }
+
+    #[test]
+    fn connect_error_reply() {
+        let addr: Address = "192.0.2.1:80".parse().unwrap();
+        let err = conn_error(
+            io::ErrorKind::TimedOut.into(),
+            addr.clone(),
+            L4Protocol::Tcp,
+        );
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::connection_timed_out(addr.clone(), L4Protocol::Tcp)
+        );
+        assert_eq!(err.cerr(), ConnectError::TtlExpired);
+        let err = conn_error(
+            io::ErrorKind::NetworkUnreachable.into(),
+            addr,
+            L4Protocol::Tcp,
+        );
+        assert_eq!(err.cerr(), ConnectError::HostUnreachable);
     }
 }
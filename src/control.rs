@@ -0,0 +1,720 @@
+//! Local control endpoint of a server
+//!
+//! [`ControlServer`] accepts commands as JSON lines on a unix domain socket or a loopback tcp port,
+//! and sends them to the server as [`ServerCommand`]s.
+//! Each request is replied by a JSON line, `{"ok":true,...}` or `{"ok":false,"error":"..."}`.
+//! A connection is closed by a line which is not a request, so that other protocols
+//! (e.g. HTTP requests of browsers to the loopback port) can not send commands,
+//! or by a line longer than 4 KiB. Up to 16 connections are served at the same time,
+//! and a connection sending nothing for 30 seconds, or not authenticated in 30 seconds, is closed.
+//!
+//! With a token (`ControlServer::set_token`, required for tcp), connections have to send it first.
+//!
+//! ```text
+//! {"command":"auth","token":"..."}
+//! {"command":"reload_rules"}
+//! {"command":"list_sessions"}
+//! {"command":"kill_session","id":3}
+//! {"command":"get_metrics"}
+//! {"command":"status"}
+//! {"command":"shutdown","grace":10}
+//! ```
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use failure::Fail;
+use log::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::load_connect_rule;
+use crate::metrics::Metrics;
+use crate::model::{Error, ErrorKind};
+use crate::server_command::ServerCommand;
+use crate::session::{SessionId, SessionInfo};
+use crate::thread::spawn_thread;
+
+/// Maximum length of a request line
+const MAX_LINE: usize = 4 * 1024;
+
+/// Maximum number of connections served at the same time, others are closed at once
+const MAX_CONNECTIONS: usize = 16;
+
+/// Connections are closed if idle, or not authenticated, for this duration
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Command received by the control endpoint
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ControlRequest {
+    /// authenticate the connection by the token given by `ControlServer::set_token`
+    Auth {
+        token: String,
+    },
+    /// load the rule file given by `ControlServer::set_rule_file` again
+    ReloadRules,
+    ListSessions,
+    KillSession {
+        id: u32,
+    },
+    GetMetrics,
+    /// the state of the server rendered for humans (`ServerStatus`)
+    Status,
+    /// terminate the server, waiting running sessions up to `grace` seconds if given
+    Shutdown {
+        #[serde(default)]
+        grace: Option<u64>,
+    },
+}
+
+/// Socket bound by [`ControlServer::bind_tcp`] or [`ControlServer::bind_unix`]
+#[derive(Debug)]
+pub enum ControlListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Translates control requests into commands of a server
+#[derive(Debug)]
+pub struct ControlServer<S> {
+    tx: Sender<ServerCommand<S>>,
+    rule_file: Option<PathBuf>,
+    token: Option<String>,
+    idle_timeout: Duration,
+}
+
+impl<S: Send + 'static> ControlServer<S> {
+    /// control the server receiving commands by `tx`
+    pub fn new(tx: Sender<ServerCommand<S>>) -> Self {
+        Self {
+            tx,
+            rule_file: None,
+            token: None,
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+
+    /// rule file loaded by `reload_rules` (not available if `None`)
+    pub fn set_rule_file(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.rule_file = path;
+        self
+    }
+
+    /// token connections have to send by an `auth` request before other requests
+    pub fn set_token(&mut self, token: Option<String>) -> &mut Self {
+        self.token = token;
+        self
+    }
+
+    fn authenticate(&self, token: &str) -> bool {
+        // compared in constant time not to leak the token by timing
+        self.token.as_ref().is_none_or(|expected| {
+            expected.len() == token.len()
+                && expected
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+    }
+
+    fn send(&self, cmd: ServerCommand<S>) -> Result<(), Error> {
+        self.tx
+            .send(cmd)
+            .map_err(|_| ErrorKind::message_fmt(format_args!("server is not running")).into())
+    }
+
+    /// send a query made by `cmd` and wait for its answer
+    fn query<T>(&self, cmd: impl FnOnce(Sender<T>) -> ServerCommand<S>) -> Result<T, Error> {
+        let (tx, rx) = mpsc::channel();
+        self.send(cmd(tx))?;
+        rx.recv()
+            .map_err(|_| ErrorKind::message_fmt(format_args!("server is not running")).into())
+    }
+
+    /// Perform `req` and return the result to be replied
+    pub fn handle(&self, req: ControlRequest) -> Result<Value, Error> {
+        use ControlRequest::*;
+        match req {
+            Auth { token } => {
+                if !self.authenticate(&token) {
+                    return Err(ErrorKind::message_fmt(format_args!("invalid token")).into());
+                }
+                Ok(json!({}))
+            }
+            ReloadRules => {
+                let path = self.rule_file.as_ref().ok_or_else(|| {
+                    ErrorKind::message_fmt(format_args!("rule file is not given"))
+                })?;
+                let rule = load_connect_rule(path).map_err(|err| {
+                    let causes: Vec<_> = <dyn Fail>::iter_chain(&err)
+                        .map(ToString::to_string)
+                        .collect();
+                    ErrorKind::message_fmt(format_args!(
+                        "reload rule: {}: {}",
+                        path.display(),
+                        causes.join(": ")
+                    ))
+                })?;
+                info!("reload rule: {}", path.display());
+                self.send(ServerCommand::ReloadRules(rule))?;
+                Ok(json!({}))
+            }
+            ListSessions => {
+                let sessions = self.query(ServerCommand::ListSessions)?;
+                Ok(json!({ "sessions": sessions.iter().map(session_json).collect::<Vec<_>>() }))
+            }
+            KillSession { id } => {
+                let id = SessionId(id);
+                let sessions = self.query(ServerCommand::ListSessions)?;
+                if !sessions.iter().any(|session| session.id == id) {
+                    return Err(
+                        ErrorKind::message_fmt(format_args!("no such session: {}", id.0)).into(),
+                    );
+                }
+                self.send(ServerCommand::Kill(id))?;
+                Ok(json!({}))
+            }
+            GetMetrics => {
+                let metrics = self.query(ServerCommand::QueryMetrics)?;
+                Ok(json!({ "metrics": metrics_json(&metrics) }))
+            }
+            Status => {
+                let status = self.query(ServerCommand::QueryStatus)?;
+                Ok(json!({ "status": status.to_string() }))
+            }
+            Shutdown { grace } => {
+                self.send(match grace {
+                    Some(secs) => ServerCommand::Shutdown {
+                        grace: Duration::from_secs(secs),
+                    },
+                    None => ServerCommand::Terminate,
+                })?;
+                Ok(json!({}))
+            }
+        }
+    }
+
+    /// Reply a line of JSON to a request `line`
+    pub fn handle_line(&self, line: &str) -> String {
+        reply_line(parse_request(line).and_then(|req| self.handle(req)))
+    }
+
+    /// Reply requests of a connection until it is closed
+    ///
+    /// The connection is closed after replying to a line which is not a request
+    /// (or longer than `MAX_LINE`), or to a request other than `auth` before authenticated.
+    /// It is also closed after replying to a line sent `IDLE_TIMEOUT` after connected
+    /// but not authenticated yet.
+    pub fn serve_conn<C: io::Read + io::Write>(&self, conn: C) -> Result<(), Error> {
+        let connected = Instant::now();
+        let mut conn = BufReader::new(conn);
+        let mut line = String::new();
+        let mut authenticated = self.token.is_none();
+        // not to hold a line of any length sent before the token
+        while (&mut conn).take(MAX_LINE as u64).read_line(&mut line)? > 0 {
+            let too_long = line.len() >= MAX_LINE && !line.ends_with('\n');
+            // e.g. blank lines sent to keep the connection
+            let too_late = !authenticated && connected.elapsed() >= self.idle_timeout;
+            if too_long || too_late || !line.trim().is_empty() {
+                let (result, close) = match parse_request(&line) {
+                    _ if too_long => (
+                        Err(ErrorKind::message_fmt(format_args!("too long request")).into()),
+                        true,
+                    ),
+                    _ if too_late => (
+                        Err(
+                            ErrorKind::message_fmt(format_args!("not authenticated in time"))
+                                .into(),
+                        ),
+                        true,
+                    ),
+                    Ok(req @ ControlRequest::Auth { .. }) => {
+                        let result = self.handle(req);
+                        authenticated = result.is_ok();
+                        (result, !authenticated)
+                    }
+                    Ok(req) if authenticated => (self.handle(req), false),
+                    Ok(_) => (
+                        Err(ErrorKind::message_fmt(format_args!("not authenticated")).into()),
+                        true,
+                    ),
+                    // e.g. the request line of HTTP
+                    Err(err) => (Err(err), true),
+                };
+                let strm = conn.get_mut();
+                strm.write_all(reply_line(result).as_bytes())?;
+                strm.write_all(b"\n")?;
+                strm.flush()?;
+                if close {
+                    return Ok(());
+                }
+            }
+            line.clear();
+        }
+        Ok(())
+    }
+
+    /// Spawn a thread accepting connections to the loopback address `addr`
+    ///
+    /// See [`ControlServer::bind_tcp`].
+    pub fn spawn_tcp(self, addr: SocketAddr) -> Result<JoinHandle<()>, Error> {
+        let listener = self.bind_tcp(addr)?;
+        self.spawn(listener)
+    }
+
+    /// Spawn a thread accepting connections to the unix domain socket `path`
+    ///
+    /// See [`ControlServer::bind_unix`].
+    #[cfg(unix)]
+    pub fn spawn_unix(self, path: &Path) -> Result<JoinHandle<()>, Error> {
+        let listener = self.bind_unix(path)?;
+        self.spawn(listener)
+    }
+
+    /// Bind the loopback address `addr` to be served by [`ControlServer::spawn`]
+    ///
+    /// Other addresses are refused, and the token is required,
+    /// since any local user (or any process) connected can control the server.
+    pub fn bind_tcp(&self, addr: SocketAddr) -> Result<ControlListener, Error> {
+        if self.token.is_none() {
+            return Err(ErrorKind::message_fmt(format_args!(
+                "control token is required: {}",
+                addr
+            ))
+            .into());
+        }
+        if !addr.ip().is_loopback() {
+            return Err(ErrorKind::message_fmt(format_args!(
+                "control address is not loopback: {}",
+                addr
+            ))
+            .into());
+        }
+        Ok(ControlListener::Tcp(TcpListener::bind(addr)?))
+    }
+
+    /// Bind the unix domain socket `path` to be served by [`ControlServer::spawn`]
+    ///
+    /// The socket is accessible only by the owner.
+    /// A socket file left by the previous run is replaced, but other files are not.
+    #[cfg(unix)]
+    pub fn bind_unix(&self, path: &Path) -> Result<ControlListener, Error> {
+        use std::fs::{self, DirBuilder, Permissions};
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(ErrorKind::message_fmt(format_args!(
+                    "bind: {}: not a socket",
+                    path.display()
+                ))
+                .into());
+            }
+        }
+        // bound in a private directory and moved to `path` after restricting the permission,
+        // so that others can never connect, without changing the umask of the process
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+        .join(format!(".{}.{}", name, std::process::id()));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let bound = dir.join("control.sock");
+        let listener = UnixListener::bind(&bound)
+            .and_then(|listener| {
+                fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+                fs::rename(&bound, path)?;
+                Ok(listener)
+            })
+            .map_err(|err| {
+                fs::remove_file(&bound).ok();
+                err.context(ErrorKind::message_fmt(format_args!(
+                    "bind: {}",
+                    path.display()
+                )))
+            });
+        fs::remove_dir(&dir).ok();
+        Ok(ControlListener::Unix(listener?, path.to_owned()))
+    }
+
+    /// Spawn a thread accepting connections to `listener`
+    ///
+    /// Binding the socket beforehand makes it possible to restrict the process
+    /// (e.g. by seccomp) before spawning the thread.
+    pub fn spawn(self, listener: ControlListener) -> Result<JoinHandle<()>, Error> {
+        // idle connections are closed by the read timeout
+        let timeout = Some(self.idle_timeout);
+        // the listener is moved into the thread
+        match listener {
+            ControlListener::Tcp(listener) => {
+                let name = listener.local_addr()?.to_string();
+                let incoming = std::iter::from_fn(move || {
+                    Some(listener.accept().and_then(|(strm, _)| {
+                        strm.set_read_timeout(timeout)?;
+                        Ok(strm)
+                    }))
+                });
+                self.spawn_incoming(name, incoming)
+            }
+            #[cfg(unix)]
+            ControlListener::Unix(listener, path) => {
+                let incoming = std::iter::from_fn(move || {
+                    Some(listener.accept().and_then(|(strm, _)| {
+                        strm.set_read_timeout(timeout)?;
+                        Ok(strm)
+                    }))
+                });
+                self.spawn_incoming(path.display().to_string(), incoming)
+            }
+        }
+    }
+
+    fn spawn_incoming<C, I>(self, name: String, incoming: I) -> Result<JoinHandle<()>, Error>
+    where
+        C: io::Read + io::Write + Send + 'static,
+        I: Iterator<Item = io::Result<C>> + Send + 'static,
+    {
+        let control = Arc::new(self);
+        let active = Arc::new(AtomicUsize::new(0));
+        let th = spawn_thread("control", move || {
+            for conn in incoming {
+                match conn {
+                    Ok(conn) => {
+                        let slot = match ConnectionSlot::acquire(&active) {
+                            Some(slot) => slot,
+                            None => {
+                                warn!("too many control connections: {}", name);
+                                continue;
+                            }
+                        };
+                        let control = control.clone();
+                        let served = spawn_thread("control-conn", move || {
+                            let _slot = slot;
+                            if let Err(err) = control.serve_conn(conn) {
+                                debug!("control connection error: {}", err);
+                            }
+                        });
+                        if let Err(err) = served {
+                            error!("spawn control connection: {}", err);
+                        }
+                    }
+                    Err(err) => error!("control accept error: {}: {}", name, err),
+                }
+            }
+        })?;
+        Ok(th)
+    }
+}
+
+/// Connection counted in the number of connections served at the same time
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// `None` if `MAX_CONNECTIONS` connections are served
+    fn acquire(active: &Arc<AtomicUsize>) -> Option<Self> {
+        active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(active.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn parse_request(line: &str) -> Result<ControlRequest, Error> {
+    let req = serde_json::from_str(line).map_err(|err| {
+        Error::from(ErrorKind::message_fmt(format_args!(
+            "invalid request: {}",
+            err
+        )))
+    })?;
+    if !matches!(req, ControlRequest::Auth { .. }) {
+        debug!("control request: {:?}", req);
+    }
+    Ok(req)
+}
+
+fn reply_line(result: Result<Value, Error>) -> String {
+    let reply = match result {
+        Ok(Value::Object(mut body)) => {
+            body.insert("ok".into(), true.into());
+            Value::Object(body)
+        }
+        Ok(body) => json!({ "ok": true, "result": body }),
+        Err(err) => json!({ "ok": false, "error": err.to_string() }),
+    };
+    reply.to_string()
+}
+
+fn session_json(session: &SessionInfo) -> Value {
+    let stats = &session.stats;
+    json!({
+        "id": session.id.0,
+        "state": session.state.to_string(),
+        "client_addr": stats.client_addr.to_string(),
+        "dst_addr": stats.dst_addr.as_ref().map(ToString::to_string),
+        "started_at": stats.started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+        "duration": stats.duration.as_secs_f64(),
+        "upload_bytes": stats.upload_bytes,
+        "download_bytes": stats.download_bytes,
+    })
+}
+
+fn metrics_json(metrics: &Metrics) -> Value {
+    json!({
+        "active_sessions": metrics.active_sessions(),
+        "accepted": metrics.accepted,
+        "rejected": metrics.rejected,
+        "errored": metrics.errored,
+        "finished": metrics.finished,
+        "upload_bytes": metrics.upload_bytes(),
+        "download_bytes": metrics.download_bytes(),
+        "banned_clients": metrics.banned_clients.iter().map(|client| json!({
+            "ip": client.ip.to_string(),
+            "remaining": client.remaining.as_secs_f64(),
+        })).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::ServerStatus;
+    use std::io::Write;
+    use std::thread;
+
+    /// answer queries of the control like a server, and return received commands
+    fn serve<F>(f: F) -> Vec<String>
+    where
+        F: FnOnce(&ControlServer<()>),
+    {
+        let (tx, rx) = mpsc::channel();
+        let th = thread::spawn(move || {
+            let mut received = vec![];
+            for cmd in rx {
+                received.push(format!("{:?}", cmd));
+                match cmd {
+                    ServerCommand::ListSessions(tx) => tx.send(vec![]).unwrap(),
+                    ServerCommand::QueryMetrics(tx) => tx.send(Metrics::default()).unwrap(),
+                    ServerCommand::QueryStatus(tx) => tx.send(ServerStatus::default()).unwrap(),
+                    _ => {}
+                }
+            }
+            received
+        });
+        f(&ControlServer::new(tx));
+        th.join().unwrap()
+    }
+
+    fn reply(control: &ControlServer<()>, line: &str) -> Value {
+        serde_json::from_str(&control.handle_line(line)).unwrap()
+    }
+
+    #[test]
+    fn control_requests() {
+        let received = serve(|control| {
+            assert_eq!(
+                reply(control, r#"{"command":"list_sessions"}"#),
+                json!({ "ok": true, "sessions": [] })
+            );
+            let metrics = reply(control, r#"{"command":"get_metrics"}"#);
+            assert_eq!(metrics["metrics"]["accepted"], 0);
+            let status = reply(control, r#"{"command":"status"}"#);
+            assert!(status["status"]
+                .as_str()
+                .unwrap()
+                .starts_with("sessions: 0 running"));
+            assert_eq!(
+                reply(control, r#"{"command":"shutdown","grace":3}"#),
+                json!({ "ok": true })
+            );
+        });
+        assert_eq!(
+            received,
+            [
+                "ListSessions(_)",
+                "QueryMetrics(_)",
+                "QueryStatus(_)",
+                "Shutdown { grace: 3s }"
+            ]
+        );
+    }
+
+    #[test]
+    fn control_errors() {
+        let received = serve(|control| {
+            for line in [
+                "kill",
+                r#"{"command":"restart"}"#,
+                r#"{"command":"kill_session"}"#,
+                r#"{"command":"kill_session","id":1}"#,
+                r#"{"command":"reload_rules"}"#,
+            ] {
+                let reply = reply(control, line);
+                assert_eq!(reply["ok"], false, "{}", line);
+                assert!(reply["error"].is_string());
+            }
+        });
+        // the session not running is not killed
+        assert_eq!(received, ["ListSessions(_)"]);
+    }
+
+    #[test]
+    fn control_socket() {
+        let (tx, rx) = mpsc::channel::<ServerCommand<()>>();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let control = |token: Option<&str>| {
+            let mut control = ControlServer::new(tx.clone());
+            control.set_token(token.map(str::to_owned));
+            control
+        };
+        assert!(control(None).spawn_tcp(addr).is_err());
+        assert!(control(Some("secret"))
+            .spawn_tcp("0.0.0.0:0".parse().unwrap())
+            .is_err());
+        control(Some("secret")).spawn_tcp(addr).unwrap();
+
+        let mut conn = std::net::TcpStream::connect(addr).unwrap();
+        conn.write_all(b"{\"command\":\"auth\",\"token\":\"secret\"}\n{\"command\":\"shutdown\"}\n\n{\"command\":\"kill_session\",\"id\":1}\n")
+            .unwrap();
+        let mut lines = BufReader::new(conn).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":true}"#);
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":true}"#);
+        assert!(matches!(rx.recv().unwrap(), ServerCommand::Terminate));
+        match rx.recv().unwrap() {
+            ServerCommand::ListSessions(tx) => tx.send(vec![]).unwrap(),
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+        assert!(lines.next().unwrap().unwrap().contains(r#""ok":false"#));
+    }
+
+    #[test]
+    fn close_connection() {
+        use crate::test_util::BufferStream;
+
+        // lines replied before the connection is closed
+        let replies = |token: Option<&str>, input: &str| {
+            let (tx, rx) = mpsc::channel::<ServerCommand<()>>();
+            let mut control = ControlServer::new(tx);
+            control.set_token(token.map(str::to_owned));
+            let conn = BufferStream::with_buffer(input.as_bytes().into(), vec![].into());
+            control.serve_conn(conn.clone()).unwrap();
+            drop(control);
+            let commands = rx.iter().count();
+            let replies = String::from_utf8(conn.wr_buff().get_ref().clone()).unwrap();
+            (replies.lines().count(), commands)
+        };
+        let shutdown = "{\"command\":\"shutdown\"}\n";
+
+        // e.g. a request from a browser
+        let post = format!("POST / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n{}", shutdown);
+        assert_eq!(replies(None, &post), (1, 0));
+        assert_eq!(replies(None, shutdown), (1, 1));
+        // not read up to the end of the line
+        let long = format!("{}{}", " ".repeat(MAX_LINE), shutdown);
+        assert_eq!(replies(None, &long), (1, 0));
+        // not authenticated in time
+        let late = |input: &str| {
+            let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+            let mut control = ControlServer::new(tx);
+            control.set_token(Some("secret".to_owned()));
+            control.idle_timeout = Duration::ZERO;
+            let conn = BufferStream::with_buffer(input.as_bytes().into(), vec![].into());
+            control.serve_conn(conn.clone()).unwrap();
+            let replies = String::from_utf8(conn.wr_buff().get_ref().clone()).unwrap();
+            replies.lines().count()
+        };
+        assert_eq!(late(&format!("\n{}", shutdown)), 1);
+
+        let auth = |token| format!("{{\"command\":\"auth\",\"token\":\"{}\"}}\n", token);
+        assert_eq!(replies(Some("secret"), shutdown), (1, 0));
+        assert_eq!(
+            replies(Some("secret"), &(auth("secreT") + shutdown)),
+            (1, 0)
+        );
+        assert_eq!(
+            replies(Some("secret"), &(auth("secret") + shutdown)),
+            (2, 1)
+        );
+    }
+
+    #[test]
+    fn close_idle_connection() {
+        use std::io::Read;
+
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let mut control = ControlServer::new(tx);
+        control.set_token(Some("secret".to_owned()));
+        control.idle_timeout = Duration::from_millis(300);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        control.spawn(ControlListener::Tcp(listener)).unwrap();
+
+        let auth = b"{\"command\":\"auth\",\"token\":\"secret\"}\n";
+        let idle: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| std::net::TcpStream::connect(addr).unwrap())
+            .collect();
+        thread::sleep(Duration::from_millis(100));
+        // no slot is left
+        let mut conn = std::net::TcpStream::connect(addr).unwrap();
+        conn.write_all(auth).ok();
+        assert_eq!(conn.read(&mut [0; 1]).unwrap_or(0), 0);
+
+        // the idle connections are closed and their slots are freed
+        for mut conn in idle {
+            assert_eq!(conn.read(&mut [0; 1]).unwrap(), 0);
+        }
+        let mut conn = std::net::TcpStream::connect(addr).unwrap();
+        conn.write_all(auth).unwrap();
+        let mut lines = BufReader::new(conn).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":true}"#);
+    }
+
+    #[test]
+    fn connection_slots() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let mut slots: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| ConnectionSlot::acquire(&active).unwrap())
+            .collect();
+        assert!(ConnectionSlot::acquire(&active).is_none());
+        slots.pop();
+        assert!(ConnectionSlot::acquire(&active).is_some());
+        drop(slots);
+        assert_eq!(active.load(Ordering::Acquire), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn control_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("gatekeeper-control-{}.sock", std::process::id()));
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        ControlServer::new(tx).spawn_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
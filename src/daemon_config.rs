@@ -0,0 +1,208 @@
+//! Configuration file of gatekeeperd
+//!
+//! `--config <FILE>` reads options from a yaml file, keyed by the long names of the options.
+//! Options given on the command line take precedence over the file.
+//!
+//! ```yaml
+//! ip: 0.0.0.0
+//! port: 1080
+//! listen: ["[::1]:1080"]
+//! rule: /etc/gatekeeper/rule.yml
+//! users: /etc/gatekeeper/users.yml
+//! connect-timeout: 10
+//! idle-timeout: 600
+//! max-sessions: 1000
+//! accept-overflow: reject
+//! forward: ["0.0.0.0:8443=internal:443"]
+//! log-level: info
+//! ```
+//!
+//! Relative paths are resolved from the working directory, as on the command line.
+use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+
+use gatekeeper as gk;
+
+use crate::Opt;
+
+/// Options read from a configuration file
+///
+/// Every field is named after a field of `Opt`, and renamed to the long name of the option.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DaemonConfig {
+    port: Option<u16>,
+    #[serde(rename = "ip")]
+    ipaddr: Option<IpAddr>,
+    listen: Option<Vec<SocketAddr>>,
+    unix_socket: Option<PathBuf>,
+    #[serde(rename = "rule")]
+    rulefile: Option<PathBuf>,
+    #[serde(rename = "users")]
+    userfile: Option<PathBuf>,
+    watch: Option<u64>,
+    rule_cache: Option<NonZeroUsize>,
+    #[serde(deserialize_with = "parse_list")]
+    allow_method: Option<Vec<gk::Method>>,
+    check_resolved: Option<bool>,
+    reject_private_resolved: Option<bool>,
+    inspect_sni: Option<bool>,
+    udp_reassembly_timeout: Option<u64>,
+    dns_cache_ttl: Option<u64>,
+    dns_negative_ttl: Option<u64>,
+    spare_connections: Option<usize>,
+    tcp_keepalive: Option<u64>,
+    outbound_bind_addr: Option<IpAddr>,
+    outbound_bind_device: Option<String>,
+    socks4: Option<bool>,
+    lenient_version: Option<bool>,
+    http_connect: Option<SocketAddr>,
+    #[serde(deserialize_with = "parse_list")]
+    forward: Option<Vec<gk::Forward>>,
+    control_socket: Option<PathBuf>,
+    control_addr: Option<SocketAddr>,
+    control_token_file: Option<PathBuf>,
+    user: Option<String>,
+    group: Option<String>,
+    sandbox: Option<bool>,
+    grace: Option<u64>,
+    max_sessions: Option<usize>,
+    ban_threshold: Option<usize>,
+    ban_window: Option<u64>,
+    ban_duration: Option<u64>,
+    accept_queue: Option<NonZeroUsize>,
+    #[serde(deserialize_with = "parse")]
+    accept_overflow: Option<gk::AcceptOverflow>,
+    connect_timeout: Option<u64>,
+    connect_retries: Option<u32>,
+    retry_backoff: Option<u64>,
+    #[serde(deserialize_with = "parse")]
+    reply_addr: Option<gk::ReplyAddr>,
+    handshake_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    max_session_duration: Option<u64>,
+    max_bytes_per_sec: Option<u64>,
+    relay_buffer_size: Option<NonZeroUsize>,
+    global_max_bytes_per_sec: Option<u64>,
+    log_level: Option<LevelFilter>,
+}
+
+/// set each field of `$opt` to the one of `$file` unless the option is given on the command line
+macro_rules! merge_options {
+    ($file:ident, $opt:ident, $matches:ident; $($field:ident),* $(,)?) => {
+        $(
+            if let Some(value) = $file.$field {
+                if $matches.value_source(stringify!($field)) != Some(ValueSource::CommandLine) {
+                    $opt.$field = value.into();
+                }
+            }
+        )*
+    };
+}
+
+impl DaemonConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        serde_yaml::from_reader(file).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    /// Set options of `opt` not given on the command line (`matches`)
+    pub fn merge_into(self, opt: &mut Opt, matches: &ArgMatches) {
+        merge_options! {
+            self, opt, matches;
+            port, ipaddr, listen, unix_socket, rulefile, userfile, watch, rule_cache, allow_method,
+            check_resolved, reject_private_resolved, inspect_sni, udp_reassembly_timeout,
+            dns_cache_ttl, dns_negative_ttl, spare_connections, tcp_keepalive,
+            outbound_bind_addr, outbound_bind_device, socks4, lenient_version, http_connect,
+            forward, control_socket, control_addr, control_token_file, user, group, sandbox, grace,
+            max_sessions, ban_threshold, ban_window, ban_duration, accept_queue, accept_overflow,
+            connect_timeout, connect_retries, retry_backoff, reply_addr, handshake_timeout,
+            idle_timeout, max_session_duration, max_bytes_per_sec, relay_buffer_size,
+            global_max_bytes_per_sec, log_level,
+        }
+    }
+}
+
+/// a value written as on the command line (e.g. `reject` of `accept-overflow`)
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// a sequence of values written as on the command line
+fn parse_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn merged(yaml: &str, args: &[&str]) -> Opt {
+        let matches = Opt::command()
+            .try_get_matches_from(std::iter::once("gatekeeperd").chain(args.iter().copied()))
+            .unwrap();
+        let mut opt = Opt::from_arg_matches(&matches).unwrap();
+        let file: DaemonConfig = serde_yaml::from_str(yaml).unwrap();
+        file.merge_into(&mut opt, &matches);
+        opt
+    }
+
+    #[test]
+    fn merge_config_file() {
+        let yaml = r#"
+ip: 127.0.0.1
+port: 1081
+rule: /etc/gatekeeper/rule.yml
+idle-timeout: 600
+socks4: true
+accept-overflow: reject
+forward: ["0.0.0.0:8443=internal:443"]
+log-level: debug
+"#;
+        let opt = merged(yaml, &["--port", "1082", "--idle-timeout", "60"]);
+        // the command line takes precedence
+        assert_eq!(opt.port, 1082);
+        assert_eq!(opt.idle_timeout, Some(60));
+        assert_eq!(opt.ipaddr, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(opt.rulefile, Some("/etc/gatekeeper/rule.yml".into()));
+        assert!(opt.socks4);
+        assert_eq!(opt.accept_overflow, gk::AcceptOverflow::Reject);
+        assert_eq!(
+            opt.forward,
+            vec!["0.0.0.0:8443=internal:443".parse().unwrap()]
+        );
+        assert_eq!(opt.log_level, Some(LevelFilter::Debug));
+        // defaults of the command line are kept
+        assert_eq!(opt.retry_backoff, 100);
+    }
+
+    #[test]
+    fn invalid_config_file() {
+        assert!(serde_yaml::from_str::<DaemonConfig>("prot: 1080").is_err());
+        assert!(serde_yaml::from_str::<DaemonConfig>("accept-overflow: drop").is_err());
+        assert!(serde_yaml::from_str::<DaemonConfig>("forward: [internal:443]").is_err());
+        assert!(serde_yaml::from_str::<DaemonConfig>("max-sessions: -1").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Limit of concurrent sessions to destinations
+//!
+//! Connect rules with `max_sessions` (`ConnectRulePattern::max_sessions`) allow only that number of
+//! sessions connected through them at the same time.
+//! Sessions are counted by the server, and released when they are disconnected.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use log::*;
+
+use crate::model::{Address, Error, ErrorKind, L4Protocol};
+use crate::policy::Decision;
+use crate::session::SessionId;
+
+#[derive(Debug, Default)]
+struct Counts {
+    /// number of sessions by limited rules
+    sessions: HashMap<String, usize>,
+    /// the rule counting each session
+    holders: HashMap<SessionId, String>,
+}
+
+/// Counters of sessions connected through limited rules
+#[derive(Debug, Default)]
+pub(crate) struct DestinationLimiter {
+    counts: Mutex<Counts>,
+}
+
+impl DestinationLimiter {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Counts> {
+        self.counts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Count the session `id` for `key`
+    ///
+    /// returns `false` if `max` sessions are already counted.
+    pub fn acquire(&self, id: SessionId, key: String, max: usize) -> bool {
+        let mut counts = self.lock();
+        let Counts { sessions, holders } = &mut *counts;
+        if holders.contains_key(&id) {
+            // a session performs a single CONNECT
+            return true;
+        }
+        let count = sessions.entry(key.clone()).or_default();
+        if *count >= max {
+            return false;
+        }
+        *count += 1;
+        holders.insert(id, key);
+        true
+    }
+
+    /// Forget the session `id`
+    pub fn release(&self, id: SessionId) {
+        let mut counts = self.lock();
+        let Counts { sessions, holders } = &mut *counts;
+        if let Some(key) = holders.remove(&id) {
+            if let Some(count) = sessions.get_mut(&key) {
+                *count -= 1;
+                if *count == 0 {
+                    sessions.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// number of sessions counted for `key`
+    #[cfg(test)]
+    pub fn sessions(&self, key: &str) -> usize {
+        self.lock().sessions.get(key).copied().unwrap_or(0)
+    }
+}
+
+/// Limiter seen from a session
+#[derive(Debug, Clone)]
+pub(crate) struct DestinationSlot {
+    limiter: Arc<DestinationLimiter>,
+    id: SessionId,
+}
+
+impl DestinationSlot {
+    pub fn new(limiter: Arc<DestinationLimiter>, id: SessionId) -> Self {
+        Self { limiter, id }
+    }
+
+    /// Count the session for the rule allowed the connection to `addr` if it is limited
+    ///
+    /// Sessions are counted per rule name, or rule index if the rule is not named.
+    /// Name the rule to keep counting sessions across rule reloads.
+    pub fn acquire(
+        &self,
+        decision: &Decision,
+        addr: &Address,
+        protocol: L4Protocol,
+    ) -> Result<(), Error> {
+        let max = match decision.max_sessions {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let key = match (&decision.rule_name, decision.matched_rule) {
+            (Some(name), _) => name.clone(),
+            (None, Some(idx)) => format!("#{}", idx),
+            // decided by a policy other than rules
+            (None, None) => addr.to_string(),
+        };
+        if self.limiter.acquire(self.id, key.clone(), max) {
+            return Ok(());
+        }
+        info!(
+            "too many sessions by rule: {}: {}: {}: {}",
+            key, max, addr, protocol
+        );
+        Err(ErrorKind::ConnectionNotAllowed {
+            addr: addr.clone(),
+            protocol,
+            rule: decision.rule_name.clone(),
+            reply: None,
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn destination_limiter() {
+        let limiter = Arc::new(DestinationLimiter::default());
+        let slot = |id: u32| DestinationSlot::new(limiter.clone(), SessionId(id));
+        let addr: Address = "192.0.2.1:443".parse().unwrap();
+        let decision = Decision::allow()
+            .with_rule(Some(1), Some("web".into()))
+            .limited_to(2);
+        let acquire = |id| slot(id).acquire(&decision, &addr, L4Protocol::Tcp);
+        assert!(acquire(1).is_ok());
+        assert!(acquire(2).is_ok());
+        let err = acquire(3).unwrap_err();
+        assert_eq!(err.cerr(), crate::model::ConnectError::ConnectionNotAllowed);
+        assert_eq!(limiter.sessions("web"), 2);
+
+        limiter.release(SessionId(1));
+        // not counted sessions are ignored
+        limiter.release(SessionId(3));
+        assert_eq!(limiter.sessions("web"), 1);
+        assert!(acquire(3).is_ok());
+
+        // not limited
+        let decision = Decision::allow().with_rule(Some(0), None);
+        assert!(slot(4).acquire(&decision, &addr, L4Protocol::Tcp).is_ok());
+        let decision = decision.limited_to(1);
+        assert!(slot(4).acquire(&decision, &addr, L4Protocol::Tcp).is_ok());
+        assert!(slot(5).acquire(&decision, &addr, L4Protocol::Tcp).is_err());
+        assert_eq!(limiter.sessions("#0"), 1);
+    }
+}
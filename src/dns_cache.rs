@@ -0,0 +1,255 @@
+//! Cache of resolved domain names
+//!
+//! [`CachingResolver`] keeps addresses resolved by another [`Resolver`] until their TTL expires,
+//! so that clients repeatedly connecting to the same hosts do not wait for the resolver.
+//! It is enabled for `TcpUdpConnector::from_config` by `ServerConfig::set_dns_cache`.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::connector::Resolver;
+use crate::model::Error;
+
+/// Parameters of [`CachingResolver`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DnsCacheConfig {
+    /// maximum number of cached domains
+    pub capacity: usize,
+    /// TTL of addresses if the resolver does not tell it (e.g. `SystemResolver`)
+    pub default_ttl: Duration,
+    /// TTL of all addresses regardless of the resolver
+    pub ttl_override: Option<Duration>,
+    /// TTL of domains not resolved (`None` does not cache them)
+    pub negative_ttl: Option<Duration>,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            default_ttl: Duration::from_secs(60),
+            ttl_override: None,
+            negative_ttl: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// empty if the domain is not resolved
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Resolver caching results of `inner`
+///
+/// Addresses are cached per domain, and the requested port is applied to them.
+/// Errors of `inner` are not cached.
+pub struct CachingResolver {
+    inner: Arc<dyn Resolver>,
+    config: DnsCacheConfig,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl fmt::Debug for CachingResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CachingResolver")
+            .field("config", &self.config)
+            .field("entries", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CachingResolver {
+    pub fn new(inner: Arc<dyn Resolver>, config: DnsCacheConfig) -> Self {
+        Self {
+            inner,
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// number of cached domains (including expired ones not evicted yet)
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// forget all cached domains
+    pub fn clear(&self) {
+        self.lock().clear()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// cached addresses of `domain` and their remaining TTL
+    fn lookup(&self, domain: &str, now: Instant) -> Option<(Vec<IpAddr>, Duration)> {
+        self.lock()
+            .get(domain)
+            .filter(|entry| now < entry.expires)
+            .map(|entry| (entry.addrs.clone(), entry.expires - now))
+    }
+
+    fn insert(&self, domain: &str, addrs: Vec<IpAddr>, ttl: Duration, now: Instant) {
+        let mut entries = self.lock();
+        if !entries.contains_key(domain) && entries.len() >= self.config.capacity {
+            entries.retain(|_, entry| now < entry.expires);
+            if entries.len() >= self.config.capacity {
+                // evict the domain expiring first
+                let first = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(domain, _)| domain.clone());
+                if let Some(first) = first {
+                    entries.remove(&first);
+                }
+            }
+        }
+        if self.config.capacity > 0 {
+            entries.insert(
+                domain.to_owned(),
+                Entry {
+                    addrs,
+                    expires: now + ttl,
+                },
+            );
+        }
+    }
+}
+
+impl Resolver for CachingResolver {
+    fn resolve(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        self.resolve_ttl(domain, port).map(|(addrs, _)| addrs)
+    }
+
+    fn resolve_ttl(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> Result<(Vec<SocketAddr>, Option<Duration>), Error> {
+        let now = Instant::now();
+        if let Some((addrs, ttl)) = self.lookup(domain, now) {
+            trace!("dns cache hit: {}: {:?}", domain, addrs);
+            let addrs = addrs
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect();
+            return Ok((addrs, Some(ttl)));
+        }
+        let (addrs, ttl) = self.inner.resolve_ttl(domain, port)?;
+        let ttl = if addrs.is_empty() {
+            self.config.negative_ttl
+        } else {
+            Some(
+                self.config
+                    .ttl_override
+                    .or(ttl)
+                    .unwrap_or(self.config.default_ttl),
+            )
+        };
+        if let Some(ttl) = ttl {
+            let ips = addrs.iter().map(SocketAddr::ip).collect();
+            self.insert(domain, ips, ttl, now);
+        }
+        Ok((addrs, ttl))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// resolve `*.test` domains into 192.0.2.1 with TTL 1 hour, and count the queries
+    #[derive(Debug, Default)]
+    struct CountResolver(AtomicUsize);
+
+    impl Resolver for CountResolver {
+        fn resolve(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+            self.resolve_ttl(domain, port).map(|(addrs, _)| addrs)
+        }
+
+        fn resolve_ttl(
+            &self,
+            domain: &str,
+            port: u16,
+        ) -> Result<(Vec<SocketAddr>, Option<Duration>), Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let addrs = if domain.ends_with(".test") {
+                vec![SocketAddr::new([192, 0, 2, 1].into(), port)]
+            } else {
+                vec![]
+            };
+            Ok((addrs, Some(Duration::from_secs(3600))))
+        }
+    }
+
+    fn caching(config: DnsCacheConfig) -> (Arc<CountResolver>, CachingResolver) {
+        let inner = Arc::new(CountResolver::default());
+        (inner.clone(), CachingResolver::new(inner, config))
+    }
+
+    #[test]
+    fn cache_addrs() {
+        let (inner, cache) = caching(DnsCacheConfig::default());
+        let addr: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        assert_eq!(cache.resolve("a.test", 80).unwrap(), [addr]);
+        // the port of the request is applied to the cached address
+        assert_eq!(
+            cache.resolve("a.test", 443).unwrap(),
+            [SocketAddr::new(addr.ip(), 443)]
+        );
+        assert_eq!(inner.0.load(Ordering::SeqCst), 1);
+
+        // not resolved domains are not cached by default
+        assert!(cache.resolve("unknown.invalid", 80).unwrap().is_empty());
+        assert!(cache.resolve("unknown.invalid", 80).unwrap().is_empty());
+        assert_eq!(inner.0.load(Ordering::SeqCst), 3);
+
+        cache.clear();
+        cache.resolve("a.test", 80).unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn cache_ttl() {
+        let (inner, cache) = caching(DnsCacheConfig {
+            ttl_override: Some(Duration::ZERO),
+            negative_ttl: Some(Duration::from_secs(60)),
+            ..DnsCacheConfig::default()
+        });
+        // expired immediately by the override
+        cache.resolve("a.test", 80).unwrap();
+        cache.resolve("a.test", 80).unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 2);
+
+        assert!(cache.resolve("unknown.invalid", 80).unwrap().is_empty());
+        assert!(cache.resolve("unknown.invalid", 80).unwrap().is_empty());
+        assert_eq!(inner.0.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn cache_capacity() {
+        let (inner, cache) = caching(DnsCacheConfig {
+            capacity: 2,
+            ..DnsCacheConfig::default()
+        });
+        for domain in ["a.test", "b.test", "c.test"] {
+            cache.resolve(domain, 80).unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+        // "a.test" expiring first has been evicted
+        cache.resolve("c.test", 80).unwrap();
+        cache.resolve("a.test", 80).unwrap();
+        assert_eq!(inner.0.load(Ordering::SeqCst), 4);
+    }
+}
@@ -79,11 +79,19 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<crate::config::ConfigError> for Error {
+    fn from(err: crate::config::ConfigError) -> Self {
+        Error {
+            inner: err.context(ErrorKind::Config),
+        }
+    }
+}
+
 impl From<model::Error> for Error {
     fn from(err: model::Error) -> Self {
         use model::ErrorKind as K;
         let ctx = match err.kind() {
-            K::Io => err.context(ErrorKind::Io),
+            K::Io { .. } => err.context(ErrorKind::Io),
             K::Poisoned(_) => err.context(ErrorKind::Io),
             K::Disconnected { .. } => err.context(ErrorKind::Io),
             K::MessageFormat { .. } => err.context(ErrorKind::Unknown),
@@ -91,6 +99,7 @@ impl From<model::Error> for Error {
             K::NoAcceptableMethod => err.context(ErrorKind::NotSupported),
             K::UnrecognizedUsernamePassword => err.context(ErrorKind::Auth),
             K::CommandNotSupported { .. } => err.context(ErrorKind::NotSupported),
+            K::AddrTypeNotSupported { .. } => err.context(ErrorKind::NotSupported),
             K::HostUnreachable { .. } => err.context(ErrorKind::Io),
             K::DomainNotResolved { .. } => err.context(ErrorKind::Io),
             K::PacketSizeLimitExceeded { .. } => err.context(ErrorKind::Io),
@@ -98,6 +107,8 @@ impl From<model::Error> for Error {
             K::AddressNotAvailable { .. } => err.context(ErrorKind::Io),
             K::ConnectionNotAllowed { .. } => err.context(ErrorKind::NotAllowed),
             K::ConnectionRefused { .. } => err.context(ErrorKind::Io),
+            K::ConnectionTimedOut { .. } => err.context(ErrorKind::Io),
+            K::HandshakeTimedOut => err.context(ErrorKind::Io),
         };
         Error { inner: ctx }
     }
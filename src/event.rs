@@ -0,0 +1,150 @@
+//! Hooks of session lifecycle
+//!
+//! [`ServerEventHandler`] is notified of the progress of each session,
+//! which allows to collect custom telemetry without parsing logs.
+//! It is set by `ServerConfig::set_event_handler`.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! use gatekeeper::audit::DisconnectEvent;
+//! use gatekeeper::event::ServerEventHandler;
+//! use gatekeeper::ServerConfig;
+//!
+//! #[derive(Debug)]
+//! struct Traffic;
+//!
+//! impl ServerEventHandler for Traffic {
+//!     fn on_disconnect(&self, event: &DisconnectEvent) {
+//!         println!("{}: {} bytes", event.dst_addr, event.upload_bytes + event.download_bytes);
+//!     }
+//! }
+//!
+//! let mut config = ServerConfig::default();
+//! config.set_event_handler(Some(Arc::new(Traffic)));
+//! ```
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::audit::{ConnectEvent, DisconnectEvent, RejectEvent, RejectReason, SessionLogger};
+use crate::metrics::Outcome;
+use crate::model::{ErrorKind, Method, SocketAddr};
+use crate::session::{SessionId, SessionStats};
+
+/// Receiver of session lifecycle events
+///
+/// All methods do nothing by default.
+pub trait ServerEventHandler: fmt::Debug + Send + Sync {
+    /// a client has connected to the server
+    fn on_connect(&self, _event: &AcceptEvent) {}
+    /// a client has been authenticated by the selected method
+    fn on_authenticated(&self, _event: &AuthEvent) {}
+    /// a request has been denied by the connect rule
+    fn on_rule_denied(&self, _event: &RejectEvent) {}
+    /// connection to the destination has been established and relay is started
+    fn on_relay_started(&self, _event: &ConnectEvent) {}
+    /// relay has been finished
+    fn on_disconnect(&self, _event: &DisconnectEvent) {}
+    /// a session has been finished and its threads have exited
+    ///
+    /// Unlike `on_disconnect`, this is notified of every session (e.g. rejected ones),
+    /// with the bytes relayed until the end.
+    fn on_session_finished(&self, _event: &SessionFinishedEvent) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct AcceptEvent {
+    pub time: SystemTime,
+    pub session_id: SessionId,
+    pub client_addr: SocketAddr,
+}
+
+impl AcceptEvent {
+    pub(crate) fn new(session_id: SessionId, client_addr: SocketAddr) -> Self {
+        Self {
+            time: SystemTime::now(),
+            session_id,
+            client_addr,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthEvent {
+    pub time: SystemTime,
+    pub session_id: SessionId,
+    pub client_addr: SocketAddr,
+    pub method: Method,
+    /// authenticated user name (e.g. the username of `UserPass` method)
+    pub user: Option<String>,
+}
+
+impl AuthEvent {
+    pub(crate) fn new(
+        session_id: SessionId,
+        client_addr: SocketAddr,
+        method: Method,
+        user: Option<&str>,
+    ) -> Self {
+        Self {
+            time: SystemTime::now(),
+            session_id,
+            client_addr,
+            method,
+            user: user.map(str::to_owned),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionFinishedEvent {
+    pub time: SystemTime,
+    pub session_id: SessionId,
+    /// final statistics of the session
+    pub stats: SessionStats,
+    /// error terminated the session (`None` if finished successfully)
+    pub error: Option<String>,
+    /// kind of `error` (`None` if finished successfully or panicked)
+    pub error_kind: Option<ErrorKind>,
+}
+
+impl SessionFinishedEvent {
+    pub(crate) fn new(session_id: SessionId, stats: SessionStats, outcome: &Outcome) -> Self {
+        let error = match outcome {
+            Outcome::Success => None,
+            Outcome::Error(err) => Some(err.to_string()),
+            Outcome::Panic => Some("session panicked".to_owned()),
+        };
+        let error_kind = match outcome {
+            Outcome::Error(err) => Some(err.kind().clone()),
+            _ => None,
+        };
+        Self {
+            time: SystemTime::now(),
+            session_id,
+            stats,
+            error,
+            error_kind,
+        }
+    }
+}
+
+/// Delivers audit events of sessions to a handler
+#[derive(Debug)]
+pub(crate) struct EventLogger(pub Arc<dyn ServerEventHandler>);
+
+impl SessionLogger for EventLogger {
+    fn on_connect(&self, event: &ConnectEvent) {
+        self.0.on_relay_started(event)
+    }
+
+    fn on_reject(&self, event: &RejectEvent) {
+        if event.reason == RejectReason::NotAllowed {
+            self.0.on_rule_denied(event)
+        }
+    }
+
+    fn on_disconnect(&self, event: &DisconnectEvent) {
+        self.0.on_disconnect(event)
+    }
+}
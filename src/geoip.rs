@@ -0,0 +1,82 @@
+//! Country lookup of ip addresses
+//!
+//! Rules with `AddressPattern::Country` match addresses located by a [`GeoIpProvider`],
+//! which is set by `ServerConfig::set_geoip_provider`.
+//! Without a provider, these patterns never match.
+//!
+//! With `geoip` feature, [`MaxMindProvider`] looks up a MaxMind DB (e.g. GeoLite2 Country).
+//!
+//! ```yaml
+//! # deny connections to destinations in North Korea
+//! - Deny:
+//!     address:
+//!       Specif:
+//!         Country:
+//!           iso_code: KP
+//!     port: Any
+//!     protocol: Any
+//! ```
+use std::fmt;
+use std::net::IpAddr;
+#[cfg(feature = "geoip")]
+use std::path::Path;
+
+#[cfg(feature = "geoip")]
+use failure::ResultExt;
+
+#[cfg(feature = "geoip")]
+use crate::error::{Error, ErrorKind};
+
+/// Locates ip addresses
+pub trait GeoIpProvider: fmt::Debug + Send + Sync {
+    /// ISO 3166-1 alpha-2 code of the country where `addr` is located (e.g. `JP`)
+    fn country(&self, addr: IpAddr) -> Option<String>;
+}
+
+/// Countries of address blocks
+///
+/// This is useful for tests, or for a small number of known networks.
+impl GeoIpProvider for Vec<(crate::model::AddressPattern, String)> {
+    fn country(&self, addr: IpAddr) -> Option<String> {
+        use crate::model::{Address, Matcher};
+        let addr = Address::IpAddr(addr, 0);
+        self.iter()
+            .find(|(pat, _)| pat.r#match(&addr))
+            .map(|(_, iso_code)| iso_code.clone())
+    }
+}
+
+/// Provider looking up a MaxMind DB
+#[cfg(feature = "geoip")]
+pub struct MaxMindProvider {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MaxMindProvider {
+    /// Load the database from `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let reader = maxminddb::Reader::open_readfile(path).context(ErrorKind::Config)?;
+        Ok(Self { reader })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl fmt::Debug for MaxMindProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MaxMindProvider")
+            .field("database_type", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoIpProvider for MaxMindProvider {
+    fn country(&self, addr: IpAddr) -> Option<String> {
+        let record: maxminddb::geoip2::Country = self.reader.lookup(addr).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_owned)
+    }
+}
@@ -0,0 +1,528 @@
+//! GSSAPI authentication method ([RFC1961](https://tools.ietf.org/html/rfc1961))
+//!
+//! This module performs the sub-negotiation and the per-message encapsulation.
+//! The security mechanism itself (e.g. Kerberos) is provided by implementing [`GssApiContext`]
+//! and [`GssApiProvider`] outside of this crate.
+//!
+//! ```no_run
+//! use gatekeeper::gssapi::{GssApiContext, GssApiProvider, GssApiService, Step};
+//! use gatekeeper::model::Error;
+//! use gatekeeper::{Server, ServerConfig};
+//!
+//! #[derive(Clone)]
+//! struct Kerberos;
+//! struct KerberosContext;
+//!
+//! impl GssApiContext for KerberosContext {
+//!     fn accept(&mut self, token: &[u8]) -> Result<Step, Error> {
+//!         // gss_accept_sec_context
+//! #       unimplemented!()
+//!     }
+//!     fn wrap(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+//!         // gss_wrap
+//! #       unimplemented!()
+//!     }
+//!     fn unwrap(&mut self, token: &[u8]) -> Result<Vec<u8>, Error> {
+//!         // gss_unwrap
+//! #       unimplemented!()
+//!     }
+//! }
+//!
+//! impl GssApiProvider for Kerberos {
+//!     type Context = KerberosContext;
+//!     fn new_context(&self) -> Result<KerberosContext, Error> {
+//!         Ok(KerberosContext)
+//!     }
+//! }
+//!
+//! let (mut server, _tx) =
+//!     Server::with_auth_service(ServerConfig::default(), GssApiService::new(Kerberos));
+//! server.serve().ok();
+//! ```
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::*;
+
+use crate::auth_service::AuthService;
+use crate::byte_stream::{BoxedStream, ByteStream};
+use crate::model::{Error, ErrorKind, Method};
+
+pub const GSSAPI_VERSION: u8 = 1;
+
+/// Message types
+const MTYP_AUTH: u8 = 1;
+const MTYP_PROTECTION: u8 = 2;
+const MTYP_ENCAPSULATION: u8 = 3;
+const MTYP_ABORT: u8 = 0xff;
+
+/// Maximum size of data wrapped into a message
+///
+/// Wrapped tokens have to fit in the 16 bits length field.
+const MAX_CHUNK_SIZE: usize = 32 * 1024;
+
+/// Per-message protection level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectionLevel {
+    /// required per-message integrity
+    Integrity,
+    /// required per-message integrity and confidentiality
+    Confidentiality,
+    /// selective per-message integrity or confidentiality based on local client policy
+    SelectiveIntegrity,
+}
+
+impl ProtectionLevel {
+    pub fn code(&self) -> u8 {
+        match self {
+            ProtectionLevel::Integrity => 1,
+            ProtectionLevel::Confidentiality => 2,
+            ProtectionLevel::SelectiveIntegrity => 3,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(ProtectionLevel::Integrity),
+            2 => Some(ProtectionLevel::Confidentiality),
+            3 => Some(ProtectionLevel::SelectiveIntegrity),
+            _ => None,
+        }
+    }
+}
+
+/// Result of processing a context establishment token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// the output token is sent to the client, and another token is expected
+    Continue(Vec<u8>),
+    /// the context is established, sending the output token if any
+    Complete(Option<Vec<u8>>),
+}
+
+/// Security context established with a client
+pub trait GssApiContext: Send + 'static {
+    /// process a token from the client (`gss_accept_sec_context`)
+    fn accept(&mut self, token: &[u8]) -> Result<Step, Error>;
+
+    /// decide the protection level from the one requested by the client
+    fn protection_level(&mut self, requested: ProtectionLevel) -> Result<ProtectionLevel, Error> {
+        Ok(requested)
+    }
+
+    /// protect a message sent to the client (`gss_wrap`)
+    fn wrap(&mut self, data: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// verify a message from the client (`gss_unwrap`)
+    fn unwrap(&mut self, token: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// name of the client authenticated by the established context, matched with `user` of connect rules
+    fn user(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Creates a security context for each session
+pub trait GssApiProvider: Send {
+    type Context: GssApiContext;
+    fn new_context(&self) -> Result<Self::Context, Error>;
+}
+
+/// `GssApi` method compeller
+#[derive(Debug, Clone)]
+pub struct GssApiService<P> {
+    provider: P,
+}
+
+impl<P: GssApiProvider> GssApiService<P> {
+    pub fn new(provider: P) -> Self {
+        Self { provider }
+    }
+}
+
+impl<P: GssApiProvider> AuthService for GssApiService<P> {
+    fn select(&self, candidates: &[Method]) -> Result<Option<Method>, Error> {
+        if candidates.contains(&Method::GssApi) {
+            Ok(Some(Method::GssApi))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn authorize<'a, B>(&self, method: Method, conn: B) -> Result<BoxedStream<'a>, Error>
+    where
+        B: ByteStream + 'a,
+    {
+        self.authorize_user(method, conn).map(|(conn, _)| conn)
+    }
+
+    fn authorize_user<'a, B>(
+        &self,
+        method: Method,
+        mut conn: B,
+    ) -> Result<(BoxedStream<'a>, Option<String>), Error>
+    where
+        B: ByteStream + 'a,
+    {
+        if method != Method::GssApi {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, method.to_string());
+            return Err(e.into());
+        }
+        let mut ctx = self.provider.new_context()?;
+        match negotiate(&mut ctx, &mut conn) {
+            Ok(level) => {
+                debug!("gssapi protection level: {:?}", level);
+                let user = ctx.user();
+                Ok((Box::new(GssApiStream::new(conn, ctx)), user))
+            }
+            Err(err) => {
+                info!("gssapi negotiation failed: {}", err);
+                // notify the client, ignoring errors on the connection
+                conn.write_all(&[GSSAPI_VERSION, MTYP_ABORT]).ok();
+                Err(ErrorKind::Authentication.into())
+            }
+        }
+    }
+}
+
+/// context establishment and protection level negotiation
+fn negotiate<C: GssApiContext, S: Read + Write>(
+    ctx: &mut C,
+    conn: &mut S,
+) -> Result<ProtectionLevel, Error> {
+    loop {
+        let token = recv_message(&mut *conn, MTYP_AUTH)?;
+        match ctx.accept(&token)? {
+            Step::Continue(token) => send_message(&mut *conn, MTYP_AUTH, &token)?,
+            Step::Complete(token) => {
+                if let Some(token) = token {
+                    send_message(&mut *conn, MTYP_AUTH, &token)?;
+                }
+                break;
+            }
+        }
+    }
+
+    let requested = ctx.unwrap(&recv_message(&mut *conn, MTYP_PROTECTION)?)?;
+    let requested = match requested[..] {
+        [code] => ProtectionLevel::from_code(code),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        ErrorKind::message_fmt(format_args!(
+            "gssapi: invalid protection level: {:?}",
+            requested
+        ))
+    })?;
+    let level = ctx.protection_level(requested)?;
+    send_message(&mut *conn, MTYP_PROTECTION, &ctx.wrap(&[level.code()])?)?;
+    Ok(level)
+}
+
+fn recv_message<R: Read>(mut strm: R, mtyp: u8) -> Result<Vec<u8>, Error> {
+    let mut head = [0u8; 2];
+    strm.read_exact(&mut head)?;
+    match head {
+        [GSSAPI_VERSION, MTYP_ABORT] => {
+            return Err(ErrorKind::message_fmt(format_args!("gssapi: aborted by client")).into())
+        }
+        [GSSAPI_VERSION, typ] if typ == mtyp => {}
+        [ver, typ] => {
+            return Err(ErrorKind::message_fmt(format_args!(
+                "gssapi: unexpected message: version: {}, type: {}",
+                ver, typ
+            ))
+            .into())
+        }
+    }
+    let mut len = [0u8; 2];
+    strm.read_exact(&mut len)?;
+    let mut token = vec![0; u16::from_be_bytes(len) as usize];
+    strm.read_exact(&mut token)?;
+    Ok(token)
+}
+
+fn send_message<W: Write>(mut strm: W, mtyp: u8, token: &[u8]) -> Result<(), Error> {
+    let len = u16::try_from(token.len()).map_err(|_| {
+        ErrorKind::message_fmt(format_args!("gssapi: too long token: {}", token.len()))
+    })?;
+    let mut msg = Vec::with_capacity(4 + token.len());
+    msg.extend_from_slice(&[GSSAPI_VERSION, mtyp]);
+    msg.extend_from_slice(&len.to_be_bytes());
+    msg.extend_from_slice(token);
+    strm.write_all(&msg)?;
+    Ok(())
+}
+
+fn to_io_error(err: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Stream encapsulating each message with the established context
+pub struct GssApiStream<S, C> {
+    inner: S,
+    ctx: Arc<Mutex<C>>,
+    /// unwrapped data not read yet
+    rd_buff: io::Cursor<Vec<u8>>,
+}
+
+impl<S, C> GssApiStream<S, C> {
+    pub fn new(inner: S, ctx: C) -> Self {
+        Self {
+            inner,
+            ctx: Arc::new(Mutex::new(ctx)),
+            rd_buff: io::Cursor::new(vec![]),
+        }
+    }
+}
+
+impl<S: fmt::Debug, C> fmt::Debug for GssApiStream<S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GssApiStream")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+/// receive an encapsulated message, `None` on EOF
+fn recv_encapsulated<R: Read>(mut strm: R) -> io::Result<Option<Vec<u8>>> {
+    let mut ver = [0u8];
+    if strm.read(&mut ver)? == 0 {
+        return Ok(None);
+    }
+    let mut head = [0u8; 3];
+    strm.read_exact(&mut head)?;
+    if ver[0] != GSSAPI_VERSION || head[0] != MTYP_ENCAPSULATION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("gssapi: unexpected message: {}, {}", ver[0], head[0]),
+        ));
+    }
+    let mut token = vec![0; u16::from_be_bytes([head[1], head[2]]) as usize];
+    strm.read_exact(&mut token)?;
+    Ok(Some(token))
+}
+
+fn read_encapsulated<R: Read, C: GssApiContext>(
+    mut strm: R,
+    ctx: &Mutex<C>,
+    rd_buff: &mut io::Cursor<Vec<u8>>,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    while rd_buff.position() == rd_buff.get_ref().len() as u64 {
+        let token = match recv_encapsulated(&mut strm)? {
+            Some(token) => token,
+            None => return Ok(0),
+        };
+        let data = lock(ctx)?.unwrap(&token).map_err(to_io_error)?;
+        *rd_buff = io::Cursor::new(data);
+    }
+    rd_buff.read(buf)
+}
+
+fn write_encapsulated<W: Write, C: GssApiContext>(
+    strm: W,
+    ctx: &Mutex<C>,
+    buf: &[u8],
+) -> io::Result<usize> {
+    let data = &buf[..buf.len().min(MAX_CHUNK_SIZE)];
+    let token = lock(ctx)?.wrap(data).map_err(to_io_error)?;
+    send_message(strm, MTYP_ENCAPSULATION, &token).map_err(to_io_error)?;
+    Ok(data.len())
+}
+
+fn lock<C>(ctx: &Mutex<C>) -> io::Result<MutexGuard<'_, C>> {
+    ctx.lock()
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+}
+
+impl<S: Read, C: GssApiContext> Read for GssApiStream<S, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_encapsulated(&mut self.inner, &self.ctx, &mut self.rd_buff, buf)
+    }
+}
+
+impl<S: Write, C: GssApiContext> Write for GssApiStream<S, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_encapsulated(&mut self.inner, &self.ctx, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: ByteStream, C: GssApiContext> ByteStream for GssApiStream<S, C> {
+    #[allow(clippy::type_complexity)]
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        let (rd, wr) = self.inner.split()?;
+        // carry over the data already unwrapped
+        let mut rd_buff = vec![];
+        rd_buff.extend_from_slice(&self.rd_buff.get_ref()[self.rd_buff.position() as usize..]);
+        Ok((
+            Box::new(GssApiReader {
+                inner: rd,
+                ctx: self.ctx.clone(),
+                rd_buff: io::Cursor::new(rd_buff),
+            }),
+            Box::new(GssApiWriter {
+                inner: wr,
+                ctx: self.ctx.clone(),
+            }),
+        ))
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.inner.read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+/// read half of a splitted `GssApiStream`
+struct GssApiReader<C> {
+    inner: Box<dyn io::Read + Send>,
+    ctx: Arc<Mutex<C>>,
+    rd_buff: io::Cursor<Vec<u8>>,
+}
+
+impl<C: GssApiContext> Read for GssApiReader<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_encapsulated(&mut self.inner, &self.ctx, &mut self.rd_buff, buf)
+    }
+}
+
+/// write half of a splitted `GssApiStream`
+struct GssApiWriter<C> {
+    inner: Box<dyn io::Write + Send>,
+    ctx: Arc<Mutex<C>>,
+}
+
+impl<C: GssApiContext> Write for GssApiWriter<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_encapsulated(&mut self.inner, &self.ctx, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::byte_stream::test::BufferStream;
+
+    /// establishes a context with 2 round trips and xors messages
+    struct XorContext {
+        round: usize,
+    }
+
+    impl GssApiContext for XorContext {
+        fn accept(&mut self, token: &[u8]) -> Result<Step, Error> {
+            self.round += 1;
+            match (self.round, token) {
+                (1, b"hello") => Ok(Step::Continue(b"challenge".to_vec())),
+                (2, b"response") => Ok(Step::Complete(Some(b"ok".to_vec()))),
+                _ => Err(ErrorKind::Authentication.into()),
+            }
+        }
+
+        fn wrap(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(data.iter().map(|b| b ^ 0x5a).collect())
+        }
+
+        fn unwrap(&mut self, token: &[u8]) -> Result<Vec<u8>, Error> {
+            self.wrap(token)
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct XorProvider;
+
+    impl GssApiProvider for XorProvider {
+        type Context = XorContext;
+        fn new_context(&self) -> Result<XorContext, Error> {
+            Ok(XorContext { round: 0 })
+        }
+    }
+
+    fn xor(data: &[u8]) -> Vec<u8> {
+        data.iter().map(|b| b ^ 0x5a).collect()
+    }
+
+    fn message(mtyp: u8, token: &[u8]) -> Vec<u8> {
+        let mut msg = vec![];
+        send_message(&mut msg, mtyp, token).unwrap();
+        msg
+    }
+
+    #[test]
+    fn gssapi_authorize() {
+        let auth = GssApiService::new(XorProvider);
+        assert_eq!(
+            auth.select(&[Method::NoAuth, Method::GssApi]).unwrap(),
+            Some(Method::GssApi)
+        );
+        assert_eq!(auth.select(&[Method::NoAuth]).unwrap(), None);
+
+        let mut input = vec![];
+        input.extend(message(MTYP_AUTH, b"hello"));
+        input.extend(message(MTYP_AUTH, b"response"));
+        input.extend(message(MTYP_PROTECTION, &xor(&[2])));
+        input.extend(message(MTYP_ENCAPSULATION, &xor(b"request")));
+        let src = BufferStream::with_buffer(input.into(), vec![].into());
+        let mut strm = auth.authorize(Method::GssApi, src.clone()).unwrap();
+
+        let mut buf = [0; 7];
+        strm.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"request");
+        assert_eq!(strm.read(&mut buf).unwrap(), 0);
+        strm.write_all(b"reply").unwrap();
+
+        let mut expected = vec![];
+        expected.extend(message(MTYP_AUTH, b"challenge"));
+        expected.extend(message(MTYP_AUTH, b"ok"));
+        expected.extend(message(MTYP_PROTECTION, &xor(&[2])));
+        expected.extend(message(MTYP_ENCAPSULATION, &xor(b"reply")));
+        assert_eq!(src.wr_buff().get_ref(), &expected);
+    }
+
+    #[test]
+    fn gssapi_rejected() {
+        let auth = GssApiService::new(XorProvider);
+        let src = BufferStream::with_buffer(message(MTYP_AUTH, b"bye").into(), vec![].into());
+        assert_eq!(
+            auth.authorize(Method::GssApi, src.clone())
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::Authentication
+        );
+        assert_eq!(src.wr_buff().get_ref(), &[GSSAPI_VERSION, MTYP_ABORT]);
+    }
+
+    #[test]
+    fn split_stream() {
+        let mut input = message(MTYP_ENCAPSULATION, &xor(b"abcdef"));
+        input.extend(message(MTYP_ENCAPSULATION, &xor(b"ghi")));
+        let src = BufferStream::with_buffer(input.into(), vec![].into());
+        let mut strm = GssApiStream::new(src.clone(), XorProvider.new_context().unwrap());
+
+        let mut buf = [0; 2];
+        strm.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ab");
+        let (mut rd, mut wr) = strm.split().unwrap();
+        let mut rest = vec![];
+        rd.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"cdefghi");
+        wr.write_all(b"xyz").unwrap();
+        assert_eq!(
+            src.wr_buff().get_ref(),
+            &message(MTYP_ENCAPSULATION, &xor(b"xyz"))
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! HTTP CONNECT method ([RFC9110](https://www.rfc-editor.org/rfc/rfc9110#section-9.3.6))
+//!
+//! Clients not speaking SOCKS (e.g. browsers) request a tunnel by `CONNECT host:port HTTP/1.1`.
+//! Only the request line is parsed, header fields are skipped,
+//! and the rest of the connection is relayed as is.
+use std::io;
+use std::net::IpAddr;
+
+use crate::model::{Address, ConnectError, ConnectResult, Error, ErrorKind};
+use crate::rw_socks_stream::check_domain;
+
+/// Maximum size of the request line and header fields
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// Status codes of replies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Established,
+    BadRequest,
+    Forbidden,
+    MethodNotAllowed,
+    InternalServerError,
+    BadGateway,
+    GatewayTimeout,
+}
+
+impl Status {
+    fn code(&self) -> (u16, &'static str) {
+        use Status::*;
+        match self {
+            Established => (200, "Connection Established"),
+            BadRequest => (400, "Bad Request"),
+            Forbidden => (403, "Forbidden"),
+            MethodNotAllowed => (405, "Method Not Allowed"),
+            InternalServerError => (500, "Internal Server Error"),
+            BadGateway => (502, "Bad Gateway"),
+            GatewayTimeout => (504, "Gateway Timeout"),
+        }
+    }
+}
+
+impl From<ConnectResult> for Status {
+    fn from(result: ConnectResult) -> Self {
+        use ConnectError::*;
+        match result {
+            Ok(()) => Status::Established,
+            Err(ConnectionNotAllowed) => Status::Forbidden,
+            Err(NetworkUnreachable) | Err(HostUnreachable) | Err(ConnectionRefused) => {
+                Status::BadGateway
+            }
+            Err(TtlExpired) => Status::GatewayTimeout,
+            Err(CommandNotSupported) => Status::MethodNotAllowed,
+            Err(AddrTypeNotSupported) => Status::BadRequest,
+            Err(ServerFailure) => Status::InternalServerError,
+        }
+    }
+}
+
+/// Receive a request, and return the requested destination
+///
+/// Malformed requests are replied `400 Bad Request`, and other methods `405 Method Not Allowed`.
+pub fn recv_request<S: io::Read + io::Write>(mut strm: S) -> Result<Address, Error> {
+    let head = read_head(&mut strm)?;
+    parse_request(&head).map_err(|(status, err)| {
+        // the client may have closed the connection
+        send_status(&mut strm, status).ok();
+        err
+    })
+}
+
+/// Read bytes until the empty line terminating header fields
+///
+/// This reads a byte at a time not to consume data following the request.
+fn read_head<R: io::Read>(mut strm: R) -> Result<Vec<u8>, Error> {
+    let mut head = vec![];
+    while !(head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n")) {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(ErrorKind::message_fmt(format_args!("http: too long request")).into());
+        }
+        let mut c = [0u8];
+        strm.read_exact(&mut c)?;
+        head.push(c[0]);
+    }
+    Ok(head)
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_request(head: &[u8]) -> Result<Address, (Status, Error)> {
+    let bad_request = |err: Error| (Status::BadRequest, err);
+    let line = head.split(|c| *c == b'\n').next().unwrap_or_default();
+    let line = std::str::from_utf8(line)
+        .map_err(|_| ErrorKind::message_fmt(format_args!("http: request line is not utf-8")))
+        .map_err(|kind| bad_request(kind.into()))?;
+    let fields: Vec<_> = line.trim_end_matches('\r').split(' ').collect();
+    match fields[..] {
+        ["CONNECT", target, version] if version.starts_with("HTTP/1.") => {
+            parse_authority(target).map_err(bad_request)
+        }
+        [method, _, version] if version.starts_with("HTTP/1.") => Err((
+            Status::MethodNotAllowed,
+            ErrorKind::message_fmt(format_args!("http: method not allowed: {}", method)).into(),
+        )),
+        _ => {
+            let kind = ErrorKind::message_fmt(format_args!("http: malformed request: {:?}", line));
+            Err(bad_request(kind.into()))
+        }
+    }
+}
+
+/// Parse `host:port` of the request target, where an ipv6 address is enclosed in brackets
+fn parse_authority(target: &str) -> Result<Address, Error> {
+    let invalid = || {
+        Error::from(ErrorKind::addr_type_not_supported(format_args!(
+            "invalid request target: {:?}",
+            target
+        )))
+    };
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    if let Some(ip) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return match ip.parse::<IpAddr>() {
+            Ok(ip @ IpAddr::V6(_)) => Ok(Address::IpAddr(ip, port)),
+            _ => Err(invalid()),
+        };
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip @ IpAddr::V4(_)) => Ok(Address::IpAddr(ip, port)),
+        Ok(IpAddr::V6(_)) => Err(invalid()),
+        Err(_) => {
+            check_domain(host.as_bytes())?;
+            Ok(Address::Domain(host.to_owned(), port))
+        }
+    }
+}
+
+/// Send a reply to the request
+///
+/// The connection is relayed after a successful reply, and should be closed after an error.
+pub fn send_reply<W: io::Write>(strm: W, result: ConnectResult) -> Result<(), Error> {
+    send_status(strm, result.into())
+}
+
+fn send_status<W: io::Write>(mut strm: W, status: Status) -> Result<(), Error> {
+    let (code, reason) = status.code();
+    let head = if status == Status::Established {
+        format!("HTTP/1.1 {} {}\r\n\r\n", code, reason)
+    } else {
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            code, reason
+        )
+    };
+    strm.write_all(head.as_bytes())?;
+    strm.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::byte_stream::test::BufferStream;
+
+    /// receive `request` and return the requested address and the reply
+    fn recv(request: &[u8]) -> (Result<Address, Error>, String) {
+        let strm = BufferStream::with_buffer(request.into(), vec![].into());
+        let addr = recv_request(strm.clone());
+        let reply = String::from_utf8(strm.wr_buff().get_ref().clone()).unwrap();
+        (addr, reply)
+    }
+
+    #[test]
+    fn http_request() {
+        let (addr, reply) =
+            recv(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\nhello");
+        assert_eq!(
+            addr.unwrap(),
+            Address::Domain("example.com".to_owned(), 443)
+        );
+        assert_eq!(reply, "");
+
+        let (addr, _) = recv(b"CONNECT 192.0.2.1:80 HTTP/1.0\n\n");
+        assert_eq!(addr.unwrap(), "192.0.2.1:80".parse().unwrap());
+        let (addr, _) = recv(b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\n\r\n");
+        assert_eq!(addr.unwrap(), "[2001:db8::1]:443".parse().unwrap());
+
+        for request in [
+            &b"CONNECT example.com HTTP/1.1\r\n\r\n"[..],
+            b"CONNECT :443 HTTP/1.1\r\n\r\n",
+            b"CONNECT 2001:db8::1:443 HTTP/1.1\r\n\r\n",
+            b"CONNECT example.com:443\r\n\r\n",
+        ] {
+            let (addr, reply) = recv(request);
+            assert!(addr.is_err());
+            assert!(reply.starts_with("HTTP/1.1 400 "), "{}", reply);
+        }
+
+        let (addr, reply) = recv(b"GET http://example.com/ HTTP/1.1\r\n\r\n");
+        assert!(addr.is_err());
+        assert!(reply.starts_with("HTTP/1.1 405 "), "{}", reply);
+
+        // not terminated by an empty line
+        let (addr, reply) = recv(b"CONNECT example.com:443 HTTP/1.1\r\n");
+        assert!(matches!(
+            addr.unwrap_err().kind(),
+            ErrorKind::Io {
+                kind: io::ErrorKind::UnexpectedEof,
+                ..
+            }
+        ));
+        assert_eq!(reply, "");
+    }
+
+    #[test]
+    fn http_reply() {
+        let mut buf = vec![];
+        send_reply(&mut buf, Ok(())).unwrap();
+        assert_eq!(buf, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        let mut buf = vec![];
+        send_reply(&mut buf, Err(ConnectError::ConnectionNotAllowed)).unwrap();
+        assert_eq!(
+            buf,
+            &b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"[..]
+        );
+    }
+}
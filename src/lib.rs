@@ -3,15 +3,22 @@
 //! # Feature
 //! ## Authentication
 //!
-//! Any authentication method is not supported.
+//! `NO AUTHENTICATION REQUIRED` and `USERNAME/PASSWORD` ([RFC1929](https://tools.ietf.org/html/rfc1929)) methods are supported.
 //!
-//! The client connects to the server is required for sending `X'00'` (`NO AUTHENTICATION REQUIRED`) as a method selection message.
+//! By default, the client connects to the server is required for sending `X'00'` (`NO AUTHENTICATION REQUIRED`) as a method selection message.
+//! If credentials are given by `ServerConfig::set_credentials`, the client is required for sending `X'02'` (`USERNAME/PASSWORD`) instead.
+//!
+//! `GSSAPI` ([RFC1961](https://tools.ietf.org/html/rfc1961)) is available by plugging in a security mechanism through [`gssapi::GssApiService`].
 //!
 //! ## Command
 //!
-//! Only `CONNECT` command is supported.
-//! Then, some protocols connecting from server to client are not able to proxy.
-//! And also protocols using UDP is not supported.
+//! `CONNECT`, `BIND` and `UDP ASSOCIATE` commands are supported.
+//! Fragmented UDP datagrams are dropped by default, or reassembled if all fragments arrive in order
+//! within `ServerConfig::udp_reassembly_timeout`.
+//!
+//! ## Async Server
+//!
+//! With `tokio` feature, [`aio::Server`](aio/struct.Server.html) performs sessions as tokio tasks instead of threads.
 //!
 //! ## Filter Rule
 //!
@@ -59,13 +66,13 @@
 //! // allow local ipv4 network 192.168.0.1/16
 //! rule.allow(
 //!     Specif(Pat::IpAddr { addr: "192.168.0.1".parse().unwrap(), prefix: 16, }),
-//!     Specif(80),
+//!     Specif(80.into()),
 //!     Any,
 //! );
 //! // allow local ipv4 network 192.168.0.1/16 port 443
 //! rule.allow(
 //!     Specif(Pat::IpAddr { addr: "192.168.0.1".parse().unwrap(), prefix: 16, }),
-//!     Specif(443),
+//!     Specif(443.into()),
 //!     Any,
 //! );
 //! // allow connecting to actcast.io
@@ -90,26 +97,57 @@
 //! th.join().unwrap();
 //! ```
 
+mod accept_queue;
 pub mod acceptor;
-mod auth_service;
-mod byte_stream;
+#[cfg(feature = "tokio")]
+pub mod aio;
+pub mod audit;
+pub mod auth_service;
+pub mod byte_stream;
+pub mod client;
+pub mod client_ban;
 pub mod config;
+pub mod conn_pool;
+mod connection_limiter;
 pub mod connector;
+pub mod control;
+mod destination_limiter;
+pub mod dns_cache;
 pub mod error;
+pub mod event;
+pub mod geoip;
+pub mod gssapi;
+mod http_connect;
+pub mod metrics;
 pub mod model;
 mod pkt_stream;
+pub mod policy;
+#[cfg(unix)]
+mod privilege;
 mod raw_message;
-mod relay;
+pub mod relay;
+pub mod rewrite;
+mod rule_cache;
 mod rw_socks_stream;
 pub mod server;
 pub mod server_command;
 mod session;
+pub mod shared;
+mod sni;
+mod socks4;
+#[cfg(all(feature = "splice", target_os = "linux"))]
+mod splice;
 mod tcp_listener_ext;
 #[cfg(test)]
 mod test;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 mod thread;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 pub use config::*;
 pub use model::model::*;
 pub use server::*;
 pub use server_command::*;
+pub use session::{SessionId, SessionInfo, SessionState, SessionStats};
@@ -2,17 +2,38 @@
 //!
 //! Gatekeeperd is an SOCKS5 proxy built on gatekeeper crate.
 //!
+use std::fs;
 use std::io;
-use std::net::IpAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
 use log::*;
 
 use gatekeeper as gk;
 
+mod daemon_config;
+mod sandbox;
+
 #[derive(clap::Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Opt {
+    #[arg(short = 'c', long = "config")]
+    /// Read options from <CONFIG> (format: yaml), overridden by options given on the command line
+    config: Option<PathBuf>,
+
+    #[arg(long = "check-config")]
+    /// Check the configuration including the rule and users files, and exit
+    check_config: bool,
+
+    #[arg(long = "log-level")]
+    /// Log messages at <LOG_LEVEL> (e.g. info) or more severe, unless RUST_LOG is set
+    log_level: Option<LevelFilter>,
+
     #[arg(short = 'p', long = "port", default_value = "1080")]
     /// Set port to listen on
     port: u16,
@@ -21,9 +42,234 @@ struct Opt {
     /// Set ipaddress to listen on
     ipaddr: IpAddr,
 
+    #[arg(short = 'l', long = "listen")]
+    /// Also listen on <LISTEN> (e.g. [::1]:1080), can be given multiple times
+    listen: Vec<SocketAddr>,
+
+    #[arg(long = "unix-socket", conflicts_with = "listen")]
+    /// Listen on the unix domain socket <UNIX_SOCKET> instead of the tcp port
+    unix_socket: Option<PathBuf>,
+
     #[arg(short = 'r', long = "rule")]
     /// Set path to connection rule file (format: yaml)
     rulefile: Option<PathBuf>,
+
+    #[arg(short = 'u', long = "users")]
+    /// Set path to username/password file (format: yaml), and require USERNAME/PASSWORD authentication
+    userfile: Option<PathBuf>,
+
+    #[arg(long = "explain", value_parser = parse_address)]
+    /// Show how the rules are applied to TCP connections to <EXPLAIN> (host:port), and exit
+    explain: Option<gk::Address>,
+
+    #[arg(short = 'w', long = "watch")]
+    /// Reload the rule file when it is modified, checking every <WATCH> seconds
+    watch: Option<u64>,
+
+    #[arg(long = "rule-cache")]
+    /// Cache the rules deciding up to <RULE_CACHE> recent connections
+    rule_cache: Option<NonZeroUsize>,
+
+    #[arg(long = "allow-method")]
+    /// Select only the auth method <ALLOW_METHOD> (`no-auth`, `gssapi` or `user-pass`), can be given multiple times
+    allow_method: Vec<gk::Method>,
+
+    #[arg(long = "check-resolved")]
+    /// Apply ip address rules also to addresses resolved from requested domains
+    check_resolved: bool,
+
+    #[arg(long = "reject-private-resolved")]
+    /// Reject private, loopback and link-local addresses resolved from requested domains unless allowed by ip address rules
+    reject_private_resolved: bool,
+
+    #[arg(long = "inspect-sni")]
+    /// Apply domain rules also to the TLS server name sent to ip addresses on port 443
+    inspect_sni: bool,
+
+    #[arg(long = "udp-reassembly-timeout")]
+    /// Reassemble fragmented UDP datagrams within <UDP_REASSEMBLY_TIMEOUT> seconds (dropped by default)
+    udp_reassembly_timeout: Option<u64>,
+
+    #[arg(long = "dns-cache-ttl")]
+    /// Cache resolved domains for <DNS_CACHE_TTL> seconds
+    dns_cache_ttl: Option<u64>,
+
+    #[arg(long = "dns-negative-ttl", requires = "dns_cache_ttl")]
+    /// Also cache domains not resolved for <DNS_NEGATIVE_TTL> seconds
+    dns_negative_ttl: Option<u64>,
+
+    #[arg(long = "spare-connections")]
+    /// Keep up to <SPARE_CONNECTIONS> spare connections to each recently connected destination
+    spare_connections: Option<usize>,
+
+    #[arg(long = "tcp-keepalive")]
+    /// Send keepalive probes on connections idle for <TCP_KEEPALIVE> seconds
+    tcp_keepalive: Option<u64>,
+
+    #[arg(long = "outbound-bind-addr")]
+    /// Connect to destinations from the local address <OUTBOUND_BIND_ADDR>
+    outbound_bind_addr: Option<IpAddr>,
+
+    #[arg(long = "outbound-bind-device")]
+    /// Connect to destinations through the network device <OUTBOUND_BIND_DEVICE> (Linux only)
+    outbound_bind_device: Option<String>,
+
+    #[arg(long = "socks4")]
+    /// Also accept SOCKS4/4a CONNECT requests (only without authentication)
+    socks4: bool,
+
+    #[arg(long = "lenient-version")]
+    /// Accept SOCKS5 messages with another protocol version from buggy clients
+    lenient_version: bool,
+
+    #[arg(long = "http-connect", conflicts_with_all = ["unix_socket", "userfile"])]
+    /// Also listen on <HTTP_CONNECT> (e.g. 0.0.0.0:8080) for HTTP CONNECT requests
+    http_connect: Option<SocketAddr>,
+
+    #[arg(
+        long = "forward",
+        value_name = "LISTEN=HOST:PORT",
+        conflicts_with = "unix_socket"
+    )]
+    /// Also forward connections to LISTEN (e.g. 0.0.0.0:8443=internal:443) to HOST:PORT if allowed by the rules (repeatable)
+    forward: Vec<gk::Forward>,
+
+    #[arg(long = "control-socket")]
+    /// Accept control commands (JSON lines) on the unix domain socket <CONTROL_SOCKET>
+    control_socket: Option<PathBuf>,
+
+    #[arg(long = "control-addr", conflicts_with = "control_socket")]
+    /// Accept control commands (JSON lines) on the loopback address <CONTROL_ADDR> (e.g. 127.0.0.1:1081)
+    control_addr: Option<SocketAddr>,
+
+    #[arg(long = "control-token-file")]
+    /// Require control connections to send the token in <CONTROL_TOKEN_FILE> first (required by --control-addr)
+    control_token_file: Option<PathBuf>,
+
+    #[arg(long = "user")]
+    /// Switch to the user <USER> (name or uid) after binding the listening sockets
+    user: Option<String>,
+
+    #[arg(long = "group")]
+    /// Switch to the group <GROUP> (name or gid) after binding the listening sockets, the primary group of --user by default
+    group: Option<String>,
+
+    #[arg(long = "sandbox")]
+    /// Restrict files and system calls available to gatekeeperd by landlock and seccomp (Linux, `sandbox` feature)
+    sandbox: bool,
+
+    #[arg(short = 'g', long = "grace")]
+    /// On SIGTERM, stop accepting connections and wait running sessions up to <GRACE> seconds
+    grace: Option<u64>,
+
+    #[arg(long = "max-sessions")]
+    /// Reject new clients while <MAX_SESSIONS> sessions are running
+    max_sessions: Option<usize>,
+
+    #[arg(long = "ban-threshold")]
+    /// Ban clients failing <BAN_THRESHOLD> times (e.g. denied by the rules or failing authentication) within --ban-window
+    ban_threshold: Option<usize>,
+
+    #[arg(long = "ban-window", default_value = "60", requires = "ban_threshold")]
+    /// Count failures of each client within <BAN_WINDOW> seconds
+    ban_window: u64,
+
+    #[arg(
+        long = "ban-duration",
+        default_value = "600",
+        requires = "ban_threshold"
+    )]
+    /// Close connections of banned clients for <BAN_DURATION> seconds
+    ban_duration: u64,
+
+    #[arg(long = "accept-queue")]
+    /// Keep up to <ACCEPT_QUEUE> accepted connections waiting for their sessions to start
+    accept_queue: Option<NonZeroUsize>,
+
+    #[arg(
+        long = "accept-overflow",
+        default_value = "block",
+        requires = "accept_queue"
+    )]
+    /// When the accept queue is full: `block` (stop accepting) or `reject` (reply failures to new connections)
+    accept_overflow: gk::AcceptOverflow,
+
+    #[arg(long = "connect-timeout")]
+    /// Give up connecting to a destination address after <CONNECT_TIMEOUT> seconds
+    connect_timeout: Option<u64>,
+
+    #[arg(long = "connect-retries", default_value = "0")]
+    /// Retry connecting to a destination refusing or timing out up to <CONNECT_RETRIES> times
+    connect_retries: u32,
+
+    #[arg(long = "retry-backoff", default_value = "100")]
+    /// Wait <RETRY_BACKOFF> milliseconds before the first retry, doubled for each subsequent one
+    retry_backoff: u64,
+
+    #[arg(long = "reply-addr", default_value = "server")]
+    /// Address replied to CONNECT requests: `server` (listening address), `local` (address connected from) or an address
+    reply_addr: gk::ReplyAddr,
+
+    #[arg(long = "handshake-timeout")]
+    /// Close connections of clients not sending a request within <HANDSHAKE_TIMEOUT> seconds
+    handshake_timeout: Option<u64>,
+
+    #[arg(long = "idle-timeout")]
+    /// Terminate sessions relaying no data for <IDLE_TIMEOUT> seconds
+    idle_timeout: Option<u64>,
+
+    #[arg(long = "max-session-duration")]
+    /// Terminate sessions after relaying for <MAX_SESSION_DURATION> seconds
+    max_session_duration: Option<u64>,
+
+    #[arg(long = "max-bytes-per-sec")]
+    /// Limit bandwidth of each session to <MAX_BYTES_PER_SEC> bytes/sec in each direction
+    max_bytes_per_sec: Option<u64>,
+
+    #[arg(long = "relay-buffer-size", default_value = "8192")]
+    /// Relay data of each direction through a buffer of <RELAY_BUFFER_SIZE> bytes
+    relay_buffer_size: NonZeroUsize,
+
+    #[arg(long = "global-max-bytes-per-sec")]
+    /// Limit bandwidth shared by all sessions to <GLOBAL_MAX_BYTES_PER_SEC> bytes/sec in each direction
+    global_max_bytes_per_sec: Option<u64>,
+}
+
+/// `ip:port` or `domain:port`
+fn parse_address(s: &str) -> Result<gk::Address, String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr.into());
+    }
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("{}: port is required", s))?;
+    let port = port.parse().map_err(|err| format!("{}: {}", s, err))?;
+    Ok(gk::Address::Domain(host.to_owned(), port))
+}
+
+/// print the evaluation of each rule
+fn explain(rule: &gk::ConnectRule, addr: &gk::Address) {
+    let protocol = gk::L4Protocol::Tcp;
+    for trace in rule.explain(addr, protocol) {
+        println!(
+            "#{} {}{}: {}{}",
+            trace.index,
+            if trace.allow { "Allow" } else { "Deny" },
+            trace
+                .name
+                .map(|name| format!(" ({})", name))
+                .unwrap_or_default(),
+            if trace.matched { "matched" } else { "-" },
+            if trace.decided { " <= decided" } else { "" },
+        );
+    }
+    let allowed = rule.check(addr.clone(), protocol);
+    println!(
+        "{}/{}: {}",
+        addr,
+        protocol,
+        if allowed { "allowed" } else { "denied" }
+    );
 }
 
 fn set_handler(signals: &[i32], handler: impl Fn(i32) + Send + 'static) -> io::Result<()> {
@@ -33,32 +279,277 @@ fn set_handler(signals: &[i32], handler: impl Fn(i32) + Send + 'static) -> io::R
     Ok(())
 }
 
+fn reload_rule<S>(path: &Path, tx: &mpsc::Sender<gk::ServerCommand<S>>) {
+    match gk::config::load_connect_rule(path) {
+        Ok(rule) => {
+            info!("reload rule: {}", path.display());
+            tx.send(gk::ServerCommand::ReloadRules(rule)).ok();
+        }
+        Err(err) => error!("reload rule error: {}: {}", path.display(), err),
+    }
+}
+
+/// directory containing `path` (the rule file may be replaced by a new file)
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// spawn a thread reloads the rule file when its modification time is changed
+fn watch_rule<S: Send + 'static>(
+    path: PathBuf,
+    interval: Duration,
+    tx: mpsc::Sender<gk::ServerCommand<S>>,
+) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let mut last = modified(&path);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let current = modified(&path);
+        if current.is_some() && current != last {
+            last = current;
+            reload_rule(&path, &tx);
+        }
+    });
+}
+
+/// options given on the command line and the configuration file
+fn options() -> Result<Opt, String> {
+    use clap::{CommandFactory, FromArgMatches};
+    let matches = Opt::command().get_matches();
+    let mut opt = Opt::from_arg_matches(&matches).map_err(|err| err.to_string())?;
+    if let Some(ref path) = opt.config {
+        daemon_config::DaemonConfig::load(path)?.merge_into(&mut opt, &matches);
+    }
+    Ok(opt)
+}
+
+fn server_config(opt: &Opt) -> Result<gk::ServerConfig, String> {
+    let mut config = match opt.rulefile {
+        Some(ref path) => gk::ServerConfig::with_file(opt.ipaddr, opt.port, path)
+            .map_err(|err| format!("{}: {}", path.display(), err))?,
+        None => gk::ServerConfig::new(opt.ipaddr, opt.port, gk::ConnectRule::any()),
+    };
+    if let Some(ref path) = opt.userfile {
+        let users = gk::config::load_credentials(path)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        config.set_credentials(Some(Arc::new(users)));
+    }
+    config.additional_addrs = opt.listen.clone();
+    config.set_allowed_methods((!opt.allow_method.is_empty()).then(|| opt.allow_method.clone()));
+    config
+        .set_max_sessions(opt.max_sessions)
+        .set_client_ban(
+            opt.ban_threshold
+                .map(|threshold| gk::client_ban::ClientBanConfig {
+                    threshold,
+                    window: Duration::from_secs(opt.ban_window),
+                    duration: Duration::from_secs(opt.ban_duration),
+                }),
+        )
+        .set_accept_queue_capacity(opt.accept_queue.map(NonZeroUsize::get))
+        .set_accept_overflow(opt.accept_overflow)
+        .set_check_resolved(opt.check_resolved)
+        .set_reject_private_resolved(opt.reject_private_resolved)
+        .set_rule_cache_capacity(opt.rule_cache.map(NonZeroUsize::get))
+        .set_inspect_sni(opt.inspect_sni)
+        .set_udp_reassembly_timeout(opt.udp_reassembly_timeout.map(Duration::from_secs))
+        .set_tcp_keepalive(
+            opt.tcp_keepalive
+                .map(|secs| gk::TcpKeepalive::new(Duration::from_secs(secs))),
+        )
+        .set_outbound_bind_addr(
+            (opt.outbound_bind_addr.is_some() || opt.outbound_bind_device.is_some()).then(|| {
+                gk::OutboundBind {
+                    addr: opt.outbound_bind_addr,
+                    device: opt.outbound_bind_device.clone(),
+                }
+            }),
+        )
+        .set_dns_cache(opt.dns_cache_ttl.map(|ttl| gk::dns_cache::DnsCacheConfig {
+            ttl_override: Some(Duration::from_secs(ttl)),
+            negative_ttl: opt.dns_negative_ttl.map(Duration::from_secs),
+            ..Default::default()
+        }))
+        .set_connection_pool(
+            opt.spare_connections
+                .map(|max| gk::conn_pool::ConnectionPoolConfig {
+                    max_idle_per_destination: max,
+                    ..Default::default()
+                }),
+        )
+        .set_accept_socks4(opt.socks4)
+        .set_lenient_version(opt.lenient_version)
+        .set_http_connect_addr(opt.http_connect)
+        .set_forwards(opt.forward.clone())
+        .set_connect_timeout(opt.connect_timeout.map(Duration::from_secs))
+        .set_connect_retries(opt.connect_retries)
+        .set_retry_backoff(Duration::from_millis(opt.retry_backoff))
+        .set_reply_addr(opt.reply_addr)
+        .set_handshake_timeout(opt.handshake_timeout.map(Duration::from_secs))
+        .set_idle_timeout(opt.idle_timeout.map(Duration::from_secs))
+        .set_max_session_duration(opt.max_session_duration.map(Duration::from_secs))
+        .set_rate_limit(opt.max_bytes_per_sec.map(gk::RateLimit::symmetric))
+        .set_relay_buffer_size(opt.relay_buffer_size.get())
+        .set_global_rate_limit(opt.global_max_bytes_per_sec.map(gk::RateLimit::symmetric));
+
+    config.set_unix_socket(opt.unix_socket.clone());
+    config.set_run_as(
+        (opt.user.is_some() || opt.group.is_some()).then(|| gk::RunAs {
+            user: opt.user.clone(),
+            group: opt.group.clone(),
+        }),
+    );
+    config.validate().map_err(|err| err.to_string())?;
+    // `bind(2)` is not allowed in the sandbox
+    if opt.sandbox && opt.outbound_bind_addr.is_some() {
+        return Err("--outbound-bind-addr is not available with --sandbox".to_owned());
+    }
+    Ok(config)
+}
+
 fn main() {
-    use signal_hook::consts::signal::*;
-    env_logger::init();
+    let opt = options().unwrap_or_else(|err| exit_with(&err));
+    let env = env_logger::Env::default();
+    match opt.log_level {
+        Some(level) => env_logger::Builder::from_env(env.default_filter_or(level.as_str())).init(),
+        None => env_logger::Builder::from_env(env).init(),
+    }
 
     println!("gatekeeperd");
-    use clap::Parser;
-    let opt = Opt::parse();
     debug!("option: {:?}", opt);
 
-    let config = match opt.rulefile {
-        Some(ref path) => gk::ServerConfig::with_file(opt.ipaddr, opt.port, path),
-        None => Ok(gk::ServerConfig::new(
-            opt.ipaddr,
-            opt.port,
-            gk::ConnectRule::any(),
-        )),
+    let config = server_config(&opt).unwrap_or_else(|err| exit_with(&err));
+    if let Some(ref addr) = opt.explain {
+        explain(&config.conn_rule, addr);
+        return;
     }
-    .expect("server config");
+    if opt.check_config {
+        println!("configuration is valid");
+        return;
+    }
+
+    // sockets passed by systemd socket activation
+    let listeners = gk::acceptor::listen_fds().unwrap_or_else(|err| exit_with(&err.to_string()));
+    if config.unix_socket.is_some() {
+        if !listeners.is_empty() {
+            exit_with("socket activation is not supported with --unix-socket");
+        }
+        let (server, tx) = gk::server::Server::with_unix_socket(config);
+        run(server, tx, opt);
+    } else if listeners.is_empty() {
+        let (server, tx) = gk::server::Server::new(config);
+        run(server, tx, opt);
+    } else {
+        info!("socket activation: {} sockets", listeners.len());
+        let (server, tx) = gk::server::Server::with_listeners(config, listeners)
+            .unwrap_or_else(|err| exit_with(&err.to_string()));
+        run(server, tx, opt);
+    }
+}
+
+/// `err` and its causes
+fn error_chain(err: &dyn failure::Fail) -> String {
+    err.iter_chain()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+fn exit_with(err: &str) -> ! {
+    eprintln!("gatekeeperd: {}", err);
+    process::exit(1)
+}
 
-    let (mut server, tx) = gk::server::Server::new(config);
-    set_handler(&[SIGTERM, SIGINT, SIGQUIT, SIGCHLD], move |_| {
-        tx.send(gk::ServerCommand::Terminate).ok();
+/// serve until terminated by a signal
+fn run<S, T, C>(
+    mut server: gk::server::Server<S, T, C>,
+    tx: mpsc::Sender<gk::ServerCommand<S>>,
+    opt: Opt,
+) where
+    S: gk::byte_stream::ByteStream + 'static,
+    T: gk::acceptor::Binder<Stream = S>,
+    C: gk::connector::Connector + Clone + 'static,
+{
+    use signal_hook::consts::signal::*;
+
+    let control_token = opt.control_token_file.as_ref().map(|path| {
+        let token = fs::read_to_string(path)
+            .unwrap_or_else(|err| exit_with(&format!("{}: {}", path.display(), err)));
+        token.trim().to_owned()
+    });
+    let mut control = gk::control::ControlServer::new(tx.clone());
+    control.set_rule_file(opt.rulefile.clone());
+    control.set_token(control_token);
+    let control_listener = match (&opt.control_socket, opt.control_addr) {
+        (Some(path), _) => Some(
+            control
+                .bind_unix(path)
+                .unwrap_or_else(|err| exit_with(&error_chain(&err))),
+        ),
+        (None, Some(addr)) => Some(
+            control
+                .bind_tcp(addr)
+                .unwrap_or_else(|err| exit_with(&error_chain(&err))),
+        ),
+        (None, None) => None,
+    };
+    // bind the listening sockets and switch to `--user` before restricting the process
+    if let Err(err) = server.bind() {
+        exit_with(&error_chain(&err));
+    }
+    // before spawning threads reading files, since landlock restricts only the calling thread
+    // and threads spawned by it
+    if opt.sandbox {
+        let rule_files = opt
+            .rulefile
+            .as_deref()
+            .map(|path| gk::config::connect_rule_files(path).map(|files| (path, files)))
+            .transpose()
+            .unwrap_or_else(|err| exit_with(&error_chain(&err)));
+        // the rule file may be replaced by a new file
+        let read_dirs: Vec<_> = rule_files
+            .iter()
+            .flat_map(|(path, files)| {
+                std::iter::once(parent_dir(path)).chain(files.iter().map(|file| parent_dir(file)))
+            })
+            .collect();
+        let socket_dirs: Vec<_> = [opt.unix_socket.as_deref(), opt.control_socket.as_deref()]
+            .into_iter()
+            .flatten()
+            .map(parent_dir)
+            .collect();
+        sandbox::restrict(&read_dirs, &socket_dirs).expect("sandbox");
+    }
+    if let Some(listener) = control_listener {
+        control.spawn(listener).expect("control");
+    }
+    if let Some(path) = opt.rulefile {
+        if let Some(secs) = opt.watch {
+            watch_rule(path.clone(), Duration::from_secs(secs.max(1)), tx.clone());
+        }
+        let tx = tx.clone();
+        set_handler(&[SIGHUP], move |_| reload_rule(&path, &tx)).expect("setting SIGHUP handler");
+    }
+    let grace = opt.grace.map(Duration::from_secs);
+    set_handler(&[SIGTERM, SIGINT, SIGQUIT, SIGCHLD], move |signal| {
+        let cmd = match grace {
+            Some(grace) if signal == SIGTERM => gk::ServerCommand::Shutdown { grace },
+            _ => gk::ServerCommand::Terminate,
+        };
+        tx.send(cmd).ok();
     })
     .expect("setting ctrl-c handler");
 
+    if let Err(err) = server.start() {
+        exit_with(&error_chain(&err));
+    }
     if let Err(err) = server.serve() {
         error!("server error: {:?}", err);
     }
+    if let Some(path) = opt.control_socket {
+        fs::remove_file(&path).ok();
+    }
 }
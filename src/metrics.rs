@@ -0,0 +1,255 @@
+//! Server metrics
+//!
+//! A snapshot is taken by `Server::metrics` or `ServerCommand::QueryMetrics`,
+//! and [`ServerStatus`] with running sessions by `Server::status` or `ServerCommand::QueryStatus`.
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::client_ban::BannedClient;
+use crate::model::{Error, ErrorKind};
+use crate::session::{SessionId, SessionInfo, SessionStats};
+
+/// Snapshot of server metrics
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// running sessions
+    pub sessions: HashMap<SessionId, SessionStats>,
+    /// number of sessions accepted since the server started
+    pub accepted: u64,
+    /// number of finished sessions rejected by authentication or connect rules,
+    /// and clients rejected by `ServerConfig::max_sessions`
+    pub rejected: u64,
+    /// number of finished sessions terminated with other errors
+    pub errored: u64,
+    /// bytes relayed from client to external network by finished sessions
+    pub finished_upload_bytes: u64,
+    /// bytes relayed from external network to client by finished sessions
+    pub finished_download_bytes: u64,
+    /// number of finished sessions
+    pub finished: u64,
+    /// total duration of finished sessions
+    pub finished_duration: Duration,
+    /// clients banned by `ServerConfig::client_ban`, in order of ip address
+    pub banned_clients: Vec<BannedClient>,
+}
+
+impl Metrics {
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// bytes relayed from client to external network by all sessions
+    pub fn upload_bytes(&self) -> u64 {
+        self.finished_upload_bytes + self.sessions.values().map(|s| s.upload_bytes).sum::<u64>()
+    }
+
+    /// bytes relayed from external network to client by all sessions
+    pub fn download_bytes(&self) -> u64 {
+        self.finished_download_bytes
+            + self
+                .sessions
+                .values()
+                .map(|s| s.download_bytes)
+                .sum::<u64>()
+    }
+}
+
+/// State of a server, rendered by `Display` for humans (e.g. a status command of a daemon)
+///
+/// ```text
+/// sessions: 2 running, 10 accepted, 1 rejected, 0 errored
+/// traffic: 2048 bytes up, 8192 bytes down
+/// SessionId(1) relaying 192.168.0.2:5000 -> example.com:443 12s up:1024 down:4096
+/// SessionId(7) handshaking 192.168.0.3:5000 -> - 0s up:0 down:0
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStatus {
+    pub metrics: Metrics,
+    /// running sessions in order of start time
+    pub sessions: Vec<SessionInfo>,
+}
+
+impl fmt::Display for ServerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let metrics = &self.metrics;
+        writeln!(
+            f,
+            "sessions: {} running, {} accepted, {} rejected, {} errored",
+            self.sessions.len(),
+            metrics.accepted,
+            metrics.rejected,
+            metrics.errored
+        )?;
+        write!(
+            f,
+            "traffic: {} bytes up, {} bytes down",
+            metrics.upload_bytes(),
+            metrics.download_bytes()
+        )?;
+        if !metrics.banned_clients.is_empty() {
+            let banned: Vec<_> = metrics
+                .banned_clients
+                .iter()
+                .map(|client| format!("{} ({}s)", client.ip, client.remaining.as_secs()))
+                .collect();
+            write!(f, "\nbanned: {}", banned.join(", "))?;
+        }
+        for session in &self.sessions {
+            write!(f, "\n{}", session)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cumulative counters held by the server
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Counters {
+    accepted: u64,
+    rejected: u64,
+    errored: u64,
+    upload_bytes: u64,
+    download_bytes: u64,
+    finished: u64,
+    duration: Duration,
+}
+
+/// Outcome of a finished session
+#[derive(Debug)]
+pub(crate) enum Outcome<'a> {
+    Success,
+    Error(&'a Error),
+    Panic,
+}
+
+impl Counters {
+    pub fn accept(&mut self) {
+        self.accepted += 1;
+    }
+
+    /// a client is rejected without starting a session
+    pub fn reject(&mut self) {
+        self.rejected += 1;
+    }
+
+    pub fn finish(&mut self, stats: &SessionStats, outcome: &Outcome) {
+        self.upload_bytes += stats.upload_bytes;
+        self.download_bytes += stats.download_bytes;
+        self.finished += 1;
+        self.duration += stats.duration;
+        match *outcome {
+            Outcome::Success => {}
+            Outcome::Error(err) if is_rejection(err) => self.rejected += 1,
+            Outcome::Error(_) | Outcome::Panic => self.errored += 1,
+        }
+    }
+
+    pub fn snapshot(&self, sessions: HashMap<SessionId, SessionStats>) -> Metrics {
+        Metrics {
+            sessions,
+            accepted: self.accepted,
+            rejected: self.rejected,
+            errored: self.errored,
+            finished_upload_bytes: self.upload_bytes,
+            finished_download_bytes: self.download_bytes,
+            finished: self.finished,
+            finished_duration: self.duration,
+            banned_clients: vec![],
+        }
+    }
+}
+
+fn is_rejection(err: &Error) -> bool {
+    use ErrorKind as K;
+    matches!(
+        err.kind(),
+        K::Authentication
+            | K::NoAcceptableMethod
+            | K::UnrecognizedUsernamePassword
+            | K::ConnectionNotAllowed { .. }
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Address, L4Protocol};
+    use crate::session::SessionState;
+    use std::time::SystemTime;
+
+    fn stats(upload_bytes: u64, download_bytes: u64) -> SessionStats {
+        SessionStats {
+            client_addr: "192.168.0.2:12345".parse().unwrap(),
+            upload_bytes,
+            download_bytes,
+            duration: Duration::from_secs(1),
+            started_at: SystemTime::UNIX_EPOCH,
+            dst_addr: None,
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let mut counters = Counters::default();
+        counters.accept();
+        counters.accept();
+        counters.accept();
+        counters.finish(&stats(10, 20), &Outcome::Success);
+        let not_allowed: Error = ErrorKind::connection_not_allowed(
+            "192.168.0.1:80".parse::<Address>().unwrap(),
+            L4Protocol::Tcp,
+        )
+        .into();
+        counters.finish(&stats(0, 0), &Outcome::Error(&not_allowed));
+
+        let running: HashMap<SessionId, SessionStats> =
+            vec![(3.into(), stats(1, 2))].into_iter().collect();
+        let metrics = counters.snapshot(running);
+        assert_eq!(metrics.active_sessions(), 1);
+        assert_eq!(metrics.accepted, 3);
+        assert_eq!(metrics.rejected, 1);
+        assert_eq!(metrics.errored, 0);
+        assert_eq!(metrics.finished, 2);
+        assert_eq!(metrics.finished_duration, Duration::from_secs(2));
+        assert_eq!(metrics.upload_bytes(), 11);
+        assert_eq!(metrics.download_bytes(), 22);
+    }
+
+    #[test]
+    fn render_status() {
+        let mut counters = Counters::default();
+        counters.accept();
+        counters.accept();
+        let relaying = SessionStats {
+            dst_addr: Some(Address::Domain("example.com".to_owned(), 443)),
+            ..stats(10, 20)
+        };
+        let sessions = vec![
+            SessionInfo {
+                id: 1.into(),
+                state: SessionState::Relaying,
+                stats: relaying.clone(),
+            },
+            SessionInfo {
+                id: 2.into(),
+                state: SessionState::Handshaking,
+                stats: stats(0, 0),
+            },
+        ];
+        let status = ServerStatus {
+            metrics: counters.snapshot(
+                vec![(1.into(), relaying), (2.into(), stats(0, 0))]
+                    .into_iter()
+                    .collect(),
+            ),
+            sessions,
+        };
+        assert_eq!(
+            status.to_string(),
+            "sessions: 2 running, 2 accepted, 0 rejected, 0 errored\n\
+             traffic: 10 bytes up, 20 bytes down\n\
+             SessionId(1) relaying 192.168.0.2:12345 -> example.com:443 1s up:10 down:20\n\
+             SessionId(2) handshaking 192.168.0.2:12345 -> - 1s up:0 down:0"
+        );
+    }
+}
@@ -12,4 +12,6 @@ pub trait SocksStream {
     fn send_method_selection(&mut self, method: MethodSelection) -> Result<(), Error>;
     fn recv_connect_request(&mut self) -> Result<ConnectRequest, Error>;
     fn send_connect_reply(&mut self, reply: ConnectReply) -> Result<(), Error>;
+    fn recv_user_pass_request(&mut self) -> Result<UserPassRequest, Error>;
+    fn send_user_pass_reply(&mut self, reply: UserPassReply) -> Result<(), Error>;
 }
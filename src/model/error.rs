@@ -1,18 +1,24 @@
 #![allow(non_local_definitions)]
 use std::fmt;
 use std::fmt::Display;
+use std::io;
 use std::sync;
 
 use failure::{Backtrace, Context, Fail};
 
 use crate::model::*;
+use crate::session::SessionId;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 #[derive(Fail, Debug, Clone, PartialEq, Eq)]
 pub enum ErrorKind {
-    #[fail(display = "io error")]
-    Io,
+    /// `kind` and `message` of the underlying `io::Error`, which is also the cause
+    #[fail(display = "io error: {}", message)]
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
     #[fail(display = "poisoned error: {}", _0)]
     Poisoned(String),
     #[fail(display = "disconnected channel error: {}", name)]
@@ -27,6 +33,9 @@ pub enum ErrorKind {
     UnrecognizedUsernamePassword,
     #[fail(display = "command not supported: {:?}", cmd)]
     CommandNotSupported { cmd: Command },
+    /// ATYP of a request is unknown, or its address is malformed (e.g. an empty domain)
+    #[fail(display = "address type not supported: {}", message)]
+    AddrTypeNotSupported { message: String },
     #[fail(display = "host unreachable: {}:{}", host, port)]
     HostUnreachable { host: String, port: u16 },
     #[fail(display = "name not resolved: {}:{}", domain, port)]
@@ -38,14 +47,35 @@ pub enum ErrorKind {
     #[fail(display = "address not available: {}", addr)]
     AddressNotAvailable { addr: SocketAddr },
     /// rejected by gatekeeper
+    ///
+    /// `rule` is the name of the rule denied the connection, if it is named.
+    /// `reply` is sent to the client instead of `ConnectionNotAllowed`, if it is specified.
     #[fail(display = "connection not allowed: {}: {}", addr, protocol)]
-    ConnectionNotAllowed { addr: Address, protocol: L4Protocol },
+    ConnectionNotAllowed {
+        addr: Address,
+        protocol: L4Protocol,
+        rule: Option<String>,
+        reply: Option<ConnectError>,
+    },
     /// rejected by external server
     #[fail(display = "connection refused: {}: {}", addr, protocol)]
     ConnectionRefused { addr: Address, protocol: L4Protocol },
+    /// external server did not respond in time
+    #[fail(display = "connection timed out: {}: {}", addr, protocol)]
+    ConnectionTimedOut { addr: Address, protocol: L4Protocol },
+    /// client did not send its request in time
+    #[fail(display = "handshake timed out")]
+    HandshakeTimedOut,
 }
 
 impl ErrorKind {
+    pub fn io(err: &io::Error) -> Self {
+        ErrorKind::Io {
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+
     pub fn disconnected<S: Into<String>>(name: S) -> Self {
         ErrorKind::Disconnected { name: name.into() }
     }
@@ -60,18 +90,65 @@ impl ErrorKind {
         ErrorKind::CommandNotSupported { cmd }
     }
 
+    pub fn addr_type_not_supported(message: fmt::Arguments) -> Self {
+        ErrorKind::AddrTypeNotSupported {
+            message: message.to_string(),
+        }
+    }
+
     pub fn connection_not_allowed(addr: Address, protocol: L4Protocol) -> Self {
-        ErrorKind::ConnectionNotAllowed { addr, protocol }
+        ErrorKind::ConnectionNotAllowed {
+            addr,
+            protocol,
+            rule: None,
+            reply: None,
+        }
+    }
+
+    /// denied by the rule named `rule`
+    pub fn not_allowed_by(addr: Address, protocol: L4Protocol, rule: Option<String>) -> Self {
+        ErrorKind::ConnectionNotAllowed {
+            addr,
+            protocol,
+            rule,
+            reply: None,
+        }
     }
 
     pub fn connection_refused(addr: Address, protocol: L4Protocol) -> Self {
         ErrorKind::ConnectionRefused { addr, protocol }
     }
+
+    pub fn connection_timed_out(addr: Address, protocol: L4Protocol) -> Self {
+        ErrorKind::ConnectionTimedOut { addr, protocol }
+    }
+}
+
+/// Session an error has been surfaced from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionContext {
+    pub session_id: SessionId,
+    /// address of the client
+    pub client_addr: SocketAddr,
+    /// destination requested by the client (`None` if not requested yet)
+    pub dst_addr: Option<Address>,
+}
+
+impl Display for SessionContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.session_id, self.client_addr)?;
+        if let Some(dst_addr) = &self.dst_addr {
+            write!(f, " -> {}", dst_addr)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct Error {
     inner: Context<ErrorKind>,
+    /// set when the error is taken from a `SessionHandle`
+    session: Option<Box<SessionContext>>,
 }
 
 impl Fail for Error {
@@ -86,24 +163,49 @@ impl Fail for Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(session) = &self.session {
+            write!(f, "{}: ", session)?;
+        }
         Display::fmt(&self.inner, f)
     }
 }
 
 impl Error {
     pub fn new(inner: Context<ErrorKind>) -> Error {
-        Error { inner }
+        Error {
+            inner,
+            session: None,
+        }
     }
 
     pub fn kind(&self) -> &ErrorKind {
         self.inner.get_context()
     }
 
+    /// session the error has been surfaced from
+    pub fn session(&self) -> Option<&SessionContext> {
+        self.session.as_deref()
+    }
+
+    pub(crate) fn in_session(self, session: SessionContext) -> Self {
+        Error {
+            session: Some(Box::new(session)),
+            ..self
+        }
+    }
+
     pub fn cerr(&self) -> ConnectError {
         use ConnectError as CErr;
         use ErrorKind as K;
         match self.kind() {
-            K::Io => CErr::ServerFailure,
+            K::Io { kind, .. } => match kind {
+                io::ErrorKind::ConnectionRefused => CErr::ConnectionRefused,
+                io::ErrorKind::TimedOut => CErr::TtlExpired,
+                io::ErrorKind::HostUnreachable => CErr::HostUnreachable,
+                io::ErrorKind::NetworkUnreachable => CErr::NetworkUnreachable,
+                io::ErrorKind::PermissionDenied => CErr::ConnectionNotAllowed,
+                _ => CErr::ServerFailure,
+            },
             K::Poisoned(_) => CErr::ServerFailure,
             K::Disconnected { .. } => CErr::ServerFailure,
             K::MessageFormat { .. } => CErr::ServerFailure,
@@ -111,36 +213,38 @@ impl Error {
             K::NoAcceptableMethod => CErr::ConnectionNotAllowed,
             K::UnrecognizedUsernamePassword => CErr::ConnectionNotAllowed,
             K::CommandNotSupported { .. } => CErr::CommandNotSupported,
+            K::AddrTypeNotSupported { .. } => CErr::AddrTypeNotSupported,
             K::HostUnreachable { .. } => CErr::HostUnreachable,
             K::DomainNotResolved { .. } => CErr::NetworkUnreachable,
             K::PacketSizeLimitExceeded { .. } => CErr::ServerFailure,
             K::AddressAlreadInUse { .. } => CErr::ServerFailure,
             K::AddressNotAvailable { .. } => CErr::ServerFailure,
-            K::ConnectionNotAllowed { .. } => CErr::ConnectionNotAllowed,
+            K::ConnectionNotAllowed { reply, .. } => {
+                reply.clone().unwrap_or(CErr::ConnectionNotAllowed)
+            }
             K::ConnectionRefused { .. } => CErr::ConnectionRefused,
+            K::ConnectionTimedOut { .. } => CErr::TtlExpired,
+            K::HandshakeTimedOut => CErr::TtlExpired,
         }
     }
 }
 
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Error {
-        Error {
-            inner: Context::new(kind),
-        }
+        Error::new(Context::new(kind))
     }
 }
 
 impl From<Context<ErrorKind>> for Error {
     fn from(inner: Context<ErrorKind>) -> Error {
-        Error { inner }
+        Error::new(inner)
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(error: std::io::Error) -> Self {
-        Error {
-            inner: error.context(ErrorKind::Io),
-        }
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        let kind = ErrorKind::io(&error);
+        Error::new(error.context(kind))
     }
 }
 
@@ -149,3 +253,39 @@ impl<T: fmt::Debug> From<sync::PoisonError<T>> for Error {
         ErrorKind::Poisoned(format!("{:?}", error)).into()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn io_error() {
+        // EMFILE on unix
+        let err: Error = io::Error::from_raw_os_error(24).into();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::Io { message, .. } if message.ends_with("(os error 24)")
+        ));
+        assert!(err.to_string().starts_with("io error: "));
+        assert_eq!(err.cerr(), ConnectError::ServerFailure);
+
+        let cerr = |kind| Error::from(io::Error::from(kind)).cerr();
+        assert_eq!(
+            cerr(io::ErrorKind::ConnectionRefused),
+            ConnectError::ConnectionRefused
+        );
+        assert_eq!(cerr(io::ErrorKind::TimedOut), ConnectError::TtlExpired);
+        assert_eq!(
+            cerr(io::ErrorKind::NetworkUnreachable),
+            ConnectError::NetworkUnreachable
+        );
+        assert_eq!(
+            cerr(io::ErrorKind::PermissionDenied),
+            ConnectError::ConnectionNotAllowed
+        );
+        assert_eq!(
+            cerr(io::ErrorKind::UnexpectedEof),
+            ConnectError::ServerFailure
+        );
+    }
+}
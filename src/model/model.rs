@@ -34,6 +34,7 @@ use std::fmt;
 use std::net::ToSocketAddrs;
 pub use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::str::FromStr;
+use std::sync::Arc;
 
 use derive_more::{Display, From, Into};
 use failure::Fail;
@@ -41,8 +42,15 @@ use log::*;
 use regex::{escape, Regex};
 use serde::*;
 
+use crate::geoip::GeoIpProvider;
+use crate::rule_cache::DecisionCache;
+
 pub const DEFAULT_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion(5);
 
+/// Version of the username/password sub-negotiation
+/// See https://tools.ietf.org/html/rfc1929
+pub const USER_PASS_VERSION: u8 = 1;
+
 // Domain labels can include letter, digit and hyphen
 // See https://tools.ietf.org/html/rfc1035#section-2.3.1
 static AVAILABLE_STRINGS_FOR_DOMAIN_LABEL: &str = r"[A-Za-z0-9-]{1,63}";
@@ -67,6 +75,19 @@ pub enum Method {
     NoMethods,
 }
 
+/// `no-auth`, `gssapi` or `user-pass`
+impl FromStr for Method {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no-auth" => Ok(Method::NoAuth),
+            "gssapi" => Ok(Method::GssApi),
+            "user-pass" => Ok(Method::UserPass),
+            s => Err(format!("{}: expected no-auth, gssapi or user-pass", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MethodCandidates {
     pub version: ProtocolVersion,
@@ -88,7 +109,43 @@ pub struct MethodSelection {
     pub method: Method,
 }
 
+/// Username/Password authentication request (RFC1929)
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserPassRequest {
+    pub version: u8,
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for UserPassRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never dump the password into logs
+        f.debug_struct("UserPassRequest")
+            .field("version", &self.version)
+            .field("username", &self.username)
+            .field("password", &"********")
+            .finish()
+    }
+}
+
+impl UserPassRequest {
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> Self {
+        Self {
+            version: USER_PASS_VERSION,
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+/// Username/Password authentication reply (RFC1929)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserPassReply {
+    pub version: u8,
+    pub success: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub enum Command {
     Connect,
     Bind,
@@ -100,6 +157,11 @@ pub enum Command {
 pub enum Address {
     IpAddr(IpAddr, u16),
     Domain(String, u16),
+    /// ipv6 address with a scope id or flow info (e.g. a link-local address of an interface)
+    ///
+    /// Converted from a `SocketAddrV6` only if it has either of them, and sent to clients as
+    /// `IpAddr` since SOCKS messages can not carry them.
+    ScopedV6(SocketAddrV6),
 }
 
 impl fmt::Display for Address {
@@ -108,6 +170,7 @@ impl fmt::Display for Address {
         match self {
             IpAddr(addr, port) => write!(f, "{}:{}", addr, port),
             Domain(host, port) => write!(f, "{}:{}", host, port),
+            ScopedV6(addr) => write!(f, "{}", addr),
         }
     }
 }
@@ -117,13 +180,35 @@ impl Address {
         match self {
             Address::IpAddr(_, port) => *port,
             Address::Domain(_, port) => *port,
+            Address::ScopedV6(addr) => addr.port(),
+        }
+    }
+
+    /// ip address without the scope (`None` for domains)
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Address::IpAddr(addr, _) => Some(*addr),
+            Address::Domain(..) => None,
+            Address::ScopedV6(addr) => Some((*addr.ip()).into()),
+        }
+    }
+
+    /// socket address keeping the scope (`None` for domains)
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Address::IpAddr(addr, port) => Some(SocketAddr::new(*addr, *port)),
+            Address::Domain(..) => None,
+            Address::ScopedV6(addr) => Some((*addr).into()),
         }
     }
 }
 
 impl From<SocketAddr> for Address {
     fn from(addr: SocketAddr) -> Self {
-        Address::IpAddr(addr.ip(), addr.port())
+        match addr {
+            SocketAddr::V4(addr) => addr.into(),
+            SocketAddr::V6(addr) => addr.into(),
+        }
     }
 }
 
@@ -135,7 +220,11 @@ impl From<SocketAddrV4> for Address {
 
 impl From<SocketAddrV6> for Address {
     fn from(addr: SocketAddrV6) -> Self {
-        Address::IpAddr((*addr.ip()).into(), addr.port())
+        if addr.scope_id() == 0 && addr.flowinfo() == 0 {
+            Address::IpAddr((*addr.ip()).into(), addr.port())
+        } else {
+            Address::ScopedV6(addr)
+        }
     }
 }
 
@@ -152,10 +241,13 @@ impl ToSocketAddrs for Address {
 
     /// Convert an address and AddrType to a SocketAddr
     fn to_socket_addrs(&self) -> std::io::Result<Self::Iter> {
-        use Address::*;
         match self {
-            IpAddr(ipaddr, port) => Ok(vec![SocketAddr::new(*ipaddr, *port)].into_iter()),
-            Domain(domain, port) => Ok((domain.as_str(), *port).to_socket_addrs()?),
+            Address::Domain(domain, port) => Ok((domain.as_str(), *port).to_socket_addrs()?),
+            addr => Ok(addr
+                .socket_addr()
+                .into_iter()
+                .collect::<Vec<_>>()
+                .into_iter()),
         }
     }
 }
@@ -202,7 +294,7 @@ impl ConnectRequest {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Display, Serialize, Deserialize)]
 pub enum ConnectError {
     /// general server failure
     ServerFailure,
@@ -264,6 +356,16 @@ pub enum AddressPattern {
         prefix: u8,
     },
     Domain(DomainPattern),
+    /// ip addresses located in the country (ISO 3166-1 alpha-2 code, e.g. `JP`)
+    ///
+    /// This matches only if a `GeoIpProvider` is set to the rule.
+    Country {
+        iso_code: String,
+    },
+    /// matches if any of the patterns matches
+    ///
+    /// In a rule file, `Group: <name>` is resolved into the list of the group.
+    List(Vec<AddressPattern>),
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -273,11 +375,29 @@ pub enum DomainPattern {
         #[serde(with = "serde_regex")]
         pattern: Regex,
     },
+    /// `*` matches a label, e.g. `*.example.com` (constructed by `DomainPattern::wildcard`)
     Wildcard {
         wildcard: String,
+        /// `wildcard` compiled for matching
+        #[serde(skip)]
+        regex: Regex,
     },
 }
 
+impl DomainPattern {
+    /// Pattern of domains matching `wildcard`
+    ///
+    /// Returns an error if `wildcard` is too large to compile.
+    pub fn wildcard<S: Into<String>>(wildcard: S) -> Result<Self, regex::Error> {
+        let wildcard = wildcard.into();
+        let regex = Regex::new(&format!(
+            r"\A{}\z",
+            &escape(&wildcard).replace(r"\*", AVAILABLE_STRINGS_FOR_DOMAIN_LABEL)
+        ))?;
+        Ok(DomainPattern::Wildcard { wildcard, regex })
+    }
+}
+
 #[derive(Fail, Debug)]
 pub enum InvalidPrefix {
     V4 { addr: Ipv4Addr, prefix: u8 },
@@ -340,6 +460,40 @@ impl AddressPattern {
     }
 }
 
+impl AddressPattern {
+    /// Match `addr` locating ip addresses by `geoip`
+    pub fn match_geoip(&self, addr: &Address, geoip: Option<&dyn GeoIpProvider>) -> bool {
+        match (self, addr, geoip) {
+            (AddressPattern::Country { iso_code }, _, Some(geoip)) => addr
+                .ip()
+                .and_then(|ip| geoip.country(ip))
+                .is_some_and(|country| country.eq_ignore_ascii_case(iso_code)),
+            (AddressPattern::List(pats), _, _) => {
+                pats.iter().any(|pat| pat.match_geoip(addr, geoip))
+            }
+            _ => self.r#match(addr),
+        }
+    }
+
+    /// Whether this has a pattern of ip addresses (including countries)
+    pub fn has_ip_pattern(&self) -> bool {
+        match self {
+            AddressPattern::IpAddr { .. } | AddressPattern::Country { .. } => true,
+            AddressPattern::Domain(_) => false,
+            AddressPattern::List(pats) => pats.iter().any(AddressPattern::has_ip_pattern),
+        }
+    }
+
+    /// Whether this has a pattern of domains
+    pub fn has_domain_pattern(&self) -> bool {
+        match self {
+            AddressPattern::Domain(_) => true,
+            AddressPattern::IpAddr { .. } | AddressPattern::Country { .. } => false,
+            AddressPattern::List(pats) => pats.iter().any(AddressPattern::has_domain_pattern),
+        }
+    }
+}
+
 impl From<Regex> for AddressPattern {
     fn from(reg: Regex) -> Self {
         AddressPattern::Domain(DomainPattern::Regex { pattern: reg })
@@ -352,6 +506,16 @@ impl Matcher for AddressPattern {
     fn r#match(&self, addr: &Self::Item) -> bool {
         use AddressPattern as P;
         use DomainPattern as DP;
+        if let Address::ScopedV6(scoped) = addr {
+            // patterns do not care the scope
+            return self.r#match(&Address::IpAddr((*scoped.ip()).into(), scoped.port()));
+        }
+        if let Address::IpAddr(IpAddr::V6(v6), port) = addr {
+            // IPv4 addresses mapped by dual-stack sockets (`::ffff:a.b.c.d`) are matched as IPv4
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return self.r#match(&Address::IpAddr(v4.into(), *port));
+            }
+        }
         match (self, addr) {
             (
                 P::IpAddr {
@@ -376,14 +540,10 @@ impl Matcher for AddressPattern {
             (P::Domain(DP::Regex { pattern }), Address::Domain(domain, _)) => {
                 pattern.is_match(domain)
             }
-            (P::Domain(DP::Wildcard { wildcard }), Address::Domain(domain, _)) => {
-                let pattern = format!(
-                    r"\A{}\z",
-                    &escape(wildcard).replace(r"\*", AVAILABLE_STRINGS_FOR_DOMAIN_LABEL)
-                );
-                let reg = Regex::new(&pattern).unwrap();
-                reg.is_match(domain)
+            (P::Domain(DP::Wildcard { regex, .. }), Address::Domain(domain, _)) => {
+                regex.is_match(domain)
             }
+            (P::List(pats), _) => pats.iter().any(|pat| pat.r#match(addr)),
 
             _ => false,
         }
@@ -395,6 +555,85 @@ pub trait Matcher {
     fn r#match(&self, t: &Self::Item) -> bool;
 }
 
+/// Pattern of port numbers
+///
+/// In yaml, a port is written as a number (`80`), a range as a string (`1024-65535`),
+/// and a list as a sequence of them (`[80, 443, 8000-8099]`).
+/// Nested sequences are flattened, and an empty sequence is an error as it matches no ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortPattern {
+    Port(u16),
+    /// inclusive range
+    Range(u16, u16),
+    /// matches if any of the patterns matches
+    List(Vec<PortPattern>),
+}
+
+impl PortPattern {
+    /// Ports from `start` to `end` inclusive
+    ///
+    /// Returns `None` if `start > end`.
+    pub fn range(start: u16, end: u16) -> Option<Self> {
+        if start <= end {
+            Some(PortPattern::Range(start, end))
+        } else {
+            None
+        }
+    }
+}
+
+impl From<u16> for PortPattern {
+    fn from(port: u16) -> Self {
+        PortPattern::Port(port)
+    }
+}
+
+impl fmt::Display for PortPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortPattern::Port(port) => write!(f, "{}", port),
+            PortPattern::Range(start, end) => write!(f, "{}-{}", start, end),
+            PortPattern::List(pats) => {
+                let pats: Vec<_> = pats.iter().map(|pat| pat.to_string()).collect();
+                write!(f, "[{}]", pats.join(", "))
+            }
+        }
+    }
+}
+
+impl FromStr for PortPattern {
+    type Err = String;
+
+    /// Parse a port (`80`) or a range (`1024-65535`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|err| format!("invalid port: {}: {}", s, err))
+        };
+        match s.split_once('-') {
+            None => parse(s).map(PortPattern::Port),
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                PortPattern::range(start, end)
+                    .ok_or_else(|| format!("invalid port range: {}-{}", start, end))
+            }
+        }
+    }
+}
+
+impl Matcher for PortPattern {
+    type Item = u16;
+
+    fn r#match(&self, port: &Self::Item) -> bool {
+        match self {
+            PortPattern::Port(p) => p == port,
+            PortPattern::Range(start, end) => start <= port && port <= end,
+            PortPattern::List(pats) => pats.iter().any(|pat| pat.r#match(port)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum RulePattern<P> {
     Any,
@@ -402,6 +641,10 @@ pub enum RulePattern<P> {
 }
 
 impl<P> RulePattern<P> {
+    pub fn any() -> Self {
+        RulePattern::Any
+    }
+
     pub fn is_any(&self) -> bool {
         matches!(self, RulePattern::Any)
     }
@@ -435,18 +678,102 @@ where
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectRulePattern {
+    /// name of the rule reported in audit logs and errors. (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// free text describing the rule. (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// reply to requests denied by the rule instead of `ConnectionNotAllowed`. (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply: Option<ConnectError>,
+    /// logging of requests decided by the rule. (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<RuleLog>,
+    /// local address or device of connections allowed by the rule
+    /// instead of `ServerConfig::outbound_bind_addr`. (optional)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind: Option<OutboundBind>,
+    /// maximum number of sessions connected through the rule at the same time. (optional)
+    ///
+    /// Requests allowed by the rule beyond it are denied while the sessions are running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+    /// address of the client. missing in yaml is treated as `Any`.
+    #[serde(
+        default = "RulePattern::any",
+        skip_serializing_if = "RulePattern::is_any"
+    )]
+    pub source: RulePattern<AddressPattern>,
+    /// user authenticated by the client. missing in yaml is treated as `Any`.
+    #[serde(
+        default = "RulePattern::any",
+        skip_serializing_if = "RulePattern::is_any"
+    )]
+    pub user: RulePattern<String>,
     pub address: RulePattern<AddressPattern>,
-    pub port: RulePattern<u16>,
+    pub port: RulePattern<PortPattern>,
     pub protocol: RulePattern<L4Protocol>,
 }
 
 impl ConnectRulePattern {
     pub fn new(
         address: RulePattern<AddressPattern>,
-        port: RulePattern<u16>,
+        port: RulePattern<PortPattern>,
+        protocol: RulePattern<L4Protocol>,
+    ) -> Self {
+        ConnectRulePattern {
+            name: None,
+            description: None,
+            reply: None,
+            log: None,
+            bind: None,
+            max_sessions: None,
+            source: RulePattern::Any,
+            user: RulePattern::Any,
+            address,
+            port,
+            protocol,
+        }
+    }
+
+    pub fn with_source(
+        source: RulePattern<AddressPattern>,
+        address: RulePattern<AddressPattern>,
+        port: RulePattern<PortPattern>,
         protocol: RulePattern<L4Protocol>,
     ) -> Self {
         ConnectRulePattern {
+            name: None,
+            description: None,
+            reply: None,
+            log: None,
+            bind: None,
+            max_sessions: None,
+            source,
+            user: RulePattern::Any,
+            address,
+            port,
+            protocol,
+        }
+    }
+
+    /// pattern applied to clients authenticated as `user`
+    pub fn with_user(
+        user: RulePattern<String>,
+        address: RulePattern<AddressPattern>,
+        port: RulePattern<PortPattern>,
+        protocol: RulePattern<L4Protocol>,
+    ) -> Self {
+        ConnectRulePattern {
+            name: None,
+            description: None,
+            reply: None,
+            log: None,
+            bind: None,
+            max_sessions: None,
+            source: RulePattern::Any,
+            user,
             address,
             port,
             protocol,
@@ -455,28 +782,218 @@ impl ConnectRulePattern {
 
     pub fn any() -> Self {
         Self {
+            name: None,
+            description: None,
+            reply: None,
+            log: None,
+            bind: None,
+            max_sessions: None,
+            source: RulePattern::Any,
+            user: RulePattern::Any,
             address: RulePattern::Any,
             port: RulePattern::Any,
             protocol: RulePattern::Any,
         }
     }
 
+    /// set the name of the rule
+    pub fn named<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// reply `reply` to requests denied by the rule
+    pub fn reply_with(mut self, reply: ConnectError) -> Self {
+        self.reply = Some(reply);
+        self
+    }
+
+    /// log requests decided by the rule as `log`
+    pub fn logged(mut self, log: RuleLog) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// connect from `bind` if allowed by the rule
+    pub fn bound_to(mut self, bind: OutboundBind) -> Self {
+        self.bind = Some(bind);
+        self
+    }
+
+    /// allow up to `max` sessions connected through the rule at the same time
+    pub fn limited_to(mut self, max: usize) -> Self {
+        self.max_sessions = Some(max);
+        self
+    }
+
+    /// `name`, `description`, `reply`, `log`, `bind` and `max_sessions` are not concerned
     pub fn is_any(&self) -> bool {
         let Self {
+            name: _,
+            description: _,
+            reply: _,
+            log: _,
+            bind: _,
+            max_sessions: _,
+            ref source,
+            ref user,
             ref address,
             ref port,
             ref protocol,
         } = self;
-        address.is_any() && port.is_any() && protocol.is_any()
+        source.is_any() && user.is_any() && address.is_any() && port.is_any() && protocol.is_any()
+    }
+
+    /// `src` is the address of the client.
+    /// If it is unknown (`None`), patterns with `Specif` source never match.
+    pub fn r#match(&self, src: Option<&Address>, addr: &Address, protocol: L4Protocol) -> bool {
+        self.match_user(src, None, addr, protocol)
+    }
+
+    /// `user` is the name authenticated by the client.
+    /// If it is not authenticated (`None`), patterns with `Specif` user never match.
+    pub fn match_user(
+        &self,
+        src: Option<&Address>,
+        user: Option<&str>,
+        addr: &Address,
+        protocol: L4Protocol,
+    ) -> bool {
+        self.match_geoip(src, user, addr, protocol, None)
     }
 
-    pub fn r#match(&self, addr: &Address, protocol: L4Protocol) -> bool {
-        self.address.r#match(addr)
-            && self.port.any_or(addr.port())
+    /// `Country` address patterns are matched by `geoip`
+    pub fn match_geoip(
+        &self,
+        src: Option<&Address>,
+        user: Option<&str>,
+        addr: &Address,
+        protocol: L4Protocol,
+        geoip: Option<&dyn GeoIpProvider>,
+    ) -> bool {
+        let source = match (&self.source, src) {
+            (RulePattern::Any, _) => true,
+            (RulePattern::Specif(pat), Some(src)) => pat.match_geoip(src, geoip),
+            (RulePattern::Specif(_), None) => false,
+        };
+        let address = match &self.address {
+            RulePattern::Any => true,
+            RulePattern::Specif(pat) => pat.match_geoip(addr, geoip),
+        };
+        let user = match (&self.user, user) {
+            (RulePattern::Any, _) => true,
+            (RulePattern::Specif(pat), Some(user)) => pat == user,
+            (RulePattern::Specif(_), None) => false,
+        };
+        source
+            && user
+            && address
+            && self.port.r#match(&addr.port())
             && self.protocol.any_or(protocol)
     }
 }
 
+/// Logging of requests decided by a rule
+///
+/// ```yaml
+/// # log 1% of requests allowed by the rule at debug level
+/// - Allow:
+///     address: Any
+///     port: Any
+///     protocol: Any
+///     log:
+///       level: debug
+///       sample: 0.01
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleLog {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`. `info` by default
+    #[serde(default = "RuleLog::default_level")]
+    pub level: LevelFilter,
+    /// ratio of requests logged, from `0.0` to `1.0` (default)
+    #[serde(default = "RuleLog::default_sample")]
+    pub sample: f64,
+}
+
+impl RuleLog {
+    pub fn new(level: LevelFilter) -> Self {
+        Self {
+            level,
+            sample: Self::default_sample(),
+        }
+    }
+
+    /// log `sample` of requests
+    pub fn sampled(self, sample: f64) -> Self {
+        Self { sample, ..self }
+    }
+
+    fn default_level() -> LevelFilter {
+        LevelFilter::Info
+    }
+
+    fn default_sample() -> f64 {
+        1.0
+    }
+
+    /// Level to log a request, which is `Off` if the request is not sampled
+    pub fn level(&self) -> LevelFilter {
+        if self.sample >= 1.0 || rand::random::<f64>() < self.sample {
+            self.level
+        } else {
+            LevelFilter::Off
+        }
+    }
+}
+
+/// Local address or network device outbound connections are bound to
+///
+/// On multi-homed hosts, this selects the uplink connections to destinations go through.
+/// Binding to a device (`SO_BINDTODEVICE`) is supported only on Linux.
+///
+/// ```yaml
+/// # connect to the destinations through eth1
+/// - Allow:
+///     address:
+///       Specif:
+///         IpAddr:
+///           addr: 10.0.0.0
+///           prefix: 8
+///     port: Any
+///     protocol: Any
+///     bind:
+///       device: eth1
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundBind {
+    /// local address of connections, which only connect to destinations of the same family
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addr: Option<IpAddr>,
+    /// name of the network device connections go through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+impl OutboundBind {
+    /// bind to the local address `addr`
+    pub fn from_addr(addr: IpAddr) -> Self {
+        Self {
+            addr: Some(addr),
+            device: None,
+        }
+    }
+
+    /// bind to the network device named `device`
+    pub fn from_device<S: Into<String>>(device: S) -> Self {
+        Self {
+            addr: None,
+            device: Some(device.into()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ConnectRuleEntry {
     Allow(ConnectRulePattern),
@@ -484,6 +1001,10 @@ pub enum ConnectRuleEntry {
 }
 
 impl ConnectRuleEntry {
+    pub fn is_allow(&self) -> bool {
+        matches!(self, ConnectRuleEntry::Allow(_))
+    }
+
     pub fn sum<R>(&self, f: impl FnOnce(&ConnectRulePattern) -> R) -> R {
         use ConnectRuleEntry::*;
         match self {
@@ -491,6 +1012,57 @@ impl ConnectRuleEntry {
             Deny(pat) => f(pat),
         }
     }
+
+    /// name of the rule if specified
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            ConnectRuleEntry::Allow(pat) | ConnectRuleEntry::Deny(pat) => pat.name.as_deref(),
+        }
+    }
+
+    /// logging of requests decided by the rule if specified
+    pub fn log(&self) -> Option<&RuleLog> {
+        match self {
+            ConnectRuleEntry::Allow(pat) | ConnectRuleEntry::Deny(pat) => pat.log.as_ref(),
+        }
+    }
+
+    /// local address or device of connections allowed by the rule if specified
+    pub fn bind(&self) -> Option<&OutboundBind> {
+        match self {
+            ConnectRuleEntry::Allow(pat) => pat.bind.as_ref(),
+            ConnectRuleEntry::Deny(_) => None,
+        }
+    }
+
+    /// maximum number of sessions connected through the rule if specified
+    pub fn max_sessions(&self) -> Option<usize> {
+        match self {
+            ConnectRuleEntry::Allow(pat) => pat.max_sessions,
+            ConnectRuleEntry::Deny(_) => None,
+        }
+    }
+
+    /// reply to requests denied by the rule if specified
+    pub fn reply(&self) -> Option<&ConnectError> {
+        match self {
+            ConnectRuleEntry::Allow(_) => None,
+            ConnectRuleEntry::Deny(pat) => pat.reply.as_ref(),
+        }
+    }
+}
+
+/// Evaluation of a rule entry by `ConnectRule::explain`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleMatchTrace {
+    /// index of the rule in the order of addition (`0` is the rule of `any` or `none`)
+    pub index: usize,
+    pub name: Option<String>,
+    pub allow: bool,
+    /// the pattern of the rule matches the connection
+    pub matched: bool,
+    /// the rule decides the connection, i.e. the last matched one
+    pub decided: bool,
 }
 
 /// Connection rules
@@ -511,7 +1083,7 @@ impl ConnectRuleEntry {
 ///         addr: "192.168.0.1".parse()?,
 ///         prefix: 16,
 ///     }),
-///     Specif(80),
+///     Specif(80.into()),
 ///     Any,
 /// );
 /// assert!(rule.check("192.168.0.2:80".parse()?, Tcp));
@@ -524,17 +1096,24 @@ impl ConnectRuleEntry {
 pub struct ConnectRule {
     // rules.len() >= 1
     rules: Vec<ConnectRuleEntry>,
+    /// locates ip addresses for `AddressPattern::Country`
+    geoip: Option<Arc<dyn GeoIpProvider>>,
+    /// rules decided recent connections
+    cache: Option<DecisionCache>,
 }
 
 mod format {
     use super::*;
     use de::Unexpected;
+    use std::collections::BTreeMap;
 
     // dummy type for acquires derived deserializer
     #[derive(Debug, Clone, Deserialize)]
     enum AddressPatternDef {
         IpAddr { addr: IpAddr, prefix: u8 },
         Domain(DomainPatternDef),
+        Country { iso_code: String },
+        List(Vec<AddressPattern>),
     }
 
     #[derive(Debug, Clone, Deserialize)]
@@ -564,10 +1143,78 @@ mod format {
                 Domain(Regex { pattern }) => {
                     Ok(AddressPattern::Domain(DomainPattern::Regex { pattern }))
                 }
-                Domain(Wildcard { wildcard }) => {
-                    Ok(AddressPattern::Domain(DomainPattern::Wildcard { wildcard }))
+                Domain(Wildcard { wildcard }) => DomainPattern::wildcard(wildcard)
+                    .map(AddressPattern::Domain)
+                    .map_err(de::Error::custom),
+                Country { iso_code } => Ok(AddressPattern::Country { iso_code }),
+                List(pats) => Ok(AddressPattern::List(pats)),
+            }
+        }
+    }
+
+    impl Serialize for PortPattern {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match self {
+                PortPattern::Port(port) => serializer.serialize_u16(*port),
+                PortPattern::Range(..) => serializer.collect_str(self),
+                PortPattern::List(pats) => serializer.collect_seq(pats),
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for PortPattern {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            use serde::de::{SeqAccess, Visitor};
+            struct PortPatternVisitor;
+
+            impl<'de> Visitor<'de> for PortPatternVisitor {
+                type Value = PortPattern;
+
+                fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                    formatter.write_str("a port number, a range of ports or a sequence of them")
+                }
+
+                fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                    u16::try_from(v)
+                        .map(PortPattern::Port)
+                        .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+                }
+
+                fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                    u16::try_from(v)
+                        .map(PortPattern::Port)
+                        .map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+                }
+
+                fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                    v.parse().map_err(E::custom)
+                }
+
+                fn visit_seq<S>(self, mut seq: S) -> Result<Self::Value, S::Error>
+                where
+                    S: SeqAccess<'de>,
+                {
+                    let mut pats = vec![];
+                    while let Some(pat) = seq.next_element()? {
+                        match pat {
+                            PortPattern::List(nested) => pats.extend(nested),
+                            pat => pats.push(pat),
+                        }
+                    }
+                    if pats.is_empty() {
+                        return Err(de::Error::invalid_length(0, &self));
+                    }
+                    Ok(PortPattern::List(pats))
                 }
             }
+
+            deserializer.deserialize_any(PortPatternVisitor)
         }
     }
 
@@ -575,6 +1222,54 @@ mod format {
     mod tests {
         use super::*;
 
+        #[test]
+        fn deserialize_port_pat() {
+            use PortPattern::*;
+            let pat: PortPattern = serde_yaml::from_str("8080").unwrap();
+            assert_eq!(pat, Port(8080));
+            let pat: PortPattern = serde_yaml::from_str("1024-65535").unwrap();
+            assert_eq!(pat, Range(1024, 65535));
+            let pat: PortPattern = serde_yaml::from_str("[80, 443, 8000-8099]").unwrap();
+            assert_eq!(pat, List(vec![Port(80), Port(443), Range(8000, 8099)]));
+            let yaml = serde_yaml::to_string(&pat).unwrap();
+            assert_eq!(serde_yaml::from_str::<PortPattern>(&yaml).unwrap(), pat);
+
+            assert!(serde_yaml::from_str::<PortPattern>("65536").is_err());
+            assert!(serde_yaml::from_str::<PortPattern>("2000-1000").is_err());
+            assert!(serde_yaml::from_str::<PortPattern>("http").is_err());
+            assert!(serde_yaml::from_str::<PortPattern>("[]").is_err());
+            assert!(serde_yaml::from_str::<PortPattern>("[80, []]").is_err());
+        }
+
+        #[test]
+        fn deserialize_port_list() {
+            use PortPattern::*;
+            let pat: PortPattern = serde_yaml::from_str("[[80, 443], 8443]").unwrap();
+            assert_eq!(pat, List(vec![Port(80), Port(443), Port(8443)]));
+
+            let yaml = r#"
+- Deny:
+    address: Any
+    port: Any
+    protocol: Any
+- Allow:
+    address: Any
+    port: { Specif: [80, 443, 8443] }
+    protocol: Any
+"#;
+            let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+            for port in [80, 443, 8443] {
+                let dst = Address::IpAddr("192.0.2.1".parse().unwrap(), port);
+                assert!(rule.check(dst, L4Protocol::Tcp));
+            }
+            let dst = Address::IpAddr("192.0.2.1".parse().unwrap(), 8080);
+            assert!(!rule.check(dst, L4Protocol::Tcp));
+            // serialized as the list again
+            let yaml = serde_yaml::to_string(&rule).unwrap();
+            let reloaded: ConnectRule = serde_yaml::from_str(&yaml).unwrap();
+            assert_eq!(serde_yaml::to_string(&reloaded).unwrap(), yaml);
+        }
+
         #[test]
         fn deserialize_addr_pat() {
             let ipv4 = r#"
@@ -594,6 +1289,24 @@ IpAddr:
             );
         }
 
+        #[test]
+        fn deserialize_wildcard_pat() {
+            let pat: AddressPattern =
+                serde_yaml::from_str("Domain:\n  wildcard: \"*.example.com\"").unwrap();
+            let www = Address::Domain("www.example.com".to_owned(), 443);
+            assert!(pat.r#match(&www));
+            assert!(!pat.r#match(&Address::Domain("example.com".to_owned(), 443)));
+            let yaml = serde_yaml::to_string(&pat).unwrap();
+            assert!(!yaml.contains("regex"));
+            assert!(serde_yaml::from_str::<AddressPattern>(&yaml)
+                .unwrap()
+                .r#match(&www));
+
+            // too large to compile
+            let yaml = format!("Domain:\n  wildcard: \"{}\"", "*".repeat(100_000));
+            assert!(serde_yaml::from_str::<AddressPattern>(&yaml).is_err());
+        }
+
         #[test]
         fn deserialize_addr_large_prefix() {
             let ipv4_invalid = r#"
@@ -624,14 +1337,31 @@ IpAddr:
     where
         D: Deserializer<'de>,
     {
-        use serde::de::{SeqAccess, Visitor};
+        use serde::de::{MapAccess, SeqAccess, Visitor};
         struct ConnectRuleVisitor;
 
         impl<'de> Visitor<'de> for ConnectRuleVisitor {
             type Value = Vec<ConnectRuleEntry>;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("a nonempty sequence of ConnectRules")
+                formatter.write_str("a nonempty sequence of ConnectRules, or a mapping with rules")
+            }
+
+            fn visit_map<M>(self, map: M) -> Result<Self::Value, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let def = RuleSetDef::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                if !def.include.is_empty() {
+                    return Err(de::Error::custom(
+                        "include is resolved only by loading a rule file",
+                    ));
+                }
+                let mut rules = def.rules;
+                resolve_groups(&mut rules, &def.groups, &mut vec![]).map_err(de::Error::custom)?;
+                ConnectRule::deserialize(rules)
+                    .map(|rule| rule.rules)
+                    .map_err(de::Error::custom)
             }
 
             fn visit_seq<S>(self, mut seq: S) -> Result<Self::Value, S::Error>
@@ -657,8 +1387,78 @@ IpAddr:
         }
 
         deserializer
-            .deserialize_seq(ConnectRuleVisitor)
-            .map(|rules| ConnectRule { rules })
+            .deserialize_any(ConnectRuleVisitor)
+            .map(|rules| ConnectRule {
+                rules,
+                geoip: None,
+                cache: None,
+            })
+    }
+
+    /// Rule file defining address groups
+    ///
+    /// ```yaml
+    /// groups:
+    ///   internal:
+    ///     - IpAddr: { addr: 10.0.0.0, prefix: 8 }
+    ///     - Domain: { wildcard: "*.corp.example" }
+    /// rules:
+    ///   - Allow: { address: Any, port: Any, protocol: Any }
+    ///   - Deny: { address: { Specif: { Group: internal } }, port: Any, protocol: Any }
+    /// ```
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct RuleSetDef {
+        #[serde(default)]
+        groups: BTreeMap<String, Vec<serde_yaml::Value>>,
+        /// rule files merged by `config::load_connect_rule`
+        #[serde(default)]
+        include: Vec<serde_yaml::Value>,
+        rules: serde_yaml::Value,
+    }
+
+    /// Replace `Group: <name>` in `value` with `List` of the patterns of the group
+    ///
+    /// `stack` holds groups being resolved to detect circular references.
+    fn resolve_groups(
+        value: &mut serde_yaml::Value,
+        groups: &BTreeMap<String, Vec<serde_yaml::Value>>,
+        stack: &mut Vec<String>,
+    ) -> Result<(), String> {
+        use serde_yaml::Value;
+        match value {
+            Value::Mapping(map) => {
+                let group = match map.get(&Value::from("Group")) {
+                    Some(Value::String(name)) if map.len() == 1 => name.clone(),
+                    _ => {
+                        for (_, v) in map.iter_mut() {
+                            resolve_groups(v, groups, stack)?;
+                        }
+                        return Ok(());
+                    }
+                };
+                if stack.contains(&group) {
+                    return Err(format!("circular reference of group: {}", group));
+                }
+                let mut pats = groups
+                    .get(&group)
+                    .ok_or_else(|| format!("undefined group: {}", group))?
+                    .clone();
+                stack.push(group);
+                for pat in &mut pats {
+                    resolve_groups(pat, groups, stack)?;
+                }
+                stack.pop();
+                let mut list = serde_yaml::Mapping::new();
+                list.insert(Value::from("List"), Value::Sequence(pats));
+                *value = Value::Mapping(list);
+                Ok(())
+            }
+            Value::Sequence(seq) => seq
+                .iter_mut()
+                .try_for_each(|v| resolve_groups(v, groups, stack)),
+            _ => Ok(()),
+        }
     }
 
     impl<'de> Deserialize<'de> for ConnectRule {
@@ -676,6 +1476,8 @@ impl ConnectRule {
     pub fn any() -> Self {
         ConnectRule {
             rules: vec![ConnectRuleEntry::Allow(ConnectRulePattern::any())],
+            geoip: None,
+            cache: None,
         }
     }
 
@@ -683,6 +1485,8 @@ impl ConnectRule {
     pub fn none() -> Self {
         ConnectRule {
             rules: vec![ConnectRuleEntry::Deny(ConnectRulePattern::any())],
+            geoip: None,
+            cache: None,
         }
     }
 
@@ -701,46 +1505,303 @@ impl ConnectRule {
     pub fn allow(
         &mut self,
         addr: RulePattern<AddressPattern>,
-        port: RulePattern<u16>,
+        port: RulePattern<PortPattern>,
         protocol: RulePattern<L4Protocol>,
     ) {
-        self.rules
-            .push(ConnectRuleEntry::Allow(ConnectRulePattern::new(
-                addr, port, protocol,
-            )));
+        self.push(ConnectRuleEntry::Allow(ConnectRulePattern::new(
+            addr, port, protocol,
+        )));
     }
 
     pub fn deny(
         &mut self,
         addr: RulePattern<AddressPattern>,
-        port: RulePattern<u16>,
+        port: RulePattern<PortPattern>,
         protocol: RulePattern<L4Protocol>,
     ) {
-        self.rules
-            .push(ConnectRuleEntry::Deny(ConnectRulePattern::new(
-                addr, port, protocol,
-            )));
+        self.push(ConnectRuleEntry::Deny(ConnectRulePattern::new(
+            addr, port, protocol,
+        )));
+    }
+
+    /// allow patterns from clients matching `source`
+    pub fn allow_from(
+        &mut self,
+        source: RulePattern<AddressPattern>,
+        addr: RulePattern<AddressPattern>,
+        port: RulePattern<PortPattern>,
+        protocol: RulePattern<L4Protocol>,
+    ) {
+        self.push(ConnectRuleEntry::Allow(ConnectRulePattern::with_source(
+            source, addr, port, protocol,
+        )));
+    }
+
+    /// deny patterns from clients matching `source`
+    pub fn deny_from(
+        &mut self,
+        source: RulePattern<AddressPattern>,
+        addr: RulePattern<AddressPattern>,
+        port: RulePattern<PortPattern>,
+        protocol: RulePattern<L4Protocol>,
+    ) {
+        self.push(ConnectRuleEntry::Deny(ConnectRulePattern::with_source(
+            source, addr, port, protocol,
+        )));
+    }
+
+    /// allow patterns for clients authenticated as `user`
+    pub fn allow_user(
+        &mut self,
+        user: RulePattern<String>,
+        addr: RulePattern<AddressPattern>,
+        port: RulePattern<PortPattern>,
+        protocol: RulePattern<L4Protocol>,
+    ) {
+        self.push(ConnectRuleEntry::Allow(ConnectRulePattern::with_user(
+            user, addr, port, protocol,
+        )));
     }
 
+    /// deny patterns for clients authenticated as `user`
+    pub fn deny_user(
+        &mut self,
+        user: RulePattern<String>,
+        addr: RulePattern<AddressPattern>,
+        port: RulePattern<PortPattern>,
+        protocol: RulePattern<L4Protocol>,
+    ) {
+        self.push(ConnectRuleEntry::Deny(ConnectRulePattern::with_user(
+            user, addr, port, protocol,
+        )));
+    }
+
+    /// Set the provider locating ip addresses for `AddressPattern::Country`
+    pub fn set_geoip_provider(&mut self, geoip: Option<Arc<dyn GeoIpProvider>>) {
+        self.geoip = geoip;
+        self.reset_cache();
+    }
+
+    /// Cache rules deciding up to `capacity` recent connections (`None` not to cache)
+    ///
+    /// Connections are cached by the destination and the protocol,
+    /// and also by the client ip address and the user if any rule has a pattern of them.
+    /// Clones of the rule start with an empty cache, so share the rule (e.g. by `Arc`)
+    /// to share the cache.
+    pub fn set_decision_cache(&mut self, capacity: Option<usize>) {
+        self.cache = capacity.map(|capacity| DecisionCache::new(capacity, &self.rules));
+    }
+
+    /// add a rule prior to the rules already added
+    pub fn push(&mut self, entry: ConnectRuleEntry) {
+        self.rules.push(entry);
+        self.reset_cache();
+    }
+
+    /// forget cached decisions made by the previous rules
+    fn reset_cache(&mut self) {
+        let capacity = self.cache.as_ref().map(DecisionCache::capacity);
+        self.set_decision_cache(capacity);
+    }
+
+    /// Check the connection regardless of the client
+    ///
+    /// Rules with a specific `source` or `user` are ignored.
     pub fn check(&self, addr: Address, protocol: L4Protocol) -> bool {
-        use ConnectRuleEntry::*;
-        for rule in self.rules.iter().rev() {
-            match rule {
-                Allow(pat) => {
-                    if pat.r#match(&addr, protocol) {
-                        trace!("match(allow): {:?}: {}/{}", pat, addr, protocol);
-                        return true;
-                    }
-                }
-                Deny(pat) => {
-                    if pat.r#match(&addr, protocol) {
-                        trace!("match(deny): {:?}: {}/{}", pat, addr, protocol);
-                        return false;
-                    }
-                }
-            }
+        self.check_rules(None, None, addr, protocol)
+    }
+
+    /// Check the connection from the client `src`
+    ///
+    /// Rules with a specific `user` are ignored.
+    pub fn check_from(&self, src: SocketAddr, addr: Address, protocol: L4Protocol) -> bool {
+        self.check_rules(Some(&src.into()), None, addr, protocol)
+    }
+
+    /// Check the connection from the client `src` authenticated as `user`
+    pub fn check_user(
+        &self,
+        src: SocketAddr,
+        user: Option<&str>,
+        addr: Address,
+        protocol: L4Protocol,
+    ) -> bool {
+        self.check_rules(Some(&src.into()), user, addr, protocol)
+    }
+
+    /// The rule decides the connection from the client `src`
+    ///
+    /// Returns the index of the rule in the order of addition (`0` is the rule of `any` or `none`).
+    pub fn matched_from(
+        &self,
+        src: SocketAddr,
+        addr: &Address,
+        protocol: L4Protocol,
+    ) -> (usize, &ConnectRuleEntry) {
+        self.find_rule(Some(&src.into()), None, addr, protocol)
+    }
+
+    /// The rule decides the connection from the client `src` authenticated as `user`
+    pub fn matched_user(
+        &self,
+        src: SocketAddr,
+        user: Option<&str>,
+        addr: &Address,
+        protocol: L4Protocol,
+    ) -> (usize, &ConnectRuleEntry) {
+        self.find_rule(Some(&src.into()), user, addr, protocol)
+    }
+
+    /// How each rule is applied to the connection regardless of the client
+    ///
+    /// Rules are returned in the order of addition.
+    /// As later rules take precedence, the last matched one decides the connection.
+    /// Rules with a specific `source` or `user` never match like `check`.
+    pub fn explain(&self, addr: &Address, protocol: L4Protocol) -> Vec<RuleMatchTrace> {
+        let (decided, _) = self.find_rule(None, None, addr, protocol);
+        self.rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| RuleMatchTrace {
+                index,
+                name: rule.name().map(str::to_owned),
+                allow: rule.is_allow(),
+                matched: rule
+                    .sum(|pat| pat.match_geoip(None, None, addr, protocol, self.geoip.as_deref())),
+                decided: index == decided,
+            })
+            .collect()
+    }
+
+    /// Check an address resolved from the domain requested by the client `src`
+    ///
+    /// Only rules with a specific ip address (or country) pattern are applied,
+    /// and the address is allowed if none of them matches.
+    pub fn check_resolved(
+        &self,
+        src: SocketAddr,
+        user: Option<&str>,
+        addr: SocketAddr,
+        protocol: L4Protocol,
+    ) -> bool {
+        self.matched_resolved(src, user, addr, protocol)
+            .is_none_or(|(_, rule)| rule.is_allow())
+    }
+
+    /// The last rule with a specific ip address (or country) pattern matching a resolved address
+    ///
+    /// `None` means the address is allowed implicitly by `check_resolved`.
+    pub fn matched_resolved(
+        &self,
+        src: SocketAddr,
+        user: Option<&str>,
+        addr: SocketAddr,
+        protocol: L4Protocol,
+    ) -> Option<(usize, &ConnectRuleEntry)> {
+        self.find_specific(
+            src,
+            user,
+            &addr.into(),
+            protocol,
+            AddressPattern::has_ip_pattern,
+        )
+    }
+
+    /// Check the server name the client `src` sent to an ip address (e.g. SNI of TLS)
+    ///
+    /// Only rules with a specific domain pattern are applied,
+    /// and the name is allowed if none of them matches.
+    pub fn check_server_name(
+        &self,
+        src: SocketAddr,
+        user: Option<&str>,
+        name: &str,
+        port: u16,
+        protocol: L4Protocol,
+    ) -> bool {
+        let addr = Address::Domain(name.to_owned(), port);
+        self.find_specific(
+            src,
+            user,
+            &addr,
+            protocol,
+            AddressPattern::has_domain_pattern,
+        )
+        .is_none_or(|(_, rule)| rule.is_allow())
+    }
+
+    /// The last rule matching `addr` among rules with a specific address pattern satisfying `specific`
+    fn find_specific(
+        &self,
+        src: SocketAddr,
+        user: Option<&str>,
+        addr: &Address,
+        protocol: L4Protocol,
+        specific: impl Fn(&AddressPattern) -> bool,
+    ) -> Option<(usize, &ConnectRuleEntry)> {
+        let src = src.into();
+        self.rules
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, rule)| {
+                rule.sum(|pat| matches!(&pat.address, RulePattern::Specif(pat) if specific(pat)))
+            })
+            .find(|(_, rule)| {
+                rule.sum(|pat| {
+                    pat.match_geoip(Some(&src), user, addr, protocol, self.geoip.as_deref())
+                })
+            })
+    }
+
+    fn find_rule(
+        &self,
+        src: Option<&Address>,
+        user: Option<&str>,
+        addr: &Address,
+        protocol: L4Protocol,
+    ) -> (usize, &ConnectRuleEntry) {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.match_rule(src, user, addr, protocol),
+        };
+        let key = cache.key(src, user, addr, protocol);
+        if let Some(index) = cache.get(&key) {
+            return (index, &self.rules[index]);
         }
-        unreachable!("ConnectRule::allow")
+        let (index, rule) = self.match_rule(src, user, addr, protocol);
+        cache.insert(key, index);
+        (index, rule)
+    }
+
+    /// the last rule matching the connection
+    fn match_rule(
+        &self,
+        src: Option<&Address>,
+        user: Option<&str>,
+        addr: &Address,
+        protocol: L4Protocol,
+    ) -> (usize, &ConnectRuleEntry) {
+        self.rules
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, rule)| {
+                rule.sum(|pat| pat.match_geoip(src, user, addr, protocol, self.geoip.as_deref()))
+            })
+            .expect("ConnectRule::allow")
+    }
+
+    fn check_rules(
+        &self,
+        src: Option<&Address>,
+        user: Option<&str>,
+        addr: Address,
+        protocol: L4Protocol,
+    ) -> bool {
+        let (_, rule) = self.find_rule(src, user, &addr, protocol);
+        trace!("match: {:?}: {}/{}", rule, addr, protocol);
+        rule.is_allow()
     }
 }
 
@@ -835,10 +1896,10 @@ mod test {
         for case in cases {
             let mut rule = ConnectRule::none();
             rule.allow(
-                Specif(AddressPattern::Domain(DomainPattern::Wildcard {
-                    wildcard: case.wildcard,
-                })),
-                Specif(443),
+                Specif(AddressPattern::Domain(
+                    DomainPattern::wildcard(case.wildcard).unwrap(),
+                )),
+                Specif(443.into()),
                 Specif(Tcp),
             );
             for domain in case.match_domains {
@@ -858,12 +1919,12 @@ mod test {
         let mut rule = ConnectRule::none();
         rule.allow(
             Specif(Pat::addr("192.168.0.1".parse().unwrap(), 24).unwrap()),
-            Specif(80),
+            Specif(80.into()),
             Any,
         );
         rule.allow(
             Specif(Pat::addr("192.168.0.1".parse().unwrap(), 24).unwrap()),
-            Specif(443),
+            Specif(443.into()),
             Any,
         );
         assert!(!rule.check("0.0.0.0:80".parse().unwrap(), Tcp));
@@ -895,6 +1956,38 @@ mod test {
             SocketAddrV6::new(Ipv6Addr::new(0xffff, 0, 0, 0, 0, 0, 0, 0x1), 80, 0, 0).into(),
             Tcp
         ));
+        // the scope is not matched
+        assert!(rule.check(
+            SocketAddrV6::new(Ipv6Addr::new(0xff01, 0, 0, 0, 0, 0, 0, 0x1), 80, 0, 2).into(),
+            Tcp
+        ));
+    }
+
+    #[test]
+    fn scoped_address() {
+        let scoped: Address = "[fe80::1%2]:80".parse().unwrap();
+        let v6 = SocketAddrV6::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1), 80, 0, 2);
+        assert_eq!(scoped, Address::ScopedV6(v6));
+        assert_eq!(scoped.to_string(), "[fe80::1%2]:80");
+        assert_eq!(scoped.port(), 80);
+        assert_eq!(scoped.ip(), Some("fe80::1".parse().unwrap()));
+        assert_eq!(
+            scoped.to_socket_addrs().unwrap().collect::<Vec<_>>(),
+            [SocketAddr::V6(v6)]
+        );
+        // flow info is also kept
+        let flow = SocketAddrV6::new(*v6.ip(), 80, 1, 0);
+        assert_eq!(Address::from(flow).socket_addr(), Some(flow.into()));
+
+        // addresses without either are not scoped
+        let unscoped: Address = "[fe80::1]:80".parse().unwrap();
+        assert_eq!(unscoped, Address::IpAddr("fe80::1".parse().unwrap(), 80));
+        assert_eq!(unscoped.ip(), scoped.ip());
+        assert_eq!(
+            Address::from(SocketAddrV6::new(*v6.ip(), 80, 0, 0)),
+            unscoped
+        );
+        assert_eq!(Address::Domain("example.com".into(), 80).ip(), None);
     }
 
     #[test]
@@ -904,12 +1997,12 @@ mod test {
         let mut rule = ConnectRule::none();
         rule.allow(
             Specif(Pat::addr("192.168.0.1".parse().unwrap(), 16).unwrap()),
-            Specif(80),
+            Specif(80.into()),
             Any,
         );
         rule.allow(
             Specif(Pat::addr("192.168.0.1".parse().unwrap(), 16).unwrap()),
-            Specif(443),
+            Specif(443.into()),
             Any,
         );
         rule.allow(
@@ -918,9 +2011,9 @@ mod test {
             Specif(L4Protocol::Tcp),
         );
         rule.allow(
-            Specif(Pat::Domain(DomainPattern::Wildcard {
-                wildcard: "*.actcast.io".to_owned(),
-            })),
+            Specif(Pat::Domain(
+                DomainPattern::wildcard("*.actcast.io".to_owned()).unwrap(),
+            )),
             Any,
             Specif(L4Protocol::Tcp),
         );
@@ -953,4 +2046,564 @@ mod test {
         println!("value2: {}", serde_yaml::to_string(&value).unwrap());
         assert_eq!(&value, &value2);
     }
+
+    #[test]
+    fn source_pattern() {
+        use RulePattern::*;
+        let mut rule = ConnectRule::none();
+        rule.allow_from(
+            Specif(AddressPattern::addr("10.0.0.0".parse().unwrap(), 8).unwrap()),
+            Any,
+            Any,
+            Any,
+        );
+        let dst: Address = "192.168.0.1:80".parse().unwrap();
+        assert!(rule.check_from("10.1.2.3:5000".parse().unwrap(), dst.clone(), Tcp));
+        assert!(!rule.check_from("11.1.2.3:5000".parse().unwrap(), dst.clone(), Tcp));
+        // unknown source never matches
+        assert!(!rule.check(dst, Tcp));
+    }
+
+    #[test]
+    fn v4_mapped_source() {
+        use RulePattern::*;
+        let mut rule = ConnectRule::any();
+        rule.deny_from(
+            Specif(AddressPattern::addr("10.0.0.0".parse().unwrap(), 8).unwrap()),
+            Any,
+            Any,
+            Any,
+        );
+        rule.deny(
+            Specif(AddressPattern::addr("192.168.0.0".parse().unwrap(), 16).unwrap()),
+            Any,
+            Any,
+        );
+        let dst: Address = "172.16.0.1:80".parse().unwrap();
+        // clients of a dual-stack listener
+        assert!(!rule.check_from("[::ffff:10.1.2.3]:5000".parse().unwrap(), dst.clone(), Tcp));
+        assert!(rule.check_from("[::ffff:11.1.2.3]:5000".parse().unwrap(), dst, Tcp));
+        // and destinations
+        assert!(!rule.check("[::ffff:192.168.0.1]:80".parse().unwrap(), Tcp));
+    }
+
+    #[test]
+    fn source_and_domain() {
+        // only 10.0.0.0/8 clients may reach *.internal.example.com
+        let yaml = r#"
+---
+- Allow:
+    address: Any
+    port: Any
+    protocol: Any
+- Deny:
+    address:
+      Specif:
+        Domain:
+          wildcard: '*.internal.example.com'
+    port: Any
+    protocol: Any
+- Allow:
+    source:
+      Specif:
+        IpAddr:
+          addr: 10.0.0.0
+          prefix: 8
+    address:
+      Specif:
+        Domain:
+          wildcard: '*.internal.example.com'
+    port: Any
+    protocol: Any
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let internal = Address::Domain("db.internal.example.com".to_owned(), 5432);
+        let public = Address::Domain("www.example.com".to_owned(), 443);
+        let inside = "10.1.2.3:5000".parse().unwrap();
+        let outside = "192.168.0.2:5000".parse().unwrap();
+        assert!(rule.check_from(inside, internal.clone(), Tcp));
+        assert!(!rule.check_from(outside, internal.clone(), Tcp));
+        assert!(rule.check_from(inside, public.clone(), Tcp));
+        assert!(rule.check_from(outside, public, Tcp));
+        // the source is unknown
+        assert!(!rule.check(internal, Tcp));
+    }
+
+    #[test]
+    fn user_rule() {
+        // only alice may reach the internal network
+        let yaml = r#"
+---
+- Allow:
+    address: Any
+    port: Any
+    protocol: Any
+- Deny:
+    address:
+      Specif:
+        IpAddr:
+          addr: 10.0.0.0
+          prefix: 8
+    port: Any
+    protocol: Any
+- Allow:
+    user:
+      Specif: alice
+    address:
+      Specif:
+        IpAddr:
+          addr: 10.0.0.0
+          prefix: 8
+    port: Any
+    protocol: Any
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let src = "192.168.0.2:5000".parse().unwrap();
+        let internal: Address = "10.1.2.3:22".parse().unwrap();
+        assert!(rule.check_user(src, Some("alice"), internal.clone(), Tcp));
+        assert!(!rule.check_user(src, Some("bob"), internal.clone(), Tcp));
+        assert!(rule.check_user(src, Some("bob"), "8.8.8.8:53".parse().unwrap(), Udp));
+        // not authenticated
+        assert!(!rule.check_user(src, None, internal.clone(), Tcp));
+        assert!(!rule.check_from(src, internal.clone(), Tcp));
+        assert_eq!(rule.matched_user(src, Some("alice"), &internal, Tcp).0, 2);
+
+        let yaml = serde_yaml::to_string(&rule).unwrap();
+        let rule: ConnectRule = serde_yaml::from_str(&yaml).unwrap();
+        assert!(rule.check_user(src, Some("alice"), internal, Tcp));
+    }
+
+    #[test]
+    fn check_resolved() {
+        // deny private networks, but allow domains not matched by the rule
+        let yaml = r#"
+---
+- Deny:
+    address: Any
+    port: Any
+    protocol: Any
+- Allow:
+    address:
+      Specif:
+        Domain:
+          wildcard: '*.example.com'
+    port: Any
+    protocol: Any
+- Deny:
+    address:
+      Specif:
+        IpAddr:
+          addr: 192.168.0.0
+          prefix: 16
+    port: Any
+    protocol: Any
+- Allow:
+    address:
+      Specif:
+        IpAddr:
+          addr: 192.168.1.1
+          prefix: 32
+    port:
+      Specif: 443
+    protocol: Any
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let src = "10.0.0.1:5000".parse().unwrap();
+        assert!(rule.check_resolved(src, None, "93.184.216.34:80".parse().unwrap(), Tcp));
+        assert!(!rule.check_resolved(src, None, "192.168.0.1:80".parse().unwrap(), Tcp));
+        assert!(rule.check_resolved(src, None, "192.168.1.1:443".parse().unwrap(), Tcp));
+        assert!(!rule.check_resolved(src, None, "192.168.1.1:80".parse().unwrap(), Tcp));
+    }
+
+    #[test]
+    fn port_pattern() {
+        use RulePattern::*;
+        let mut rule = ConnectRule::none();
+        rule.allow(
+            Any,
+            Specif(PortPattern::List(vec![
+                443.into(),
+                PortPattern::range(8000, 8099).unwrap(),
+            ])),
+            Any,
+        );
+        assert!(rule.check("192.168.0.1:443".parse().unwrap(), Tcp));
+        assert!(rule.check("192.168.0.1:8000".parse().unwrap(), Tcp));
+        assert!(rule.check("192.168.0.1:8099".parse().unwrap(), Tcp));
+        assert!(!rule.check("192.168.0.1:80".parse().unwrap(), Tcp));
+        assert!(!rule.check("192.168.0.1:8100".parse().unwrap(), Tcp));
+        assert_eq!(PortPattern::range(2, 1), None);
+    }
+
+    #[test]
+    fn matched_rule() {
+        use RulePattern::*;
+        let mut rule = ConnectRule::none();
+        rule.allow(Any, Specif(80.into()), Any);
+        rule.deny(Any, Specif(80.into()), Specif(Udp));
+        let src = "10.1.2.3:5000".parse().unwrap();
+        let dst: Address = "192.168.0.1:80".parse().unwrap();
+        let (idx, entry) = rule.matched_from(src, &dst, Tcp);
+        assert_eq!(idx, 1);
+        assert!(entry.is_allow());
+        let (idx, entry) = rule.matched_from(src, &dst, Udp);
+        assert_eq!(idx, 2);
+        assert!(!entry.is_allow());
+        let (idx, _) = rule.matched_from(src, &"192.168.0.1:443".parse().unwrap(), Tcp);
+        assert_eq!(idx, 0);
+    }
+
+    #[test]
+    fn deserialize_source() {
+        let yaml = r#"
+---
+- Deny:
+    address: Any
+    port: Any
+    protocol: Any
+- Allow:
+    source:
+      Specif:
+        IpAddr:
+          addr: 10.0.0.0
+          prefix: 8
+    address: Any
+    port: Any
+    protocol: Any
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let dst: Address = "192.168.0.1:80".parse().unwrap();
+        assert!(rule.check_from("10.1.2.3:5000".parse().unwrap(), dst.clone(), Udp));
+        assert!(!rule.check_from("11.1.2.3:5000".parse().unwrap(), dst, Udp));
+    }
+
+    #[test]
+    fn named_rules() {
+        let yaml = r#"
+---
+- Allow:
+    name: default
+    address: Any
+    port: Any
+    protocol: Any
+- Deny:
+    name: no-smtp
+    description: outgoing mails must be relayed by the mail server
+    address: Any
+    port:
+      Specif: 25
+    protocol: Any
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let src = "10.1.2.3:5000".parse().unwrap();
+        let (idx, entry) = rule.matched_from(src, &"192.168.0.1:25".parse().unwrap(), Tcp);
+        assert_eq!((idx, entry.name()), (1, Some("no-smtp")));
+        let (_, entry) = rule.matched_from(src, &"192.168.0.1:80".parse().unwrap(), Tcp);
+        assert_eq!(entry.name(), Some("default"));
+
+        let yaml = serde_yaml::to_string(&rule).unwrap();
+        assert!(yaml.contains("description: outgoing mails"));
+        let rule: ConnectRule = serde_yaml::from_str(&yaml).unwrap();
+        assert!(!rule.check("192.168.0.1:25".parse().unwrap(), Tcp));
+
+        // unnamed rules are serialized as before
+        let yaml = serde_yaml::to_string(&ConnectRule::any()).unwrap();
+        assert!(!yaml.contains("name"));
+    }
+
+    #[test]
+    fn explain_rules() {
+        let mut rule = ConnectRule::any();
+        rule.push(ConnectRuleEntry::Deny(
+            ConnectRulePattern::new(
+                RulePattern::Any,
+                RulePattern::Specif(25.into()),
+                RulePattern::Any,
+            )
+            .named("no-smtp"),
+        ));
+        rule.deny(
+            RulePattern::Specif(AddressPattern::addr("10.0.0.0".parse().unwrap(), 8).unwrap()),
+            RulePattern::Any,
+            RulePattern::Any,
+        );
+
+        let trace = rule.explain(&"192.168.0.1:25".parse().unwrap(), Tcp);
+        assert_eq!(
+            trace,
+            vec![
+                RuleMatchTrace {
+                    index: 0,
+                    name: None,
+                    allow: true,
+                    matched: true,
+                    decided: false,
+                },
+                RuleMatchTrace {
+                    index: 1,
+                    name: Some("no-smtp".to_owned()),
+                    allow: false,
+                    matched: true,
+                    decided: true,
+                },
+                RuleMatchTrace {
+                    index: 2,
+                    name: None,
+                    allow: false,
+                    matched: false,
+                    decided: false,
+                },
+            ]
+        );
+        let trace = rule.explain(&"192.168.0.1:80".parse().unwrap(), Tcp);
+        let decided: Vec<_> = trace
+            .iter()
+            .filter(|t| t.decided)
+            .map(|t| t.index)
+            .collect();
+        assert_eq!(decided, vec![0]);
+    }
+
+    #[test]
+    fn deny_reply() {
+        let yaml = r#"
+---
+- Allow:
+    address: Any
+    port: Any
+    protocol: Any
+- Deny:
+    address: Any
+    port:
+      Specif: 25
+    protocol: Any
+    reply: HostUnreachable
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let src = "10.1.2.3:5000".parse().unwrap();
+        let (_, entry) = rule.matched_from(src, &"192.168.0.1:25".parse().unwrap(), Tcp);
+        assert_eq!(entry.reply(), Some(&ConnectError::HostUnreachable));
+        let (_, entry) = rule.matched_from(src, &"192.168.0.1:80".parse().unwrap(), Tcp);
+        assert_eq!(entry.reply(), None);
+
+        let mut rule = ConnectRule::any();
+        rule.push(ConnectRuleEntry::Deny(
+            ConnectRulePattern::new(
+                RulePattern::Any,
+                RulePattern::Specif(25.into()),
+                RulePattern::Any,
+            )
+            .reply_with(ConnectError::HostUnreachable),
+        ));
+        let yaml = serde_yaml::to_string(&rule).unwrap();
+        assert!(yaml.contains("reply: HostUnreachable"));
+    }
+
+    #[test]
+    fn decision_cache() {
+        let src = "192.168.0.2:5000".parse().unwrap();
+        let dst = Address::Domain("www.example.com".to_owned(), 443);
+        let mut rule = ConnectRule::any();
+        rule.set_decision_cache(Some(10));
+        rule.deny(
+            RulePattern::Specif(AddressPattern::Domain(
+                DomainPattern::wildcard("*.example.com".to_owned()).unwrap(),
+            )),
+            RulePattern::Any,
+            RulePattern::Any,
+        );
+        assert_eq!(rule.matched_from(src, &dst, Tcp).0, 1);
+        assert_eq!(rule.matched_from(src, &dst, Tcp).0, 1);
+        assert_eq!(rule.cache.as_ref().unwrap().len(), 1);
+
+        // decisions are forgotten by changing the rules
+        rule.allow_user(
+            RulePattern::Specif("alice".to_owned()),
+            RulePattern::Any,
+            RulePattern::Any,
+            RulePattern::Any,
+        );
+        assert_eq!(rule.cache.as_ref().unwrap().len(), 0);
+        assert_eq!(rule.matched_user(src, Some("alice"), &dst, Tcp).0, 2);
+        // cached by the user as well
+        assert_eq!(rule.matched_user(src, Some("bob"), &dst, Tcp).0, 1);
+        assert_eq!(rule.matched_user(src, Some("alice"), &dst, Tcp).0, 2);
+        assert!(!rule.check(dst.clone(), Tcp));
+
+        let mut rule = rule.clone();
+        assert_eq!(rule.cache.as_ref().unwrap().len(), 0);
+        rule.set_decision_cache(None);
+        assert!(!rule.check(dst, Tcp));
+    }
+
+    #[test]
+    fn rule_bind() {
+        let yaml = r#"
+---
+- Allow:
+    address: Any
+    port: Any
+    protocol: Any
+- Allow:
+    address: Any
+    port:
+      Specif: 443
+    protocol: Any
+    bind:
+      addr: 192.0.2.1
+      device: eth1
+- Deny:
+    address: Any
+    port:
+      Specif: 25
+    protocol: Any
+    bind:
+      device: eth1
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let src = "10.1.2.3:5000".parse().unwrap();
+        let (_, entry) = rule.matched_from(src, &"192.168.0.1:443".parse().unwrap(), Tcp);
+        assert_eq!(
+            entry.bind(),
+            Some(&OutboundBind {
+                addr: Some("192.0.2.1".parse().unwrap()),
+                device: Some("eth1".to_owned()),
+            })
+        );
+        // denied connections are not bound
+        let (_, entry) = rule.matched_from(src, &"192.168.0.1:25".parse().unwrap(), Tcp);
+        assert_eq!(entry.bind(), None);
+        let (_, entry) = rule.matched_from(src, &"192.168.0.1:80".parse().unwrap(), Tcp);
+        assert_eq!(entry.bind(), None);
+
+        assert!(serde_yaml::from_str::<OutboundBind>("interface: eth1").is_err());
+    }
+
+    #[test]
+    fn country_pattern() {
+        let yaml = r#"
+---
+- Allow:
+    address: Any
+    port: Any
+    protocol: Any
+- Deny:
+    address:
+      Specif:
+        Country:
+          iso_code: jp
+    port: Any
+    protocol: Any
+"#;
+        let mut rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let jp: Address = "203.0.113.1:80".parse().unwrap();
+        let other: Address = "198.51.100.1:80".parse().unwrap();
+        // never matches without a provider
+        assert!(rule.check(jp.clone(), Tcp));
+
+        let geoip: Vec<(AddressPattern, String)> = vec![(
+            AddressPattern::addr("203.0.113.0".parse().unwrap(), 24).unwrap(),
+            "JP".to_owned(),
+        )];
+        rule.set_geoip_provider(Some(Arc::new(geoip)));
+        assert!(!rule.check(jp, Tcp));
+        assert!(rule.check(other, Tcp));
+        assert!(rule.check(Address::Domain("example.jp".to_owned(), 80), Tcp));
+        assert!(!rule.check_resolved(
+            "192.168.0.2:5000".parse().unwrap(),
+            None,
+            "203.0.113.1:80".parse().unwrap(),
+            Tcp
+        ));
+    }
+
+    #[test]
+    fn address_groups() {
+        let yaml = r#"
+---
+groups:
+  internal:
+    - IpAddr:
+        addr: 10.0.0.0
+        prefix: 8
+    - Group: corp
+  corp:
+    - Domain:
+        wildcard: "*.corp.example"
+rules:
+  - Allow:
+      address: Any
+      port: Any
+      protocol: Any
+  - Deny:
+      address:
+        Specif:
+          Group: internal
+      port: Any
+      protocol: Any
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        assert!(!rule.check("10.1.2.3:80".parse().unwrap(), Tcp));
+        assert!(!rule.check(Address::Domain("www.corp.example".to_owned(), 80), Tcp));
+        assert!(rule.check("192.168.0.1:80".parse().unwrap(), Tcp));
+        // both of ip addresses and domains are in the group
+        let src = "192.168.0.2:5000".parse().unwrap();
+        assert!(!rule.check_resolved(src, None, "10.1.2.3:80".parse().unwrap(), Tcp));
+        assert!(!rule.check_server_name(src, None, "www.corp.example", 443, Tcp));
+
+        // resolved groups are serialized as lists
+        let yaml = serde_yaml::to_string(&rule).unwrap();
+        let rule: ConnectRule = serde_yaml::from_str(&yaml).unwrap();
+        assert!(!rule.check(Address::Domain("www.corp.example".to_owned(), 80), Tcp));
+
+        let undefined = yaml_rules("groups: {}", "Group: unknown");
+        let err = serde_yaml::from_str::<ConnectRule>(&undefined).unwrap_err();
+        assert!(err.to_string().contains("undefined group: unknown"));
+        let circular = yaml_rules("groups: { a: [Group: b], b: [Group: a] }", "Group: a");
+        let err = serde_yaml::from_str::<ConnectRule>(&circular).unwrap_err();
+        assert!(err.to_string().contains("circular reference of group"));
+    }
+
+    #[test]
+    fn rule_log() {
+        let yaml = r#"
+---
+- Allow:
+    address: Any
+    port: Any
+    protocol: Any
+    log:
+      level: debug
+      sample: 0.0
+- Deny:
+    address: Any
+    port:
+      Specif: 25
+    protocol: Any
+    log: {}
+"#;
+        let rule: ConnectRule = serde_yaml::from_str(yaml).unwrap();
+        let src = "10.1.2.3:5000".parse().unwrap();
+        let (_, entry) = rule.matched_from(src, &"192.168.0.1:80".parse().unwrap(), Tcp);
+        let log = entry.log().unwrap();
+        assert_eq!(log, &RuleLog::new(LevelFilter::Debug).sampled(0.0));
+        // never sampled
+        assert_eq!(log.level(), LevelFilter::Off);
+        let (_, entry) = rule.matched_from(src, &"192.168.0.1:25".parse().unwrap(), Tcp);
+        assert_eq!(entry.log().unwrap().level(), LevelFilter::Info);
+
+        let yaml = serde_yaml::to_string(&rule).unwrap();
+        assert!(yaml.contains("sample: 0.0"));
+        let err = serde_yaml::from_str::<RuleLog>("level: verbose").unwrap_err();
+        assert!(err.to_string().contains("verbose"));
+    }
+
+    /// rule set with `groups` denying `address`
+    fn yaml_rules(groups: &str, address: &str) -> String {
+        format!(
+            "{}\nrules:\n  - Allow: {{ address: Any, port: Any, protocol: Any }}\n  \
+             - Deny: {{ address: {{ Specif: {{ {} }} }}, port: Any, protocol: Any }}\n",
+            groups, address
+        )
+    }
 }
@@ -1,28 +1,32 @@
 use std::io;
-use std::net;
+use std::net::{self, SocketAddr};
+use std::time::{Duration, Instant};
 
-use crate::model::{Error, ErrorKind};
+use crate::model::{Address, Error, ErrorKind, UdpDatagram};
 
-#[allow(unused)]
-pub trait PktStream {
+/// Upper bound of the size of UDP datagrams
+pub const MAX_PKT_SIZE: usize = 65535;
+
+/// send/recv operations on datagram socket
+pub trait PktStream: Send {
     fn pkt_size(&self) -> usize;
-    fn recv_pkt(&mut self) -> Result<&[u8], Error>;
-    fn send_pkt(&self, pkt: &[u8]) -> Result<(), Error>;
+    fn local_addr(&self) -> Result<SocketAddr, Error>;
+    /// receive a packet into `buf`
+    ///
+    /// returns `None` if no packets arrived within the read timeout.
+    fn recv_pkt(&self, buf: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, Error>;
+    fn send_pkt(&self, pkt: &[u8], addr: SocketAddr) -> Result<(), Error>;
 }
 
+#[derive(Debug)]
 pub struct UdpPktStream {
     pkt_size: usize,
     socket: net::UdpSocket,
-    buf: Vec<u8>,
 }
 
 impl UdpPktStream {
     pub fn new(pkt_size: usize, socket: net::UdpSocket) -> Self {
-        Self {
-            pkt_size,
-            socket,
-            buf: Vec::with_capacity(pkt_size),
-        }
+        Self { pkt_size, socket }
     }
 }
 
@@ -31,12 +35,20 @@ impl PktStream for UdpPktStream {
         self.pkt_size
     }
 
-    fn recv_pkt(&mut self) -> Result<&[u8], Error> {
-        self.socket.recv(&mut self.buf)?;
-        Ok(&self.buf)
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn recv_pkt(&self, buf: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, Error> {
+        use io::ErrorKind as K;
+        match self.socket.recv_from(buf) {
+            Ok((size, addr)) => Ok(Some((size, addr))),
+            Err(err) if err.kind() == K::WouldBlock || err.kind() == K::TimedOut => Ok(None),
+            Err(err) => Err(err.into()),
+        }
     }
 
-    fn send_pkt(&self, pkt: &[u8]) -> Result<(), Error> {
+    fn send_pkt(&self, pkt: &[u8], addr: SocketAddr) -> Result<(), Error> {
         if pkt.len() > self.pkt_size {
             return Err(ErrorKind::PacketSizeLimitExceeded {
                 size: pkt.len(),
@@ -45,7 +57,7 @@ impl PktStream for UdpPktStream {
             .into());
         }
         self.socket
-            .send(pkt)
+            .send_to(pkt, addr)
             .and_then(|size| {
                 if size == pkt.len() {
                     Ok(())
@@ -59,3 +71,154 @@ impl PktStream for UdpPktStream {
             .map_err(Into::into)
     }
 }
+
+/// High-order bit of `FRAG` marking the end of a fragment sequence
+const END_OF_FRAGMENTS: u8 = 0x80;
+
+/// Reassembly queue of fragmented UDP datagrams (RFC 1928, section 7)
+///
+/// The queue is reinitialized when the timer expires, or a fragment arrives out of order
+/// (positions must be `1, 2, ...`). Fragments following a broken sequence are discarded
+/// until the next position `1`.
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    /// upper bound of the reassembled datagram
+    max_size: usize,
+    dst_addr: Option<Address>,
+    data: Vec<u8>,
+    /// position of the last queued fragment (`0` if the queue is empty)
+    last_pos: u8,
+    deadline: Option<Instant>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, max_size: usize) -> Self {
+        Self {
+            timeout,
+            max_size,
+            dst_addr: None,
+            data: vec![],
+            last_pos: 0,
+            deadline: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.dst_addr = None;
+        self.data.clear();
+        self.last_pos = 0;
+        self.deadline = None;
+    }
+
+    /// Queue `datagram`
+    ///
+    /// Returns the reassembled datagram if `datagram` is the last fragment of a sequence,
+    /// or `datagram` itself if it is not fragmented.
+    pub fn push<'a>(
+        &'a mut self,
+        datagram: UdpDatagram<'a>,
+    ) -> Result<Option<UdpDatagram<'a>>, Error> {
+        if datagram.frag == 0 {
+            return Ok(Some(datagram));
+        }
+        if matches!(self.deadline, Some(deadline) if deadline <= Instant::now()) {
+            self.reset();
+        }
+        let pos = datagram.frag & !END_OF_FRAGMENTS;
+        if pos == 1 {
+            self.reset();
+            self.dst_addr = Some(datagram.dst_addr.clone());
+            self.deadline = Some(Instant::now() + self.timeout);
+        } else if pos != self.last_pos + 1 {
+            self.reset();
+            return Ok(None);
+        }
+        let size = self.data.len() + datagram.data.len();
+        if size > self.max_size {
+            self.reset();
+            return Err(ErrorKind::PacketSizeLimitExceeded {
+                size,
+                limit: self.max_size,
+            }
+            .into());
+        }
+        self.data.extend_from_slice(datagram.data);
+        self.last_pos = pos;
+        if datagram.frag & END_OF_FRAGMENTS == 0 {
+            return Ok(None);
+        }
+        self.last_pos = 0;
+        self.deadline = None;
+        Ok(Some(UdpDatagram {
+            frag: 0,
+            dst_addr: self
+                .dst_addr
+                .take()
+                .expect("first fragment has been queued"),
+            data: &self.data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment(frag: u8, data: &[u8]) -> UdpDatagram {
+        UdpDatagram {
+            frag,
+            dst_addr: "192.0.2.1:53".parse().unwrap(),
+            data,
+        }
+    }
+
+    #[test]
+    fn reassemble() {
+        let mut queue = Reassembler::new(Duration::from_secs(5), 8);
+        assert_eq!(
+            queue.push(fragment(0, b"single")).unwrap(),
+            Some(fragment(0, b"single"))
+        );
+
+        assert_eq!(queue.push(fragment(1, b"ab")).unwrap(), None);
+        assert_eq!(queue.push(fragment(2, b"cd")).unwrap(), None);
+        assert_eq!(
+            queue.push(fragment(3 | END_OF_FRAGMENTS, b"ef")).unwrap(),
+            Some(fragment(0, b"abcdef"))
+        );
+
+        // a missing fragment discards the sequence
+        assert_eq!(queue.push(fragment(1, b"ab")).unwrap(), None);
+        assert_eq!(
+            queue.push(fragment(3 | END_OF_FRAGMENTS, b"ef")).unwrap(),
+            None
+        );
+        assert_eq!(
+            queue.push(fragment(4 | END_OF_FRAGMENTS, b"gh")).unwrap(),
+            None
+        );
+        // a new sequence starts at position 1
+        assert_eq!(
+            queue.push(fragment(1 | END_OF_FRAGMENTS, b"gh")).unwrap(),
+            Some(fragment(0, b"gh"))
+        );
+
+        assert_eq!(queue.push(fragment(1, b"01234")).unwrap(), None);
+        assert!(queue.push(fragment(2, b"56789")).is_err());
+        assert_eq!(
+            queue.push(fragment(3 | END_OF_FRAGMENTS, b"x")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn reassembly_timeout() {
+        let mut queue = Reassembler::new(Duration::ZERO, 8);
+        assert_eq!(queue.push(fragment(1, b"ab")).unwrap(), None);
+        assert_eq!(
+            queue.push(fragment(2 | END_OF_FRAGMENTS, b"cd")).unwrap(),
+            None
+        );
+    }
+}
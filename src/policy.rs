@@ -0,0 +1,296 @@
+//! Pluggable filtering of connect requests
+//!
+//! Requests of clients are decided by a [`ConnectPolicy`], which is `ServerConfig::conn_rule` by default.
+//! Applications can decide requests dynamically (e.g. by database lookups or an external
+//! authorization service) with their own policy set by `ServerConfig::set_connect_policy`.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! use gatekeeper::policy::{ConnectContext, ConnectPolicy, Decision};
+//! use gatekeeper::ServerConfig;
+//!
+//! /// allow only authenticated users
+//! #[derive(Debug)]
+//! struct UsersOnly;
+//!
+//! impl ConnectPolicy for UsersOnly {
+//!     fn check(&self, ctx: &ConnectContext) -> Decision {
+//!         if ctx.user.is_some() {
+//!             Decision::allow()
+//!         } else {
+//!             Decision::deny()
+//!         }
+//!     }
+//! }
+//!
+//! let mut config = ServerConfig::default();
+//! config.set_connect_policy(Some(Arc::new(UsersOnly)));
+//! ```
+use std::fmt;
+
+use log::LevelFilter;
+
+use crate::model::{
+    Address, ConnectError, ConnectRule, L4Protocol, OutboundBind, RuleLog, SocketAddr,
+};
+
+/// What is checked by a policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStage {
+    /// the destination requested by the client
+    Request,
+    /// an address resolved from the requested domain (`ServerConfig::check_resolved`)
+    ResolvedAddress,
+    /// the TLS server name sent to the requested ip address (`ServerConfig::inspect_sni`)
+    ServerName,
+}
+
+/// Connection to be checked
+#[derive(Debug, Clone)]
+pub struct ConnectContext<'a> {
+    /// address of the client
+    pub src_addr: SocketAddr,
+    /// user authenticated by the client
+    pub user: Option<&'a str>,
+    /// destination of the connection
+    pub dst: &'a Address,
+    pub protocol: L4Protocol,
+    pub stage: CheckStage,
+}
+
+impl<'a> ConnectContext<'a> {
+    /// context of a request
+    pub fn new(
+        src_addr: SocketAddr,
+        user: Option<&'a str>,
+        dst: &'a Address,
+        protocol: L4Protocol,
+    ) -> Self {
+        Self {
+            src_addr,
+            user,
+            dst,
+            protocol,
+            stage: CheckStage::Request,
+        }
+    }
+
+    pub fn with_stage(self, stage: CheckStage) -> Self {
+        Self { stage, ..self }
+    }
+}
+
+/// Result of checking a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub allow: bool,
+    /// index of the rule decided the connection, reported to audit events
+    pub matched_rule: Option<usize>,
+    /// name of the rule decided the connection, reported to audit events and logs
+    pub rule_name: Option<String>,
+    /// reply to the denied client instead of `ConnectionNotAllowed`
+    pub reply: Option<ConnectError>,
+    /// level to log the decision at (`Off` not to log it)
+    ///
+    /// If `None`, only denials by named rules are logged at `Info` level.
+    pub log: Option<LevelFilter>,
+    /// local address or device to connect from instead of `ServerConfig::outbound_bind_addr`
+    pub bind: Option<OutboundBind>,
+    /// maximum number of sessions connected by the decision at the same time (CONNECT command)
+    ///
+    /// Sessions are counted per rule name, or `matched_rule` if not named,
+    /// or the requested destination if neither is given.
+    pub max_sessions: Option<usize>,
+}
+
+impl Decision {
+    pub fn allow() -> Self {
+        Self {
+            allow: true,
+            matched_rule: None,
+            rule_name: None,
+            reply: None,
+            log: None,
+            bind: None,
+            max_sessions: None,
+        }
+    }
+
+    pub fn deny() -> Self {
+        Self {
+            allow: false,
+            ..Self::allow()
+        }
+    }
+
+    fn with(allow: bool) -> Self {
+        Self {
+            allow,
+            ..Self::allow()
+        }
+    }
+
+    /// decided by the rule of `index` named `name`
+    pub fn with_rule(self, index: Option<usize>, name: Option<String>) -> Self {
+        Self {
+            matched_rule: index,
+            rule_name: name,
+            ..self
+        }
+    }
+
+    /// reply `reply` to the client if denied
+    pub fn reply_with(self, reply: ConnectError) -> Self {
+        Self {
+            reply: Some(reply),
+            ..self
+        }
+    }
+
+    /// log the decision at `level`
+    pub fn logged(self, level: LevelFilter) -> Self {
+        Self {
+            log: Some(level),
+            ..self
+        }
+    }
+
+    /// connect from `bind` if allowed
+    pub fn bound_to(self, bind: OutboundBind) -> Self {
+        Self {
+            bind: Some(bind),
+            ..self
+        }
+    }
+
+    /// allow up to `max` sessions connected by the decision at the same time
+    pub fn limited_to(self, max: usize) -> Self {
+        Self {
+            max_sessions: Some(max),
+            ..self
+        }
+    }
+}
+
+/// Decides whether connections are allowed
+///
+/// A request may be checked more than once (e.g. again for its audit event),
+/// so policies with expensive lookups should cache their decisions.
+pub trait ConnectPolicy: fmt::Debug + Send + Sync {
+    fn check(&self, ctx: &ConnectContext) -> Decision;
+}
+
+/// Policy of the rule set
+///
+/// As `ConnectRule::check` is also an inherent method, call this by `ConnectPolicy::check(&rule, &ctx)`.
+impl ConnectPolicy for ConnectRule {
+    fn check(&self, ctx: &ConnectContext) -> Decision {
+        let ConnectContext {
+            src_addr,
+            user,
+            dst,
+            protocol,
+            stage,
+        } = *ctx;
+        match (stage, dst) {
+            (CheckStage::Request, _) => {
+                let (idx, entry) = self.matched_user(src_addr, user, dst, protocol);
+                Decision {
+                    reply: entry.reply().cloned(),
+                    log: entry.log().map(RuleLog::level),
+                    bind: entry.bind().cloned(),
+                    max_sessions: entry.max_sessions(),
+                    ..Decision::with(entry.is_allow())
+                        .with_rule(Some(idx), entry.name().map(str::to_owned))
+                }
+            }
+            (CheckStage::ResolvedAddress, Address::Domain(..)) => Decision::allow(),
+            (CheckStage::ResolvedAddress, dst) => {
+                let resolved = dst.socket_addr().expect("ip address");
+                match self.matched_resolved(src_addr, user, resolved, protocol) {
+                    Some((idx, entry)) => Decision::with(entry.is_allow())
+                        .with_rule(Some(idx), entry.name().map(str::to_owned)),
+                    // allowed implicitly
+                    None => Decision::allow(),
+                }
+            }
+            (CheckStage::ServerName, Address::Domain(name, port)) => {
+                Decision::with(self.check_server_name(src_addr, user, name, *port, protocol))
+            }
+            // nothing to check
+            (CheckStage::ServerName, _) => Decision::allow(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{
+        AddressPattern, ConnectRuleEntry, ConnectRulePattern, DomainPattern, RulePattern,
+    };
+
+    #[test]
+    fn rule_policy() {
+        let src: SocketAddr = "192.168.0.2:5000".parse().unwrap();
+        let mut rule = ConnectRule::any();
+        rule.push(ConnectRuleEntry::Deny(
+            ConnectRulePattern::new(
+                RulePattern::Specif(AddressPattern::Domain(
+                    DomainPattern::wildcard("*.blocked.test".to_owned()).unwrap(),
+                )),
+                RulePattern::Any,
+                RulePattern::Any,
+            )
+            .named("blocked"),
+        ));
+
+        let dst = Address::Domain("www.blocked.test".into(), 443);
+        let ctx = ConnectContext::new(src, None, &dst, L4Protocol::Tcp);
+        assert_eq!(
+            ConnectPolicy::check(&rule, &ctx),
+            Decision::deny().with_rule(Some(1), Some("blocked".into()))
+        );
+        // the server name is checked by domain rules
+        let server_name = ctx.clone().with_stage(CheckStage::ServerName);
+        assert!(!ConnectPolicy::check(&rule, &server_name).allow);
+
+        let dst = Address::Domain("example.com".into(), 443);
+        let ctx = ConnectContext::new(src, None, &dst, L4Protocol::Tcp);
+        assert_eq!(
+            ConnectPolicy::check(&rule, &ctx),
+            Decision::allow().with_rule(Some(0), None)
+        );
+        // ip address rules are not given, so any resolved address is allowed
+        let resolved = "192.0.2.1:443".parse().unwrap();
+        let ctx = ConnectContext::new(src, None, &resolved, L4Protocol::Tcp)
+            .with_stage(CheckStage::ResolvedAddress);
+        assert!(ConnectPolicy::check(&rule, &ctx).allow);
+    }
+
+    #[test]
+    fn rule_log_level() {
+        let src: SocketAddr = "192.168.0.2:5000".parse().unwrap();
+        let mut rule = ConnectRule::any();
+        rule.push(ConnectRuleEntry::Deny(
+            ConnectRulePattern::new(
+                RulePattern::Any,
+                RulePattern::Specif(25.into()),
+                RulePattern::Any,
+            )
+            .logged(RuleLog::new(LevelFilter::Warn)),
+        ));
+        let dst = "192.0.2.1:25".parse().unwrap();
+        let ctx = ConnectContext::new(src, None, &dst, L4Protocol::Tcp);
+        assert_eq!(
+            ConnectPolicy::check(&rule, &ctx),
+            Decision::deny()
+                .with_rule(Some(1), None)
+                .logged(LevelFilter::Warn)
+        );
+        // the level is not given by the rule
+        let dst = "192.0.2.1:80".parse().unwrap();
+        let ctx = ConnectContext::new(src, None, &dst, L4Protocol::Tcp);
+        assert_eq!(ConnectPolicy::check(&rule, &ctx).log, None);
+    }
+}
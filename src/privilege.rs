@@ -0,0 +1,100 @@
+//! Switching to an unprivileged account after binding listening sockets
+//!
+//! A server started as root to bind privileged ports switches to `ServerConfig::run_as`
+//! before accepting clients.
+use std::io;
+
+use failure::Fail;
+use log::*;
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+use crate::config::RunAs;
+use crate::error::{Error, ErrorKind};
+
+/// user or group not found (e.g. a typo in the name)
+fn not_found(kind: &str, name: &str) -> Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found: {}", kind, name),
+    )
+    .context(ErrorKind::Permission)
+    .into()
+}
+
+fn os_error(errno: nix::Error) -> Error {
+    io::Error::from(errno).context(ErrorKind::Permission).into()
+}
+
+/// uid and gid of `run_as`, the current ones if not given
+fn resolve(run_as: &RunAs) -> Result<(Uid, Gid), Error> {
+    let user = match &run_as.user {
+        Some(name) => {
+            let user = match name.parse() {
+                Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+                Err(_) => User::from_name(name),
+            };
+            Some(
+                user.map_err(os_error)?
+                    .ok_or_else(|| not_found("user", name))?,
+            )
+        }
+        None => None,
+    };
+    let gid = match &run_as.group {
+        Some(name) => match name.parse() {
+            Ok(gid) => Gid::from_raw(gid),
+            Err(_) => {
+                let group = Group::from_name(name).map_err(os_error)?;
+                group.ok_or_else(|| not_found("group", name))?.gid
+            }
+        },
+        None => user.as_ref().map_or_else(Gid::effective, |user| user.gid),
+    };
+    let uid = user.map_or_else(Uid::effective, |user| user.uid);
+    Ok((uid, gid))
+}
+
+/// Switch the process to `run_as`
+///
+/// Supplementary groups are dropped, and the process cannot regain the privilege.
+pub(crate) fn switch_to(run_as: &RunAs) -> Result<(), Error> {
+    let (uid, gid) = resolve(run_as)?;
+    if uid == Uid::effective() && gid == Gid::effective() && !uid.is_root() {
+        return Ok(());
+    }
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    unistd::setgroups(&[gid]).map_err(os_error)?;
+    unistd::setgid(gid).map_err(os_error)?;
+    unistd::setuid(uid).map_err(os_error)?;
+    if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err(
+            io::Error::new(io::ErrorKind::Other, "root privilege is not dropped")
+                .context(ErrorKind::Permission)
+                .into(),
+        );
+    }
+    info!("run as uid: {}, gid: {}", uid, gid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve_account() {
+        let run_as = |user: Option<&str>, group: Option<&str>| RunAs {
+            user: user.map(str::to_owned),
+            group: group.map(str::to_owned),
+        };
+        let root = (Uid::from_raw(0), Gid::from_raw(0));
+        assert_eq!(resolve(&run_as(Some("root"), None)).unwrap(), root);
+        assert_eq!(resolve(&run_as(Some("0"), Some("0"))).unwrap(), root);
+        assert_eq!(
+            resolve(&run_as(None, Some("12345"))).unwrap(),
+            (Uid::effective(), Gid::from_raw(12345))
+        );
+        assert!(resolve(&run_as(Some("gatekeeper-no-such-user"), None)).is_err());
+        assert!(resolve(&run_as(None, Some("gatekeeper-no-such-group"))).is_err());
+    }
+}
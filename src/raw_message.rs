@@ -213,6 +213,60 @@ impl std::error::Error for TryFromU8Error {
     }
 }
 
+/// RFC1929 Username/Password Authentication Request
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserPassRequest {
+    pub ver: u8,
+    pub uname: Vec<u8>,
+    pub passwd: Vec<u8>,
+}
+
+impl From<UserPassRequest> for model::UserPassRequest {
+    fn from(req: UserPassRequest) -> Self {
+        model::UserPassRequest {
+            version: req.ver,
+            username: String::from_utf8_lossy(&req.uname).to_string(),
+            password: String::from_utf8_lossy(&req.passwd).to_string(),
+        }
+    }
+}
+
+impl From<model::UserPassRequest> for UserPassRequest {
+    fn from(req: model::UserPassRequest) -> Self {
+        UserPassRequest {
+            ver: req.version,
+            uname: req.username.into_bytes(),
+            passwd: req.password.into_bytes(),
+        }
+    }
+}
+
+/// RFC1929 Username/Password Authentication Reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserPassReply {
+    pub ver: u8,
+    /// `X'00'` indicates success
+    pub status: u8,
+}
+
+impl From<UserPassReply> for model::UserPassReply {
+    fn from(rep: UserPassReply) -> Self {
+        model::UserPassReply {
+            version: rep.ver,
+            success: rep.status == 0,
+        }
+    }
+}
+
+impl From<model::UserPassReply> for UserPassReply {
+    fn from(rep: model::UserPassReply) -> Self {
+        UserPassReply {
+            ver: rep.version,
+            status: if rep.success { 0x00 } else { 0x01 },
+        }
+    }
+}
+
 /// ATYP
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AddrType {
@@ -264,6 +318,7 @@ impl From<model::Address> for Addr {
     fn from(addr: model::Address) -> Self {
         match addr {
             model::Address::IpAddr(addr, _) => Addr::IpAddr(addr),
+            model::Address::ScopedV6(addr) => Addr::IpAddr((*addr.ip()).into()),
             model::Address::Domain(domain, _) => Addr::Domain(domain.as_bytes().to_vec()),
         }
     }
@@ -456,6 +511,8 @@ impl From<model::ConnectRequest> for ConnectRequest {
         let (atyp, dst_addr, dst_port) = match req.connect_to {
             A::IpAddr(addr @ IpAddr::V4(_), port) => (AddrType::V4, Addr::IpAddr(addr), port),
             A::IpAddr(addr @ IpAddr::V6(_), port) => (AddrType::V6, Addr::IpAddr(addr), port),
+            // the scope is local to the server
+            A::ScopedV6(addr) => (AddrType::V6, Addr::IpAddr((*addr.ip()).into()), addr.port()),
             A::Domain(addr, port) => (
                 AddrType::Domain,
                 Addr::Domain(addr.as_bytes().to_vec()),
@@ -500,6 +557,8 @@ impl From<model::ConnectReply> for ConnectReply {
         let (atyp, addr, port) = match rep.server_addr {
             A::IpAddr(addr @ IpAddr::V4(_), port) => (AddrType::V4, Addr::IpAddr(addr), port),
             A::IpAddr(addr @ IpAddr::V6(_), port) => (AddrType::V6, Addr::IpAddr(addr), port),
+            // the scope is local to the server
+            A::ScopedV6(addr) => (AddrType::V6, Addr::IpAddr((*addr.ip()).into()), addr.port()),
             A::Domain(addr, port) => (
                 AddrType::Domain,
                 Addr::Domain(addr.as_bytes().to_vec()),
@@ -526,3 +585,26 @@ pub struct UdpHeader {
     pub dst_addr: Addr,
     pub dst_port: u16,
 }
+
+impl From<&model::UdpDatagram<'_>> for UdpHeader {
+    fn from(datagram: &model::UdpDatagram<'_>) -> Self {
+        use model::Address as A;
+        let (atyp, dst_addr, dst_port) = match &datagram.dst_addr {
+            A::IpAddr(addr @ IpAddr::V4(_), port) => (AddrType::V4, Addr::IpAddr(*addr), *port),
+            A::IpAddr(addr @ IpAddr::V6(_), port) => (AddrType::V6, Addr::IpAddr(*addr), *port),
+            A::ScopedV6(addr) => (AddrType::V6, Addr::IpAddr((*addr.ip()).into()), addr.port()),
+            A::Domain(addr, port) => (
+                AddrType::Domain,
+                Addr::Domain(addr.as_bytes().to_vec()),
+                *port,
+            ),
+        };
+        UdpHeader {
+            rsv: 0,
+            frag: datagram.frag,
+            atyp,
+            dst_addr,
+            dst_port,
+        }
+    }
+}
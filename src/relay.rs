@@ -1,32 +1,84 @@
+//! Relays of bytes between two streams
+//!
+//! Sessions relay their clients and destinations by the machinery of this module.
+//! [`Relay`] exposes it for other uses, e.g. port forwarding or tunnels.
+//!
+//! ```no_run
+//! use std::net::{TcpListener, TcpStream};
+//! use gatekeeper::relay::Relay;
+//!
+//! // forward connections to 127.0.0.1:8080 to 192.0.2.1:80
+//! let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+//! let relay = Relay::new();
+//! for conn in listener.incoming() {
+//!     let conn = conn.unwrap();
+//!     let client_addr = conn.peer_addr().unwrap();
+//!     let upstream_addr = "192.0.2.1:80".parse().unwrap();
+//!     let upstream = TcpStream::connect(upstream_addr).unwrap();
+//!     let handle = relay.start(conn, client_addr, upstream, upstream_addr).unwrap();
+//!     std::thread::spawn(move || {
+//!         handle.join().unwrap().ok();
+//!     });
+//! }
+//! ```
+use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use log::*;
 
 use crate::byte_stream::{BoxedStream, ByteStream};
-use crate::model::{Error, ErrorKind};
-use crate::session::DisconnectGuard;
+use crate::config::RateLimit;
+use crate::connector::Resolver;
+use crate::model::{Address, Error, ErrorKind, L4Protocol, UdpDatagram};
+use crate::pkt_stream::{PktStream, Reassembler};
+use crate::policy::{ConnectContext, ConnectPolicy};
+use crate::rw_socks_stream::{read_datagram, write_datagram};
+use crate::session::{resolve_checked, DisconnectGuard, ResolvedCheck};
 use crate::thread::spawn_thread;
 
+/// Relay threads
 #[derive(Debug)]
 pub struct RelayHandle {
     /// handle to relay: client -> external network
     outbound_th: JoinHandle<Result<(), Error>>,
     /// handle to relay: client <- external network
     incoming_th: JoinHandle<Result<(), Error>>,
+    /// bytes relayed by the threads
+    traffic: Traffic,
+    /// Sender to send termination messages to the threads (only if started by `Relay`)
+    tx: Option<mpsc::SyncSender<()>>,
 }
 
 impl RelayHandle {
     fn new(
         outbound_th: JoinHandle<Result<(), Error>>,
         incoming_th: JoinHandle<Result<(), Error>>,
+        traffic: Traffic,
     ) -> Self {
         Self {
             outbound_th,
             incoming_th,
+            traffic,
+            tx: None,
+        }
+    }
+
+    /// counter of relayed bytes, which keeps counting until the threads exit
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.clone()
+    }
+
+    /// Request the relay started by [`Relay`] to stop without waiting for the threads
+    pub fn stop(&self) {
+        if let Some(tx) = &self.tx {
+            // a message for each thread. the threads may have exited already
+            tx.try_send(()).ok();
+            tx.try_send(()).ok();
         }
     }
 
@@ -35,6 +87,254 @@ impl RelayHandle {
     }
 }
 
+/// Interval of relay threads to check stop requests, if streams have no read timeout
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Relay between two streams independent of sessions
+///
+/// The relay finishes when either stream is closed, [`Lifetime`] is exceeded,
+/// or it is stopped by `RelayHandle::stop`.
+#[derive(Debug, Clone)]
+pub struct Relay {
+    bandwidth: Bandwidth,
+    lifetime: Lifetime,
+    buffer_size: usize,
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Self {
+            bandwidth: Bandwidth::default(),
+            lifetime: Lifetime::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+
+    /// limit bandwidth of relays, in total of relays sharing `bandwidth`
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) -> &mut Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    pub fn set_lifetime(&mut self, lifetime: Lifetime) -> &mut Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// size of the buffer of each direction (not used by `splice`)
+    pub fn set_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Spawn threads relaying between `client` and `server`
+    ///
+    /// Bytes from `client` are counted as upload, and ones from `server` as download.
+    /// The addresses are used only for logs.
+    /// Streams without a read timeout are given one to check stop requests periodically.
+    pub fn start(
+        &self,
+        client: impl ByteStream + 'static,
+        client_addr: SocketAddr,
+        server: impl ByteStream + 'static,
+        server_addr: SocketAddr,
+    ) -> Result<RelayHandle, Error> {
+        for strm in [&client as &dyn ByteStream, &server] {
+            if strm.read_timeout()?.is_none() {
+                strm.set_read_timeout(Some(STOP_POLL_INTERVAL))?;
+            }
+        }
+        let (tx, rx) = mpsc::sync_channel(2);
+        let mut handle = spawn_relay(
+            client_addr,
+            server_addr,
+            Box::new(client),
+            server,
+            self.bandwidth.clone(),
+            Traffic::default(),
+            self.lifetime,
+            self.buffer_size,
+            Arc::new(Mutex::new(rx)),
+            // threads keep the channel connected even if the handle is dropped
+            Arc::new(tx.clone()),
+        )?;
+        handle.tx = Some(tx);
+        Ok(handle)
+    }
+}
+
+/// Number of bytes relayed in each direction
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    pub(crate) upload: Arc<AtomicU64>,
+    pub(crate) download: Arc<AtomicU64>,
+}
+
+impl Traffic {
+    /// bytes relayed from client to external network
+    pub fn upload(&self) -> u64 {
+        self.upload.load(Ordering::Relaxed)
+    }
+
+    /// bytes relayed from external network to client
+    pub fn download(&self) -> u64 {
+        self.download.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+/// Bandwidth limits applied to relays
+///
+/// Relays sharing the same `Bandwidth` (cloned one) are limited in total.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    pub(crate) upload: Vec<SharedBucket>,
+    pub(crate) download: Vec<SharedBucket>,
+}
+
+impl Bandwidth {
+    /// `None` or `0` bytes/sec means unlimited
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        let bucket = |bps: u64| {
+            Some(bps)
+                .filter(|&bps| bps > 0)
+                .map(|bps| Arc::new(Mutex::new(TokenBucket::new(bps))))
+        };
+        Self {
+            upload: limit
+                .and_then(|l| bucket(l.upload_bps))
+                .into_iter()
+                .collect(),
+            download: limit
+                .and_then(|l| bucket(l.download_bps))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Limited by `other` as well
+    pub fn and(mut self, other: &Bandwidth) -> Self {
+        self.upload.extend(other.upload.iter().cloned());
+        self.download.extend(other.download.iter().cloned());
+        self
+    }
+}
+
+/// Block until `size` bytes are allowed to be sent by all `buckets`
+///
+/// Unlike `Throttle`, a datagram is not split, so the tokens are taken all at once.
+fn wait_tokens(buckets: &[SharedBucket], size: usize) {
+    for bucket in buckets {
+        let mut rest = size;
+        while rest > 0 {
+            rest -= take_tokens(bucket, rest);
+        }
+    }
+}
+
+/// Take tokens for `size` bytes at most from `bucket`.
+/// This blocks until enough tokens are filled.
+///
+/// The tokens are taken at once even if they are not filled yet, and the balance may become
+/// negative, so that relays sharing the bucket wait in turn and never exceed the rate in total.
+/// The bucket is not locked while waiting, so that other relays sharing it are not blocked.
+fn take_tokens(bucket: &SharedBucket, size: usize) -> usize {
+    let (size, wait) = {
+        // a bucket is consistent even if another relay panicked while holding it
+        let mut bucket = bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        let size = size.min(bucket.rate as usize).max(1);
+        bucket.refill();
+        bucket.consume(size);
+        // until the deficit is filled
+        (size, bucket.wait_time(0))
+    };
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+    size
+}
+
+/// Size of the buffer of each direction of a relay by default, which is the same as `io::copy`
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Limits of the lifetime of a session
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lifetime {
+    /// terminate the session when no data is relayed for this duration
+    pub idle_timeout: Option<Duration>,
+    /// terminate the session when it has been relaying for this duration
+    pub max_duration: Option<Duration>,
+}
+
+/// Watches relayed bytes to terminate a session exceeding its [`Lifetime`]
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    traffic: Traffic,
+    idle_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    /// total bytes relayed at `last_active`
+    last_bytes: u64,
+    last_active: Instant,
+}
+
+impl Watchdog {
+    pub fn new(traffic: Traffic, lifetime: Lifetime) -> Self {
+        let now = Instant::now();
+        Self {
+            last_bytes: traffic.upload() + traffic.download(),
+            traffic,
+            idle_timeout: lifetime.idle_timeout,
+            deadline: lifetime.max_duration.map(|dur| now + dur),
+            last_active: now,
+        }
+    }
+
+    /// Returns the reason if the session should be terminated
+    pub fn expired(&mut self) -> Option<&'static str> {
+        let now = Instant::now();
+        if matches!(self.deadline, Some(deadline) if deadline <= now) {
+            return Some("max session duration exceeded");
+        }
+        let bytes = self.traffic.upload() + self.traffic.download();
+        if bytes != self.last_bytes {
+            self.last_bytes = bytes;
+            self.last_active = now;
+            return None;
+        }
+        match self.idle_timeout {
+            Some(timeout) if now.duration_since(self.last_active) >= timeout => {
+                Some("idle timeout")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reader fails with `TimedOut` after the deadline
+///
+/// This interrupts `io::copy` relaying data continuously.
+#[derive(Debug)]
+struct Deadline<R> {
+    inner: R,
+    deadline: Option<Instant>,
+}
+
+impl<R: io::Read> io::Read for Deadline<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.deadline {
+            Some(deadline) if deadline <= Instant::now() => Err(io::ErrorKind::TimedOut.into()),
+            _ => self.inner.read(buf),
+        }
+    }
+}
+
 /// Spawn relay thread(s)
 ///
 /// * `client_addr`
@@ -45,83 +345,631 @@ impl RelayHandle {
 ///    Connection between client and this proxy.
 /// * `server_conn`
 ///    Connection between external host and this proxy.
+/// * `bandwidth`
+///    Bandwidth limit of each direction.
+/// * `traffic`
+///    Counter of relayed bytes.
+/// * `lifetime`
+///    Limits of the lifetime of the relay.
+/// * `buffer_size`
+///    Size of the buffer of each direction (not used by `splice`).
 /// * `rx`
 ///    Relay termination message Receiver.
 ///    It is needed to send 2 messages for terminates 2 relays.
 /// * `guard`
-///    Send `Disconnect` to the main thread when the relay thread is completed.
-pub fn spawn_relay<S>(
+///    Kept until the relay threads are completed,
+///    e.g. `DisconnectGuard` sending `Disconnect` to the main thread.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn_relay<G>(
     client_addr: SocketAddr,
     server_addr: SocketAddr,
     client_conn: BoxedStream,
     server_conn: impl ByteStream,
+    bandwidth: Bandwidth,
+    traffic: Traffic,
+    lifetime: Lifetime,
+    buffer_size: usize,
     rx: Arc<Mutex<mpsc::Receiver<()>>>,
-    guard: Arc<Mutex<DisconnectGuard<S>>>,
+    guard: Arc<G>,
 ) -> Result<RelayHandle, Error>
 where
-    S: Send + 'static,
+    G: Send + Sync + 'static,
 {
-    let (read_client, write_client) = client_conn.split()?;
-    let (read_server, write_server) = server_conn.split()?;
     let thread_shutdown = Arc::new(AtomicBool::new(false));
+    let outbound_watchdog = Watchdog::new(traffic.clone(), lifetime);
+    let incoming_watchdog = Watchdog::new(traffic.clone(), lifetime);
+    let deadline = outbound_watchdog.deadline;
+
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    let halves = splice_halves(&*client_conn, &server_conn, &bandwidth, &traffic, deadline)?;
+    #[cfg(not(all(feature = "splice", target_os = "linux")))]
+    let halves = None;
+    let (outbound, incoming) = match halves {
+        Some(halves) => halves,
+        None => {
+            let (read_client, write_client) = client_conn.split()?;
+            let (read_server, write_server) = server_conn.split()?;
+            let outbound = copy_stream(
+                read_client,
+                Throttle::new(
+                    Counted::new(write_server, traffic.upload.clone()),
+                    bandwidth.upload,
+                ),
+                deadline,
+                buffer_size,
+            );
+            let incoming = copy_stream(
+                read_server,
+                Throttle::new(
+                    Counted::new(write_client, traffic.download.clone()),
+                    bandwidth.download,
+                ),
+                deadline,
+                buffer_size,
+            );
+            (outbound, incoming)
+        }
+    };
 
     let outbound_th = {
         let guard = guard.clone();
         let thread_shutdown = thread_shutdown.clone();
         let rx = rx.clone();
+        let watchdog = outbound_watchdog;
         spawn_thread("outbound", move || {
             let _guard = guard;
             let result = spawn_relay_half(
                 rx,
                 thread_shutdown.clone(),
+                watchdog,
                 client_addr,
                 server_addr,
-                read_client,
-                write_server,
+                outbound,
             );
             thread_shutdown.store(true, Ordering::Relaxed);
             result
         })?
     };
     let incoming_th = {
+        let watchdog = incoming_watchdog;
         spawn_thread("incoming", move || {
             let _guard = guard;
             let result = spawn_relay_half(
                 rx,
                 thread_shutdown.clone(),
+                watchdog,
                 server_addr,
                 client_addr,
-                read_server,
-                write_client,
+                incoming,
+            );
+            thread_shutdown.store(true, Ordering::Relaxed);
+            result
+        })?
+    };
+    Ok(RelayHandle::new(outbound_th, incoming_th, traffic))
+}
+
+/// Spawn UDP relay thread(s)
+///
+/// * `client_addr`
+///    The address of the client of this session.
+/// * `client_udp_addr`
+///    The address the client expects to send datagrams from (`DST.ADDR` of UDP ASSOCIATE).
+///    Unspecified ip address or port (e.g. `0.0.0.0:0`) is substituted by the client's one.
+/// * `client_conn`
+///    Control connection between client and this proxy.
+///    The association is terminated when this connection is closed.
+/// * `pkt_stream`
+///    Packet stream relaying datagrams between the client and external hosts.
+/// * `policy`
+///    Policy for filtering destinations of datagrams.
+/// * `resolver`
+///    Resolver of domain names of destinations.
+/// * `check`
+///    Checks of addresses resolved from domain names.
+/// * `user`
+///    The user authenticated by the client, to which `policy` is applied.
+/// * `reassembly_timeout`
+///    Timeout of reassembling fragmented datagrams from the client.
+///    If `None`, fragmented datagrams are dropped.
+/// * `bandwidth`
+///    Bandwidth limit of each direction.
+/// * `traffic`
+///    Counter of relayed payload bytes.
+/// * `lifetime`
+///    Limits of the lifetime of the association.
+/// * `rx`
+///    Relay termination message Receiver.
+///    It is needed to send 2 messages for terminates 2 relays.
+/// * `guard`
+///    Send `Disconnect` to the main thread when the relay thread is completed.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn_udp_relay<S, P>(
+    client_addr: SocketAddr,
+    client_udp_addr: Address,
+    client_conn: BoxedStream,
+    pkt_stream: P,
+    policy: Arc<dyn ConnectPolicy>,
+    resolver: Arc<dyn Resolver>,
+    check: ResolvedCheck,
+    user: Option<String>,
+    reassembly_timeout: Option<Duration>,
+    bandwidth: Bandwidth,
+    traffic: Traffic,
+    lifetime: Lifetime,
+    rx: Arc<Mutex<mpsc::Receiver<()>>>,
+    guard: Arc<Mutex<DisconnectGuard<S>>>,
+) -> Result<RelayHandle, Error>
+where
+    S: Send + 'static,
+    P: PktStream + 'static,
+{
+    let (read_client, _) = client_conn.split()?;
+    let relay_addr = pkt_stream.local_addr()?;
+    let thread_shutdown = Arc::new(AtomicBool::new(false));
+
+    let control_th = {
+        let guard = guard.clone();
+        let thread_shutdown = thread_shutdown.clone();
+        let rx = rx.clone();
+        let watchdog = Watchdog::new(traffic.clone(), lifetime);
+        spawn_thread("udp control", move || {
+            let _guard = guard;
+            // nothing is expected on the control connection, just wait for closing it.
+            let copy = copy_stream(
+                read_client,
+                io::sink(),
+                watchdog.deadline,
+                DEFAULT_BUFFER_SIZE,
+            );
+            let result = spawn_relay_half(
+                rx,
+                thread_shutdown.clone(),
+                watchdog,
+                client_addr,
+                relay_addr,
+                copy,
+            );
+            thread_shutdown.store(true, Ordering::Relaxed);
+            result
+        })?
+    };
+    let datagram_th = {
+        let watchdog = Watchdog::new(traffic.clone(), lifetime);
+        spawn_thread("udp relay", move || {
+            let _guard = guard;
+            let result = relay_datagrams(
+                rx,
+                thread_shutdown.clone(),
+                client_addr,
+                client_udp_addr,
+                pkt_stream,
+                policy,
+                resolver,
+                check,
+                user,
+                reassembly_timeout,
+                bandwidth,
+                watchdog,
             );
             thread_shutdown.store(true, Ordering::Relaxed);
             result
         })?
     };
-    Ok(RelayHandle::new(outbound_th, incoming_th))
+    Ok(RelayHandle::new(control_th, datagram_th, traffic))
+}
+
+/// Whether the datagram from `src` is sent by the client
+fn from_client(client_addr: SocketAddr, client_udp_addr: &Address, src: SocketAddr) -> bool {
+    let port = client_udp_addr.port();
+    let ip = match client_udp_addr.ip() {
+        Some(ip) if !ip.is_unspecified() => ip,
+        _ => client_addr.ip(),
+    };
+    ip == src.ip() && (port == 0 || port == src.port())
+}
+
+/// External hosts tracked by a UDP association up to this number
+const MAX_UDP_PEERS: usize = 1024;
+/// Datagrams from an external host are relayed within this duration since the client sent to it
+const UDP_PEER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// External hosts the client sent datagrams to, with the time of the last one
+///
+/// The least recently sent host is forgotten beyond `MAX_UDP_PEERS`.
+#[derive(Debug, Default)]
+struct UdpPeers(HashMap<SocketAddr, Instant>);
+
+impl UdpPeers {
+    /// The client sent a datagram to `peer`
+    fn sent(&mut self, peer: SocketAddr) {
+        let now = Instant::now();
+        if self.0.len() >= MAX_UDP_PEERS && !self.0.contains_key(&peer) {
+            self.0
+                .retain(|_, last| now.duration_since(*last) < UDP_PEER_TIMEOUT);
+            if self.0.len() >= MAX_UDP_PEERS {
+                let oldest = self
+                    .0
+                    .iter()
+                    .min_by_key(|(_, last)| **last)
+                    .map(|(p, _)| *p);
+                if let Some(oldest) = oldest {
+                    self.0.remove(&oldest);
+                }
+            }
+        }
+        self.0.insert(peer, now);
+    }
+
+    /// Whether datagrams from `peer` are relayed to the client
+    fn contains(&self, peer: SocketAddr) -> bool {
+        matches!(self.0.get(&peer), Some(last) if last.elapsed() < UDP_PEER_TIMEOUT)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn relay_datagrams(
+    rx: Arc<Mutex<mpsc::Receiver<()>>>,
+    thread_shutdown: Arc<AtomicBool>,
+    client_addr: SocketAddr,
+    client_udp_addr: Address,
+    pkt_stream: impl PktStream,
+    policy: Arc<dyn ConnectPolicy>,
+    resolver: Arc<dyn Resolver>,
+    check: ResolvedCheck,
+    user: Option<String>,
+    reassembly_timeout: Option<Duration>,
+    bandwidth: Bandwidth,
+    mut watchdog: Watchdog,
+) -> Result<(), Error> {
+    let name = thread::current().name().unwrap_or("<anonymous>").to_owned();
+    info!(
+        "spawned relay: {}: {} <=> {}",
+        name,
+        client_udp_addr,
+        pkt_stream.local_addr()?
+    );
+    // fixed when the first datagram from the client arrives
+    let mut client_udp: Option<SocketAddr> = None;
+    let mut peers = UdpPeers::default();
+    let mut buf = vec![0u8; pkt_stream.pkt_size()];
+    let mut out = Vec::with_capacity(pkt_stream.pkt_size());
+    let mut fragments =
+        reassembly_timeout.map(|timeout| Reassembler::new(timeout, pkt_stream.pkt_size()));
+    loop {
+        // also when the session is dropped, e.g. with the server dropped by the application
+        if check_termination(&rx).unwrap_or(true) {
+            info!(
+                "relay thread is requested termination: {}: {}",
+                name, client_udp_addr
+            );
+            return Ok(());
+        }
+        if let Some(reason) = watchdog.expired() {
+            info!(
+                "relay thread is terminated: {}: {}: {}",
+                name, client_udp_addr, reason
+            );
+            return Ok(());
+        }
+        let (size, src) = match pkt_stream.recv_pkt(&mut buf)? {
+            Some(recv) => recv,
+            None => {
+                if thread_shutdown.load(Ordering::Relaxed) {
+                    // the control connection is closed, so finish this association
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        let is_client = match client_udp {
+            Some(addr) => addr == src,
+            None => from_client(client_addr, &client_udp_addr, src),
+        };
+        if is_client {
+            client_udp = Some(src);
+            let datagram = match read_datagram(&buf[..size]) {
+                Ok(datagram) => datagram,
+                Err(err) => {
+                    warn!("invalid datagram: {}: {}", src, err);
+                    continue;
+                }
+            };
+            let datagram = match fragments.as_mut() {
+                Some(fragments) => match fragments.push(datagram) {
+                    Ok(Some(datagram)) => datagram,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        warn!("drop fragmented datagram: {}: {}", src, err);
+                        continue;
+                    }
+                },
+                None if datagram.frag != 0 => {
+                    // reassembly is disabled
+                    debug!("drop fragmented datagram: {}: {}", src, datagram.frag);
+                    continue;
+                }
+                None => datagram,
+            };
+            let ctx = ConnectContext::new(
+                client_addr,
+                user.as_deref(),
+                &datagram.dst_addr,
+                L4Protocol::Udp,
+            );
+            if !policy.check(&ctx).allow {
+                info!("datagram not allowed: {}: {}", src, datagram.dst_addr);
+                continue;
+            }
+            let dst = match resolve_checked(
+                &*resolver,
+                &*policy,
+                check,
+                client_addr,
+                user.as_deref(),
+                &datagram.dst_addr,
+                L4Protocol::Udp,
+            ) {
+                Ok(addrs) if !addrs.is_empty() => addrs[0],
+                Ok(_) => {
+                    info!("datagram not allowed: {}: {}", src, datagram.dst_addr);
+                    continue;
+                }
+                Err(err) => {
+                    info!("name not resolved: {}: {}", datagram.dst_addr, err);
+                    continue;
+                }
+            };
+            peers.sent(dst);
+            trace!(
+                "{}: {} ==> {}: {} bytes",
+                name,
+                src,
+                dst,
+                datagram.data.len()
+            );
+            wait_tokens(&bandwidth.upload, datagram.data.len());
+            match pkt_stream.send_pkt(datagram.data, dst) {
+                Ok(()) => {
+                    watchdog
+                        .traffic
+                        .upload
+                        .fetch_add(datagram.data.len() as u64, Ordering::Relaxed);
+                }
+                Err(err) => warn!("send datagram error: {}: {}", dst, err),
+            }
+        } else if let (Some(client), true) = (client_udp, peers.contains(src)) {
+            out.clear();
+            write_datagram(
+                &mut out,
+                &UdpDatagram {
+                    frag: 0,
+                    dst_addr: src.into(),
+                    data: &buf[..size],
+                },
+            )?;
+            trace!("{}: {} ==> {}: {} bytes", name, src, client, size);
+            wait_tokens(&bandwidth.download, size);
+            match pkt_stream.send_pkt(&out, client) {
+                Ok(()) => {
+                    watchdog
+                        .traffic
+                        .download
+                        .fetch_add(size as u64, Ordering::Relaxed);
+                }
+                Err(err) => warn!("send datagram error: {}: {}", client, err),
+            }
+        } else {
+            debug!("drop datagram from unknown host: {}", src);
+        }
+    }
+}
+
+/// Token bucket limits the number of bytes per second
+///
+/// The bucket holds tokens at most for 1 second.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// bytes/sec
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    /// Return tokens taken but not consumed
+    fn give_back(&mut self, size: usize) {
+        self.tokens = (self.tokens + size as f64).min(self.rate as f64);
+    }
+
+    /// Number of bytes allowed without waiting
+    pub fn available(&mut self) -> usize {
+        self.refill();
+        self.tokens.max(0.0) as usize
+    }
+
+    /// Duration until tokens for `size` bytes are filled
+    pub fn wait_time(&self, size: usize) -> Duration {
+        let lack = size as f64 - self.tokens;
+        Duration::from_secs_f64(lack.max(0.0) / self.rate as f64)
+    }
+
+    /// Consume tokens for `size` bytes without waiting
+    ///
+    /// Tokens may be negative, then following `available` returns `0` until they are refilled.
+    pub fn consume(&mut self, size: usize) {
+        self.tokens -= size as f64;
+    }
+}
+
+/// Writer throttled by token buckets
+#[derive(Debug)]
+struct Throttle<W> {
+    inner: W,
+    /// no buckets means unlimited
+    buckets: Vec<SharedBucket>,
+}
+
+impl<W> Throttle<W> {
+    fn new(inner: W, buckets: Vec<SharedBucket>) -> Self {
+        Self { inner, buckets }
+    }
+}
+
+impl<W: io::Write> io::Write for Throttle<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buckets.is_empty() || buf.is_empty() {
+            return self.inner.write(buf);
+        }
+        let mut taken = Vec::with_capacity(self.buckets.len());
+        let mut size = buf.len();
+        for bucket in &self.buckets {
+            size = take_tokens(bucket, size);
+            taken.push(size);
+        }
+        let result = self.inner.write(&buf[..size]);
+        let written = *result.as_ref().unwrap_or(&0);
+        for (bucket, taken) in self.buckets.iter().zip(taken) {
+            bucket
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .give_back(taken - written);
+        }
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer counts written bytes
+#[derive(Debug)]
+struct Counted<W> {
+    inner: W,
+    count: Arc<AtomicU64>,
+}
+
+impl<W> Counted<W> {
+    fn new(inner: W, count: Arc<AtomicU64>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<W: io::Write> io::Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.count.fetch_add(size as u64, Ordering::Relaxed);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Relays data from a source to a destination
+///
+/// Returns the number of bytes relayed, and `0` means the end of the source.
+type CopyFn = Box<dyn FnMut() -> io::Result<u64> + Send>;
+
+fn copy_stream(
+    src: impl io::Read + Send + 'static,
+    mut dst: impl io::Write + Send + 'static,
+    deadline: Option<Instant>,
+    buffer_size: usize,
+) -> CopyFn {
+    let mut src = Deadline {
+        inner: src,
+        deadline,
+    };
+    let mut buf = vec![0u8; buffer_size];
+    Box::new(move || copy_buffered(&mut src, &mut dst, &mut buf))
+}
+
+/// `io::copy` through `buf`
+fn copy_buffered(
+    src: &mut impl io::Read,
+    dst: &mut impl io::Write,
+    buf: &mut [u8],
+) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let size = match src.read(buf) {
+            Ok(0) => return Ok(total),
+            Ok(size) => size,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        dst.write_all(&buf[..size])?;
+        total += size as u64;
+    }
+}
+
+/// Relay by `splice(2)` if both connections are plain sockets without bandwidth limits
+#[cfg(all(feature = "splice", target_os = "linux"))]
+fn splice_halves(
+    client_conn: &dyn ByteStream,
+    server_conn: &dyn ByteStream,
+    bandwidth: &Bandwidth,
+    traffic: &Traffic,
+    deadline: Option<Instant>,
+) -> Result<Option<(CopyFn, CopyFn)>, Error> {
+    use crate::splice::Splice;
+
+    if !bandwidth.upload.is_empty() || !bandwidth.download.is_empty() {
+        return Ok(None);
+    }
+    let (client_fd, server_fd) = match (client_conn.raw_fd(), server_conn.raw_fd()) {
+        (Some(client_fd), Some(server_fd)) => (client_fd, server_fd),
+        _ => return Ok(None),
+    };
+    let mut outbound = Splice::new(client_fd, server_fd, deadline, traffic.upload.clone())?;
+    let mut incoming = Splice::new(server_fd, client_fd, deadline, traffic.download.clone())?;
+    debug!("relay by splice");
+    Ok(Some((
+        Box::new(move || outbound.copy()),
+        Box::new(move || incoming.copy()),
+    )))
 }
 
 fn spawn_relay_half(
     rx: Arc<Mutex<mpsc::Receiver<()>>>,
     thread_shutdown: Arc<AtomicBool>,
+    mut watchdog: Watchdog,
     src_addr: SocketAddr,
     dst_addr: SocketAddr,
-    mut src: impl io::Read + Send + 'static,
-    mut dst: impl io::Write + Send + 'static,
+    mut copy: CopyFn,
 ) -> Result<(), Error> {
     // thread_name
     let name = thread::current().name().unwrap_or("<anonymous>").to_owned();
     info!("spawned relay: {}: {} ==> {}", name, src_addr, dst_addr);
     loop {
         use io::ErrorKind as K;
-        if check_termination(&rx).expect("main thread must be alive") {
+        // also when the session is dropped, e.g. with the server dropped by the application
+        if check_termination(&rx).unwrap_or(true) {
             info!(
                 "relay thread is requested termination: {} ==> {}",
                 src_addr, dst_addr
             );
             return Ok(());
         }
-        match io::copy(&mut src, &mut dst) {
+        match copy() {
             Ok(0) => {
                 info!(
                     "relay thread has been finished: {}: {} ==> {}",
@@ -135,6 +983,13 @@ fn spawn_relay_half(
                     // the other thread is already terminated, so finish this loop
                     return Ok(());
                 }
+                if let Some(reason) = watchdog.expired() {
+                    info!(
+                        "relay thread is terminated: {}: {} ==> {}: {}",
+                        name, src_addr, dst_addr, reason
+                    );
+                    return Ok(());
+                }
             }
             Err(err) => {
                 return Err(err.into());
@@ -213,6 +1068,10 @@ mod tests {
                 server_addr,
                 dummy_client_conn,
                 dummy_server_conn,
+                Bandwidth::default(),
+                Traffic::default(),
+                Lifetime::default(),
+                DEFAULT_BUFFER_SIZE,
                 rx_relay,
                 guard,
             )
@@ -225,7 +1084,7 @@ mod tests {
         ));
 
         let result = handle.join().unwrap();
-        assert!(matches!(result, Err(e) if e.kind() == &ErrorKind::Io));
+        assert!(matches!(result, Err(e) if matches!(e.kind(), ErrorKind::Io { .. })));
     }
 
     #[test]
@@ -248,6 +1107,7 @@ mod tests {
             wr_buff: server_writer.clone(),
         };
 
+        let traffic = Traffic::default();
         let (tx_relay, rx_relay) = mpsc::channel();
         let (tx_server, rx_server) = mpsc::channel();
         let guard = Arc::new(Mutex::new(DisconnectGuard::<()>::new(0.into(), tx_server)));
@@ -259,6 +1119,10 @@ mod tests {
                 server_addr,
                 dummy_client_conn,
                 dummy_server_conn,
+                Bandwidth::default(),
+                traffic.clone(),
+                Lifetime::default(),
+                DEFAULT_BUFFER_SIZE,
                 rx_relay,
                 guard,
             )
@@ -281,5 +1145,304 @@ mod tests {
             server_writer.lock().unwrap().get_ref().as_slice(),
             &b"hello client"[..]
         );
+        assert_eq!(traffic.upload(), 12);
+        assert_eq!(traffic.download(), 12);
+    }
+
+    #[test]
+    fn relay_outlives_server() {
+        use crate::byte_stream::test::IterBuffer;
+
+        let stream = |data: &[u8]| IterBuffer {
+            iter: vec![data.to_vec()].into_iter(),
+            wr_buff: Arc::new(Mutex::new(io::Cursor::new(vec![]))),
+        };
+        // channels of the session and the server dropped by the application
+        let (tx_relay, rx_relay) = mpsc::channel();
+        let (tx_server, rx_server) = mpsc::channel();
+        let guard = Arc::new(Mutex::new(DisconnectGuard::<()>::new(0.into(), tx_server)));
+        drop(tx_relay);
+        drop(rx_server);
+
+        let handle = spawn_relay(
+            "192.168.1.1:45678".parse().unwrap(),
+            "192.168.1.1:45679".parse().unwrap(),
+            Box::new(stream(b"hello")),
+            stream(b"hello"),
+            Bandwidth::default(),
+            Traffic::default(),
+            Lifetime::default(),
+            DEFAULT_BUFFER_SIZE,
+            Arc::new(Mutex::new(rx_relay)),
+            guard,
+        )
+        .unwrap();
+        // the relay threads terminate without panics
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn standalone_relay() {
+        use std::net::{TcpListener, TcpStream};
+
+        // connected pair of tcp streams
+        let pair = || {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let strm = TcpStream::connect(addr).unwrap();
+            let (accepted, peer) = listener.accept().unwrap();
+            (strm, accepted, peer)
+        };
+        let (mut client, client_end, client_addr) = pair();
+        let (mut server, server_end, server_addr) = pair();
+        let handle = Relay::new()
+            .start(client_end, client_addr, server_end, server_addr)
+            .unwrap();
+
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        server.write_all(b"hi").unwrap();
+        client.read_exact(&mut buf[..2]).unwrap();
+        assert_eq!(&buf[..2], b"hi");
+        let traffic = handle.traffic();
+        assert_eq!((traffic.upload(), traffic.download()), (5, 2));
+
+        // the streams are still open
+        handle.stop();
+        handle.join().unwrap().unwrap();
+
+        // finished by the client
+        let (client, client_end, client_addr) = pair();
+        let (_server, server_end, server_addr) = pair();
+        let handle = Relay::new()
+            .start(client_end, client_addr, server_end, server_addr)
+            .unwrap();
+        drop(client);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn measure_relay_throughput() {
+        use crate::test_util::measure_relay;
+
+        let mut relay = Relay::new();
+        relay.set_buffer_size(1024);
+        let throughput = measure_relay(&relay, 100_000, 300_000).unwrap();
+        assert_eq!(throughput.bytes, 400_000);
+        assert!(throughput.mb_per_sec() > 0.0);
+        assert!(throughput.to_string().starts_with("400000 bytes in "));
+    }
+
+    /// stream never receives data
+    #[derive(Debug, Clone)]
+    struct SilentStream;
+    impl Read for SilentStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(10));
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for SilentStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ByteStream for SilentStream {
+        fn split(&self) -> Result<(Box<dyn Read + Send>, Box<dyn Write + Send>), Error> {
+            Ok((Box::new(self.clone()), Box::new(self.clone())))
+        }
+    }
+
+    #[test]
+    fn shutdown_relay_by_idle_timeout() {
+        use crate::server_command::ServerCommand;
+        use crate::session::SessionId;
+
+        let (_tx_relay, rx_relay) = mpsc::channel();
+        let (tx_server, rx_server) = mpsc::channel();
+        let guard = Arc::new(Mutex::new(DisconnectGuard::<()>::new(0.into(), tx_server)));
+
+        let start = Instant::now();
+        let handle = spawn_relay(
+            "192.168.1.1:45678".parse().unwrap(),
+            "192.168.1.1:45679".parse().unwrap(),
+            Box::new(SilentStream),
+            SilentStream,
+            Bandwidth::default(),
+            Traffic::default(),
+            Lifetime {
+                idle_timeout: Some(Duration::from_millis(100)),
+                max_duration: None,
+            },
+            DEFAULT_BUFFER_SIZE,
+            Arc::new(Mutex::new(rx_relay)),
+            guard,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            rx_server.recv().unwrap(),
+            ServerCommand::Disconnect(SessionId(0))
+        ));
+        handle.join().unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn copy_through_buffer() {
+        /// reader interrupted before each read
+        struct Interrupted<'a>(&'a [u8], bool);
+        impl Read for Interrupted<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.1 = !self.1;
+                if self.1 {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                self.0.read(buf)
+            }
+        }
+
+        let data: Vec<u8> = (0..100).collect();
+        let mut dst = vec![];
+        let mut copy = copy_stream(io::Cursor::new(data.clone()), io::sink(), None, 7);
+        assert_eq!(copy().unwrap(), 100);
+        let mut buf = [0; 7];
+        let size = copy_buffered(&mut Interrupted(&data, false), &mut dst, &mut buf).unwrap();
+        assert_eq!(size, 100);
+        assert_eq!(dst, data);
+    }
+
+    #[test]
+    fn watchdog() {
+        let traffic = Traffic::default();
+        let mut watchdog = Watchdog::new(
+            traffic.clone(),
+            Lifetime {
+                idle_timeout: Some(Duration::from_millis(100)),
+                max_duration: Some(Duration::from_millis(300)),
+            },
+        );
+        assert_eq!(watchdog.expired(), None);
+        thread::sleep(Duration::from_millis(60));
+        traffic.upload.fetch_add(1, Ordering::Relaxed);
+        // activity resets the idle timer
+        assert_eq!(watchdog.expired(), None);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(watchdog.expired(), None);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(watchdog.expired(), Some("idle timeout"));
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(watchdog.expired(), Some("max session duration exceeded"));
+    }
+
+    #[test]
+    fn throttle_write() {
+        let limit = |bps| {
+            Bandwidth::new(Some(RateLimit {
+                upload_bps: bps,
+                download_bps: 0,
+            }))
+        };
+        let mut wr = Throttle::new(vec![], limit(1000).upload);
+        let start = Instant::now();
+        // the first 1000 bytes are sent without waiting
+        wr.write_all(&[0u8; 2500]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(1400));
+        assert_eq!(wr.inner.len(), 2500);
+
+        let mut wr = Throttle::new(vec![], limit(0).upload);
+        let start = Instant::now();
+        wr.write_all(&[0u8; 2500]).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn wait_tokens_for_datagram() {
+        let bandwidth = Bandwidth::new(Some(RateLimit {
+            upload_bps: 1000,
+            download_bps: 0,
+        }));
+        let start = Instant::now();
+        // a datagram larger than the rate consumes tokens of 2 seconds
+        wait_tokens(&bandwidth.upload, 1500);
+        wait_tokens(&bandwidth.upload, 100);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        let start = Instant::now();
+        wait_tokens(&bandwidth.download, 100_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn shared_bandwidth() {
+        let global = Bandwidth::new(Some(RateLimit::symmetric(1000)));
+        let session = |bps| Bandwidth::new(Some(RateLimit::symmetric(bps))).and(&global);
+        // the global limit is tighter than the session's one
+        let mut wr1 = Throttle::new(vec![], session(100_000).upload);
+        let mut wr2 = Throttle::new(vec![], session(100_000).upload);
+        let start = Instant::now();
+        // the first 1000 bytes are sent without waiting
+        wr1.write_all(&[0u8; 1000]).unwrap();
+        wr2.write_all(&[0u8; 500]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(wr1.inner.len() + wr2.inner.len(), 1500);
+
+        // the session limit is tighter than the global one
+        let mut wr = Throttle::new(vec![], session(500).download);
+        let start = Instant::now();
+        wr.write_all(&[0u8; 1000]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn wait_tokens_unlocked() {
+        let bucket: SharedBucket = Arc::new(Mutex::new(TokenBucket::new(1000)));
+        bucket.lock().unwrap().consume(1000);
+        let start = Instant::now();
+        // one waits for the tokens taken by the other, without holding the bucket
+        let ths: Vec<_> = (0..2)
+            .map(|_| {
+                let bucket = bucket.clone();
+                thread::spawn(move || take_tokens(&bucket, 1000))
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(500));
+        assert!(bucket.try_lock().is_ok());
+        for th in ths {
+            assert_eq!(th.join().unwrap(), 1000);
+        }
+        let elapsed = start.elapsed();
+        // 2000 bytes never pass a bucket of 1000 bytes/sec in less than 2 seconds
+        assert!(elapsed >= Duration::from_millis(1900), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2800), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn limit_udp_peers() {
+        let peer = |n: usize| SocketAddr::from(([192, 0, 2, 1], 1000 + n as u16));
+        let mut peers = UdpPeers::default();
+        peers.sent(peer(0));
+        thread::sleep(Duration::from_millis(1));
+        for n in 1..=MAX_UDP_PEERS {
+            peers.sent(peer(n));
+        }
+        // the least recently sent host is forgotten
+        assert_eq!(peers.0.len(), MAX_UDP_PEERS);
+        assert!(!peers.contains(peer(0)));
+        assert!(peers.contains(peer(1)));
+        assert!(peers.contains(peer(MAX_UDP_PEERS)));
+
+        // hosts not sent to for a while are not relayed
+        if let Some(last) = Instant::now().checked_sub(UDP_PEER_TIMEOUT) {
+            peers.0.insert(peer(1), last);
+            assert!(!peers.contains(peer(1)));
+        }
     }
 }
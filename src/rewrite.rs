@@ -0,0 +1,42 @@
+//! Rewriting destinations of connections
+//!
+//! Destinations of CONNECT requests allowed by the policy are passed to a [`ConnectRewriter`]
+//! set by `ServerConfig::set_connect_rewriter`, which may connect to another address instead
+//! (e.g. a service of the mesh, or an internal gateway for plain HTTP).
+//! The rewritten destination is not checked by the policy again,
+//! and the requested one is still reported to audit events and logs.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use gatekeeper::policy::ConnectContext;
+//! use gatekeeper::rewrite::ConnectRewriter;
+//! use gatekeeper::{Address, ServerConfig};
+//!
+//! /// connect to `*.service.local` through the local gateway
+//! #[derive(Debug)]
+//! struct ServiceGateway;
+//!
+//! impl ConnectRewriter for ServiceGateway {
+//!     fn rewrite(&self, ctx: &ConnectContext) -> Option<Address> {
+//!         match ctx.dst {
+//!             Address::Domain(host, _) if host.ends_with(".service.local") => {
+//!                 Some("10.0.0.1:8080".parse().unwrap())
+//!             }
+//!             _ => None,
+//!         }
+//!     }
+//! }
+//!
+//! let mut config = ServerConfig::default();
+//! config.set_connect_rewriter(Some(Arc::new(ServiceGateway)));
+//! ```
+use std::fmt;
+
+use crate::model::Address;
+use crate::policy::ConnectContext;
+
+/// Rewrites destinations before connecting to them
+pub trait ConnectRewriter: fmt::Debug + Send + Sync {
+    /// address to connect to instead of `ctx.dst`, or `None` to connect to `ctx.dst`
+    fn rewrite(&self, ctx: &ConnectContext) -> Option<Address>;
+}
@@ -0,0 +1,203 @@
+//! Cache of rules deciding connections
+//!
+//! With many rules, finding the rule deciding a connection is dominant in the latency of requests.
+//! [`DecisionCache`] keeps the rules decided recent connections in LRU order.
+//! It is enabled by `ConnectRule::set_decision_cache` or `ServerConfig::rule_cache_capacity`.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Mutex, PoisonError};
+
+use crate::model::{Address, ConnectRuleEntry, IpAddr, L4Protocol};
+
+/// What a rule is decided by
+///
+/// The client and the user are included only if any rule has a pattern of them.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct DecisionKey {
+    src: Option<IpAddr>,
+    user: Option<String>,
+    addr: Address,
+    protocol: L4Protocol,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// index of the deciding rule and the time used
+    decided: BTreeMap<DecisionKey, (usize, u64)>,
+    /// keys by the time used
+    used: BTreeMap<u64, DecisionKey>,
+    clock: u64,
+}
+
+impl Entries {
+    fn touch(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+/// Indices of rules deciding recent connections
+///
+/// As the cache is built for a set of rules, it is rebuilt whenever the rules are changed.
+pub(crate) struct DecisionCache {
+    capacity: usize,
+    by_source: bool,
+    by_user: bool,
+    entries: Mutex<Entries>,
+}
+
+impl fmt::Debug for DecisionCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DecisionCache")
+            .field("capacity", &self.capacity)
+            .field("entries", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A clone starts empty, since it may be changed apart from the original rules
+impl Clone for DecisionCache {
+    fn clone(&self) -> Self {
+        Self {
+            entries: Mutex::default(),
+            ..*self
+        }
+    }
+}
+
+impl DecisionCache {
+    /// cache for `rules` keeping up to `capacity` connections
+    pub(crate) fn new(capacity: usize, rules: &[ConnectRuleEntry]) -> Self {
+        let specific = |f: fn(&ConnectRuleEntry) -> bool| rules.iter().any(f);
+        Self {
+            capacity,
+            by_source: specific(|rule| rule.sum(|pat| !pat.source.is_any())),
+            by_user: specific(|rule| rule.sum(|pat| !pat.user.is_any())),
+            entries: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// number of cached connections
+    pub(crate) fn len(&self) -> usize {
+        self.lock().decided.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn key(
+        &self,
+        src: Option<&Address>,
+        user: Option<&str>,
+        addr: &Address,
+        protocol: L4Protocol,
+    ) -> DecisionKey {
+        let src = src.and_then(Address::ip).filter(|_| self.by_source);
+        DecisionKey {
+            src,
+            user: user.filter(|_| self.by_user).map(str::to_owned),
+            addr: addr.clone(),
+            protocol,
+        }
+    }
+
+    /// index of the rule decided `key`
+    pub(crate) fn get(&self, key: &DecisionKey) -> Option<usize> {
+        let mut entries = self.lock();
+        let now = entries.touch();
+        let Entries { decided, used, .. } = &mut *entries;
+        let (index, last) = decided.get_mut(key)?;
+        let key = used.remove(last).expect("DecisionCache::used");
+        used.insert(now, key);
+        *last = now;
+        Some(*index)
+    }
+
+    /// remember the rule of `index` decided `key`, evicting the least recently used one if full
+    pub(crate) fn insert(&self, key: DecisionKey, index: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.lock();
+        let now = entries.touch();
+        let Entries { decided, used, .. } = &mut *entries;
+        if let Some((_, last)) = decided.get(&key) {
+            used.remove(last);
+        } else if decided.len() >= self.capacity {
+            if let Some((_, oldest)) = used.pop_first() {
+                decided.remove(&oldest);
+            }
+        }
+        used.insert(now, key.clone());
+        decided.insert(key, (index, now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{AddressPattern, ConnectRulePattern, RulePattern};
+
+    fn cache(capacity: usize) -> DecisionCache {
+        DecisionCache::new(
+            capacity,
+            &[ConnectRuleEntry::Allow(ConnectRulePattern::any())],
+        )
+    }
+
+    fn key(cache: &DecisionCache, addr: &str) -> DecisionKey {
+        cache.key(None, None, &addr.parse().unwrap(), L4Protocol::Tcp)
+    }
+
+    #[test]
+    fn least_recently_used() {
+        let cache = cache(2);
+        cache.insert(key(&cache, "192.0.2.1:80"), 1);
+        cache.insert(key(&cache, "192.0.2.2:80"), 2);
+        assert_eq!(cache.get(&key(&cache, "192.0.2.1:80")), Some(1));
+        // "192.0.2.2:80" is evicted as it is used less recently
+        cache.insert(key(&cache, "192.0.2.3:80"), 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key(&cache, "192.0.2.2:80")), None);
+        assert_eq!(cache.get(&key(&cache, "192.0.2.1:80")), Some(1));
+        assert_eq!(cache.get(&key(&cache, "192.0.2.3:80")), Some(3));
+
+        cache.insert(key(&cache, "192.0.2.3:80"), 0);
+        assert_eq!(cache.get(&key(&cache, "192.0.2.3:80")), Some(0));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.clone().len(), 0);
+    }
+
+    #[test]
+    fn key_by_patterns() {
+        let src: Address = "192.168.0.2:5000".parse().unwrap();
+        let dst: Address = "192.0.2.1:80".parse().unwrap();
+        let cache = cache(10);
+        assert_eq!(
+            cache.key(Some(&src), Some("alice"), &dst, L4Protocol::Tcp),
+            cache.key(None, None, &dst, L4Protocol::Tcp)
+        );
+
+        let rules = [
+            ConnectRuleEntry::Allow(ConnectRulePattern::any()),
+            ConnectRuleEntry::Deny(ConnectRulePattern::with_source(
+                RulePattern::Specif(AddressPattern::IpAddr {
+                    addr: "192.168.0.0".parse().unwrap(),
+                    prefix: 16,
+                }),
+                RulePattern::Any,
+                RulePattern::Any,
+                RulePattern::Any,
+            )),
+        ];
+        let cache = DecisionCache::new(10, &rules);
+        let key = cache.key(Some(&src), Some("alice"), &dst, L4Protocol::Tcp);
+        assert_eq!(key.src, Some("192.168.0.2".parse().unwrap()));
+        assert_eq!(key.user, None);
+    }
+}
@@ -22,6 +22,32 @@ trait ReadSocksExt {
     fn read_atyp(&mut self) -> Result<AddrType, Error>;
     fn read_addr(&mut self, atyp: AddrType) -> Result<Addr, Error>;
     fn read_udp(&mut self) -> Result<UdpHeader, Error>;
+    fn read_bytes_u8(&mut self) -> Result<Vec<u8>, Error>;
+}
+
+/// Parse ATYP, which is `AddrTypeNotSupported` if unknown
+pub(crate) fn parse_atyp(atyp: u8) -> Result<AddrType, Error> {
+    TryInto::<AddrType>::try_into(atyp).map_err(|_| {
+        ErrorKind::addr_type_not_supported(format_args!("unknown atyp: {:#04x}", atyp)).into()
+    })
+}
+
+/// Check a domain name in a message
+///
+/// The name must be a non-empty string without whitespaces or control characters.
+pub(crate) fn check_domain(domain: &[u8]) -> Result<(), Error> {
+    let name = std::str::from_utf8(domain).map_err(|_| {
+        ErrorKind::addr_type_not_supported(format_args!("domain is not utf-8: {:?}", domain))
+    })?;
+    if name.is_empty() {
+        return Err(ErrorKind::addr_type_not_supported(format_args!("empty domain")).into());
+    }
+    if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(
+            ErrorKind::addr_type_not_supported(format_args!("invalid domain: {:?}", name)).into(),
+        );
+    }
+    Ok(())
 }
 
 #[allow(unused)]
@@ -35,6 +61,7 @@ trait WriteSocksExt {
     fn write_methods(&mut self, nmethods: &[AuthMethods]) -> Result<(), Error>;
     fn write_rep(&mut self, rep: ResponseCode) -> Result<(), Error>;
     fn write_udp(&mut self, header: &UdpHeader) -> Result<(), Error>;
+    fn write_bytes_u8(&mut self, bytes: &[u8]) -> Result<(), Error>;
 }
 
 impl<T> ReadSocksExt for T
@@ -74,7 +101,8 @@ where
     }
 
     fn read_rep(&mut self) -> Result<ResponseCode, Error> {
-        let rep = ResponseCode::from_u8(self.read_u8()?).context(ErrorKind::Io)?;
+        let rep = ResponseCode::from_u8(self.read_u8()?)
+            .context(ErrorKind::message_fmt(format_args!("ConnectReply::rep")))?;
         Ok(rep)
     }
 
@@ -85,9 +113,7 @@ where
     }
 
     fn read_atyp(&mut self) -> Result<AddrType, Error> {
-        let atyp = TryInto::<AddrType>::try_into(self.read_u8()?)
-            .context(ErrorKind::message_fmt(format_args!("ConnectRequest::atyp")))?;
-        Ok(atyp)
+        parse_atyp(self.read_u8()?)
     }
 
     fn read_addr(&mut self, atyp: AddrType) -> Result<Addr, Error> {
@@ -104,6 +130,7 @@ where
                 let len = self.read_u8()? as usize;
                 let mut buf = vec![0u8; len];
                 self.read_exact(&mut buf)?;
+                check_domain(&buf)?;
                 Ok(Addr::Domain(buf))
             }
             V6 => {
@@ -138,6 +165,14 @@ where
             dst_port,
         })
     }
+
+    /// read bytes prefixed with its length (1 byte)
+    fn read_bytes_u8(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.read_u8()? as usize;
+        let mut buf = vec![0u8; len];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
 }
 
 impl<T> WriteSocksExt for T
@@ -201,6 +236,18 @@ where
         self.write_u16(header.dst_port)?;
         Ok(())
     }
+    fn write_bytes_u8(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if bytes.len() > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("too long bytes: {}", bytes.len()),
+            )
+            .into());
+        }
+        self.write_u8(bytes.len() as u8)?;
+        self.write_all(bytes)?;
+        Ok(())
+    }
 }
 
 /// Wrapper of Read/Write stream
@@ -277,10 +324,24 @@ where
         self.strm.write_all(&buf[..pos])?;
         Ok(())
     }
+
+    fn recv_user_pass_request(&mut self) -> Result<model::UserPassRequest, Error> {
+        trace!("recv_user_pass_request");
+        let ver = self.strm.read_u8()?;
+        let uname = self.strm.read_bytes_u8()?;
+        let passwd = self.strm.read_bytes_u8()?;
+        Ok(raw::UserPassRequest { ver, uname, passwd }.into())
+    }
+
+    fn send_user_pass_reply(&mut self, reply: model::UserPassReply) -> Result<(), Error> {
+        trace!("send_user_pass_reply: {:?}", reply);
+        let reply: raw::UserPassReply = reply.into();
+        self.strm.write_all(&[reply.ver, reply.status])?;
+        Ok(())
+    }
 }
 
 /// Parse socks5 udp header expected for UDP_ASSOCIATE-d socket
-#[allow(dead_code)]
 pub fn read_datagram(buf: &[u8]) -> Result<model::UdpDatagram<'_>, model::Error> {
     let mut cur = io::Cursor::new(buf);
     let header = cur.read_udp()?;
@@ -294,6 +355,16 @@ pub fn read_datagram(buf: &[u8]) -> Result<model::UdpDatagram<'_>, model::Error>
     })
 }
 
+/// Emit socks5 udp header followed by the data to `buf`
+pub fn write_datagram(
+    buf: &mut Vec<u8>,
+    datagram: &model::UdpDatagram<'_>,
+) -> Result<(), model::Error> {
+    buf.write_udp(&datagram.into())?;
+    buf.extend_from_slice(datagram.data);
+    Ok(())
+}
+
 pub struct ReadWriteStream<T> {
     strm: T,
 }
@@ -338,18 +409,23 @@ where
     fn send_connect_reply(&mut self, connect_reply: model::ConnectReply) -> Result<(), Error> {
         self.rw_stream().send_connect_reply(connect_reply)
     }
+    fn recv_user_pass_request(&mut self) -> Result<model::UserPassRequest, Error> {
+        self.rw_stream().recv_user_pass_request()
+    }
+    fn send_user_pass_reply(&mut self, reply: model::UserPassReply) -> Result<(), Error> {
+        self.rw_stream().send_user_pass_reply(reply)
+    }
 }
 
-#[cfg(test)]
-pub mod test {
+/// Client side of the socks5 protocol
+pub(crate) mod client {
     use super::*;
-    use crate::byte_stream::test::BufferStream;
 
     pub fn write_method_candidates<T: io::Write>(
         mut strm: T,
         cand: model::MethodCandidates,
     ) -> Result<(), Error> {
-        trace!("recv_method_candidates");
+        trace!("write_method_candidates");
         let cand: raw::MethodCandidates = cand.into();
         strm.write_version(cand.ver)?;
         strm.write_methods(cand.methods.as_ref())?;
@@ -360,7 +436,7 @@ pub mod test {
         mut strm: T,
         req: model::ConnectRequest,
     ) -> Result<(), Error> {
-        trace!("recv_connect_request");
+        trace!("write_connect_request");
         let req: raw::ConnectRequest = req.into();
         strm.write_version(req.ver)?;
         strm.write_cmd(req.cmd)?;
@@ -371,6 +447,25 @@ pub mod test {
         Ok(())
     }
 
+    pub fn write_user_pass_request<T: io::Write>(
+        mut strm: T,
+        req: model::UserPassRequest,
+    ) -> Result<(), Error> {
+        trace!("write_user_pass_request");
+        let req: raw::UserPassRequest = req.into();
+        strm.write_u8(req.ver)?;
+        strm.write_bytes_u8(&req.uname)?;
+        strm.write_bytes_u8(&req.passwd)?;
+        Ok(())
+    }
+
+    pub fn read_user_pass_reply<T: io::Read>(mut strm: T) -> Result<model::UserPassReply, Error> {
+        trace!("read_user_pass_reply");
+        let ver = strm.read_u8()?;
+        let status = strm.read_u8()?;
+        Ok(raw::UserPassReply { ver, status }.into())
+    }
+
     pub fn read_method_selection<T: io::Read>(
         mut strm: T,
     ) -> Result<model::MethodSelection, Error> {
@@ -399,6 +494,13 @@ pub mod test {
         .try_into()
         .map_err(Into::into)
     }
+}
+
+#[cfg(test)]
+pub mod test {
+    pub use super::client::*;
+    use super::*;
+    use crate::byte_stream::test::BufferStream;
 
     #[derive(Debug, Clone, PartialEq, Eq)]
     struct Prim {
@@ -593,6 +695,13 @@ pub mod test {
             server_addr: Address::Domain("example.com".into(), 8335),
         })
         .unwrap();
+        // the scope can not be sent
+        strm.send_connect_reply(ConnectReply {
+            version: 5.into(),
+            connect_result: Ok(()),
+            server_addr: "[fe80::1%2]:1080".parse().unwrap(),
+        })
+        .unwrap();
 
         let inner = strm.into_inner();
         // consumed all bytes
@@ -612,6 +721,9 @@ pub mod test {
                 ]
                 .iter(),
             )
+            .chain([5, 0, 0, 4, 0xfe, 0x80].iter())
+            .chain([0; 13].iter())
+            .chain([1, 0x4, 0x38].iter())
             .cloned()
             .collect();
         assert_eq!(inner.wr_buff.lock().unwrap().clone().into_inner(), out_exp);
@@ -620,4 +732,71 @@ pub mod test {
             out_exp.len() as u64
         );
     }
+
+    /// `recv_connect_request` of `bytes`
+    fn connect_request(bytes: &[u8]) -> Result<model::ConnectRequest, Error> {
+        ReadWriteStream::new(BufferStream::with_buffer(
+            bytes.to_vec().into(),
+            vec![].into(),
+        ))
+        .recv_connect_request()
+    }
+
+    #[test]
+    fn malformed_address() {
+        let not_supported = |bytes: &[u8]| {
+            matches!(
+                connect_request(bytes).unwrap_err().kind(),
+                ErrorKind::AddrTypeNotSupported { .. }
+            )
+        };
+        for atyp in (0..=u8::MAX).filter(|atyp| ![1, 3, 4].contains(atyp)) {
+            assert!(
+                not_supported(&[5, 1, 0, atyp, 1, 2, 3, 4, 0, 80]),
+                "{}",
+                atyp
+            );
+        }
+        // empty domain
+        assert!(not_supported(&[5, 1, 0, 3, 0, 0, 80]));
+        // not utf-8
+        assert!(not_supported(&[5, 1, 0, 3, 2, 0xff, 0xfe, 0, 80]));
+        // control characters
+        assert!(not_supported(&[5, 1, 0, 3, 3, b'a', 0, b'b', 0, 80]));
+        assert!(not_supported(b"\x05\x01\x00\x03\x04a b\n\x00\x50"));
+        // truncated domain
+        assert!(matches!(
+            connect_request(&[5, 1, 0, 3, 10, b'a', b'b'])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Io { .. }
+        ));
+        assert_eq!(
+            connect_request(&[5, 1, 0, 3, 1, b'a', 0, 80]).unwrap(),
+            model::ConnectRequest::connect_to(model::Address::Domain("a".into(), 80))
+        );
+    }
+
+    #[test]
+    fn fuzz_connect_request() {
+        use rand::prelude::*;
+        let mut rng = StdRng::seed_from_u64(0x50c5);
+        for _ in 0..10000 {
+            let len = rng.gen_range(0..32);
+            let mut bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            // make the header valid in most cases to reach the address
+            if len >= 4 && rng.gen_bool(0.9) {
+                bytes[..3].copy_from_slice(&[5, 1, 0]);
+            }
+            match connect_request(&bytes) {
+                Ok(req) => assert!(matches!(bytes[3], 1 | 3 | 4), "{:?}: {:?}", bytes, req),
+                Err(err) => match err.kind() {
+                    ErrorKind::Io { .. }
+                    | ErrorKind::MessageFormat { .. }
+                    | ErrorKind::AddrTypeNotSupported { .. } => {}
+                    kind => panic!("{:?}: {:?}", bytes, kind),
+                },
+            }
+        }
+    }
 }
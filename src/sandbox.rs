@@ -0,0 +1,204 @@
+//! Sandboxing of gatekeeperd
+//!
+//! With `--sandbox`, gatekeeperd restricts itself after binding the listening sockets
+//! and switching to `--user`, before serving clients:
+//!
+//! - Landlock allows only reading the system libraries and configurations (e.g. to resolve domains)
+//!   and the directories of the rule files, and removing the unix sockets given.
+//! - A seccomp filter allows only system calls used to relay connections (e.g. `socket`,
+//!   `connect`, `accept4`, `read` and `write`), and others fail with `EPERM`.
+//!   Since `bind` and `listen` are not allowed, `BIND` and `UDP ASSOCIATE` commands fail.
+//!
+//! The seccomp filter restricts all threads of the process, while landlock restricts
+//! the calling thread and threads spawned by it, so [`restrict`] should be called
+//! before spawning threads reading files (e.g. to reload rules).
+//! Sandboxing is available on Linux with the `sandbox` feature.
+use std::path::Path;
+
+/// Restrict the process to read files in `read_dirs` and remove sockets in `socket_dirs`
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+pub fn restrict(read_dirs: &[&Path], socket_dirs: &[&Path]) -> Result<(), String> {
+    filesystem::restrict(read_dirs, socket_dirs)?;
+    syscalls::restrict()
+}
+
+#[cfg(not(all(target_os = "linux", feature = "sandbox")))]
+pub fn restrict(_read_dirs: &[&Path], _socket_dirs: &[&Path]) -> Result<(), String> {
+    Err("gatekeeperd is built without the sandbox feature".to_owned())
+}
+
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+mod filesystem {
+    use std::path::Path;
+
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+    use log::*;
+
+    /// directories read by the system (e.g. shared libraries loaded to resolve domains)
+    const SYSTEM_DIRS: &[&str] = &["/etc", "/lib", "/lib64", "/usr", "/dev/urandom"];
+
+    pub fn restrict(read_dirs: &[&Path], socket_dirs: &[&Path]) -> Result<(), String> {
+        let abi = ABI::V3;
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))
+            .and_then(|ruleset| ruleset.create())
+            .and_then(|ruleset| {
+                ruleset.add_rules(path_beneath_rules(SYSTEM_DIRS, AccessFs::from_read(abi)))
+            })
+            .and_then(|ruleset| {
+                ruleset.add_rules(path_beneath_rules(read_dirs, AccessFs::ReadFile))
+            })
+            .and_then(|ruleset| {
+                ruleset.add_rules(path_beneath_rules(socket_dirs, AccessFs::RemoveFile))
+            })
+            .and_then(|ruleset| ruleset.restrict_self())
+            .map_err(|err| format!("landlock: {}", err))?;
+        match status.ruleset {
+            RulesetStatus::FullyEnforced => info!("landlock: enforced"),
+            RulesetStatus::PartiallyEnforced => warn!("landlock: partially enforced"),
+            RulesetStatus::NotEnforced => warn!("landlock: not supported by the kernel"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+mod syscalls {
+    use std::collections::BTreeMap;
+
+    use log::*;
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+
+    /// system calls allowed on any architecture
+    const SYSCALLS: &[libc::c_long] = &[
+        // io
+        libc::SYS_read,
+        libc::SYS_readv,
+        libc::SYS_pread64,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_openat,
+        libc::SYS_close,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        libc::SYS_splice,
+        libc::SYS_unlinkat,
+        libc::SYS_readlinkat,
+        libc::SYS_faccessat,
+        libc::SYS_faccessat2,
+        libc::SYS_fchmodat,
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_eventfd2,
+        // sockets
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_connect,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_shutdown,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        // memory
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        // threads and signals
+        libc::SYS_clone,
+        libc::SYS_clone3,
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_prctl,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        // misc
+        libc::SYS_getrandom,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_uname,
+        libc::SYS_prlimit64,
+    ];
+
+    /// system calls allowed only on x86_64, replaced by the others on newer architectures
+    #[cfg(target_arch = "x86_64")]
+    const LEGACY_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_open,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_access,
+        libc::SYS_poll,
+        libc::SYS_select,
+        libc::SYS_epoll_wait,
+        libc::SYS_pipe,
+        libc::SYS_dup2,
+        libc::SYS_chmod,
+        libc::SYS_unlink,
+        libc::SYS_readlink,
+        libc::SYS_arch_prctl,
+    ];
+
+    #[cfg(not(target_arch = "x86_64"))]
+    const LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+    pub fn restrict() -> Result<(), String> {
+        let rules = SYSCALLS
+            .iter()
+            .chain(LEGACY_SYSCALLS)
+            .map(|&syscall| (syscall, vec![]))
+            .collect::<BTreeMap<_, _>>();
+        let arch = std::env::consts::ARCH
+            .try_into()
+            .map_err(|err| format!("seccomp: {}", err))?;
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Errno(libc::EPERM as u32),
+            SeccompAction::Allow,
+            arch,
+        )
+        .map_err(|err| format!("seccomp: {}", err))?;
+        let program: BpfProgram = filter
+            .try_into()
+            .map_err(|err| format!("seccomp: {}", err))?;
+        // threads spawned before (e.g. acceptors) are restricted too
+        seccompiler::apply_filter_all_threads(&program)
+            .map_err(|err| format!("seccomp: {}", err))?;
+        info!("seccomp: enforced");
+        Ok(())
+    }
+}
@@ -64,28 +64,43 @@
 //!   |                          |
 //! ```
 use std::collections::HashMap;
-use std::net::TcpStream;
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::sync::{
-    mpsc::{self, Receiver, Sender, SyncSender},
+    mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender},
     Arc, Mutex,
 };
 use std::thread;
+use std::time::Duration;
 
 use log::*;
 use rand::prelude::*;
 
-use crate::acceptor::{Binder, TcpBinder};
-use crate::auth_service::{AuthService, NoAuthService};
+use crate::accept_queue::{AcceptQueue, Admission, ReplySlot};
+#[cfg(unix)]
+use crate::acceptor::UnixBinder;
+use crate::acceptor::{canonical_peer, Binder, TcpBinder};
+use crate::auth_service::{AuthService, ConfigAuthService};
 use crate::byte_stream::ByteStream;
+use crate::client_ban::is_failed_attempt;
 use crate::config::ServerConfig;
 use crate::connector::{Connector, TcpUdpConnector};
+use crate::destination_limiter::{DestinationLimiter, DestinationSlot};
 use crate::error::Error;
-use crate::model::{ProtocolVersion, SocketAddr};
+use crate::event::SessionFinishedEvent;
+use crate::metrics::{Metrics, Outcome, ServerStatus};
+use crate::model::{Address, ConnectError, ProtocolVersion, SocketAddr};
+use crate::policy::ConnectPolicy;
+use crate::relay::{Bandwidth, Lifetime};
 use crate::server_command::ServerCommand;
-use crate::session::{Session, SessionHandle, SessionId};
+use crate::session::{
+    reject_client, reject_http_client, Session, SessionHandle, SessionId, SessionInfo, SessionStats,
+};
+use crate::shared::SharedContext;
 use crate::thread::spawn_thread;
 
-pub struct Server<S, T, C> {
+pub struct Server<S, T, C, A = ConfigAuthService> {
     config: ServerConfig,
     tx_cmd: Sender<ServerCommand<S>>,
     rx_cmd: Receiver<ServerCommand<S>>,
@@ -93,18 +108,63 @@ pub struct Server<S, T, C> {
     binder: T,
     /// send termination message to the acceptor
     tx_acceptor_done: SyncSender<()>,
-    /// make connection to service host
-    connector: C,
+    /// make connection to service host, and resources shared with other servers
+    shared: SharedContext<C>,
+    /// authenticate clients
+    auth_service: A,
     protocol_version: ProtocolVersion,
     session: HashMap<SessionId, SessionHandle>,
+    /// sessions through rules with `max_sessions`, released on `Disconnect`
+    destination_limiter: Arc<DestinationLimiter>,
+    /// connections sent by acceptors and not taken yet
+    accept_queue: Arc<AcceptQueue>,
+    /// policy shared by sessions, built again when the rule is reloaded
+    policy: Arc<dyn ConnectPolicy>,
     /// random context for generating SessionIds
     id_rng: StdRng,
+    /// sockets bound by `bind` and not accepted by acceptors yet
+    bound: Vec<(BoundAcceptor<S>, ClientProtocol)>,
+    /// acceptor threads spawned by `start`
+    accept_th: Vec<thread::JoinHandle<()>>,
+    phase: Phase,
+}
+
+/// Connections accepted by a socket bound by `Server::bind`
+type BoundAcceptor<S> = Box<dyn Iterator<Item = (S, SocketAddr)> + Send>;
+
+/// Phase of the server main loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    NotStarted,
+    /// the listening sockets have been bound by `bind`
+    Bound,
+    Running,
+    /// the acceptor has been stopped by `Shutdown`
+    Draining,
+    Stopped,
+}
+
+/// What clients of an acceptor speak
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ClientProtocol {
+    Socks,
+    /// HTTP CONNECT
+    Http,
+    /// nothing, connections are forwarded to the address (`ServerConfig::forwards`)
+    Forward(Address),
 }
 
 /// spawn a thread send accepted stream to `tx`
+///
+/// Streams are sent by `Connect`, `ConnectHttp` or `ConnectForward` command by `protocol`.
+/// Streams overflowing `queue` are replied `ServerFailure` by `reply` while reply slots of
+/// `queue` are left, and closed at once otherwise.
 fn spawn_acceptor<S>(
     acceptor: impl Iterator<Item = (S, SocketAddr)> + Send + 'static,
     tx: Sender<ServerCommand<S>>,
+    protocol: ClientProtocol,
+    queue: Arc<AcceptQueue>,
+    reply: RejectReply,
 ) -> Result<thread::JoinHandle<()>, Error>
 where
     S: ByteStream + 'static,
@@ -112,7 +172,31 @@ where
     use ServerCommand::*;
     Ok(spawn_thread("acceptor", move || {
         for (strm, addr) in acceptor {
-            if tx.send(Connect(strm, addr)).is_err() {
+            let addr = canonical_peer(addr);
+            match queue.push() {
+                Admission::Admit => {}
+                Admission::Full => {
+                    match queue.reply_slot() {
+                        Some(slot) => {
+                            warn!("accept queue is full, reject connection: {}", addr);
+                            let cerr = ConnectError::ServerFailure;
+                            spawn_reject(strm, addr, cerr, &protocol, reply, slot);
+                        }
+                        None => {
+                            warn!("accept queue is full, close connection: {}", addr);
+                            drop(strm);
+                        }
+                    }
+                    continue;
+                }
+                Admission::Closed => break,
+            }
+            let cmd = match &protocol {
+                ClientProtocol::Socks => Connect(strm, addr),
+                ClientProtocol::Http => ConnectHttp(strm, addr),
+                ClientProtocol::Forward(to) => ConnectForward(strm, addr, to.clone()),
+            };
+            if tx.send(cmd).is_err() {
                 info!("disconnected ServerCommand chan");
                 break;
             }
@@ -120,6 +204,53 @@ where
     })?)
 }
 
+/// Parameters to reply to clients rejected without sessions
+#[derive(Debug, Clone, Copy)]
+struct RejectReply {
+    version: ProtocolVersion,
+    server_addr: SocketAddr,
+    handshake_timeout: Option<Duration>,
+}
+
+/// spawn a thread reply `cerr` to the client connected by `stream`
+///
+/// The client has to send its request within `handshake_timeout`.
+/// Forwarded connections are just closed, as there is no reply to them.
+/// `slot` is held until the reply finishes.
+fn spawn_reject<S: ByteStream + 'static>(
+    stream: S,
+    addr: SocketAddr,
+    cerr: ConnectError,
+    protocol: &ClientProtocol,
+    reply: RejectReply,
+    slot: ReplySlot,
+) {
+    let http = match protocol {
+        ClientProtocol::Socks => false,
+        ClientProtocol::Http => true,
+        ClientProtocol::Forward(_) => return,
+    };
+    let RejectReply {
+        version,
+        server_addr,
+        handshake_timeout,
+    } = reply;
+    let res = spawn_thread(&format!("reject: {}", addr), move || {
+        let _slot = slot;
+        let res = if http {
+            reject_http_client(stream, cerr, handshake_timeout)
+        } else {
+            reject_client(version, server_addr, stream, cerr, handshake_timeout)
+        };
+        if let Err(err) = res {
+            debug!("reject error: {}: {}", addr, err);
+        }
+    });
+    if let Err(err) = res {
+        error!("spawn thread error: {}: {}", addr, err);
+    }
+}
+
 /// spawn a thread perform `Session.start`
 ///
 ///
@@ -142,25 +273,134 @@ where
     D: Connector + 'static,
     M: AuthService + 'static,
 {
-    let session_th = spawn_thread(&format!("{}: {}", session.id, addr), move || {
+    let id = session.id;
+    let traffic = session.traffic();
+    let destination = session.destination();
+    let session_th = spawn_thread(&format!("{}: {}", id, addr), move || {
         session.start(addr, strm)
     })
     .unwrap();
-    SessionHandle::new(addr, session_th, tx)
+    SessionHandle::new(id, addr, session_th, tx, traffic, destination)
+}
+
+/// Binder of `listen_addrs` of `config`, and the sender of termination messages to its acceptors
+fn tcp_binder(config: &ServerConfig) -> (TcpBinder, SyncSender<()>) {
+    // a termination message for each acceptor
+    let (tx_done, rx_done) = mpsc::sync_channel(config.acceptor_count());
+    let mut binder = TcpBinder::new(
+        config.client_rw_timeout,
+        Arc::new(Mutex::new(rx_done)),
+        config.accept_timeout,
+    );
+    binder.set_tcp_keepalive(config.tcp_keepalive);
+    (binder, tx_done)
 }
 
 impl Server<TcpStream, TcpBinder, TcpUdpConnector> {
     pub fn new(config: ServerConfig) -> (Self, mpsc::Sender<ServerCommand<TcpStream>>) {
+        let auth_service = ConfigAuthService::new(config.credentials.clone());
+        Self::with_auth_service(config, auth_service)
+    }
+
+    /// Server accepting clients on `listeners` passed by the parent process (e.g. by [`listen_fds`])
+    ///
+    /// Listeners bound to `ServerConfig::http_connect_addr` or addresses of `ServerConfig::forwards`
+    /// are used for them, and the others replace `ServerConfig::listen_addrs`.
+    /// Addresses without a listener are bound as usual.
+    ///
+    /// [`listen_fds`]: crate::acceptor::listen_fds
+    pub fn with_listeners(
+        mut config: ServerConfig,
+        listeners: Vec<TcpListener>,
+    ) -> Result<(Self, mpsc::Sender<ServerCommand<TcpStream>>), Error> {
+        let mut socks_addrs = vec![];
+        for listener in &listeners {
+            let addr = listener.local_addr()?;
+            let other = config.http_connect_addr == Some(addr)
+                || config.forwards.iter().any(|forward| forward.listen == addr);
+            if !other {
+                socks_addrs.push(addr);
+            }
+        }
+        config.set_listen_addrs(&socks_addrs);
+        let (mut binder, tx_done) = tcp_binder(&config);
+        binder.set_inherited_listeners(listeners);
+        let auth_service = ConfigAuthService::new(config.credentials.clone());
+        let connector = TcpUdpConnector::from_config(&config);
+        Ok(Self::with_binder_and_auth_service(
+            config,
+            binder,
+            tx_done,
+            connector,
+            auth_service,
+        ))
+    }
+
+    /// Server sharing the connector and resources of `shared` with other servers
+    ///
+    /// `ServerConfig::global_rate_limit` and `ServerConfig::connection_rate_limit` of `config`
+    /// are not used, but those of the configuration `shared` is created with.
+    pub fn with_shared_context(
+        config: ServerConfig,
+        shared: &SharedContext,
+    ) -> (Self, mpsc::Sender<ServerCommand<TcpStream>>) {
+        let (binder, tx_done) = tcp_binder(&config);
+        let auth_service = ConfigAuthService::new(config.credentials.clone());
+        Self::with_binder_and_shared_context(config, binder, tx_done, shared.clone(), auth_service)
+    }
+}
+
+#[cfg(unix)]
+impl Server<UnixStream, UnixBinder, TcpUdpConnector> {
+    /// Server listening on the unix domain socket `ServerConfig::unix_socket`
+    ///
+    /// Clients are seen as [`UNIX_CLIENT_ADDR`](crate::acceptor::UNIX_CLIENT_ADDR),
+    /// and tcp addresses of `config` are not listened on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ServerConfig::unix_socket` is not set.
+    pub fn with_unix_socket(
+        mut config: ServerConfig,
+    ) -> (Self, mpsc::Sender<ServerCommand<UnixStream>>) {
+        let path = config
+            .unix_socket
+            .clone()
+            .expect("ServerConfig::unix_socket is not set");
+        // the socket is bound once for the server address
+        config.additional_addrs.clear();
+        config.http_connect_addr = None;
+        config.forwards.clear();
         let (tx_done, rx_done) = mpsc::sync_channel(1);
-        Server::<TcpStream, TcpBinder, TcpUdpConnector>::with_binder(
+        let binder = UnixBinder::new(
+            path,
+            config.client_rw_timeout,
+            Arc::new(Mutex::new(rx_done)),
+            config.accept_timeout,
+        );
+        let connector = TcpUdpConnector::from_config(&config);
+        Self::with_binder(config, binder, tx_done, connector)
+    }
+}
+
+impl<A> Server<TcpStream, TcpBinder, TcpUdpConnector, A>
+where
+    A: AuthService + Clone + 'static,
+{
+    /// Server authenticates clients by `auth_service`
+    ///
+    /// `ServerConfig::credentials` is not used.
+    pub fn with_auth_service(
+        config: ServerConfig,
+        auth_service: A,
+    ) -> (Self, mpsc::Sender<ServerCommand<TcpStream>>) {
+        let (binder, tx_done) = tcp_binder(&config);
+        Self::with_binder_and_auth_service(
             config.clone(),
-            TcpBinder::new(
-                config.client_rw_timeout,
-                Arc::new(Mutex::new(rx_done)),
-                config.accept_timeout,
-            ),
+            binder,
             tx_done,
-            TcpUdpConnector::new(config.server_rw_timeout),
+            TcpUdpConnector::from_config(&config),
+            auth_service,
         )
     }
 }
@@ -176,19 +416,66 @@ where
         binder: T,
         tx_acceptor_done: SyncSender<()>,
         connector: C,
+    ) -> (Self, Sender<ServerCommand<S>>) {
+        let auth_service = ConfigAuthService::new(config.credentials.clone());
+        Self::with_binder_and_auth_service(
+            config,
+            binder,
+            tx_acceptor_done,
+            connector,
+            auth_service,
+        )
+    }
+}
+
+impl<S, T, C, A> Server<S, T, C, A>
+where
+    S: ByteStream + 'static,
+    T: Binder<Stream = S>,
+    C: Connector + Clone + 'static,
+    A: AuthService + Clone + 'static,
+{
+    pub fn with_binder_and_auth_service(
+        config: ServerConfig,
+        binder: T,
+        tx_acceptor_done: SyncSender<()>,
+        connector: C,
+        auth_service: A,
+    ) -> (Self, Sender<ServerCommand<S>>) {
+        let shared = SharedContext::with_connector(&config, connector);
+        Self::with_binder_and_shared_context(config, binder, tx_acceptor_done, shared, auth_service)
+    }
+
+    /// Server sharing the connector and resources of `shared` with other servers, see [`Server::with_shared_context`]
+    pub fn with_binder_and_shared_context(
+        config: ServerConfig,
+        binder: T,
+        tx_acceptor_done: SyncSender<()>,
+        shared: SharedContext<C>,
+        auth_service: A,
     ) -> (Self, Sender<ServerCommand<S>>) {
         let (tx, rx) = mpsc::channel();
         (
             Self {
+                destination_limiter: Arc::default(),
+                accept_queue: Arc::new(AcceptQueue::new(
+                    config.accept_queue_capacity,
+                    config.accept_overflow,
+                )),
+                policy: config.connect_policy(),
                 config,
                 tx_cmd: tx.clone(),
                 rx_cmd: rx,
                 binder,
                 tx_acceptor_done,
-                connector,
+                shared,
+                auth_service,
                 protocol_version: ProtocolVersion::from(5),
                 session: HashMap::new(),
                 id_rng: StdRng::from_entropy(),
+                bound: vec![],
+                accept_th: vec![],
+                phase: Phase::NotStarted,
             },
             tx,
         )
@@ -205,55 +492,401 @@ where
         }
     }
 
+    /// `ServerConfig::max_sessions` sessions are running
+    fn is_full(&self) -> bool {
+        matches!(self.config.max_sessions, Some(max) if self.session.len() >= max)
+    }
+
+    /// `false` if the client exceeds `ServerConfig::connection_rate_limit`
+    fn check_connection_rate(&mut self, addr: SocketAddr) -> bool {
+        self.shared.check_connection_rate(addr.ip())
+    }
+
+    /// reply `cerr` to the client without starting a session
+    ///
+    /// The client is closed without a reply if no reply slot of `accept_queue` is left.
+    fn reject(
+        &mut self,
+        stream: S,
+        addr: SocketAddr,
+        cerr: ConnectError,
+        protocol: &ClientProtocol,
+    ) {
+        let mut counters = self.shared.counters();
+        counters.accept();
+        counters.reject();
+        drop(counters);
+        match self.accept_queue.reply_slot() {
+            Some(slot) => spawn_reject(stream, addr, cerr, protocol, self.reject_reply(), slot),
+            None => {
+                warn!("too many rejected connections, close connection: {}", addr);
+                drop(stream);
+            }
+        }
+    }
+
+    fn reject_reply(&self) -> RejectReply {
+        RejectReply {
+            version: self.protocol_version,
+            server_addr: self.config.server_addr(),
+            handshake_timeout: self.config.handshake_timeout,
+        }
+    }
+
+    /// Statistics of running sessions
+    pub fn session_stats(&self) -> HashMap<SessionId, SessionStats> {
+        self.session
+            .iter()
+            .map(|(id, ss)| (*id, ss.stats()))
+            .collect()
+    }
+
+    /// Running sessions in order of start time
+    pub fn list_sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<_> = self
+            .session
+            .iter()
+            .map(|(id, ss)| SessionInfo {
+                id: *id,
+                state: ss.state(),
+                stats: ss.stats(),
+            })
+            .collect();
+        sessions.sort_by_key(|info| (info.stats.started_at, info.id));
+        sessions
+    }
+
+    /// Snapshot of server metrics
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.shared.counters().snapshot(self.session_stats());
+        // closed by acceptors without being sent to the server
+        let overflowed = self.accept_queue.overflowed();
+        metrics.accepted += overflowed;
+        metrics.rejected += overflowed;
+        metrics.banned_clients = self.shared.banned_clients();
+        metrics
+    }
+
+    /// Snapshot of server metrics and running sessions
+    pub fn status(&self) -> ServerStatus {
+        ServerStatus {
+            metrics: self.metrics(),
+            sessions: self.list_sessions(),
+        }
+    }
+
+    /// send a termination message to each acceptor
+    fn stop_acceptors(&self, n: usize) {
+        // acceptors may be waiting for the queue
+        self.accept_queue.close();
+        for _ in 0..n {
+            self.tx_acceptor_done.send(()).ok();
+        }
+        self.binder.wake_acceptors();
+    }
+
     /// Server main loop
+    ///
+    /// An acceptor thread is spawned for each of `ServerConfig::listen_addrs`.
+    /// This blocks until the server is terminated; see [`Server::run_once`] to drive the server
+    /// from another loop.
     pub fn serve(&mut self) -> Result<(), Error> {
-        let acceptor = self.binder.bind(self.config.server_addr())?;
-        let accept_th = spawn_acceptor(acceptor, self.tx_cmd.clone())?;
-
-        while let Ok(cmd) = self.rx_cmd.recv() {
-            use ServerCommand::*;
-            info!("cmd: {:?}", cmd);
-            match cmd {
-                Terminate => {
-                    self.tx_acceptor_done.send(()).ok();
-                    self.session.iter().for_each(|(_, ss)| ss.stop());
-
-                    self.session.drain().for_each(|(_, ss)| {
-                        ss.join().ok();
-                    });
-                    debug!("join accept thread");
-                    accept_th.join().ok();
-                    break;
+        while self.run_once(None)? {}
+        Ok(())
+    }
+
+    /// Bind the listening sockets without accepting clients
+    ///
+    /// After binding them, the process switches to `ServerConfig::run_as` if given.
+    /// This makes it possible to restrict the process (e.g. by seccomp) before serving clients.
+    /// This is called by [`Server::start`] if not called yet.
+    pub fn bind(&mut self) -> Result<(), Error> {
+        if self.phase != Phase::NotStarted {
+            return Ok(());
+        }
+        self.bound =
+            self.config
+                .listen_addrs()
+                .into_iter()
+                .map(|addr| (addr, ClientProtocol::Socks))
+                .chain(
+                    self.config
+                        .http_connect_addr
+                        .map(|addr| (addr, ClientProtocol::Http)),
+                )
+                .chain(
+                    self.config.forwards.iter().map(|forward| {
+                        (forward.listen, ClientProtocol::Forward(forward.to.clone()))
+                    }),
+                )
+                .map(|(addr, protocol)| {
+                    let acceptor: BoundAcceptor<S> = Box::new(self.binder.bind(addr)?);
+                    Ok((acceptor, protocol))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+        #[cfg(unix)]
+        if let Some(run_as) = &self.config.run_as {
+            crate::privilege::switch_to(run_as)?;
+        }
+        self.phase = Phase::Bound;
+        Ok(())
+    }
+
+    /// Spawn the acceptors
+    ///
+    /// The listening sockets are bound by [`Server::bind`] if not bound yet.
+    /// This is called by the first [`Server::run_once`] if not called yet.
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.bind()?;
+        if self.phase != Phase::Bound {
+            return Ok(());
+        }
+        self.accept_th = std::mem::take(&mut self.bound)
+            .into_iter()
+            .map(|(acceptor, protocol)| {
+                spawn_acceptor(
+                    acceptor,
+                    self.tx_cmd.clone(),
+                    protocol,
+                    self.accept_queue.clone(),
+                    self.reject_reply(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.phase = Phase::Running;
+        Ok(())
+    }
+
+    /// Process a command, waiting for it up to `timeout` (forever if `None`)
+    ///
+    /// This makes it possible to run the server in a loop of an application,
+    /// e.g. `while server.run_once(Some(Duration::from_millis(100)))? { .. }`.
+    /// Returns `false` once the server is terminated.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> Result<bool, Error> {
+        self.start()?;
+        if self.phase == Phase::Stopped {
+            return Ok(false);
+        }
+        let cmd = match timeout {
+            None => self
+                .rx_cmd
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+            Some(timeout) => self.rx_cmd.recv_timeout(timeout),
+        };
+        match cmd {
+            Ok(cmd) => self.handle_command(cmd)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => self.phase = Phase::Stopped,
+        }
+        if self.phase == Phase::Draining && self.session.is_empty() {
+            self.join_acceptors();
+            self.phase = Phase::Stopped;
+        }
+        if self.phase == Phase::Stopped {
+            info!("server shutdown");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn join_acceptors(&mut self) {
+        debug!("join accept thread");
+        self.accept_th.drain(..).for_each(|th| {
+            th.join().ok();
+        });
+    }
+
+    /// Reply `ServerFailure` to connections sent by the stopped acceptors but not taken yet
+    ///
+    /// Other pending commands are dropped.
+    fn reject_pending(&mut self) {
+        use ServerCommand::*;
+        while let Ok(cmd) = self.rx_cmd.try_recv() {
+            let (stream, addr, protocol) = match cmd {
+                Connect(stream, addr) => (stream, addr, ClientProtocol::Socks),
+                ConnectHttp(stream, addr) => (stream, addr, ClientProtocol::Http),
+                ConnectForward(stream, addr, to) => (stream, addr, ClientProtocol::Forward(to)),
+                _ => continue,
+            };
+            info!("reject connection in shutdown: {}", addr);
+            self.reject(stream, addr, ConnectError::ServerFailure, &protocol);
+        }
+    }
+
+    /// Start a session of the client connected by `stream`
+    ///
+    /// `protocol` is what the client speaks.
+    fn accept(&mut self, stream: S, addr: SocketAddr, protocol: ClientProtocol) {
+        if self.phase == Phase::Draining {
+            info!("reject connection in shutdown: {}", addr);
+            self.reject(stream, addr, ConnectError::ServerFailure, &protocol);
+            return;
+        }
+        if self.shared.is_banned(addr.ip()) {
+            debug!("banned client, close connection: {}", addr);
+            let mut counters = self.shared.counters();
+            counters.accept();
+            counters.reject();
+            return;
+        }
+        if !self.check_connection_rate(addr) {
+            // closed without a reply, not to spend a thread for each of them
+            warn!("connection rate limit exceeded, close connection: {}", addr);
+            let mut counters = self.shared.counters();
+            counters.accept();
+            counters.reject();
+            return;
+        }
+        if self.is_full() {
+            warn!("too many sessions, reject connection: {}", addr);
+            let cerr = self.config.max_sessions_reply.clone();
+            self.reject(stream, addr, cerr, &protocol);
+            return;
+        }
+        self.shared.counters().accept();
+        let (mut session, tx) = Session::new(
+            self.next_session_id(),
+            self.protocol_version,
+            self.shared.connector.clone(),
+            self.auth_service.clone(),
+            self.config.server_addr(),
+            self.policy.clone(),
+            self.tx_cmd.clone(),
+        );
+        session.bandwidth = Bandwidth::new(self.config.rate_limit).and(&self.shared.bandwidth);
+        session.logger = self.config.audit_logger();
+        session.events = self.config.event_handler.clone();
+        session.reply_addr = self.config.reply_addr;
+        session.bind_timeout = self.config.bind_timeout;
+        session.bind_ports = self.config.bind_ports.clone();
+        session.check_resolved = self.config.check_resolved;
+        session.reject_private_resolved = self.config.reject_private_resolved;
+        session.accept_socks4 = self.config.accept_socks4;
+        session.lenient_version = self.config.lenient_version;
+        session.allowed_methods = self.config.allowed_methods.clone();
+        session.http_connect = protocol == ClientProtocol::Http;
+        if let ClientProtocol::Forward(to) = protocol {
+            session.forward_to = Some(to);
+        }
+        session.inspect_sni = self.config.inspect_sni;
+        session.udp_reassembly_timeout = self.config.udp_reassembly_timeout;
+        session.handshake_timeout = self.config.handshake_timeout;
+        session.relay_buffer_size = self.config.relay_buffer_size;
+        session.rewriter = self.config.rewriter.clone();
+        session.destination_slot = Some(DestinationSlot::new(
+            self.destination_limiter.clone(),
+            session.id,
+        ));
+        session.lifetime = Lifetime {
+            idle_timeout: self.config.idle_timeout,
+            max_duration: self.config.max_session_duration,
+        };
+        self.session
+            .insert(session.id, spawn_session(session, tx, addr, stream));
+    }
+
+    fn handle_command(&mut self, cmd: ServerCommand<S>) -> Result<(), Error> {
+        use ServerCommand::*;
+        info!("cmd: {:?}", cmd);
+        let draining = self.phase == Phase::Draining;
+        match cmd {
+            Terminate => {
+                if !draining {
+                    self.stop_acceptors(self.accept_th.len());
+                }
+                self.session.iter().for_each(|(_, ss)| ss.stop());
+
+                self.session.drain().for_each(|(_, ss)| {
+                    ss.join().ok();
+                });
+                self.join_acceptors();
+                self.reject_pending();
+                self.phase = Phase::Stopped;
+            }
+            Shutdown { grace } => {
+                if !draining {
+                    self.phase = Phase::Draining;
+                    self.stop_acceptors(self.accept_th.len());
+                    let tx = self.tx_cmd.clone();
+                    spawn_thread("shutdown", move || {
+                        thread::sleep(grace);
+                        tx.send(Terminate).ok();
+                    })?;
                 }
-                Connect(stream, addr) => {
-                    let (session, tx) = Session::new(
-                        self.next_session_id(),
-                        self.protocol_version,
-                        self.connector.clone(),
-                        NoAuthService::new(),
-                        self.config.server_addr(),
-                        self.config.connect_rule(),
-                        self.tx_cmd.clone(),
-                    );
-                    self.session
-                        .insert(session.id, spawn_session(session, tx, addr, stream));
+            }
+            Connect(stream, addr) => {
+                self.accept_queue.pop();
+                self.accept(stream, addr, ClientProtocol::Socks)
+            }
+            ConnectHttp(stream, addr) => {
+                self.accept_queue.pop();
+                self.accept(stream, addr, ClientProtocol::Http)
+            }
+            ConnectForward(stream, addr, to) => {
+                self.accept_queue.pop();
+                self.accept(stream, addr, ClientProtocol::Forward(to))
+            }
+            QueryStats(tx) => {
+                tx.send(self.session_stats()).ok();
+            }
+            ListSessions(tx) => {
+                tx.send(self.list_sessions()).ok();
+            }
+            Kill(id) => match self.session.get(&id) {
+                // `Disconnect` is sent when the session is stopped
+                Some(session) => session.stop(),
+                None => warn!("no such session: {}", id),
+            },
+            QueryMetrics(tx) => {
+                tx.send(self.metrics()).ok();
+            }
+            QueryStatus(tx) => {
+                tx.send(self.status()).ok();
+            }
+            ReloadRules(rule) => {
+                if self.config.policy.is_some() {
+                    warn!("connect rule is reloaded, but not applied with a connect policy");
                 }
-                Disconnect(id) => {
-                    if let Some(session) = self.session.remove(&id) {
-                        let addr = session.client_addr();
-                        session.stop();
-                        match session.join() {
-                            Ok(Ok(())) => info!("session is stopped: {}: {}", addr, id),
-                            Ok(Err(err)) => error!("session error: {}: {}: {}", addr, id, err),
-                            Err(err) => error!("session panic: {}: {}: {:?}", addr, id, err),
+                // running sessions keep the rule they started with
+                self.config.set_connect_rule(rule);
+                self.policy = self.config.connect_policy();
+            }
+            Disconnect(id) => {
+                self.destination_limiter.release(id);
+                if let Some(session) = self.session.remove(&id) {
+                    let addr = session.client_addr();
+                    session.stop();
+                    let (stats, result) = session.finish();
+                    let outcome = match &result {
+                        Ok(Ok(())) => {
+                            info!("session is stopped: {}: {}", addr, id);
+                            Outcome::Success
+                        }
+                        Ok(Err(err)) => {
+                            // the error tells the session and its destination
+                            error!("session error: {}", err);
+                            Outcome::Error(err)
                         }
-                    } else {
-                        error!("session has already been stopped: {}", id);
+                        Err(err) => {
+                            error!("session panic: {}: {}: {:?}", addr, id, err);
+                            Outcome::Panic
+                        }
+                    };
+                    self.shared.counters().finish(&stats, &outcome);
+                    if matches!(outcome, Outcome::Error(err) if is_failed_attempt(addr, err))
+                        && self.shared.fail(addr.ip())
+                    {
+                        warn!("client is banned for failing repeatedly: {}", addr.ip());
+                    }
+                    if let Some(events) = &self.config.event_handler {
+                        events.on_session_finished(&SessionFinishedEvent::new(id, stats, &outcome));
                     }
+                } else {
+                    error!("session has already been stopped: {}", id);
                 }
             }
         }
-        info!("server shutdown");
         Ok(())
     }
 }
@@ -266,6 +899,8 @@ mod test {
     use crate::config::*;
     use crate::connector::*;
     use crate::model;
+    use crate::session::SessionState;
+    use crate::test_util::DummyBinder;
 
     use std::borrow::Cow;
     use std::ops::Deref;
@@ -304,20 +939,6 @@ mod test {
         assert!(&shutdown > req_shutdown.lock().unwrap().deref());
     }
 
-    struct DummyBinder {
-        stream: BufferStream,
-        src_addr: SocketAddr,
-    }
-
-    impl Binder for DummyBinder {
-        type Stream = BufferStream;
-        type Iter = std::iter::Once<(Self::Stream, SocketAddr)>;
-        fn bind(&self, addr: SocketAddr) -> Result<Self::Iter, model::Error> {
-            println!("bind: {}", addr);
-            Ok(std::iter::once((self.stream.clone(), self.src_addr)))
-        }
-    }
-
     #[test]
     fn dummy_binder() {
         let binder = DummyBinder {
@@ -352,4 +973,987 @@ mod test {
             .unwrap();
         th.join().unwrap();
     }
+
+    /// binder accepting no clients
+    struct NoClients;
+
+    impl Binder for NoClients {
+        type Stream = BufferStream;
+        type Iter = std::iter::Empty<(Self::Stream, SocketAddr)>;
+        fn bind(&self, _: SocketAddr) -> Result<Self::Iter, model::Error> {
+            Ok(std::iter::empty())
+        }
+    }
+
+    #[test]
+    fn run_once() {
+        let (tx_done, _rx_done) = mpsc::sync_channel(1);
+        let (mut server, tx) = Server::with_binder(
+            ServerConfig::default(),
+            NoClients,
+            tx_done,
+            TcpUdpConnector::new(None),
+        );
+        // no command
+        assert!(server.run_once(Some(Duration::from_millis(10))).unwrap());
+
+        let (tx_metrics, rx_metrics) = mpsc::channel();
+        tx.send(ServerCommand::QueryMetrics(tx_metrics)).unwrap();
+        assert!(server.run_once(Some(Duration::from_millis(10))).unwrap());
+        assert_eq!(rx_metrics.try_recv().unwrap().accepted, 0);
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        assert!(!server.run_once(None).unwrap());
+        assert!(!server.run_once(None).unwrap());
+        server.serve().unwrap();
+    }
+
+    /// binder accepting clients connected by `streams`
+    struct Clients(Vec<BufferStream>);
+
+    impl Binder for Clients {
+        type Stream = BufferStream;
+        type Iter = std::vec::IntoIter<(Self::Stream, SocketAddr)>;
+        fn bind(&self, _: SocketAddr) -> Result<Self::Iter, model::Error> {
+            let addr = "127.0.0.1:5000".parse().unwrap();
+            let clients: Vec<_> = self.0.iter().map(|strm| (strm.clone(), addr)).collect();
+            Ok(clients.into_iter())
+        }
+    }
+
+    #[test]
+    fn accept_queue_overflow() {
+        // NoAuth, then CONNECT 192.0.2.1:80
+        let request = [5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 0, 80];
+        let clients: Vec<_> = (0..3)
+            .map(|_| BufferStream::with_buffer(Cow::from(&request[..]), Cow::from(vec![])))
+            .collect();
+        let mut config = ServerConfig::default();
+        config
+            .set_accept_queue_capacity(Some(1))
+            .set_accept_overflow(AcceptOverflow::Reject);
+        let (tx_done, _rx_done) = mpsc::sync_channel(1);
+        let (mut server, tx) = Server::with_binder(
+            config,
+            Clients(clients.clone()),
+            tx_done,
+            TcpUdpConnector::new(None),
+        );
+        // the server does not take the first client yet
+        server.start().unwrap();
+        thread::sleep(Duration::from_millis(500));
+        let metrics = server.metrics();
+        assert_eq!(metrics.accepted, 2);
+        assert_eq!(metrics.rejected, 2);
+        for client in &clients[1..] {
+            // NoAuth, then ServerFailure
+            assert_eq!(&client.wr_buff().get_ref()[..4], &[5, 0, 5, 1]);
+        }
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server.serve().unwrap();
+        assert_eq!(server.metrics().accepted, 3);
+    }
+
+    #[test]
+    fn reload_rules() {
+        let binder = DummyBinder {
+            stream: BufferStream::new(),
+            src_addr: "127.0.0.1:1080".parse().unwrap(),
+        };
+        let (tx_done, _rx_done) = mpsc::sync_channel(1);
+        let (mut server, tx) = Server::with_binder(
+            ServerConfig::default(),
+            binder,
+            tx_done,
+            TcpUdpConnector::new(None),
+        );
+        assert!(server.config.connect_rule().is_any());
+
+        tx.send(ServerCommand::ReloadRules(model::ConnectRule::none()))
+            .unwrap();
+        tx.send(ServerCommand::Terminate).unwrap();
+        server.serve().unwrap();
+        assert!(!server.config.connect_rule().is_any());
+    }
+
+    #[test]
+    fn custom_auth_service() {
+        use crate::auth_service::test::RejectService;
+
+        // method candidates: [NoAuth]
+        let stream = BufferStream::with_buffer(Cow::from(vec![5, 1, 0]), Cow::from(vec![]));
+        let binder = DummyBinder {
+            stream: stream.clone(),
+            src_addr: "127.0.0.1:1080".parse().unwrap(),
+        };
+        let (tx_done, _rx_done) = mpsc::sync_channel(1);
+        let (mut server, tx) = Server::with_binder_and_auth_service(
+            ServerConfig::default(),
+            binder,
+            tx_done,
+            TcpUdpConnector::new(None),
+            RejectService,
+        );
+        let th = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            let (tx_metrics, rx_metrics) = mpsc::channel();
+            tx.send(ServerCommand::QueryMetrics(tx_metrics)).unwrap();
+            tx.send(ServerCommand::Terminate).unwrap();
+            rx_metrics.recv().unwrap()
+        });
+        server.serve().unwrap();
+        let metrics = th.join().unwrap();
+
+        assert_eq!(metrics.rejected, 1);
+        // NoAuth is not selected by the auth service
+        assert_eq!(stream.wr_buff().get_ref().as_slice(), &[5, 0xff]);
+    }
+
+    fn spawn_echo_server() -> SocketAddr {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut strm, _) = echo.accept().unwrap();
+            let mut buf = [0; 1024];
+            while let Ok(size @ 1..) = strm.read(&mut buf) {
+                strm.write_all(&buf[..size]).unwrap();
+            }
+        });
+        echo_addr
+    }
+
+    /// spawn a server listening on a free port of localhost
+    fn spawn_server(
+        mut config: ServerConfig,
+    ) -> (
+        u16,
+        Sender<ServerCommand<TcpStream>>,
+        thread::JoinHandle<()>,
+    ) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        config
+            .set_server_addr(SocketAddr::new("127.0.0.1".parse().unwrap(), port))
+            .set_accept_timeout(Some(Duration::from_millis(100)));
+        let (tx_server, rx_server) = mpsc::channel();
+        let server_th = thread::spawn(move || {
+            let (mut server, tx) = Server::new(config);
+            tx_server.send(tx).unwrap();
+            server.serve().unwrap();
+        });
+        (port, rx_server.recv().unwrap(), server_th)
+    }
+
+    fn connect(port: u16, dst: SocketAddr) -> (TcpStream, model::ConnectReply) {
+        use crate::rw_socks_stream as socks;
+
+        let mut client = loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(client) => break client,
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        socks::test::write_method_candidates(
+            &mut client,
+            model::MethodCandidates::new(&[model::Method::NoAuth]),
+        )
+        .unwrap();
+        socks::test::write_connect_request(&mut client, model::ConnectRequest::connect_to(dst))
+            .unwrap();
+        socks::test::read_method_selection(&mut client).unwrap();
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        (client, reply)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        use crate::rw_socks_stream as socks;
+        use std::io::{Read, Write};
+
+        let echo_addr = spawn_echo_server();
+        let path =
+            std::env::temp_dir().join(format!("gatekeeper-server-{}.sock", std::process::id()));
+        let mut config = ServerConfig::default();
+        config
+            .set_unix_socket(Some(path.clone()))
+            .set_accept_timeout(Some(Duration::from_millis(100)));
+        let (mut server, tx) = Server::with_unix_socket(config);
+        let server_th = thread::spawn(move || server.serve().unwrap());
+
+        let mut client = loop {
+            match UnixStream::connect(&path) {
+                Ok(client) => break client,
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        socks::test::write_method_candidates(
+            &mut client,
+            model::MethodCandidates::new(&[model::Method::NoAuth]),
+        )
+        .unwrap();
+        socks::test::write_connect_request(
+            &mut client,
+            model::ConnectRequest::connect_to(echo_addr),
+        )
+        .unwrap();
+        socks::test::read_method_selection(&mut client).unwrap();
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        assert_eq!(reply.connect_result, Ok(()));
+
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        use std::io::{Read, Write};
+
+        let echo_addr = spawn_echo_server();
+        let (port, tx, server_th) = spawn_server(ServerConfig::default());
+        let (mut client, reply) = connect(port, echo_addr);
+        assert_eq!(reply.connect_result, Ok(()));
+
+        let shutdown = SystemTime::now();
+        tx.send(ServerCommand::Shutdown {
+            grace: Duration::from_secs(60),
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        // the running session is still relaying
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // the server stops once the session is finished
+        drop(client);
+        server_th.join().unwrap();
+        assert!(shutdown.elapsed().unwrap() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn connection_flood_in_shutdown() {
+        use crate::accept_queue::MAX_REJECT_REPLIES;
+        use std::io::Read;
+
+        let echo_addr = spawn_echo_server();
+        let mut config = ServerConfig::default();
+        config.set_client_rw_timeout(None);
+        let (port, tx, server_th) = spawn_server(config);
+        let (client, reply) = connect(port, echo_addr);
+        assert_eq!(reply.connect_result, Ok(()));
+        tx.send(ServerCommand::Shutdown {
+            grace: Duration::from_secs(60),
+        })
+        .unwrap();
+
+        // clients not sending anything keep their reply threads waiting
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let clients: Vec<TcpStream> = (0..MAX_REJECT_REPLIES * 2)
+            .map(|_| {
+                let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (stream, addr) = listener.accept().unwrap();
+                tx.send(ServerCommand::Connect(stream, addr)).unwrap();
+                client
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(500));
+        let mut replying = 0;
+        for mut client in &clients {
+            client.set_nonblocking(true).unwrap();
+            // the others are closed without replies
+            if client.read(&mut [0; 1]).is_err() {
+                replying += 1;
+            }
+        }
+        assert_eq!(replying, MAX_REJECT_REPLIES);
+
+        drop(client);
+        drop(clients);
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn terminate_in_handshake() {
+        use crate::rw_socks_stream as socks;
+
+        let echo_addr = spawn_echo_server();
+        let (port, tx, server_th) = spawn_server(ServerConfig::default());
+        // starts the acceptors
+        let (_client, reply) = connect(port, echo_addr);
+        assert_eq!(reply.connect_result, Ok(()));
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        socks::test::write_method_candidates(
+            &mut client,
+            model::MethodCandidates::new(&[model::Method::NoAuth]),
+        )
+        .unwrap();
+        socks::test::read_method_selection(&mut client).unwrap();
+        tx.send(ServerCommand::Terminate).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        // the request sent after the termination is replied
+        socks::test::write_connect_request(
+            &mut client,
+            model::ConnectRequest::connect_to(echo_addr),
+        )
+        .unwrap();
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        assert_eq!(
+            reply.connect_result,
+            Err(model::ConnectError::ServerFailure)
+        );
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn shared_context() {
+        use std::io::{Read, Write};
+
+        let echo_addr = spawn_echo_server();
+        let mut config = ServerConfig::default();
+        config.set_connection_rate_limit(Some(1));
+        let shared = SharedContext::new(&config);
+        let servers = (0..2)
+            .map(|_| {
+                let port = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port();
+                let mut config = config.clone();
+                config.set_server_addr(SocketAddr::new("127.0.0.1".parse().unwrap(), port));
+                let (mut server, tx) = Server::with_shared_context(config, &shared);
+                (port, tx, thread::spawn(move || server.serve().unwrap()))
+            })
+            .collect::<Vec<_>>();
+
+        let (_client, reply) = connect(servers[0].0, echo_addr);
+        assert_eq!(reply.connect_result, Ok(()));
+        // the rate limit is shared by the servers, and the connection is closed without a reply
+        let mut client = loop {
+            match TcpStream::connect(("127.0.0.1", servers[1].0)) {
+                Ok(client) => break client,
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        client.write_all(&[5, 1, 0]).ok();
+        let mut buf = [0; 2];
+        assert!(!matches!(client.read(&mut buf), Ok(n) if n > 0));
+        let metrics = shared.metrics();
+        assert_eq!(metrics.accepted, 2);
+        assert_eq!(metrics.rejected, 1);
+
+        for (_, tx, th) in servers {
+            tx.send(ServerCommand::Terminate).unwrap();
+            th.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn ban_failing_client() {
+        use std::io::{Read, Write};
+
+        let echo_addr = spawn_echo_server();
+        let mut config = ServerConfig::default();
+        config
+            .set_connect_rule(model::ConnectRule::none())
+            .set_client_ban(Some(crate::client_ban::ClientBanConfig {
+                threshold: 2,
+                ..Default::default()
+            }));
+        let (port, tx, server_th) = spawn_server(config);
+        let metrics = || {
+            let (tx_metrics, rx_metrics) = mpsc::channel();
+            tx.send(ServerCommand::QueryMetrics(tx_metrics)).unwrap();
+            rx_metrics.recv().unwrap()
+        };
+
+        for _ in 0..2 {
+            let (_, reply) = connect(port, echo_addr);
+            assert_eq!(
+                reply.connect_result,
+                Err(model::ConnectError::ConnectionNotAllowed)
+            );
+        }
+        // banned when the second session is finished
+        while metrics().banned_clients.is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let banned = metrics().banned_clients;
+        assert_eq!(
+            banned[0].ip,
+            "127.0.0.1".parse::<std::net::IpAddr>().unwrap()
+        );
+
+        // closed without a reply
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(&[5, 1, 0]).ok();
+        let mut buf = [0; 2];
+        assert!(!matches!(client.read(&mut buf), Ok(n) if n > 0));
+        assert_eq!(metrics().rejected, 3);
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn dual_stack_client() {
+        use model::{AddressPattern, RulePattern::*};
+
+        let echo_addr = spawn_echo_server();
+        let mut rule = model::ConnectRule::any();
+        rule.deny_from(
+            Specif(AddressPattern::addr("127.0.0.0".parse().unwrap(), 8).unwrap()),
+            Any,
+            Any,
+            Any,
+        );
+        let mut config = ServerConfig::default();
+        config
+            .set_connect_rule(rule)
+            .set_client_ban(Some(crate::client_ban::ClientBanConfig {
+                threshold: 1,
+                ..Default::default()
+            }));
+        // listening on all addresses of both families
+        let addr = std::net::TcpListener::bind("[::]:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let port = addr.port();
+        config
+            .set_server_addr(addr)
+            .set_accept_timeout(Some(Duration::from_millis(100)));
+        let (tx_server, rx_server) = mpsc::channel();
+        let server_th = thread::spawn(move || {
+            let (mut server, tx) = Server::new(config);
+            tx_server.send(tx).unwrap();
+            server.serve().unwrap();
+        });
+        let tx = rx_server.recv().unwrap();
+
+        // denied as an IPv4 client
+        let (_, reply) = connect(port, echo_addr);
+        assert_eq!(
+            reply.connect_result,
+            Err(model::ConnectError::ConnectionNotAllowed)
+        );
+        let banned = loop {
+            let (tx_metrics, rx_metrics) = mpsc::channel();
+            tx.send(ServerCommand::QueryMetrics(tx_metrics)).unwrap();
+            let banned = rx_metrics.recv().unwrap().banned_clients;
+            if !banned.is_empty() {
+                break banned;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(
+            banned[0].ip,
+            "127.0.0.1".parse::<std::net::IpAddr>().unwrap()
+        );
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn max_sessions() {
+        let echo_addr = spawn_echo_server();
+        let mut config = ServerConfig::default();
+        config
+            .set_max_sessions(Some(1))
+            .set_max_sessions_reply(model::ConnectError::ConnectionNotAllowed);
+        let (port, tx, server_th) = spawn_server(config);
+
+        let (_client, reply) = connect(port, echo_addr);
+        assert_eq!(reply.connect_result, Ok(()));
+        let (_, reply) = connect(port, echo_addr);
+        assert_eq!(
+            reply.connect_result,
+            Err(model::ConnectError::ConnectionNotAllowed)
+        );
+
+        let (tx_metrics, rx_metrics) = mpsc::channel();
+        tx.send(ServerCommand::QueryMetrics(tx_metrics)).unwrap();
+        let metrics = rx_metrics.recv().unwrap();
+        assert_eq!(metrics.accepted, 2);
+        assert_eq!(metrics.rejected, 1);
+        assert_eq!(metrics.active_sessions(), 1);
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn max_sessions_flood() {
+        use crate::accept_queue::MAX_REJECT_REPLIES;
+        use std::io::Read;
+
+        let echo_addr = spawn_echo_server();
+        let mut config = ServerConfig::default();
+        config.set_max_sessions(Some(1)).set_client_rw_timeout(None);
+        let (port, tx, server_th) = spawn_server(config);
+        let (client, reply) = connect(port, echo_addr);
+        assert_eq!(reply.connect_result, Ok(()));
+
+        // clients not sending anything keep their reply threads waiting
+        let clients: Vec<TcpStream> = (0..MAX_REJECT_REPLIES * 2)
+            .map(|_| loop {
+                match TcpStream::connect(("127.0.0.1", port)) {
+                    Ok(client) => break client,
+                    Err(_) => thread::sleep(Duration::from_millis(100)),
+                }
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(500));
+        let mut replying = 0;
+        for mut client in &clients {
+            client.set_nonblocking(true).unwrap();
+            // the others are closed without replies
+            if client.read(&mut [0; 1]).is_err() {
+                replying += 1;
+            }
+        }
+        assert_eq!(replying, MAX_REJECT_REPLIES);
+
+        let (tx_metrics, rx_metrics) = mpsc::channel();
+        tx.send(ServerCommand::QueryMetrics(tx_metrics)).unwrap();
+        let metrics = rx_metrics.recv().unwrap();
+        assert_eq!(metrics.accepted, MAX_REJECT_REPLIES as u64 * 2 + 1);
+        assert_eq!(metrics.rejected, MAX_REJECT_REPLIES as u64 * 2);
+
+        drop(client);
+        drop(clients);
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn max_sessions_per_rule() {
+        // connections are established in the backlog, not accepted
+        let dst = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = dst.local_addr().unwrap();
+        let mut rule = model::ConnectRule::any();
+        rule.push(model::ConnectRuleEntry::Allow(
+            model::ConnectRulePattern::new(
+                model::RulePattern::Any,
+                model::RulePattern::Specif(dst_addr.port().into()),
+                model::RulePattern::Any,
+            )
+            .limited_to(1),
+        ));
+        let mut config = ServerConfig::default();
+        config.set_connect_rule(rule);
+        let (port, tx, server_th) = spawn_server(config);
+
+        let (_client, reply) = connect(port, dst_addr);
+        assert_eq!(reply.connect_result, Ok(()));
+        let (_, reply) = connect(port, dst_addr);
+        assert_eq!(
+            reply.connect_result,
+            Err(model::ConnectError::ConnectionNotAllowed)
+        );
+        // other destinations are not limited
+        let (_, reply) = connect(port, spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+
+        let (tx_list, rx_list) = mpsc::channel();
+        tx.send(ServerCommand::ListSessions(tx_list)).unwrap();
+        let relaying = rx_list.recv().unwrap();
+        let first = relaying
+            .iter()
+            .find(|session| session.stats.dst_addr == Some(dst_addr.into()))
+            .unwrap();
+        tx.send(ServerCommand::Kill(first.id)).unwrap();
+        // released on `Disconnect` of the killed session
+        loop {
+            let (_client, reply) = connect(port, dst_addr);
+            if reply.connect_result.is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn multiple_listeners() {
+        let port2 = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = ServerConfig {
+            additional_addrs: vec![SocketAddr::new("127.0.0.1".parse().unwrap(), port2)],
+            ..ServerConfig::default()
+        };
+        let (port, tx, server_th) = spawn_server(config);
+
+        let (_client1, reply) = connect(port, spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+        let (_client2, reply) = connect(port2, spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+
+        // all acceptors are stopped
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn inherited_listeners() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = ServerConfig::default();
+        config
+            .set_http_connect_addr(Some(http.local_addr().unwrap()))
+            .set_accept_timeout(Some(Duration::from_millis(100)));
+        let (mut server, tx) = Server::with_listeners(config, vec![http, listener]).unwrap();
+        // listened on instead of the default address
+        assert_eq!(server.config.listen_addrs(), vec![addr]);
+        let server_th = thread::spawn(move || server.serve().unwrap());
+
+        let (_client, reply) = connect(addr.port(), spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn http_connect() {
+        use std::io::{Read, Write};
+
+        let port2 = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut rule = model::ConnectRule::any();
+        rule.push(model::ConnectRuleEntry::Deny(
+            model::ConnectRulePattern::new(
+                model::RulePattern::Any,
+                model::RulePattern::Specif(25.into()),
+                model::RulePattern::Any,
+            ),
+        ));
+        let mut config = ServerConfig::default();
+        config
+            .set_connect_rule(rule)
+            .set_http_connect_addr(Some(SocketAddr::new("127.0.0.1".parse().unwrap(), port2)));
+        let (port, tx, server_th) = spawn_server(config);
+
+        // returns the status line of the reply
+        let request = |target: &str| {
+            let mut client = TcpStream::connect(("127.0.0.1", port2)).unwrap();
+            write!(
+                client,
+                "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+                target, target
+            )
+            .unwrap();
+            let mut head = vec![];
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut c = [0];
+                client.read_exact(&mut c).unwrap();
+                head.push(c[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            (client, head.lines().next().unwrap().to_owned())
+        };
+
+        // SOCKS is still accepted on the server address, which also starts the acceptors
+        let (_client, reply) = connect(port, spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+        let (mut client, status) = request(&spawn_echo_server().to_string());
+        assert_eq!(status, "HTTP/1.1 200 Connection Established");
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let (_, status) = request("192.0.2.1:25");
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn forward() {
+        use crate::config::Forward;
+        use std::io::{Read, Write};
+
+        let free_addr = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let mut rule = model::ConnectRule::any();
+        rule.push(model::ConnectRuleEntry::Deny(
+            model::ConnectRulePattern::new(
+                model::RulePattern::Any,
+                model::RulePattern::Specif(25.into()),
+                model::RulePattern::Any,
+            ),
+        ));
+        let echo = Forward {
+            listen: free_addr(),
+            to: spawn_echo_server().into(),
+        };
+        let denied = Forward {
+            listen: free_addr(),
+            to: "127.0.0.1:25".parse().unwrap(),
+        };
+        let mut config = ServerConfig::default();
+        config
+            .set_connect_rule(rule)
+            .set_forwards(vec![echo.clone(), denied.clone()]);
+        let (port, tx, server_th) = spawn_server(config);
+
+        // starts the acceptors
+        let (_client, reply) = connect(port, spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+        let mut client = TcpStream::connect(echo.listen).unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // closed without a reply
+        let mut client = TcpStream::connect(denied.listen).unwrap();
+        assert!(matches!(client.read(&mut buf), Ok(0) | Err(_)));
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn kill_session() {
+        use std::io::Read;
+
+        let (port, tx, server_th) = spawn_server(ServerConfig::default());
+        let (mut client, reply) = connect(port, spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+
+        let (tx_list, rx_list) = mpsc::channel();
+        tx.send(ServerCommand::ListSessions(tx_list.clone()))
+            .unwrap();
+        let sessions = rx_list.recv().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].stats.client_addr, client.local_addr().unwrap());
+
+        tx.send(ServerCommand::Kill(sessions[0].id)).unwrap();
+        // closed by the server
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        while {
+            tx.send(ServerCommand::ListSessions(tx_list.clone()))
+                .unwrap();
+            !rx_list.recv().unwrap().is_empty()
+        } {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn query_status() {
+        let (port, tx, server_th) = spawn_server(ServerConfig::default());
+        let echo = spawn_echo_server();
+        let (client, reply) = connect(port, echo);
+        assert_eq!(reply.connect_result, Ok(()));
+        // not sending any request
+        let idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+        let (tx_status, rx_status) = mpsc::channel();
+        let status = loop {
+            tx.send(ServerCommand::QueryStatus(tx_status.clone()))
+                .unwrap();
+            let status = rx_status.recv().unwrap();
+            // the session starts relaying just after the reply
+            if status.sessions.len() == 2 && status.sessions[0].state == SessionState::Relaying {
+                break status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(status.metrics.accepted, 2);
+        let relaying = &status.sessions[0];
+        assert_eq!(relaying.stats.client_addr, client.local_addr().unwrap());
+        assert_eq!(relaying.stats.dst_addr, Some(echo.into()));
+        let handshaking = &status.sessions[1];
+        assert_eq!(handshaking.state, SessionState::Handshaking);
+        assert_eq!(handshaking.stats.client_addr, idle.local_addr().unwrap());
+        let rendered = status.to_string();
+        assert!(rendered.starts_with("sessions: 2 running, 2 accepted"));
+        assert!(rendered.contains(&format!(
+            "relaying {} -> {}",
+            relaying.stats.client_addr, echo
+        )));
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordEvents(Mutex<Vec<String>>);
+
+    impl crate::event::ServerEventHandler for RecordEvents {
+        fn on_connect(&self, _: &crate::event::AcceptEvent) {
+            self.0.lock().unwrap().push("connect".to_owned());
+        }
+        fn on_authenticated(&self, event: &crate::event::AuthEvent) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("auth {}", event.method));
+        }
+        fn on_rule_denied(&self, event: &crate::audit::RejectEvent) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("denied {}", event.dst_addr));
+        }
+        fn on_relay_started(&self, _: &crate::audit::ConnectEvent) {
+            self.0.lock().unwrap().push("relay".to_owned());
+        }
+        fn on_disconnect(&self, event: &crate::audit::DisconnectEvent) {
+            self.0.lock().unwrap().push(format!(
+                "disconnect {} {}",
+                event.upload_bytes, event.download_bytes
+            ));
+        }
+        fn on_session_finished(&self, event: &crate::event::SessionFinishedEvent) {
+            self.0.lock().unwrap().push(format!(
+                "finished {} {} {}",
+                event.stats.upload_bytes,
+                event.stats.download_bytes,
+                event.error.is_none()
+            ));
+            if let Some(error) = &event.error {
+                self.0.lock().unwrap().push(error.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn event_handler() {
+        use crate::model::{AddressPattern, ConnectRule, RulePattern};
+        use std::io::{Read, Write};
+
+        let events = Arc::new(RecordEvents::default());
+        let mut rule = ConnectRule::any();
+        rule.deny(
+            RulePattern::Specif(AddressPattern::IpAddr {
+                addr: "127.0.0.2".parse().unwrap(),
+                prefix: 32,
+            }),
+            RulePattern::Any,
+            RulePattern::Any,
+        );
+        let mut config = ServerConfig::default();
+        config
+            .set_connect_rule(rule)
+            .set_event_handler(Some(events.clone()));
+        let (port, tx, server_th) = spawn_server(config);
+
+        let (mut client, reply) = connect(port, spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+        client.write_all(b"hello").unwrap();
+        client.read_exact(&mut [0; 5]).unwrap();
+        drop(client);
+        while events.0.lock().unwrap().len() < 5 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            [
+                "connect",
+                "auth NoAuth",
+                "relay",
+                "disconnect 5 5",
+                "finished 5 5 true"
+            ]
+        );
+
+        events.0.lock().unwrap().clear();
+        let (client, reply) = connect(port, "127.0.0.2:80".parse().unwrap());
+        assert_eq!(
+            reply.connect_result,
+            Err(model::ConnectError::ConnectionNotAllowed)
+        );
+        // rejected sessions are also finished
+        while events.0.lock().unwrap().len() < 5 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let events = events.0.lock().unwrap();
+        assert_eq!(
+            events[..4],
+            [
+                "connect",
+                "auth NoAuth",
+                "denied 127.0.0.2:80",
+                "finished 0 0 false"
+            ]
+        );
+        // the error tells which session failed
+        assert!(events[4].starts_with("SessionId("));
+        assert!(events[4].ends_with(&format!(
+            ": {} -> 127.0.0.2:80: connection not allowed: 127.0.0.2:80: Tcp",
+            client.local_addr().unwrap()
+        )));
+        drop(events);
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+
+    #[test]
+    fn reply_addr() {
+        let mut config = ServerConfig::default();
+        config.set_reply_addr(ReplyAddr::LocalAddr);
+        let (port, tx, server_th) = spawn_server(config);
+        let (_client, reply) = connect(port, spawn_echo_server());
+        assert_eq!(reply.connect_result, Ok(()));
+        match reply.server_addr {
+            model::Address::IpAddr(ip, local_port) => {
+                assert!(ip.is_loopback());
+                assert_ne!(local_port, port);
+                assert_ne!(local_port, 0);
+            }
+            addr => panic!("unexpected address: {}", addr),
+        }
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+
+        let external = "203.0.113.1:1080".parse().unwrap();
+        let mut config = ServerConfig::default();
+        config.set_reply_addr(ReplyAddr::Fixed(external));
+        let (port, tx, server_th) = spawn_server(config);
+        let (_client, reply) = connect(port, spawn_echo_server());
+        assert_eq!(reply.server_addr, external.into());
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
 }
@@ -1,16 +1,46 @@
 //! Server control command
 //!
+use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::time::Duration;
 
-use crate::session::SessionId;
+use crate::metrics::{Metrics, ServerStatus};
+use crate::model::{Address, ConnectRule};
+use crate::session::{SessionId, SessionInfo, SessionStats};
 
 pub enum ServerCommand<T> {
     /// terminate
     Terminate,
+    /// stop accepting connections and terminate after running sessions are finished
+    ///
+    /// Sessions still running after `grace` are terminated.
+    /// Connections sent meanwhile are replied `ServerFailure`, or closed without the reply
+    /// while too many rejected connections are being replied.
+    Shutdown {
+        grace: Duration,
+    },
     /// connected stream and client address
     Connect(T, SocketAddr),
+    /// stream connected to `ServerConfig::http_connect_addr` and client address
+    ConnectHttp(T, SocketAddr),
+    /// stream connected to a listen address of `ServerConfig::forwards`, client address
+    /// and the destination to forward to
+    ConnectForward(T, SocketAddr, Address),
     Disconnect(SessionId),
+    /// send statistics of running sessions to the sender
+    QueryStats(mpsc::Sender<HashMap<SessionId, SessionStats>>),
+    /// send running sessions to the sender, in order of start time
+    ListSessions(mpsc::Sender<Vec<SessionInfo>>),
+    /// terminate the session
+    Kill(SessionId),
+    /// send a snapshot of server metrics to the sender
+    QueryMetrics(mpsc::Sender<Metrics>),
+    /// send the state of the server with running sessions to the sender
+    QueryStatus(mpsc::Sender<ServerStatus>),
+    /// replace the connect rule applied to new sessions
+    ReloadRules(ConnectRule),
 }
 
 impl<T> fmt::Debug for ServerCommand<T> {
@@ -18,8 +48,17 @@ impl<T> fmt::Debug for ServerCommand<T> {
         use ServerCommand::*;
         match self {
             Terminate => write!(f, "Terminate"),
+            Shutdown { grace } => write!(f, "Shutdown {{ grace: {:?} }}", grace),
             Connect(_, addr) => write!(f, "Connect(_, {})", addr),
+            ConnectHttp(_, addr) => write!(f, "ConnectHttp(_, {})", addr),
+            ConnectForward(_, addr, to) => write!(f, "ConnectForward(_, {}, {})", addr, to),
             Disconnect(id) => write!(f, "Disconnect({})", id),
+            QueryStats(_) => write!(f, "QueryStats(_)"),
+            ListSessions(_) => write!(f, "ListSessions(_)"),
+            Kill(id) => write!(f, "Kill({})", id),
+            QueryMetrics(_) => write!(f, "QueryMetrics(_)"),
+            QueryStatus(_) => write!(f, "QueryStatus(_)"),
+            ReloadRules(_) => write!(f, "ReloadRules(_)"),
         }
     }
 }
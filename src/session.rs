@@ -1,22 +1,36 @@
 use std::fmt;
-use std::ops::{Deref, DerefMut};
+use std::io;
+use std::ops::{Deref, DerefMut, RangeInclusive};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, SyncSender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use log::*;
+use serde::Serialize;
 
+use crate::audit::{ConnectEvent, DisconnectLog, RejectEvent, RejectReason, SessionLogger};
 use crate::auth_service::AuthService;
-use crate::byte_stream::ByteStream;
-use crate::connector::Connector;
+use crate::byte_stream::{BoxedStream, ByteStream};
+use crate::config::ReplyAddr;
+use crate::connector::{resolve_with, Connector, Resolver, StreamListener};
+use crate::destination_limiter::DestinationSlot;
+use crate::event::{AcceptEvent, AuthEvent, ServerEventHandler};
+use crate::http_connect;
 use crate::model::dao::*;
 use crate::model::model::*;
-use crate::model::{Error, ErrorKind};
-use crate::relay::{self, RelayHandle};
+use crate::model::{Error, ErrorKind, SessionContext};
+use crate::pkt_stream::PktStream;
+use crate::policy::{CheckStage, ConnectContext, ConnectPolicy, Decision};
+use crate::relay::{self, Bandwidth, Lifetime, RelayHandle, Traffic};
+use crate::rewrite::ConnectRewriter;
 use crate::rw_socks_stream::ReadWriteStream;
 use crate::server_command::ServerCommand;
+use crate::sni;
+use crate::socks4;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
 pub struct SessionId(pub u32);
 
 impl From<u32> for SessionId {
@@ -31,29 +45,159 @@ impl fmt::Display for SessionId {
     }
 }
 
+/// Statistics of a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionStats {
+    /// client address
+    pub client_addr: SocketAddr,
+    /// bytes relayed from client to external network
+    pub upload_bytes: u64,
+    /// bytes relayed from external network to client
+    pub download_bytes: u64,
+    /// duration since the connection from the client has been accepted
+    pub duration: Duration,
+    /// when the connection from the client has been accepted
+    pub started_at: SystemTime,
+    /// destination requested by the client (`None` until the request is received)
+    pub dst_addr: Option<Address>,
+}
+
+/// Phase of a running session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// negotiating the method, authenticating the client, or connecting to the destination
+    Handshaking,
+    /// relaying data between the client and the destination
+    Relaying,
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionState::Handshaking => write!(f, "handshaking"),
+            SessionState::Relaying => write!(f, "relaying"),
+        }
+    }
+}
+
+/// Running session listed by `ServerCommand::ListSessions`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub state: SessionState,
+    pub stats: SessionStats,
+}
+
+/// A line of the session, e.g. `SessionId(1) relaying 192.168.0.2:5000 -> example.com:443 12s up:1024 down:2048`
+impl fmt::Display for SessionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = &self.stats;
+        write!(f, "{} {} {} -> ", self.id, self.state, stats.client_addr)?;
+        match &stats.dst_addr {
+            Some(dst) => write!(f, "{}", dst)?,
+            None => write!(f, "-")?,
+        }
+        write!(
+            f,
+            " {}s up:{} down:{}",
+            stats.duration.as_secs(),
+            stats.upload_bytes,
+            stats.download_bytes
+        )
+    }
+}
+
+/// Destination requested to a session and its state, shared with its handle
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Destination {
+    addr: Arc<Mutex<Option<Address>>>,
+    relaying: Arc<AtomicBool>,
+}
+
+impl Destination {
+    pub fn set(&self, addr: Address) {
+        *self.addr.lock().unwrap_or_else(PoisonError::into_inner) = Some(addr);
+    }
+
+    pub fn get(&self) -> Option<Address> {
+        self.addr
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// the session has started relaying
+    pub fn set_relaying(&self) {
+        self.relaying.store(true, Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> SessionState {
+        if self.relaying.load(Ordering::Relaxed) {
+            SessionState::Relaying
+        } else {
+            SessionState::Handshaking
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SessionHandle {
+    id: SessionId,
     /// client address
     addr: SocketAddr,
     /// thread performs relay bytes
     handle: thread::JoinHandle<Result<RelayHandle, Error>>,
     /// Sender to send termination messages to relay threads
     tx: SyncSender<()>,
+    /// bytes relayed by the session
+    traffic: Traffic,
+    /// destination requested to the session
+    destination: Destination,
+    /// when the session has been started
+    started: Instant,
+    started_at: SystemTime,
 }
 
 impl SessionHandle {
-    pub fn new(
+    pub(crate) fn new(
+        id: SessionId,
         addr: SocketAddr,
         handle: thread::JoinHandle<Result<RelayHandle, Error>>,
         tx: SyncSender<()>,
+        traffic: Traffic,
+        destination: Destination,
     ) -> Self {
-        Self { addr, handle, tx }
+        Self {
+            id,
+            addr,
+            handle,
+            tx,
+            traffic,
+            destination,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        }
     }
 
     pub fn client_addr(&self) -> SocketAddr {
         self.addr
     }
 
+    pub fn stats(&self) -> SessionStats {
+        SessionStats {
+            client_addr: self.addr,
+            upload_bytes: self.traffic.upload(),
+            download_bytes: self.traffic.download(),
+            duration: self.started.elapsed(),
+            started_at: self.started_at,
+            dst_addr: self.destination.get(),
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.destination.state()
+    }
+
     pub fn stop(&self) {
         trace!("stop session: {}", self.addr);
         // ignore disconnected error. if the receiver is deallocated,
@@ -64,15 +208,46 @@ impl SessionHandle {
         }
     }
 
+    /// Wait for the session to finish, and take its statistics after relay threads have exited
+    ///
+    /// Unlike `stats` taken before `stop`, bytes relayed until the threads exit are counted.
+    pub fn finish(self) -> (SessionStats, thread::Result<Result<(), Error>>) {
+        let traffic = self.traffic.clone();
+        let started = self.started;
+        let mut stats = self.stats();
+        let result = self.join();
+        stats.upload_bytes = traffic.upload();
+        stats.download_bytes = traffic.download();
+        stats.duration = started.elapsed();
+        (stats, result)
+    }
+
+    /// Wait for the session to finish
+    ///
+    /// The error of the session is given its `SessionContext`.
     pub fn join(self) -> thread::Result<Result<(), Error>> {
         trace!("join session: {}", self.addr);
-        match self.handle.join()? {
-            Ok(relay) => relay.join(),
-            Err(err) => Ok(Err(err)),
-        }
+        let result = match self.handle.join()? {
+            Ok(relay) => relay.join()?,
+            Err(err) => Err(err),
+        };
+        // the destination is known after the session thread has exited
+        let context = SessionContext {
+            session_id: self.id,
+            client_addr: self.addr,
+            dst_addr: self.destination.get(),
+        };
+        Ok(result.map_err(|err| err.in_session(context)))
     }
 }
 
+/// Interval to check termination of the session waiting an incoming connection
+const BIND_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Prefix of the identity of a client authenticated by the connection (`ByteStream::peer_identity`)
+/// matched with `user` of rules, e.g. `cert:device-01` for a certificate of `CN=device-01`
+pub const PEER_IDENTITY_PREFIX: &str = "cert:";
+
 #[derive(Debug)]
 pub struct Session<D, A, S> {
     pub id: SessionId,
@@ -80,7 +255,52 @@ pub struct Session<D, A, S> {
     pub dst_connector: D,
     pub authorizer: A,
     pub server_addr: SocketAddr,
-    pub conn_rule: ConnectRule,
+    /// decides requests of the client
+    pub policy: Arc<dyn ConnectPolicy>,
+    /// bandwidth limit of relays
+    pub bandwidth: Bandwidth,
+    /// receiver of audit events
+    pub logger: Option<Arc<dyn SessionLogger>>,
+    /// address replied to CONNECT requests
+    pub reply_addr: ReplyAddr,
+    /// receiver of lifecycle events
+    pub events: Option<Arc<dyn ServerEventHandler>>,
+    /// timeout of waiting an incoming connection for BIND command
+    pub bind_timeout: Option<Duration>,
+    /// ports listened on for BIND command (`None` means an ephemeral port)
+    pub bind_ports: Option<RangeInclusive<u16>>,
+    /// limits of the lifetime of relays
+    pub lifetime: Lifetime,
+    /// apply ip address rules to addresses resolved from a requested domain
+    pub check_resolved: bool,
+    /// reject private addresses resolved from a requested domain unless allowed by rules explicitly
+    pub reject_private_resolved: bool,
+    /// accept SOCKS4/4a CONNECT requests if `NoAuth` is acceptable
+    pub accept_socks4: bool,
+    /// accept messages of the client with a protocol version other than `version`
+    pub lenient_version: bool,
+    /// auth methods allowed to be selected by `authorizer` (`None` for any)
+    pub allowed_methods: Option<Vec<Method>>,
+    /// the client speaks HTTP CONNECT instead of SOCKS
+    pub http_connect: bool,
+    /// connect to this address without reading a request from the client
+    pub forward_to: Option<Address>,
+    /// apply domain rules to the TLS server name sent to an ip address on port 443
+    pub inspect_sni: bool,
+    /// timeout of reassembling fragmented UDP datagrams (`None` drops them)
+    pub udp_reassembly_timeout: Option<Duration>,
+    /// timeout of reading the request from the connection
+    pub handshake_timeout: Option<Duration>,
+    /// size of the buffer of each direction of relays
+    pub relay_buffer_size: usize,
+    /// rewrites destinations allowed by `policy` before connecting
+    pub rewriter: Option<Arc<dyn ConnectRewriter>>,
+    /// counter of sessions through rules with `max_sessions` (not limited if `None`)
+    pub(crate) destination_slot: Option<DestinationSlot>,
+    /// bytes relayed by this session
+    traffic: Traffic,
+    /// destination requested by the client
+    destination: Destination,
     /// termination message receiver
     rx: Arc<Mutex<mpsc::Receiver<()>>>,
     /// Send `Disconnect` command to the main thread.
@@ -101,7 +321,7 @@ where
         dst_connector: D,
         authorizer: A,
         server_addr: SocketAddr,
-        conn_rule: ConnectRule,
+        policy: Arc<dyn ConnectPolicy>,
         tx_cmd: mpsc::Sender<ServerCommand<S>>,
     ) -> (Self, mpsc::SyncSender<()>) {
         let (tx, rx) = mpsc::sync_channel(2);
@@ -112,7 +332,29 @@ where
                 dst_connector,
                 authorizer,
                 server_addr,
-                conn_rule,
+                policy,
+                bandwidth: Bandwidth::default(),
+                logger: None,
+                reply_addr: ReplyAddr::default(),
+                events: None,
+                bind_timeout: None,
+                bind_ports: None,
+                lifetime: Lifetime::default(),
+                check_resolved: false,
+                reject_private_resolved: false,
+                accept_socks4: false,
+                lenient_version: false,
+                allowed_methods: None,
+                http_connect: false,
+                forward_to: None,
+                inspect_sni: false,
+                udp_reassembly_timeout: None,
+                handshake_timeout: None,
+                relay_buffer_size: relay::DEFAULT_BUFFER_SIZE,
+                rewriter: None,
+                destination_slot: None,
+                traffic: Traffic::default(),
+                destination: Destination::default(),
                 rx: Arc::new(Mutex::new(rx)),
                 guard: Arc::new(Mutex::new(DisconnectGuard::new(id, tx_cmd))),
             },
@@ -120,6 +362,23 @@ where
         )
     }
 
+    /// counter of bytes relayed by this session
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.clone()
+    }
+
+    /// destination requested to this session
+    pub(crate) fn destination(&self) -> Destination {
+        self.destination.clone()
+    }
+
+    fn resolved_check(&self) -> ResolvedCheck {
+        ResolvedCheck {
+            rules: self.check_resolved,
+            reject_private: self.reject_private_resolved,
+        }
+    }
+
     fn connect_reply(&self, connect_result: Result<(), ConnectError>) -> ConnectReply {
         ConnectReply {
             version: self.version,
@@ -128,234 +387,2197 @@ where
         }
     }
 
+    /// Reply to a successful CONNECT request
+    ///
+    /// * `local`
+    ///    The local address of the connection to the destination.
+    fn connected_reply(&self, local: Option<SocketAddr>) -> ConnectReply {
+        ConnectReply {
+            server_addr: self.reply_addr.select(self.server_addr, local).into(),
+            ..self.connect_reply(Ok(()))
+        }
+    }
+
+    /// Log the connection of the request decided by `decision` (`None` if not decided by rules)
+    fn log_connect(
+        &self,
+        src_addr: SocketAddr,
+        command: Command,
+        dst: Address,
+        peer: SocketAddr,
+        decision: Option<&Decision>,
+    ) {
+        if let Some(logger) = &self.logger {
+            let (matched_rule, matched_rule_name) = matched_rule(decision);
+            let event = ConnectEvent {
+                time: SystemTime::now(),
+                session_id: self.id,
+                client_addr: src_addr,
+                command,
+                matched_rule,
+                matched_rule_name,
+                dst_addr: dst,
+                peer_addr: peer,
+            };
+            logger.on_connect(&event);
+            self.guard
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .log = Some(DisconnectLog::new(
+                logger.clone(),
+                event,
+                self.traffic.clone(),
+            ));
+        }
+    }
+
+    /// Log the rejection of the request by `err`, decided by `decision` if decided by rules
+    fn log_reject(
+        &self,
+        src_addr: SocketAddr,
+        command: Command,
+        dst: &Address,
+        err: &Error,
+        decision: Option<&Decision>,
+    ) {
+        if let Some(logger) = &self.logger {
+            let (matched_rule, matched_rule_name) = matched_rule(decision);
+            logger.on_reject(&RejectEvent {
+                time: SystemTime::now(),
+                session_id: self.id,
+                client_addr: src_addr,
+                command,
+                dst_addr: dst.clone(),
+                reason: reject_reason(err),
+                matched_rule,
+                matched_rule_name,
+                reply: err.cerr(),
+            });
+        }
+    }
+
     fn make_session<'a>(
+        &self,
+        src_addr: SocketAddr,
+        src_conn: impl ByteStream + 'a,
+    ) -> Result<RelayHandle, Error> {
+        let handshake = Arc::new(HandshakeDeadline::new(self.handshake_timeout));
+        let src_conn = HandshakeStream::new(src_conn, handshake.clone());
+        let res = if let Some(forward_to) = &self.forward_to {
+            handshake.finish();
+            self.make_forward_session(src_addr, src_conn, forward_to.clone())
+        } else if self.http_connect {
+            self.make_http_session(src_addr, src_conn, &handshake)
+        } else {
+            self.make_socks_session(src_addr, src_conn, &handshake)
+        };
+        res.map_err(|err| {
+            if handshake.is_expired() {
+                info!("handshake timed out: {}", src_addr);
+                ErrorKind::HandshakeTimedOut.into()
+            } else {
+                err
+            }
+        })
+    }
+
+    /// `handshake` is finished when the request of the client is read
+    fn make_socks_session<'a>(
         &self,
         src_addr: SocketAddr,
         mut src_conn: impl ByteStream + 'a,
+        handshake: &HandshakeDeadline,
     ) -> Result<RelayHandle, Error> {
-        let mut socks = ReadWriteStream::new(&mut src_conn);
+        let mut version = [0u8];
+        src_conn.read_exact(&mut version)?;
+        // the underlying connection has authenticated the client by the first read, if ever
+        let peer = match self.peer_principal(&src_conn) {
+            Ok(peer) => peer,
+            Err(err) => {
+                self.reject_peer(version[0], &mut src_conn);
+                return Err(err);
+            }
+        };
+        if version[0] == socks4::SOCKS4_VERSION {
+            return self.make_socks4_session(src_addr, src_conn, peer, handshake);
+        }
+        let mut socks = ReadWriteStream::new(Replay::new(version[0], &mut src_conn));
 
-        let select = negotiate_auth_method(self.version, &self.authorizer, &mut socks)?;
+        let select = negotiate_auth_method(
+            self.version,
+            self.lenient_version,
+            &self.authorizer,
+            self.allowed_methods.as_deref(),
+            &mut socks,
+        )?;
         debug!("auth method: {:?}", select);
-        let mut socks = ReadWriteStream::new(self.authorizer.authorize(select.method, src_conn)?);
+        // the client certificate identifies the client unless the method authenticates a user
+        let (conn, user) = self.authorizer.authorize_user(select.method, src_conn)?;
+        let user = user.or(peer);
+        let user = user.as_deref();
+        if let Some(user) = user {
+            debug!("authenticated user: {}", user);
+        }
+        if let Some(events) = &self.events {
+            events.on_authenticated(&AuthEvent::new(self.id, src_addr, select.method, user));
+        }
+        let mut socks = ReadWriteStream::new(conn);
 
-        let req = socks.recv_connect_request()?;
+        let req = socks.recv_connect_request().and_then(|req| {
+            if !self.lenient_version {
+                expect_version(self.version, req.version, "connect request")?;
+            }
+            Ok(req)
+        });
+        let req = match req {
+            Ok(req) => {
+                handshake.finish();
+                req
+            }
+            Err(err) => {
+                // the client waits for a reply to a malformed address or version
+                if let ErrorKind::AddrTypeNotSupported { .. } | ErrorKind::MessageFormat { .. } =
+                    err.kind()
+                {
+                    socks
+                        .send_connect_reply(self.connect_reply(Err(err.cerr())))
+                        .ok();
+                }
+                return Err(err);
+            }
+        };
+        self.destination.set(req.connect_to.clone());
         debug!("connect request: {:?}", req);
 
-        let (conn, dst_addr) = match perform_command(
-            req.command,
-            &self.dst_connector,
-            &self.conn_rule,
-            req.connect_to.clone(),
-        ) {
+        match req.command {
+            Command::UdpAssociate => {
+                return self.udp_associate(src_addr, user, socks, req.connect_to)
+            }
+            Command::Bind => return self.bind(src_addr, user, socks, req.connect_to),
+            Command::Connect => {}
+        }
+
+        // the session may be stopped during the handshake
+        let (decision, res) = match self.check_stopped() {
+            Ok(()) => perform_command(
+                req.command,
+                &self.dst_connector,
+                &*self.policy,
+                self.destination_slot.as_ref(),
+                self.rewriter.as_deref(),
+                self.resolved_check(),
+                src_addr,
+                user,
+                req.connect_to.clone(),
+            ),
+            Err(err) => (None, Err(err)),
+        };
+        let (conn, dst_addr) = match res {
             Ok((conn, dst_addr)) => {
                 info!("connected: {}: {}", req.connect_to, dst_addr);
-                socks.send_connect_reply(self.connect_reply(Ok(())))?;
+                socks.send_connect_reply(self.connected_reply(conn.local_addr()))?;
                 (conn, dst_addr)
             }
             Err(err) => {
                 error!("command error: {}", err);
                 trace!("command error: {:?}", err);
+                self.log_reject(
+                    src_addr,
+                    req.command,
+                    &req.connect_to,
+                    &err,
+                    decision.as_ref(),
+                );
                 // reply error
                 socks.send_connect_reply(self.connect_reply(Err(err.cerr())))?;
                 return Err(err);
             }
         };
 
+        let mut client = socks.into_inner();
+        let mut conn = conn;
+        if self.inspect_sni {
+            if let (server_name, Err(err)) =
+                self.inspect_server_name(src_addr, user, &req.connect_to, &mut client, &mut conn)
+            {
+                let decision = server_name.as_ref().or(decision.as_ref());
+                self.log_reject(src_addr, req.command, &req.connect_to, &err, decision);
+                return Err(err);
+            }
+        }
+
+        self.log_connect(
+            src_addr,
+            req.command,
+            req.connect_to,
+            dst_addr,
+            decision.as_ref(),
+        );
+
         relay::spawn_relay(
             src_addr,
             dst_addr,
-            socks.into_inner(),
+            client,
             conn,
+            self.bandwidth.clone(),
+            self.traffic.clone(),
+            self.lifetime,
+            self.relay_buffer_size,
             self.rx.clone(),
             self.guard.clone(),
         )
     }
 
-    pub fn start<'a>(
-        self,
+    /// `Disconnected` if the session has been stopped (e.g. by `Terminate`) before performing the request
+    ///
+    /// The client is replied `ServerFailure` instead of seeing the connection closed.
+    fn check_stopped(&self) -> Result<(), Error> {
+        match self.rx.lock()?.try_recv() {
+            Ok(()) => {
+                info!("session is stopped before connecting: {}", self.id);
+                Err(ErrorKind::disconnected("session").into())
+            }
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Apply domain rules to the TLS server name the client sends to an ip address
+    ///
+    /// The first record read from `client` is forwarded to `server` if it is allowed.
+    /// The decision on the server name is returned if it is checked.
+    fn inspect_server_name(
+        &self,
         src_addr: SocketAddr,
-        src_conn: impl ByteStream + 'a,
-    ) -> Result<RelayHandle, Error> {
-        self.make_session(src_addr, src_conn)
+        user: Option<&str>,
+        connect_to: &Address,
+        client: &mut impl io::Read,
+        server: &mut impl io::Write,
+    ) -> Decided<()> {
+        let port = match connect_to {
+            Address::Domain(..) => return (None, Ok(())),
+            addr if addr.port() == sni::HTTPS_PORT => sni::HTTPS_PORT,
+            _ => return (None, Ok(())),
+        };
+        let record = match sni::read_record(client, sni::READ_TIMEOUT) {
+            Ok(record) => record,
+            Err(err) => return (None, Err(err.into())),
+        };
+        let decision = sni::server_name(&record).map(|name| {
+            debug!("server name: {}: {}", connect_to, name);
+            let addr = Address::Domain(name, port);
+            let decision = self.policy.check(
+                &ConnectContext::new(src_addr, user, &addr, L4Protocol::Tcp)
+                    .with_stage(CheckStage::ServerName),
+            );
+            (addr, decision)
+        });
+        let decision = match decision {
+            Some((addr, decision)) if !decision.allow => {
+                info!("server name is not allowed: {}: {}", connect_to, addr);
+                let res = check_decision(&decision, &addr, L4Protocol::Tcp);
+                return (Some(decision), res);
+            }
+            decision => decision.map(|(_, decision)| decision),
+        };
+        let res = server.write_all(&record).map_err(Error::from).map(|()| {
+            self.traffic
+                .upload
+                .fetch_add(record.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        });
+        (decision, res)
     }
-}
 
-fn perform_command(
-    cmd: Command,
-    connector: impl Deref<Target = impl Connector>,
-    rule: &ConnectRule,
-    connect_to: Address,
-) -> Result<(impl ByteStream, SocketAddr), Error> {
-    match cmd {
-        Command::Connect => {}
-        cmd @ Command::Bind | cmd @ Command::UdpAssociate => {
-            return Err(ErrorKind::command_not_supported(cmd).into());
+    /// Session of SOCKS4/4a client following the version field
+    ///
+    /// reply to the client rejected by `peer_principal` as to the one no method is acceptable to
+    ///
+    /// The request following `version` is read first, the client waits for the reply to it.
+    fn reject_peer(&self, version: u8, src_conn: &mut impl ByteStream) {
+        if version == socks4::SOCKS4_VERSION {
+            socks4::recv_request(&mut *src_conn).ok();
+            socks4::send_reply(src_conn, Err(ConnectError::ConnectionNotAllowed)).ok();
+        } else {
+            let mut socks = ReadWriteStream::new(Replay::new(version, src_conn));
+            socks.recv_method_candidates().ok();
+            socks
+                .send_method_selection(MethodSelection {
+                    version: self.version,
+                    method: Method::NoMethods,
+                })
+                .ok();
         }
-    };
-    // filter out request not sufficies the connection rule
-    check_rule(rule, connect_to.clone(), L4Protocol::Tcp)?;
-    connector.connect_byte_stream(connect_to)
-}
+    }
 
-fn negotiate_auth_method(
-    version: ProtocolVersion,
-    auth: impl Deref<Target = impl AuthService>,
-    mut socks: impl DerefMut<Target = impl SocksStream>,
-) -> Result<MethodSelection, Error> {
-    let candidates = socks.recv_method_candidates()?;
-    trace!("candidates: {:?}", candidates);
+    /// `peer` identifies the client by the underlying connection (`peer_principal`).
+    fn make_socks4_session<'a>(
+        &self,
+        src_addr: SocketAddr,
+        mut src_conn: impl ByteStream + 'a,
+        peer: Option<String>,
+        handshake: &HandshakeDeadline,
+    ) -> Result<RelayHandle, Error> {
+        let req = match socks4::recv_request(&mut src_conn) {
+            Ok(req) => req,
+            Err(err) => {
+                // the client waits for a reply to a malformed request
+                if let ErrorKind::MessageFormat { .. } = err.kind() {
+                    socks4::send_reply(&mut src_conn, Err(err.cerr())).ok();
+                }
+                return Err(err);
+            }
+        };
+        handshake.finish();
+        self.destination.set(req.connect_to.clone());
+        debug!("socks4 request: {:?}", req);
 
-    let selection = auth.select(&candidates.method)?;
-    trace!("selection: {:?}", selection);
+        // SOCKS4 has no authentication methods, only the client certificate identifies the client
+        let user = peer.as_deref();
+        let accepted = if self.accept_socks4 {
+            self.select_no_auth()
+        } else {
+            Err(ErrorKind::NoAcceptableMethod.into())
+        };
+        let (decision, res) = match accepted.and_then(|()| self.check_stopped()) {
+            Ok(()) => perform_command(
+                req.command,
+                &self.dst_connector,
+                &*self.policy,
+                self.destination_slot.as_ref(),
+                self.rewriter.as_deref(),
+                self.resolved_check(),
+                src_addr,
+                user,
+                req.connect_to.clone(),
+            ),
+            Err(err) => (None, Err(err)),
+        };
+        let (mut conn, dst_addr) = match res {
+            Ok((conn, dst_addr)) => {
+                info!("connected: {}: {}", req.connect_to, dst_addr);
+                socks4::send_reply(&mut src_conn, Ok(()))?;
+                (conn, dst_addr)
+            }
+            Err(err) => {
+                error!("socks4 command error: {}", err);
+                self.log_reject(
+                    src_addr,
+                    req.command,
+                    &req.connect_to,
+                    &err,
+                    decision.as_ref(),
+                );
+                socks4::send_reply(&mut src_conn, Err(err.cerr()))?;
+                return Err(err);
+            }
+        };
+        if self.inspect_sni {
+            if let (server_name, Err(err)) =
+                self.inspect_server_name(src_addr, user, &req.connect_to, &mut src_conn, &mut conn)
+            {
+                let decision = server_name.as_ref().or(decision.as_ref());
+                self.log_reject(src_addr, req.command, &req.connect_to, &err, decision);
+                return Err(err);
+            }
+        }
 
-    let method_sel = MethodSelection {
-        version,
-        method: selection.unwrap_or(Method::NoMethods),
-    };
-    socks.send_method_selection(method_sel)?;
-    match method_sel.method {
-        Method::NoMethods => Err(ErrorKind::NoAcceptableMethod.into()),
-        _ => Ok(method_sel),
-    }
-}
+        self.log_connect(
+            src_addr,
+            req.command,
+            req.connect_to,
+            dst_addr,
+            decision.as_ref(),
+        );
 
-fn check_rule(rule: &ConnectRule, addr: Address, proto: L4Protocol) -> Result<(), Error> {
-    if rule.check(addr.clone(), proto) {
-        Ok(())
-    } else {
-        Err(ErrorKind::connection_not_allowed(addr, proto).into())
+        relay::spawn_relay(
+            src_addr,
+            dst_addr,
+            Box::new(src_conn),
+            conn,
+            self.bandwidth.clone(),
+            self.traffic.clone(),
+            self.lifetime,
+            self.relay_buffer_size,
+            self.rx.clone(),
+            self.guard.clone(),
+        )
     }
-}
-
-#[derive(Debug, Clone)]
-pub struct DisconnectGuard<S> {
-    id: SessionId,
-    tx: mpsc::Sender<ServerCommand<S>>,
-}
 
-impl<S> DisconnectGuard<S> {
-    pub fn new(id: SessionId, tx: mpsc::Sender<ServerCommand<S>>) -> Self {
-        Self { id, tx }
+    /// Identity of the client authenticated by the underlying connection, accepted by `authorizer`
+    ///
+    /// e.g. the common name of the certificate of a TLS client.
+    /// It is prefixed by `PEER_IDENTITY_PREFIX` to be matched with `user` of rules,
+    /// so that it is never confused with the name of a user authenticated by a method.
+    fn peer_principal(&self, conn: &impl ByteStream) -> Result<Option<String>, Error> {
+        let identity = conn.peer_identity();
+        self.authorizer.authorize_peer(identity.as_deref())?;
+        Ok(identity.map(|identity| format!("{}{}", PEER_IDENTITY_PREFIX, identity)))
     }
-}
 
-impl<S> Drop for DisconnectGuard<S> {
-    fn drop(&mut self) {
-        debug!("DisconnectGuard: {}", self.id);
-        self.tx.send(ServerCommand::Disconnect(self.id)).unwrap()
+    /// `NoAcceptableMethod` unless the authorizer accepts clients without authentication
+    ///
+    /// SOCKS4 and HTTP CONNECT clients have no methods to negotiate.
+    fn select_no_auth(&self) -> Result<(), Error> {
+        let candidates = MethodCandidates::new(&[Method::NoAuth]);
+        let allowed = self.allowed_methods.as_deref();
+        match select_method(self.version, true, &self.authorizer, allowed, &candidates)? {
+            Method::NoAuth => Ok(()),
+            _ => Err(ErrorKind::NoAcceptableMethod.into()),
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::auth_service::test::RejectService;
-    use crate::byte_stream::test::BufferStream;
-    use crate::connector::test::BufferConnector;
-    use crate::rw_socks_stream as socks;
-    use std::io;
-    use std::iter::FromIterator;
-    use std::str::FromStr;
+    /// Session of a client forwarded to `forward_to`
+    fn make_forward_session<'a>(
+        &self,
+        src_addr: SocketAddr,
+        mut src_conn: impl ByteStream + 'a,
+        forward_to: Address,
+    ) -> Result<RelayHandle, Error> {
+        self.destination.set(forward_to.clone());
+        debug!("forward: {}: {}", src_addr, forward_to);
 
-    #[test]
-    fn no_acceptable_method() {
-        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
-        let (session, _) = Session::new(
-            0.into(),
-            5.into(),
-            BufferConnector::from_iter(vec![(
-                "192.168.0.1:5123".parse().unwrap(),
-                Ok(BufferStream::new()),
-            )]),
-            RejectService,
-            "0.0.0.0:1080".parse().unwrap(),
-            ConnectRule::any(),
-            tx,
+        // forwarded connections are not authenticated except by the client certificate
+        let peer = match self.peer_principal(&src_conn) {
+            Ok(peer) => peer,
+            Err(err) => {
+                self.log_reject(src_addr, Command::Connect, &forward_to, &err, None);
+                return Err(err);
+            }
+        };
+        let user = peer.as_deref();
+        let (decision, res) = perform_command(
+            Command::Connect,
+            &self.dst_connector,
+            &*self.policy,
+            self.destination_slot.as_ref(),
+            self.rewriter.as_deref(),
+            self.resolved_check(),
+            src_addr,
+            user,
+            forward_to.clone(),
         );
-        println!("session: {:?}", session);
-        let src = BufferStream::with_buffer(vec![5, 1, 0].into(), vec![].into());
-        assert_eq!(
-            session
-                .make_session("192.168.0.2:12345".parse().unwrap(), src)
-                .unwrap_err()
-                .kind(),
-            &ErrorKind::NoAcceptableMethod
+        let (mut conn, dst_addr) = match res {
+            Ok((conn, dst_addr)) => {
+                info!("connected: {}: {}", forward_to, dst_addr);
+                (conn, dst_addr)
+            }
+            Err(err) => {
+                error!("forward error: {}", err);
+                self.log_reject(
+                    src_addr,
+                    Command::Connect,
+                    &forward_to,
+                    &err,
+                    decision.as_ref(),
+                );
+                return Err(err);
+            }
+        };
+        if self.inspect_sni {
+            if let (server_name, Err(err)) =
+                self.inspect_server_name(src_addr, user, &forward_to, &mut src_conn, &mut conn)
+            {
+                let decision = server_name.as_ref().or(decision.as_ref());
+                self.log_reject(src_addr, Command::Connect, &forward_to, &err, decision);
+                return Err(err);
+            }
+        }
+
+        self.log_connect(
+            src_addr,
+            Command::Connect,
+            forward_to,
+            dst_addr,
+            decision.as_ref(),
         );
+
+        relay::spawn_relay(
+            src_addr,
+            dst_addr,
+            Box::new(src_conn),
+            conn,
+            self.bandwidth.clone(),
+            self.traffic.clone(),
+            self.lifetime,
+            self.relay_buffer_size,
+            self.rx.clone(),
+            self.guard.clone(),
+        )
+    }
+
+    /// Session of a client speaking HTTP CONNECT
+    fn make_http_session<'a>(
+        &self,
+        src_addr: SocketAddr,
+        mut src_conn: impl ByteStream + 'a,
+        handshake: &HandshakeDeadline,
+    ) -> Result<RelayHandle, Error> {
+        let connect_to = http_connect::recv_request(&mut src_conn)?;
+        handshake.finish();
+        self.destination.set(connect_to.clone());
+        debug!("http connect request: {}", connect_to);
+
+        // HTTP CONNECT is not authenticated except by the client certificate
+        let (peer, accepted) = match self.peer_principal(&src_conn) {
+            Ok(peer) => (peer, self.select_no_auth()),
+            Err(err) => (None, Err(err)),
+        };
+        let user = peer.as_deref();
+        let (decision, res) = match accepted.and_then(|()| self.check_stopped()) {
+            Ok(()) => perform_command(
+                Command::Connect,
+                &self.dst_connector,
+                &*self.policy,
+                self.destination_slot.as_ref(),
+                self.rewriter.as_deref(),
+                self.resolved_check(),
+                src_addr,
+                user,
+                connect_to.clone(),
+            ),
+            Err(err) => (None, Err(err)),
+        };
+        let (mut conn, dst_addr) = match res {
+            Ok((conn, dst_addr)) => {
+                info!("connected: {}: {}", connect_to, dst_addr);
+                http_connect::send_reply(&mut src_conn, Ok(()))?;
+                (conn, dst_addr)
+            }
+            Err(err) => {
+                error!("http connect error: {}", err);
+                self.log_reject(
+                    src_addr,
+                    Command::Connect,
+                    &connect_to,
+                    &err,
+                    decision.as_ref(),
+                );
+                http_connect::send_reply(&mut src_conn, Err(err.cerr()))?;
+                return Err(err);
+            }
+        };
+        if self.inspect_sni {
+            if let (server_name, Err(err)) =
+                self.inspect_server_name(src_addr, user, &connect_to, &mut src_conn, &mut conn)
+            {
+                let decision = server_name.as_ref().or(decision.as_ref());
+                self.log_reject(src_addr, Command::Connect, &connect_to, &err, decision);
+                return Err(err);
+            }
+        }
+
+        self.log_connect(
+            src_addr,
+            Command::Connect,
+            connect_to,
+            dst_addr,
+            decision.as_ref(),
+        );
+
+        relay::spawn_relay(
+            src_addr,
+            dst_addr,
+            Box::new(src_conn),
+            conn,
+            self.bandwidth.clone(),
+            self.traffic.clone(),
+            self.lifetime,
+            self.relay_buffer_size,
+            self.rx.clone(),
+            self.guard.clone(),
+        )
+    }
+
+    /// Associate a UDP relay with the control connection `socks`
+    fn udp_associate(
+        &self,
+        src_addr: SocketAddr,
+        user: Option<&str>,
+        mut socks: ReadWriteStream<BoxedStream>,
+        client_udp_addr: Address,
+    ) -> Result<RelayHandle, Error> {
+        let relay_addr = SocketAddr::new(self.server_addr.ip(), 0);
+        let (pkt, bound) = match self
+            .dst_connector
+            .bind_pkt_stream(relay_addr)
+            .and_then(|pkt| pkt.local_addr().map(|addr| (pkt, addr)))
+        {
+            Ok(pkt) => pkt,
+            Err(err) => {
+                error!("udp associate error: {}", err);
+                trace!("udp associate error: {:?}", err);
+                socks.send_connect_reply(self.connect_reply(Err(err.cerr())))?;
+                return Err(err);
+            }
+        };
+        info!("udp associated: {}: {}", client_udp_addr, bound);
+        socks.send_connect_reply(ConnectReply {
+            version: self.version,
+            connect_result: Ok(()),
+            server_addr: SocketAddr::new(self.server_addr.ip(), bound.port()).into(),
+        })?;
+        // destinations are checked per datagram, so `peer_addr` is the relay socket
+        self.log_connect(
+            src_addr,
+            Command::UdpAssociate,
+            client_udp_addr.clone(),
+            bound,
+            None,
+        );
+
+        relay::spawn_udp_relay(
+            src_addr,
+            client_udp_addr,
+            socks.into_inner(),
+            pkt,
+            self.policy.clone(),
+            self.dst_connector.resolver(),
+            self.resolved_check(),
+            user.map(str::to_owned),
+            self.udp_reassembly_timeout,
+            self.bandwidth.clone(),
+            self.traffic.clone(),
+            self.lifetime,
+            self.rx.clone(),
+            self.guard.clone(),
+        )
+    }
+
+    /// Wait for a connection from `expected` host (BIND command)
+    ///
+    /// The first reply tells the address listening on,
+    /// and the second reply tells the address of the connected host.
+    fn bind(
+        &self,
+        src_addr: SocketAddr,
+        user: Option<&str>,
+        mut socks: ReadWriteStream<BoxedStream>,
+        expected: Address,
+    ) -> Result<RelayHandle, Error> {
+        let decision = decide_rule(&*self.policy, src_addr, user, &expected, L4Protocol::Tcp);
+        let allowed = check_decision(&decision, &expected, L4Protocol::Tcp);
+        let decision = Some(decision);
+        let (listener, bound) = match allowed
+            .and_then(|()| self.bind_listener())
+            .and_then(|listener| listener.local_addr().map(|addr| (listener, addr)))
+        {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("bind error: {}", err);
+                trace!("bind error: {:?}", err);
+                self.log_reject(src_addr, Command::Bind, &expected, &err, decision.as_ref());
+                socks.send_connect_reply(self.connect_reply(Err(err.cerr())))?;
+                return Err(err);
+            }
+        };
+        info!("bind: {}: {}", expected, bound);
+        socks.send_connect_reply(ConnectReply {
+            version: self.version,
+            connect_result: Ok(()),
+            server_addr: SocketAddr::new(self.server_addr.ip(), bound.port()).into(),
+        })?;
+
+        let (conn, peer) = match self.accept_bind(&listener, src_addr, user, &expected) {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("bind error: {}", err);
+                trace!("bind error: {:?}", err);
+                self.log_reject(src_addr, Command::Bind, &expected, &err, decision.as_ref());
+                socks.send_connect_reply(self.connect_reply(Err(err.cerr())))?;
+                return Err(err);
+            }
+        };
+        info!("bind accepted: {}: {}", expected, peer);
+        socks.send_connect_reply(ConnectReply {
+            version: self.version,
+            connect_result: Ok(()),
+            server_addr: peer.into(),
+        })?;
+        self.log_connect(src_addr, Command::Bind, expected, peer, decision.as_ref());
+
+        relay::spawn_relay(
+            src_addr,
+            peer,
+            socks.into_inner(),
+            conn,
+            self.bandwidth.clone(),
+            self.traffic.clone(),
+            self.lifetime,
+            self.relay_buffer_size,
+            self.rx.clone(),
+            self.guard.clone(),
+        )
+    }
+
+    /// Listen on the first available port in `bind_ports`
+    fn bind_listener(&self) -> Result<D::L, Error> {
+        let ports = self.bind_ports.clone().unwrap_or(0..=0);
+        let mut last_err = None;
+        for port in ports {
+            let addr = SocketAddr::new(self.server_addr.ip(), port);
+            match self.dst_connector.listen_byte_stream(addr) {
+                Ok(listener) => return Ok(listener),
+                Err(err) => {
+                    debug!("bind: can not listen on {}: {}", addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            ErrorKind::message_fmt(format_args!("no ports to listen on: {:?}", self.bind_ports))
+                .into()
+        }))
+    }
+
+    /// Accept a connection from `expected` host within `bind_timeout`
+    ///
+    /// Connections from other hosts are dropped.
+    fn accept_bind<L: StreamListener>(
+        &self,
+        listener: &L,
+        src_addr: SocketAddr,
+        user: Option<&str>,
+        expected: &Address,
+    ) -> Result<(L::B, SocketAddr), Error> {
+        // a domain is resolved as the destinations of `CONNECT`
+        let resolved = match expected {
+            Address::Domain(..) => resolve_checked(
+                &*self.dst_connector.resolver(),
+                &*self.policy,
+                self.resolved_check(),
+                src_addr,
+                user,
+                expected,
+                L4Protocol::Tcp,
+            )?,
+            _ => vec![],
+        };
+        let deadline = self.bind_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // the session may be terminated while waiting
+            if let Ok(()) = self.rx.lock()?.try_recv() {
+                return Err(ErrorKind::disconnected("bind").into());
+            }
+            let wait = match deadline {
+                Some(deadline) => {
+                    let remain = deadline.saturating_duration_since(Instant::now());
+                    if remain.is_zero() {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "bind").into());
+                    }
+                    remain.min(BIND_POLL_INTERVAL)
+                }
+                None => BIND_POLL_INTERVAL,
+            };
+            if let Some((conn, peer)) = listener.accept(wait)? {
+                if !expected_peer(expected, &resolved, peer) {
+                    // keep waiting, so that other hosts can not cancel the BIND request
+                    warn!("bind: drop connection from unexpected host: {}", peer);
+                    continue;
+                }
+                return Ok((conn, peer));
+            }
+        }
+    }
+
+    pub fn start<'a>(
+        self,
+        src_addr: SocketAddr,
+        src_conn: impl ByteStream + 'a,
+    ) -> Result<RelayHandle, Error> {
+        if let Some(events) = &self.events {
+            events.on_connect(&AcceptEvent::new(self.id, src_addr));
+        }
+        let relay = self.make_session(src_addr, src_conn)?;
+        self.destination.set_relaying();
+        Ok(relay)
+    }
+}
+
+/// Deadline of the handshake shared by a session and the stream of its client
+#[derive(Debug)]
+struct HandshakeDeadline {
+    /// `None` if there is no timeout
+    at: Option<Instant>,
+    /// the request has been read
+    done: AtomicBool,
+    /// a read has failed by the deadline
+    expired: AtomicBool,
+}
+
+impl HandshakeDeadline {
+    fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            at: timeout.map(|timeout| Instant::now() + timeout),
+            done: AtomicBool::new(false),
+            expired: AtomicBool::new(false),
+        }
+    }
+
+    fn finish(&self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Remaining time of the handshake in progress
+    ///
+    /// Returns `Ok(None)` if there is no deadline, or the handshake has been finished.
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        match self.at {
+            Some(at) if !self.done.load(Ordering::Relaxed) => {
+                let remaining = at.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    self.expired.store(true, Ordering::Relaxed);
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "handshake timed out",
+                    ));
+                }
+                Ok(Some(remaining))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Stream of a client failing to read after the deadline of the handshake
+///
+/// The read timeout of the stream is shortened to the deadline during the handshake,
+/// and restored after it.
+#[derive(Debug)]
+struct HandshakeStream<S> {
+    strm: S,
+    deadline: Arc<HandshakeDeadline>,
+    /// read timeout of `strm` before the handshake
+    read_timeout: Option<Duration>,
+    /// the read timeout of `strm` has been shortened
+    shortened: AtomicBool,
+}
+
+impl<S: ByteStream> HandshakeStream<S> {
+    fn new(strm: S, deadline: Arc<HandshakeDeadline>) -> Self {
+        let read_timeout = strm.read_timeout().unwrap_or(None);
+        Self {
+            strm,
+            deadline,
+            read_timeout,
+            shortened: AtomicBool::new(false),
+        }
+    }
+
+    /// Set the read timeout of `strm` by the deadline
+    fn update_read_timeout(&self) -> io::Result<()> {
+        match self.deadline.remaining()? {
+            Some(remaining) => {
+                let timeout = self
+                    .read_timeout
+                    .map_or(remaining, |timeout| timeout.min(remaining));
+                self.shortened.store(true, Ordering::Relaxed);
+                self.strm.set_read_timeout(Some(timeout))
+            }
+            None if self.shortened.swap(false, Ordering::Relaxed) => {
+                self.strm.set_read_timeout(self.read_timeout)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S: ByteStream> io::Read for HandshakeStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.update_read_timeout()?;
+        match self.strm.read(buf) {
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                // fails if the deadline has passed
+                self.deadline.remaining()?;
+                Err(err)
+            }
+            res => res,
+        }
+    }
+}
+
+impl<S: ByteStream> io::Write for HandshakeStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.strm.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.strm.flush()
+    }
+}
+
+impl<S: ByteStream> ByteStream for HandshakeStream<S> {
+    #[allow(clippy::type_complexity)]
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        self.update_read_timeout()?;
+        self.strm.split()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.strm.local_addr()
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.strm.read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.shortened.store(false, Ordering::Relaxed);
+        self.strm.set_read_timeout(timeout)
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.strm.peer_identity()
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.update_read_timeout().ok()?;
+        self.strm.raw_fd()
+    }
+}
+
+/// Stream replaying a byte already read for detecting the protocol version
+struct Replay<'a, S> {
+    head: Option<u8>,
+    strm: &'a mut S,
+}
+
+impl<'a, S> Replay<'a, S> {
+    fn new(head: u8, strm: &'a mut S) -> Self {
+        Self {
+            head: Some(head),
+            strm,
+        }
+    }
+}
+
+impl<S: io::Read> io::Read for Replay<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.head {
+            Some(head) if !buf.is_empty() => {
+                buf[0] = head;
+                self.head = None;
+                Ok(1)
+            }
+            _ => self.strm.read(buf),
+        }
+    }
+}
+
+impl<S: io::Write> io::Write for Replay<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.strm.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.strm.flush()
+    }
+}
+
+/// Result of a request with the decision of the policy on it (`None` if not decided by rules),
+/// reported to audit events even if the request fails
+pub(crate) type Decided<T> = (Option<Decision>, Result<T, Error>);
+
+#[allow(clippy::too_many_arguments)]
+fn perform_command(
+    cmd: Command,
+    connector: impl Deref<Target = impl Connector>,
+    policy: &dyn ConnectPolicy,
+    slot: Option<&DestinationSlot>,
+    rewriter: Option<&dyn ConnectRewriter>,
+    resolved: ResolvedCheck,
+    src_addr: SocketAddr,
+    user: Option<&str>,
+    connect_to: Address,
+) -> Decided<(impl ByteStream, SocketAddr)> {
+    match cmd {
+        Command::Connect => {}
+        cmd @ Command::Bind | cmd @ Command::UdpAssociate => {
+            return (None, Err(ErrorKind::command_not_supported(cmd).into()));
+        }
+    };
+    // filter out request not sufficies the connection rule
+    let decision = decide_rule(policy, src_addr, user, &connect_to, L4Protocol::Tcp);
+    let res = connect_decided(
+        &*connector,
+        &decision,
+        policy,
+        slot,
+        rewriter,
+        resolved,
+        src_addr,
+        user,
+        connect_to,
+    );
+    (Some(decision), res)
+}
+
+/// connect to `connect_to` as decided by `decision`
+#[allow(clippy::too_many_arguments)]
+fn connect_decided<C: Connector>(
+    connector: &C,
+    decision: &Decision,
+    policy: &dyn ConnectPolicy,
+    slot: Option<&DestinationSlot>,
+    rewriter: Option<&dyn ConnectRewriter>,
+    resolved: ResolvedCheck,
+    src_addr: SocketAddr,
+    user: Option<&str>,
+    connect_to: Address,
+) -> Result<(C::B, SocketAddr), Error> {
+    check_decision(decision, &connect_to, L4Protocol::Tcp)?;
+    if let Some(slot) = slot {
+        slot.acquire(decision, &connect_to, L4Protocol::Tcp)?;
+    }
+    let bind = decision.bind.as_ref();
+    let ctx = ConnectContext::new(src_addr, user, &connect_to, L4Protocol::Tcp);
+    if let Some(rewritten) = rewriter.and_then(|rewriter| rewriter.rewrite(&ctx)) {
+        info!("rewrite destination: {} -> {}", connect_to, rewritten);
+        // chosen by the application, so not checked again
+        return connect_from(connector, rewritten, bind);
+    }
+    match connect_to {
+        Address::Domain(..) if resolved.rules || resolved.reject_private => connect_resolved(
+            connector, policy, resolved, src_addr, user, connect_to, bind,
+        ),
+        _ => connect_from(connector, connect_to, bind),
+    }
+}
+
+/// connect to `addr` from `bind` given by the policy, or as configured to the connector
+fn connect_from<C: Connector>(
+    connector: &C,
+    addr: Address,
+    bind: Option<&OutboundBind>,
+) -> Result<(C::B, SocketAddr), Error> {
+    match bind {
+        Some(bind) => connector.connect_byte_stream_from(addr, bind),
+        None => connector.connect_byte_stream(addr),
+    }
+}
+
+/// Checks of addresses resolved from a requested domain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ResolvedCheck {
+    /// apply ip address rules (`ServerConfig::check_resolved`)
+    pub rules: bool,
+    /// reject private addresses not allowed by rules explicitly (`ServerConfig::reject_private_resolved`)
+    pub reject_private: bool,
+}
+
+/// Whether `ip` is in a private or reserved range not to be reached through domains
+///
+/// This includes private networks (RFC1918, RFC6598 and unique local addresses),
+/// loopback, link-local and unspecified addresses.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+/// Resolve `addr` by `resolver` into the addresses passing `check`
+///
+/// Only addresses resolved from a domain are checked,
+/// requests of ip addresses are decided by `policy` before.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn resolve_checked(
+    resolver: &dyn Resolver,
+    policy: &dyn ConnectPolicy,
+    check: ResolvedCheck,
+    src_addr: SocketAddr,
+    user: Option<&str>,
+    addr: &Address,
+    proto: L4Protocol,
+) -> Result<Vec<SocketAddr>, Error> {
+    let mut addrs = resolve_with(resolver, addr)?;
+    if let Address::Domain(..) = addr {
+        addrs.retain(|resolved| {
+            let resolved_addr = (*resolved).into();
+            let ctx = ConnectContext::new(src_addr, user, &resolved_addr, proto)
+                .with_stage(CheckStage::ResolvedAddress);
+            let decision = policy.check(&ctx);
+            if check.reject_private && is_private(resolved.ip()) {
+                // a rule of the address, not just a rule of the domain, has to allow it
+                if !(decision.allow && decision.matched_rule.is_some()) {
+                    info!("resolved private address: {}: {}", addr, resolved);
+                    return false;
+                }
+            } else if check.rules && !decision.allow {
+                info!("resolved address is not allowed: {}: {}", addr, resolved);
+                return false;
+            }
+            true
+        });
+    }
+    Ok(addrs)
+}
+
+/// connect to one of the addresses resolved from `connect_to` allowed by `policy`
+fn connect_resolved<C: Connector>(
+    connector: &C,
+    policy: &dyn ConnectPolicy,
+    check: ResolvedCheck,
+    src_addr: SocketAddr,
+    user: Option<&str>,
+    connect_to: Address,
+    bind: Option<&OutboundBind>,
+) -> Result<(C::B, SocketAddr), Error> {
+    let mut last_err = None;
+    let resolver = connector.resolver();
+    let addrs = resolve_checked(
+        &*resolver,
+        policy,
+        check,
+        src_addr,
+        user,
+        &connect_to,
+        L4Protocol::Tcp,
+    )?;
+    for addr in addrs {
+        match connect_from(connector, addr.into(), bind) {
+            Ok(conn) => return Ok(conn),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| ErrorKind::connection_not_allowed(connect_to, L4Protocol::Tcp).into()))
+}
+
+/// Reject a message of the client with a protocol version other than `expected`
+///
+/// Skipped if the server is lenient (`ServerConfig::lenient_version`) for buggy clients.
+pub(crate) fn expect_version(
+    expected: ProtocolVersion,
+    version: ProtocolVersion,
+    message: &str,
+) -> Result<(), Error> {
+    if version == expected {
+        return Ok(());
+    }
+    Err(ErrorKind::message_fmt(format_args!(
+        "unexpected protocol version of {}: {} (expected {})",
+        message, version, expected
+    ))
+    .into())
+}
+
+fn negotiate_auth_method(
+    version: ProtocolVersion,
+    lenient_version: bool,
+    auth: impl Deref<Target = impl AuthService>,
+    allowed: Option<&[Method]>,
+    mut socks: impl DerefMut<Target = impl SocksStream>,
+) -> Result<MethodSelection, Error> {
+    let candidates = socks.recv_method_candidates()?;
+    trace!("candidates: {:?}", candidates);
+    let selection = select_method(version, lenient_version, &*auth, allowed, &candidates);
+    trace!("selection: {:?}", selection);
+    match selection {
+        Ok(method) => {
+            let method_sel = MethodSelection { version, method };
+            socks.send_method_selection(method_sel)?;
+            Ok(method_sel)
+        }
+        Err(err) => {
+            // the client waits for the selection even if its candidates are malformed
+            socks
+                .send_method_selection(MethodSelection {
+                    version,
+                    method: Method::NoMethods,
+                })
+                .ok();
+            Err(err)
+        }
+    }
+}
+
+/// Method selected by `auth` from `candidates`, `NoAcceptableMethod` if none is acceptable
+///
+/// Only methods in `allowed` (`ServerConfig::allowed_methods`) are offered to `auth`,
+/// and a method selected out of them is refused.
+/// The client should be replied `Method::NoMethods` on any error.
+pub(crate) fn select_method(
+    version: ProtocolVersion,
+    lenient_version: bool,
+    auth: &impl AuthService,
+    allowed: Option<&[Method]>,
+    candidates: &MethodCandidates,
+) -> Result<Method, Error> {
+    if !lenient_version {
+        expect_version(version, candidates.version, "method candidates")?;
+    }
+    let is_allowed = |method: &Method| allowed.is_none_or(|allowed| allowed.contains(method));
+    let offered: Vec<_> = candidates
+        .method
+        .iter()
+        .copied()
+        .filter(is_allowed)
+        .collect();
+    match auth.select(&offered)? {
+        Some(method) if is_allowed(&method) => Ok(method),
+        Some(method) => {
+            warn!("auth method not allowed: {:?}", method);
+            Err(ErrorKind::NoAcceptableMethod.into())
+        }
+        None => Err(ErrorKind::NoAcceptableMethod.into()),
+    }
+}
+
+/// reply `cerr` to the connect request of a client without starting a session
+///
+/// The request has to be read within `handshake_timeout`.
+pub(crate) fn reject_client(
+    version: ProtocolVersion,
+    server_addr: SocketAddr,
+    strm: impl ByteStream,
+    cerr: ConnectError,
+    handshake_timeout: Option<Duration>,
+) -> Result<(), Error> {
+    let deadline = Arc::new(HandshakeDeadline::new(handshake_timeout));
+    let mut socks = ReadWriteStream::new(HandshakeStream::new(strm, deadline));
+    let candidates = socks.recv_method_candidates()?;
+    let method = if candidates.method.contains(&Method::NoAuth) {
+        Method::NoAuth
+    } else {
+        Method::NoMethods
+    };
+    socks.send_method_selection(MethodSelection { version, method })?;
+    if method == Method::NoMethods {
+        return Ok(());
+    }
+    socks.recv_connect_request()?;
+    socks.send_connect_reply(ConnectReply {
+        version,
+        connect_result: Err(cerr),
+        server_addr: server_addr.into(),
+    })
+}
+
+/// reply `cerr` to the HTTP CONNECT request of a client without starting a session
+pub(crate) fn reject_http_client(
+    strm: impl ByteStream,
+    cerr: ConnectError,
+    handshake_timeout: Option<Duration>,
+) -> Result<(), Error> {
+    let deadline = Arc::new(HandshakeDeadline::new(handshake_timeout));
+    let mut strm = HandshakeStream::new(strm, deadline);
+    http_connect::recv_request(&mut strm)?;
+    http_connect::send_reply(&mut strm, Err(cerr))
+}
+
+/// Decision of `policy` on the request, logged at the level given by the policy
+pub(crate) fn decide_rule(
+    policy: &dyn ConnectPolicy,
+    src_addr: SocketAddr,
+    user: Option<&str>,
+    addr: &Address,
+    proto: L4Protocol,
+) -> Decision {
+    let decision = policy.check(&ConnectContext::new(src_addr, user, addr, proto));
+    log_decision(&decision, addr, proto);
+    decision
+}
+
+/// `ConnectionNotAllowed` unless `decision` allows the request to `addr`
+pub(crate) fn check_decision(
+    decision: &Decision,
+    addr: &Address,
+    proto: L4Protocol,
+) -> Result<(), Error> {
+    if decision.allow {
+        return Ok(());
+    }
+    Err(ErrorKind::ConnectionNotAllowed {
+        addr: addr.clone(),
+        protocol: proto,
+        rule: decision.rule_name.clone(),
+        reply: decision.reply.clone(),
+    }
+    .into())
+}
+
+/// Index and name of the connect rule made `decision`, reported to audit events
+pub(crate) fn matched_rule(decision: Option<&Decision>) -> (Option<usize>, Option<String>) {
+    decision.map_or((None, None), |decision| {
+        (decision.matched_rule, decision.rule_name.clone())
+    })
+}
+
+/// Log `decision` at the level given by the policy
+fn log_decision(decision: &Decision, addr: &Address, proto: L4Protocol) {
+    let level = match decision.log {
+        Some(level) => level,
+        None if !decision.allow && decision.rule_name.is_some() => LevelFilter::Info,
+        None => return,
+    };
+    let level = match level.to_level() {
+        Some(level) => level,
+        None => return,
+    };
+    let action = if decision.allow { "allowed" } else { "denied" };
+    match (&decision.rule_name, decision.matched_rule) {
+        (Some(name), _) => log!(level, "{} by rule: {}: {}: {}", action, name, addr, proto),
+        (None, Some(idx)) => log!(level, "{} by rule #{}: {}: {}", action, idx, addr, proto),
+        (None, None) => log!(level, "{}: {}: {}", action, addr, proto),
+    }
+}
+
+/// Whether `peer` is the host the client expects to connect with (BIND command)
+///
+/// `resolved` is the addresses of `expected` if it is a domain.
+/// The port number is not checked,
+/// since the client usually does not know it in advance.
+fn expected_peer(expected: &Address, resolved: &[SocketAddr], peer: SocketAddr) -> bool {
+    match expected {
+        Address::IpAddr(ip, _) => ip.is_unspecified() || *ip == peer.ip(),
+        Address::ScopedV6(addr) => *addr.ip() == peer.ip(),
+        Address::Domain(..) => resolved.iter().any(|addr| addr.ip() == peer.ip()),
+    }
+}
+
+pub(crate) fn reject_reason(err: &Error) -> RejectReason {
+    match err.kind() {
+        ErrorKind::ConnectionNotAllowed { .. } => RejectReason::NotAllowed,
+        _ => RejectReason::Error(err.to_string()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DisconnectGuard<S> {
+    id: SessionId,
+    tx: mpsc::Sender<ServerCommand<S>>,
+    /// emitted before sending `Disconnect`
+    pub(crate) log: Option<DisconnectLog>,
+}
+
+impl<S> DisconnectGuard<S> {
+    pub fn new(id: SessionId, tx: mpsc::Sender<ServerCommand<S>>) -> Self {
+        Self { id, tx, log: None }
+    }
+}
+
+impl<S> Drop for DisconnectGuard<S> {
+    fn drop(&mut self) {
+        debug!("DisconnectGuard: {}", self.id);
+        if let Some(log) = self.log.take() {
+            log.emit();
+        }
+        // the server may have been dropped by the application
+        self.tx.send(ServerCommand::Disconnect(self.id)).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth_service::test::RejectService;
+    use crate::byte_stream::test::BufferStream;
+    use crate::connector::test::BufferConnector;
+    use crate::rw_socks_stream as socks;
+    use std::io;
+    use std::iter::FromIterator;
+    use std::str::FromStr;
+
+    #[test]
+    fn no_acceptable_method() {
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (session, _) = Session::new(
+            0.into(),
+            5.into(),
+            BufferConnector::from_iter(vec![(
+                "192.168.0.1:5123".parse().unwrap(),
+                Ok(BufferStream::new()),
+            )]),
+            RejectService,
+            "0.0.0.0:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+        println!("session: {:?}", session);
+        let src = BufferStream::with_buffer(vec![5, 1, 0].into(), vec![].into());
+        assert_eq!(
+            session
+                .make_session("192.168.0.2:12345".parse().unwrap(), src.clone())
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::NoAcceptableMethod
+        );
+        src.wr_buff().set_position(0);
+        assert_eq!(vec_from_read(&mut *src.wr_buff()), [5, 0xff]);
+    }
+
+    /// reply to the client sending `input` to a session failing the handshake
+    fn failed_handshake<A: AuthService>(auth: A, input: Vec<u8>, socks4: bool) -> Vec<u8> {
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _) = Session::new(
+            0.into(),
+            5.into(),
+            BufferConnector::<BufferStream>::from_iter(vec![]),
+            auth,
+            "0.0.0.0:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+        session.accept_socks4 = socks4;
+        let src = BufferStream::with_buffer(input.into(), vec![].into());
+        assert!(session
+            .make_session("192.168.0.2:12345".parse().unwrap(), src.clone())
+            .is_err());
+        let reply = src.wr_buff.lock().unwrap().get_ref().clone();
+        reply
+    }
+
+    /// fails to select any method
+    #[derive(Debug)]
+    struct FailingService;
+
+    impl AuthService for FailingService {
+        fn select(&self, _candidates: &[Method]) -> Result<Option<Method>, Error> {
+            Err(ErrorKind::Authentication.into())
+        }
+
+        fn authorize<'a, B>(&self, _method: Method, _conn: B) -> Result<BoxedStream<'a>, Error>
+        where
+            B: ByteStream + 'a,
+        {
+            Err(ErrorKind::Authentication.into())
+        }
+    }
+
+    #[test]
+    fn malformed_method_candidates() {
+        use crate::auth_service::NoAuthService;
+        // no methods
+        let reply = failed_handshake(NoAuthService::new(), vec![5, 0], false);
+        assert_eq!(reply, [5, 0xff]);
+        // closed before sending the candidates
+        let reply = failed_handshake(NoAuthService::new(), vec![5, 2, 0], false);
+        assert!(reply.is_empty());
+    }
+
+    #[test]
+    fn method_selection_error() {
+        let reply = failed_handshake(FailingService, vec![5, 1, 0], false);
+        assert_eq!(reply, [5, 0xff]);
+        // SOCKS4 clients are replied rejected
+        let reply = failed_handshake(FailingService, vec![4, 1, 0, 80, 192, 0, 2, 1, 0], true);
+        assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+    }
+
+    /// rejects any client by `authorize_peer`
+    #[derive(Debug)]
+    struct RejectingPeerService;
+
+    impl AuthService for RejectingPeerService {
+        fn select(&self, _candidates: &[Method]) -> Result<Option<Method>, Error> {
+            Ok(Some(Method::NoAuth))
+        }
+
+        fn authorize<'a, B>(&self, _method: Method, conn: B) -> Result<BoxedStream<'a>, Error>
+        where
+            B: ByteStream + 'a,
+        {
+            Ok(Box::new(conn))
+        }
+
+        fn authorize_peer(&self, _identity: Option<&str>) -> Result<(), Error> {
+            Err(ErrorKind::Authentication.into())
+        }
+    }
+
+    #[test]
+    fn peer_rejected() {
+        let reply = failed_handshake(RejectingPeerService, vec![5, 1, 0], false);
+        assert_eq!(reply, [5, 0xff]);
+        let reply = failed_handshake(
+            RejectingPeerService,
+            vec![4, 1, 0, 80, 192, 0, 2, 1, 0],
+            true,
+        );
+        assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+        // closed before sending the candidates
+        let reply = failed_handshake(RejectingPeerService, vec![5, 2, 0], false);
+        assert_eq!(reply, [5, 0xff]);
+    }
+
+    /// selects `NoAuth` whatever the client offers
+    #[derive(Debug)]
+    struct OpenService;
+
+    impl AuthService for OpenService {
+        fn select(&self, _candidates: &[Method]) -> Result<Option<Method>, Error> {
+            Ok(Some(Method::NoAuth))
+        }
+
+        fn authorize<'a, B>(&self, _method: Method, conn: B) -> Result<BoxedStream<'a>, Error>
+        where
+            B: ByteStream + 'a,
+        {
+            Ok(Box::new(conn))
+        }
+    }
+
+    #[test]
+    fn allowed_methods() {
+        use crate::auth_service::NoAuthService;
+        let version = 5.into();
+        let candidates = MethodCandidates::new(&[Method::NoAuth, Method::UserPass]);
+        let no_auth = NoAuthService::new();
+        let user_pass: &[Method] = &[Method::UserPass];
+        assert_eq!(
+            select_method(version, false, &no_auth, None, &candidates).unwrap(),
+            Method::NoAuth
+        );
+        // `NoAuth` is not offered to the service
+        assert_eq!(
+            select_method(version, false, &no_auth, Some(user_pass), &candidates)
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::NoAcceptableMethod
+        );
+        // nor selected by a misconfigured service
+        assert_eq!(
+            select_method(version, false, &OpenService, Some(user_pass), &candidates)
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::NoAcceptableMethod
+        );
+        let any: &[Method] = &[Method::NoAuth, Method::UserPass];
+        assert_eq!(
+            select_method(version, false, &OpenService, Some(any), &candidates).unwrap(),
+            Method::NoAuth
+        );
+    }
+
+    #[test]
+    fn malformed_request_reply() {
+        use crate::auth_service::NoAuthService;
+        // unknown version of the connect request
+        let reply = failed_handshake(
+            NoAuthService::new(),
+            vec![5, 1, 0, 6, 1, 0, 1, 192, 0, 2, 1, 0, 80],
+            false,
+        );
+        assert_eq!(&reply[..4], [5, 0, 5, 1]);
+        // unknown command of SOCKS4
+        let reply = failed_handshake(
+            NoAuthService::new(),
+            vec![4, 3, 0, 80, 192, 0, 2, 1, 0],
+            true,
+        );
+        assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn bind_not_allowed() {
+        use crate::auth_service::NoAuthService;
+        let mcand = MethodCandidates::new(&[Method::NoAuth]);
+        let req = ConnectRequest::bind(Address::from_str("192.168.0.1:5123").unwrap());
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (session, _) = Session::new(
+            1.into(),
+            5.into(),
+            BufferConnector::from_iter(vec![(req.connect_to.clone(), Ok(BufferStream::new()))]),
+            NoAuthService::new(),
+            "0.0.0.0:1080".parse().unwrap(),
+            Arc::new(ConnectRule::none()),
+            tx,
+        );
+        println!("session: {:?}", session);
+
+        let buff = {
+            let mut cursor = io::Cursor::new(vec![]);
+            socks::test::write_method_candidates(&mut cursor, mcand).unwrap();
+            socks::test::write_connect_request(&mut cursor, req.clone()).unwrap();
+            cursor.into_inner()
+        };
+        let src = BufferStream::with_buffer(buff.into(), vec![].into());
+        assert_eq!(
+            session
+                .make_session("192.168.1.1:34567".parse().unwrap(), src)
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::connection_not_allowed(req.connect_to, L4Protocol::Tcp)
+        );
+    }
+
+    #[test]
+    fn addr_type_not_supported() {
+        use crate::auth_service::NoAuthService;
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (session, _) = Session::new(
+            2.into(),
+            5.into(),
+            BufferConnector::<BufferStream>::from_iter(vec![]),
+            NoAuthService::new(),
+            "0.0.0.0:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+        // unknown atyp 0x02
+        let src = BufferStream::with_buffer(
+            vec![5, 1, 0, 5, 1, 0, 2, 1, 2, 3, 4, 0, 80].into(),
+            vec![].into(),
+        );
+        let err = session
+            .make_session("192.168.1.1:34567".parse().unwrap(), src.clone())
+            .unwrap_err();
+        assert_eq!(err.cerr(), ConnectError::AddrTypeNotSupported);
+        // method selection and the reply
+        assert_eq!(
+            src.wr_buff.lock().unwrap().get_ref().as_slice(),
+            [5, 0, 5, 8, 0, 1, 0, 0, 0, 0, 4, 56]
+        );
+    }
+
+    #[test]
+    fn protocol_version() {
+        use crate::auth_service::NoAuthService;
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _tx_term) = Session::new(
+            2.into(),
+            5.into(),
+            BufferConnector::from_iter(vec![(
+                "127.0.0.1:80".parse().unwrap(),
+                Ok(BufferStream::new()),
+            )]),
+            NoAuthService::new(),
+            "0.0.0.0:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+        let src_addr = "192.168.1.1:34567".parse().unwrap();
+        let message_format = |err: Error| matches!(err.kind(), ErrorKind::MessageFormat { .. });
+
+        let src = BufferStream::with_buffer(vec![6, 1, 0].into(), vec![].into());
+        let err = session.make_session(src_addr, src.clone()).unwrap_err();
+        assert!(message_format(err));
+        // no methods are acceptable to the unknown version
+        assert_eq!(src.wr_buff.lock().unwrap().get_ref().as_slice(), [5, 0xff]);
+
+        // the request of SOCKS4 in the layout of SOCKS5
+        let request = [5, 1, 0, 4, 1, 0, 1, 127, 0, 0, 1, 0, 80];
+        let src = BufferStream::with_buffer(request.to_vec().into(), vec![].into());
+        let err = session.make_session(src_addr, src.clone()).unwrap_err();
+        assert!(message_format(err));
+        // method selection and the reply
+        assert_eq!(
+            src.wr_buff.lock().unwrap().get_ref().as_slice(),
+            [5, 0, 5, 1, 0, 1, 0, 0, 0, 0, 4, 56]
+        );
+
+        session.lenient_version = true;
+        let src = BufferStream::with_buffer(request.to_vec().into(), vec![].into());
+        let relay = session.make_session(src_addr, src).unwrap();
+        assert!(relay.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        use crate::auth_service::NoAuthService;
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (strm, src_addr) = listener.accept().unwrap();
+        let rw_timeout = Some(Duration::from_secs(5));
+        strm.set_read_timeout(rw_timeout).unwrap();
+
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _) = Session::new(
+            2.into(),
+            5.into(),
+            BufferConnector::<BufferStream>::from_iter(vec![]),
+            NoAuthService::new(),
+            "0.0.0.0:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+        session.handshake_timeout = Some(Duration::from_millis(200));
+        // only the method candidates
+        client.write_all(&[5, 1, 0]).unwrap();
+        let start = Instant::now();
+        let err = session
+            .make_session(src_addr, strm.try_clone().unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::HandshakeTimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // the read timeout is restored after the handshake
+        strm.set_read_timeout(rw_timeout).unwrap();
+        let deadline = Arc::new(HandshakeDeadline::new(Some(Duration::from_secs(1))));
+        let mut hs = HandshakeStream::new(strm, deadline.clone());
+        client.write_all(&[0]).unwrap();
+        hs.read_exact(&mut [0]).unwrap();
+        assert!(hs.read_timeout().unwrap() <= Some(Duration::from_secs(1)));
+        deadline.finish();
+        hs.split().unwrap();
+        assert_eq!(hs.read_timeout().unwrap(), rw_timeout);
+    }
+
+    #[test]
+    fn reject_handshake_timeout() {
+        use std::io::Write;
+        use std::net::{TcpListener, TcpStream};
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (strm, _) = listener.accept().unwrap();
+        strm.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // the method candidates, but not the request
+        client.write_all(&[5, 1, 0]).unwrap();
+        let start = Instant::now();
+        let timeout = Some(Duration::from_millis(200));
+        let cerr = ConnectError::ServerFailure;
+        let err = reject_client(
+            5.into(),
+            "0.0.0.0:1080".parse().unwrap(),
+            strm,
+            cerr,
+            timeout,
+        )
+        .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(2), "{}", err);
+    }
+
+    #[test]
+    fn connect_not_allowed() {
+        use crate::auth_service::NoAuthService;
+        let version: ProtocolVersion = 5.into();
+        let connect_to = Address::from_str("192.168.0.1:5123").unwrap();
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (session, _) = Session::new(
+            2.into(),
+            version,
+            BufferConnector::from_iter(vec![(connect_to.clone(), Ok(BufferStream::new()))]),
+            NoAuthService::new(),
+            "0.0.0.0:1080".parse().unwrap(),
+            Arc::new(ConnectRule::none()),
+            tx,
+        );
+        println!("session: {:?}", session);
+
+        let buff = {
+            let mut cursor = io::Cursor::new(vec![]);
+            socks::test::write_method_candidates(
+                &mut cursor,
+                MethodCandidates::new(&[Method::NoAuth]),
+            )
+            .unwrap();
+            socks::test::write_connect_request(
+                &mut cursor,
+                ConnectRequest::connect_to(connect_to.clone()),
+            )
+            .unwrap();
+            cursor.into_inner()
+        };
+        let src = BufferStream::with_buffer(buff.into(), vec![].into());
+        assert_eq!(
+            session
+                .make_session("192.168.1.1:34567".parse().unwrap(), src)
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::connection_not_allowed(connect_to, L4Protocol::Tcp)
+        );
+    }
+
+    #[test]
+    fn peer_identity_user() {
+        use crate::auth_service::NoAuthService;
+
+        /// stream of a client identified by its TLS certificate
+        #[derive(Debug)]
+        struct Identified(BufferStream);
+
+        impl io::Read for Identified {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        impl io::Write for Identified {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.flush()
+            }
+        }
+
+        impl ByteStream for Identified {
+            fn split(
+                &self,
+            ) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+                self.0.split()
+            }
+            fn peer_identity(&self) -> Option<String> {
+                Some("device-01".to_owned())
+            }
+        }
+
+        /// accepts only clients identified as `device-01`
+        #[derive(Debug)]
+        struct PeerService;
+
+        impl AuthService for PeerService {
+            fn select(&self, candidates: &[Method]) -> Result<Option<Method>, Error> {
+                NoAuthService::new().select(candidates)
+            }
+            fn authorize<'a, B>(&self, method: Method, conn: B) -> Result<BoxedStream<'a>, Error>
+            where
+                B: ByteStream + 'a,
+            {
+                NoAuthService::new().authorize(method, conn)
+            }
+            fn authorize_peer(&self, identity: Option<&str>) -> Result<(), Error> {
+                match identity {
+                    Some("device-01") => Ok(()),
+                    _ => Err(ErrorKind::Authentication.into()),
+                }
+            }
+        }
+
+        let connect_to = Address::from_str("192.168.0.1:5123").unwrap();
+        let session = |user: &str| {
+            let mut rule = ConnectRule::none();
+            rule.push(ConnectRuleEntry::Allow(ConnectRulePattern::with_user(
+                RulePattern::Specif(user.to_owned()),
+                RulePattern::Any,
+                RulePattern::Any,
+                RulePattern::Any,
+            )));
+            let (tx, rx) = mpsc::channel::<ServerCommand<()>>();
+            let (mut session, tx_session_term) = Session::new(
+                2.into(),
+                5.into(),
+                BufferConnector::from_iter(vec![(connect_to.clone(), Ok(BufferStream::new()))]),
+                PeerService,
+                "0.0.0.0:1080".parse().unwrap(),
+                Arc::new(rule),
+                tx,
+            );
+            session.accept_socks4 = true;
+            // the channels are kept until the session is dropped
+            (rx, tx_session_term, session)
+        };
+        let socks5 = {
+            let mut buff = vec![];
+            socks::test::write_method_candidates(
+                &mut buff,
+                MethodCandidates::new(&[Method::NoAuth]),
+            )
+            .unwrap();
+            socks::test::write_connect_request(
+                &mut buff,
+                ConnectRequest::connect_to(connect_to.clone()),
+            )
+            .unwrap();
+            buff
+        };
+        // SOCKS4 CONNECT 192.168.0.1:5123
+        let socks4 = vec![4, 1, 0x14, 0x03, 192, 168, 0, 1, 0];
+        let connect = |user: &str, request: &[u8], identified: bool| {
+            let src = BufferStream::with_buffer(request.to_vec().into(), vec![].into());
+            let reply = src.wr_buff.clone();
+            let src_addr = "192.168.1.1:34567".parse().unwrap();
+            let (_rx, _tx_session_term, session) = session(user);
+            let res = if identified {
+                session.make_session(src_addr, Identified(src))
+            } else {
+                session.make_session(src_addr, src)
+            };
+            let reply = reply.lock().unwrap().get_ref().clone();
+            (res.map(|relay| relay.join().unwrap()), reply)
+        };
+
+        let (res, reply) = connect("cert:device-01", &socks5, true);
+        assert!(res.unwrap().is_ok());
+        let mut reply = io::Cursor::new(reply);
+        socks::test::read_method_selection(&mut reply).unwrap();
+        assert_eq!(
+            socks::test::read_connect_reply(&mut reply)
+                .unwrap()
+                .connect_result,
+            Ok(())
+        );
+        let (res, reply) = connect("cert:device-01", &socks4, true);
+        assert!(res.unwrap().is_ok());
+        assert_eq!(reply[..2], [0, 90]);
+
+        // a user with the same name as the certificate is another client
+        let (res, reply) = connect("device-01", &socks4, true);
+        assert!(matches!(
+            res.unwrap_err().kind(),
+            ErrorKind::ConnectionNotAllowed { .. }
+        ));
+        assert_eq!(reply[..2], [0, 91]);
+        // rejected by the auth service before the handshake, as no method is acceptable
+        let (res, reply) = connect("cert:device-01", &socks5, false);
+        assert_eq!(res.unwrap_err().kind(), &ErrorKind::Authentication);
+        assert_eq!(reply, [5, 0xff]);
+        let (res, reply) = connect("cert:device-01", &socks4, false);
+        assert_eq!(res.unwrap_err().kind(), &ErrorKind::Authentication);
+        assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn denied_by_named_rule() {
+        let connect_to = Address::from_str("192.168.0.1:25").unwrap();
+        let mut rule = ConnectRule::any();
+        rule.push(ConnectRuleEntry::Deny(
+            ConnectRulePattern::new(
+                RulePattern::Any,
+                RulePattern::Specif(25.into()),
+                RulePattern::Any,
+            )
+            .named("no-smtp"),
+        ));
+        let decision = decide_rule(
+            &rule,
+            "192.168.1.1:34567".parse().unwrap(),
+            None,
+            &connect_to,
+            L4Protocol::Tcp,
+        );
+        let err = check_decision(&decision, &connect_to, L4Protocol::Tcp).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::not_allowed_by(connect_to, L4Protocol::Tcp, Some("no-smtp".to_owned()))
+        );
+    }
+
+    #[test]
+    fn deny_reply() {
+        let connect_to = Address::from_str("192.168.0.1:25").unwrap();
+        let mut rule = ConnectRule::any();
+        rule.push(ConnectRuleEntry::Deny(
+            ConnectRulePattern::new(
+                RulePattern::Any,
+                RulePattern::Specif(25.into()),
+                RulePattern::Any,
+            )
+            .reply_with(ConnectError::HostUnreachable),
+        ));
+        let decision = decide_rule(
+            &rule,
+            "192.168.1.1:34567".parse().unwrap(),
+            None,
+            &connect_to,
+            L4Protocol::Tcp,
+        );
+        let err = check_decision(&decision, &connect_to, L4Protocol::Tcp).unwrap_err();
+        assert_eq!(reject_reason(&err), RejectReason::NotAllowed);
+        assert_eq!(err.cerr(), ConnectError::HostUnreachable);
+    }
+
+    #[test]
+    fn audit_matched_rule() {
+        use crate::audit::{DisconnectEvent, SessionLogger};
+        use crate::auth_service::NoAuthService;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// decided by the rule numbered by the count of checks, denies port 25
+        #[derive(Debug, Default)]
+        struct CountPolicy(AtomicUsize);
+
+        impl ConnectPolicy for CountPolicy {
+            fn check(&self, ctx: &ConnectContext) -> Decision {
+                let count = self.0.fetch_add(1, Ordering::Relaxed);
+                let decision = if ctx.dst.port() == 25 {
+                    Decision::deny()
+                } else {
+                    Decision::allow()
+                };
+                decision.with_rule(Some(count), None)
+            }
+        }
+
+        #[derive(Debug, Default)]
+        struct RuleLogger(Mutex<Vec<(&'static str, Option<usize>)>>);
+
+        impl SessionLogger for RuleLogger {
+            fn on_connect(&self, event: &ConnectEvent) {
+                self.0.lock().unwrap().push(("connect", event.matched_rule));
+            }
+            fn on_reject(&self, event: &RejectEvent) {
+                self.0.lock().unwrap().push(("reject", event.matched_rule));
+            }
+            fn on_disconnect(&self, _: &DisconnectEvent) {}
+        }
+
+        for port in [5123, 25] {
+            let connect_to = Address::from_str(&format!("192.168.0.1:{}", port)).unwrap();
+            let policy = Arc::new(CountPolicy::default());
+            let logger = Arc::new(RuleLogger::default());
+            let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+            let (mut session, _tx_session_term) = Session::new(
+                2.into(),
+                5.into(),
+                BufferConnector::from_iter(vec![(connect_to.clone(), Ok(BufferStream::new()))]),
+                NoAuthService::new(),
+                "0.0.0.0:1080".parse().unwrap(),
+                policy.clone(),
+                tx,
+            );
+            session.logger = Some(logger.clone());
+            let mut buff = vec![];
+            socks::test::write_method_candidates(
+                &mut buff,
+                MethodCandidates::new(&[Method::NoAuth]),
+            )
+            .unwrap();
+            socks::test::write_connect_request(&mut buff, ConnectRequest::connect_to(connect_to))
+                .unwrap();
+            let src = BufferStream::with_buffer(buff.into(), vec![].into());
+            let res = session.make_session("192.168.1.1:34567".parse().unwrap(), src);
+            if let Ok(relay) = res {
+                relay.join().unwrap().unwrap();
+            }
+            // the decision is reported without checking the request again
+            assert_eq!(policy.0.load(Ordering::Relaxed), 1);
+            let event = if port == 25 { "reject" } else { "connect" };
+            assert_eq!(*logger.0.lock().unwrap(), [(event, Some(0))]);
+        }
     }
 
     #[test]
-    fn command_not_supported() {
+    fn inspect_sni() {
         use crate::auth_service::NoAuthService;
-        let mcand = MethodCandidates::new(&[Method::NoAuth]);
-        // udp is not unsupported
-        let req = ConnectRequest::udp_associate(Address::from_str("192.168.0.1:5123").unwrap());
+        use crate::sni::test::client_hello;
+
+        let mut rule = ConnectRule::any();
+        rule.deny(
+            RulePattern::Specif(AddressPattern::Domain(
+                DomainPattern::wildcard("*.blocked.test".to_owned()).unwrap(),
+            )),
+            RulePattern::Any,
+            RulePattern::Any,
+        );
         let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
-        let (session, _) = Session::new(
+        let (session, _tx_session_term) = Session::new(
             1.into(),
             5.into(),
-            BufferConnector::from_iter(vec![(req.connect_to.clone(), Ok(BufferStream::new()))]),
+            BufferConnector::<BufferStream>::from_iter(vec![]),
             NoAuthService::new(),
             "0.0.0.0:1080".parse().unwrap(),
-            ConnectRule::any(),
+            Arc::new(rule),
             tx,
         );
-        println!("session: {:?}", session);
+        let src = "192.168.1.1:34567".parse().unwrap();
+        let connect_to = Address::from_str("192.0.2.1:443").unwrap();
 
-        let buff = {
-            let mut cursor = io::Cursor::new(vec![]);
-            socks::test::write_method_candidates(&mut cursor, mcand).unwrap();
-            socks::test::write_connect_request(&mut cursor, req).unwrap();
-            cursor.into_inner()
-        };
-        let src = BufferStream::with_buffer(buff.into(), vec![].into());
+        let mut server = vec![];
+        let hello = client_hello("www.example.test");
+        session
+            .inspect_server_name(src, None, &connect_to, &mut &hello[..], &mut server)
+            .1
+            .unwrap();
+        // forwarded to the server
+        assert_eq!(server, hello);
+        assert_eq!(session.traffic().upload(), hello.len() as u64);
+
+        let hello = client_hello("www.blocked.test");
+        let err = session
+            .inspect_server_name(src, None, &connect_to, &mut &hello[..], &mut vec![])
+            .1
+            .unwrap_err();
         assert_eq!(
-            session
-                .make_session("192.168.1.1:34567".parse().unwrap(), src)
-                .unwrap_err()
-                .kind(),
-            &ErrorKind::command_not_supported(Command::UdpAssociate)
+            err.kind(),
+            &ErrorKind::connection_not_allowed(
+                Address::Domain("www.blocked.test".to_owned(), 443),
+                L4Protocol::Tcp
+            )
         );
+
+        // other ports are not inspected
+        let connect_to = Address::from_str("192.0.2.1:8443").unwrap();
+        let mut server = vec![];
+        session
+            .inspect_server_name(src, None, &connect_to, &mut &hello[..], &mut server)
+            .1
+            .unwrap();
+        assert!(server.is_empty());
     }
 
     #[test]
-    fn connect_not_allowed() {
+    fn inspect_sni_socks4() {
         use crate::auth_service::NoAuthService;
-        let version: ProtocolVersion = 5.into();
-        let connect_to = Address::from_str("192.168.0.1:5123").unwrap();
+        use crate::sni::test::client_hello;
+
+        let mut rule = ConnectRule::any();
+        rule.deny(
+            RulePattern::Specif(AddressPattern::Domain(
+                DomainPattern::wildcard("*.blocked.test".to_owned()).unwrap(),
+            )),
+            RulePattern::Any,
+            RulePattern::Any,
+        );
+        let connect_to = Address::from_str("192.0.2.1:443").unwrap();
         let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
-        let (session, _) = Session::new(
-            2.into(),
-            version,
+        let (mut session, _tx_session_term) = Session::new(
+            1.into(),
+            5.into(),
             BufferConnector::from_iter(vec![(connect_to.clone(), Ok(BufferStream::new()))]),
             NoAuthService::new(),
             "0.0.0.0:1080".parse().unwrap(),
-            ConnectRule::none(),
+            Arc::new(rule),
             tx,
         );
-        println!("session: {:?}", session);
+        session.accept_socks4 = true;
+        session.inspect_sni = true;
+        // CONNECT 192.0.2.1:443, then ClientHello
+        let request = |server_name| {
+            let mut buff = vec![4, 1, 1, 187, 192, 0, 2, 1, 0];
+            buff.extend_from_slice(&client_hello(server_name));
+            BufferStream::with_buffer(buff.into(), vec![].into())
+        };
+        let src_addr = "192.168.1.1:34567".parse().unwrap();
 
-        let buff = {
-            let mut cursor = io::Cursor::new(vec![]);
-            socks::test::write_method_candidates(
-                &mut cursor,
-                MethodCandidates::new(&[Method::NoAuth]),
-            )
-            .unwrap();
-            socks::test::write_connect_request(
-                &mut cursor,
-                ConnectRequest::connect_to(connect_to.clone()),
+        let err = session
+            .make_session(src_addr, request("www.blocked.test"))
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::connection_not_allowed(
+                Address::Domain("www.blocked.test".to_owned(), 443),
+                L4Protocol::Tcp
             )
+        );
+        let dst = session.dst_connector.stream(&connect_to).clone();
+        assert!(dst.wr_buff().get_ref().is_empty());
+
+        let relay = session
+            .make_session(src_addr, request("www.example.test"))
             .unwrap();
-            cursor.into_inner()
-        };
-        let src = BufferStream::with_buffer(buff.into(), vec![].into());
+        assert!(relay.join().is_ok());
         assert_eq!(
-            session
-                .make_session("192.168.1.1:34567".parse().unwrap(), src)
-                .unwrap_err()
-                .kind(),
-            &ErrorKind::connection_not_allowed(connect_to, L4Protocol::Tcp)
+            dst.wr_buff().get_ref()[..],
+            client_hello("www.example.test")[..]
         );
     }
 
@@ -374,7 +2596,7 @@ mod test {
             )]),
             NoAuthService::new(),
             "0.0.0.0:1080".parse().unwrap(),
-            ConnectRule::any(),
+            Arc::new(ConnectRule::any()),
             tx,
         );
         println!("session: {:?}", session);
@@ -403,6 +2625,54 @@ mod test {
         );
     }
 
+    #[test]
+    fn socks4a_connect() {
+        use crate::auth_service::NoAuthService;
+        let connect_to = Address::Domain("example.com".into(), 80);
+        let request = || {
+            let mut buff = vec![4, 1, 0, 80, 0, 0, 0, 1, 0];
+            buff.extend_from_slice(b"example.com\0");
+            BufferStream::with_buffer(buff.into(), vec![].into())
+        };
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _tx_session_term) = Session::new(
+            5.into(),
+            5.into(),
+            BufferConnector::from_iter(vec![(connect_to.clone(), Ok(BufferStream::new()))]),
+            NoAuthService::new(),
+            "0.0.0.0:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+
+        // disabled by default
+        let src = request();
+        assert_eq!(
+            session
+                .make_session("192.168.1.1:34567".parse().unwrap(), src.clone())
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::NoAcceptableMethod
+        );
+        src.wr_buff().set_position(0);
+        assert_eq!(
+            vec_from_read(&mut *src.wr_buff()),
+            [0, 91, 0, 0, 0, 0, 0, 0]
+        );
+
+        session.accept_socks4 = true;
+        let src = request();
+        let relay = session
+            .make_session("192.168.1.1:34567".parse().unwrap(), src.clone())
+            .unwrap();
+        assert!(relay.join().is_ok());
+        src.wr_buff().set_position(0);
+        assert_eq!(
+            vec_from_read(&mut *src.wr_buff()),
+            [0, 90, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
     fn gen_random_vec(size: usize) -> Vec<u8> {
         use rand::distributions::Standard;
         use rand::{thread_rng, Rng};
@@ -436,7 +2706,7 @@ mod test {
             )]),
             NoAuthService::new(),
             "0.0.0.0:1080".parse().unwrap(),
-            ConnectRule::any(),
+            Arc::new(ConnectRule::any()),
             tx,
         );
 
@@ -509,4 +2779,622 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn udp_associate() {
+        use crate::auth_service::NoAuthService;
+        use crate::connector::TcpUdpConnector;
+        use crate::rw_socks_stream::{read_datagram, write_datagram};
+        use std::net::{TcpListener, TcpStream, UdpSocket};
+        use std::time::Duration;
+
+        // echo server replies received data with prefix
+        let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (size, peer) = echo.recv_from(&mut buf).unwrap();
+            let mut reply = b"pong:".to_vec();
+            reply.extend_from_slice(&buf[..size]);
+            echo.send_to(&reply, peer).unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (src, src_addr) = listener.accept().unwrap();
+
+        let mut rule = ConnectRule::none();
+        rule.allow(
+            RulePattern::Any,
+            RulePattern::Specif(echo_addr.port().into()),
+            RulePattern::Specif(L4Protocol::Udp),
+        );
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _tx_session_term) = Session::new(
+            5.into(),
+            5.into(),
+            TcpUdpConnector::new(Some(Duration::from_millis(100))),
+            NoAuthService::new(),
+            "127.0.0.1:1080".parse().unwrap(),
+            Arc::new(rule),
+            tx,
+        );
+        session.udp_reassembly_timeout = Some(Duration::from_secs(5));
+
+        socks::test::write_method_candidates(&mut client, MethodCandidates::new(&[Method::NoAuth]))
+            .unwrap();
+        socks::test::write_connect_request(
+            &mut client,
+            ConnectRequest::udp_associate(Address::from_str("0.0.0.0:0").unwrap()),
+        )
+        .unwrap();
+        let relay = session.make_session(src_addr, src).unwrap();
+        assert_eq!(
+            session.destination().get(),
+            Some(Address::from_str("0.0.0.0:0").unwrap())
+        );
+
+        socks::test::read_method_selection(&mut client).unwrap();
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        assert_eq!(reply.connect_result, Ok(()));
+        let relay_addr = match reply.server_addr {
+            Address::IpAddr(addr, port) => SocketAddr::new(addr, port),
+            addr => panic!("unexpected address: {}", addr),
+        };
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        let send = |frag: u8, dst: SocketAddr, data: &[u8]| {
+            let mut buf = vec![];
+            write_datagram(
+                &mut buf,
+                &UdpDatagram {
+                    frag,
+                    dst_addr: dst.into(),
+                    data,
+                },
+            )
+            .unwrap();
+            udp.send_to(&buf, relay_addr).unwrap();
+        };
+        // not allowed by the rule
+        send(0, "127.0.0.1:9".parse().unwrap(), b"drop");
+        // reassembled from 2 fragments
+        send(1, echo_addr, b"pi");
+        send(0x82, echo_addr, b"ng");
+
+        let mut buf = [0u8; 1024];
+        let (size, _) = udp.recv_from(&mut buf).unwrap();
+        let datagram = read_datagram(&buf[..size]).unwrap();
+        assert_eq!(datagram.dst_addr, echo_addr.into());
+        assert_eq!(datagram.data, b"pong:ping");
+
+        // closing the control connection terminates the association
+        drop(client);
+        assert!(relay.join().unwrap().is_ok());
+        assert_eq!(session.traffic().upload(), 4);
+        assert_eq!(session.traffic().download(), 9);
+    }
+
+    #[test]
+    fn udp_associate_resolved() {
+        use crate::auth_service::NoAuthService;
+        use crate::connector::test::MapResolver;
+        use crate::connector::TcpUdpConnector;
+        use crate::rw_socks_stream::{read_datagram, write_datagram};
+        use std::net::{TcpListener, TcpStream, UdpSocket};
+        use std::time::Duration;
+
+        let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (size, peer) = echo.recv_from(&mut buf).unwrap();
+            echo.send_to(&buf[..size], peer).unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (src, src_addr) = listener.accept().unwrap();
+
+        let mut connector = TcpUdpConnector::new(Some(Duration::from_millis(100)));
+        connector.set_resolver(Arc::new(MapResolver(
+            vec![("internal.test".to_owned(), echo_addr)]
+                .into_iter()
+                .collect(),
+        )));
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _tx_session_term) = Session::new(
+            5.into(),
+            5.into(),
+            connector,
+            NoAuthService::new(),
+            "127.0.0.1:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+        session.reject_private_resolved = true;
+
+        socks::test::write_method_candidates(&mut client, MethodCandidates::new(&[Method::NoAuth]))
+            .unwrap();
+        socks::test::write_connect_request(
+            &mut client,
+            ConnectRequest::udp_associate(Address::from_str("0.0.0.0:0").unwrap()),
+        )
+        .unwrap();
+        let relay = session.make_session(src_addr, src).unwrap();
+        socks::test::read_method_selection(&mut client).unwrap();
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        let relay_addr = match reply.server_addr {
+            Address::IpAddr(addr, port) => SocketAddr::new(addr, port),
+            addr => panic!("unexpected address: {}", addr),
+        };
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        let send = |dst_addr: Address, data: &[u8]| {
+            let mut buf = vec![];
+            write_datagram(
+                &mut buf,
+                &UdpDatagram {
+                    frag: 0,
+                    dst_addr,
+                    data,
+                },
+            )
+            .unwrap();
+            udp.send_to(&buf, relay_addr).unwrap();
+        };
+        // the domain is resolved to a private address by the resolver of the connector
+        send(
+            Address::Domain("internal.test".to_owned(), echo_addr.port()),
+            b"drop",
+        );
+        // requests of ip addresses are decided only by rules
+        send(echo_addr.into(), b"ping");
+
+        let mut buf = [0u8; 1024];
+        let (size, _) = udp.recv_from(&mut buf).unwrap();
+        let datagram = read_datagram(&buf[..size]).unwrap();
+        assert_eq!(datagram.data, b"ping");
+
+        drop(client);
+        assert!(relay.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn bind() {
+        use crate::auth_service::NoAuthService;
+        use crate::connector::TcpUdpConnector;
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (src, src_addr) = listener.accept().unwrap();
+
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _tx_session_term) = Session::new(
+            6.into(),
+            5.into(),
+            TcpUdpConnector::new(Some(Duration::from_millis(100))),
+            NoAuthService::new(),
+            "127.0.0.1:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+        session.bind_timeout = Some(Duration::from_secs(3));
+
+        socks::test::write_method_candidates(&mut client, MethodCandidates::new(&[Method::NoAuth]))
+            .unwrap();
+        socks::test::write_connect_request(
+            &mut client,
+            ConnectRequest::bind(Address::from_str("127.0.0.1:0").unwrap()),
+        )
+        .unwrap();
+        let session_th = thread::spawn(move || session.make_session(src_addr, src));
+
+        socks::test::read_method_selection(&mut client).unwrap();
+        // 1st reply: the address listening on
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        assert_eq!(reply.connect_result, Ok(()));
+        let bound = match reply.server_addr {
+            Address::IpAddr(addr, port) => SocketAddr::new(addr, port),
+            addr => panic!("unexpected address: {}", addr),
+        };
+
+        // 2nd reply: the address connected from
+        let mut remote = TcpStream::connect(bound).unwrap();
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        assert_eq!(reply.connect_result, Ok(()));
+        assert_eq!(reply.server_addr, remote.local_addr().unwrap().into());
+        let relay = session_th.join().unwrap().unwrap();
+
+        remote.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        drop(remote);
+        drop(client);
+        assert!(relay.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn bind_ports() {
+        use crate::auth_service::NoAuthService;
+        use crate::connector::TcpUdpConnector;
+        use std::net::TcpListener;
+
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _tx_session_term) = Session::new(
+            8.into(),
+            5.into(),
+            TcpUdpConnector::new(None),
+            NoAuthService::new(),
+            "127.0.0.1:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+
+        // the first port of the range is in use
+        let used = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = used.local_addr().unwrap().port();
+        session.bind_ports = Some(port..=port.saturating_add(10));
+        let bound = session.bind_listener().unwrap().local_addr().unwrap();
+        assert!(port < bound.port() && bound.port() <= port.saturating_add(10));
+
+        session.bind_ports = Some(port..=port);
+        assert!(session.bind_listener().is_err());
+    }
+
+    #[test]
+    fn bind_unexpected_peer() {
+        use crate::auth_service::NoAuthService;
+        use crate::connector::TcpUdpConnector;
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (src, src_addr) = listener.accept().unwrap();
+
+        let (tx, _rx) = mpsc::channel::<ServerCommand<()>>();
+        let (mut session, _tx_session_term) = Session::new(
+            7.into(),
+            5.into(),
+            TcpUdpConnector::new(Some(Duration::from_millis(100))),
+            NoAuthService::new(),
+            "127.0.0.1:1080".parse().unwrap(),
+            Arc::new(ConnectRule::any()),
+            tx,
+        );
+        session.bind_timeout = Some(Duration::from_secs(3));
+
+        socks::test::write_method_candidates(&mut client, MethodCandidates::new(&[Method::NoAuth]))
+            .unwrap();
+        socks::test::write_connect_request(
+            &mut client,
+            ConnectRequest::bind(Address::from_str("127.0.0.2:0").unwrap()),
+        )
+        .unwrap();
+        let session_th = thread::spawn(move || session.make_session(src_addr, src));
+
+        socks::test::read_method_selection(&mut client).unwrap();
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        assert_eq!(reply.connect_result, Ok(()));
+        let bound = match reply.server_addr {
+            Address::IpAddr(addr, port) => SocketAddr::new(addr, port),
+            addr => panic!("unexpected address: {}", addr),
+        };
+
+        // connection from 127.0.0.1 is dropped
+        let mut other = TcpStream::connect(bound).unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+        assert_eq!(other.read(&mut [0u8; 1]).unwrap(), 0);
+
+        // connection from the expected host is accepted
+        let remote =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        remote
+            .bind(&"127.0.0.2:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        remote.connect(&bound.into()).unwrap();
+        let remote = TcpStream::from(remote);
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        assert_eq!(reply.connect_result, Ok(()));
+        assert_eq!(reply.server_addr, remote.local_addr().unwrap().into());
+        let relay = session_th.join().unwrap().unwrap();
+
+        drop(remote);
+        drop(client);
+        assert!(relay.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn connect_resolved() {
+        use crate::connector::test::MapResolver;
+        use crate::connector::TcpUdpConnector;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut connector = TcpUdpConnector::new(None);
+        connector.set_resolver(Arc::new(MapResolver(
+            vec![
+                ("allowed.test".to_owned(), "127.0.0.1:0".parse().unwrap()),
+                ("rebound.test".to_owned(), "127.0.0.2:0".parse().unwrap()),
+            ]
+            .into_iter()
+            .collect(),
+        )));
+        let mut rule = ConnectRule::any();
+        rule.deny(
+            RulePattern::Specif(AddressPattern::IpAddr {
+                addr: "127.0.0.2".parse().unwrap(),
+                prefix: 32,
+            }),
+            RulePattern::Any,
+            RulePattern::Any,
+        );
+        let src = "192.168.0.2:12345".parse().unwrap();
+        let connect = |domain: &str| {
+            perform_command(
+                Command::Connect,
+                &connector,
+                &rule,
+                None,
+                None,
+                ResolvedCheck {
+                    rules: true,
+                    reject_private: false,
+                },
+                src,
+                None,
+                Address::Domain(domain.to_owned(), port),
+            )
+            .1
+        };
+
+        let (_conn, peer) = connect("allowed.test").unwrap();
+        assert_eq!(peer, listener.local_addr().unwrap());
+        let err = connect("rebound.test").unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::connection_not_allowed(
+                Address::Domain("rebound.test".to_owned(), port),
+                L4Protocol::Tcp
+            )
+        );
+    }
+
+    #[test]
+    fn reject_private_resolved() {
+        use crate::connector::test::MapResolver;
+        use crate::connector::TcpUdpConnector;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst = listener.local_addr().unwrap();
+        let mut connector = TcpUdpConnector::new(None);
+        connector.set_resolver(Arc::new(MapResolver(
+            vec![("internal.test".to_owned(), dst)]
+                .into_iter()
+                .collect(),
+        )));
+        let src = "192.168.0.2:12345".parse().unwrap();
+        let check = ResolvedCheck {
+            rules: false,
+            reject_private: true,
+        };
+        let connect = |rule: &ConnectRule, addr: Address| {
+            perform_command(
+                Command::Connect,
+                &connector,
+                rule,
+                None,
+                None,
+                check,
+                src,
+                None,
+                addr,
+            )
+            .1
+        };
+        let domain = Address::Domain("internal.test".to_owned(), dst.port());
+
+        let err = connect(&ConnectRule::any(), domain.clone()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::connection_not_allowed(domain.clone(), L4Protocol::Tcp)
+        );
+        // requests of ip addresses are decided only by rules
+        assert!(connect(&ConnectRule::any(), dst.into()).is_ok());
+
+        // allowed by a rule of the address
+        let mut rule = ConnectRule::any();
+        rule.allow(
+            RulePattern::Specif(AddressPattern::IpAddr {
+                addr: "127.0.0.0".parse().unwrap(),
+                prefix: 8,
+            }),
+            RulePattern::Any,
+            RulePattern::Any,
+        );
+        let (_conn, peer) = connect(&rule, domain).unwrap();
+        assert_eq!(peer, dst);
+
+        for private in ["10.1.2.3", "172.31.0.1", "169.254.0.1", "100.64.0.1", "::1"] {
+            assert!(is_private(private.parse().unwrap()), "{}", private);
+        }
+        for private in ["fd00::1", "fe80::1", "::ffff:192.168.0.1", "0.0.0.0"] {
+            assert!(is_private(private.parse().unwrap()), "{}", private);
+        }
+        for public in ["192.0.2.1", "172.32.0.1", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!is_private(public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn relay_link_local() {
+        use crate::connector::test::{link_local_addr, MapResolver};
+        use crate::connector::TcpUdpConnector;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        let listener = match link_local_addr() {
+            Some(addr) => TcpListener::bind(addr).unwrap(),
+            None => return,
+        };
+        let dst = listener.local_addr().unwrap();
+        let mut connector = TcpUdpConnector::new(None);
+        connector.set_resolver(Arc::new(MapResolver(
+            vec![("link-local.test".to_owned(), dst)]
+                .into_iter()
+                .collect(),
+        )));
+        let src = "192.168.0.2:12345".parse().unwrap();
+        let connect = |rule: &ConnectRule| {
+            perform_command(
+                Command::Connect,
+                &connector,
+                rule,
+                None,
+                None,
+                ResolvedCheck {
+                    rules: true,
+                    reject_private: false,
+                },
+                src,
+                None,
+                Address::Domain("link-local.test".to_owned(), dst.port()),
+            )
+            .1
+        };
+
+        let (mut conn, peer) = connect(&ConnectRule::any()).unwrap();
+        assert_eq!(peer, dst);
+        let (mut accepted, _) = listener.accept().unwrap();
+        conn.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // ip address rules match regardless of the scope
+        let mut rule = ConnectRule::any();
+        rule.deny(
+            RulePattern::Specif(AddressPattern::IpAddr {
+                addr: "fe80::".parse().unwrap(),
+                prefix: 10,
+            }),
+            RulePattern::Any,
+            RulePattern::Any,
+        );
+        assert_eq!(
+            connect(&rule).unwrap_err().cerr(),
+            ConnectError::ConnectionNotAllowed
+        );
+    }
+
+    #[test]
+    fn rewrite_destination() {
+        use crate::connector::TcpUdpConnector;
+        use std::net::TcpListener;
+
+        #[derive(Debug)]
+        struct ServiceGateway(SocketAddr);
+
+        impl ConnectRewriter for ServiceGateway {
+            fn rewrite(&self, ctx: &ConnectContext) -> Option<Address> {
+                match ctx.dst {
+                    Address::Domain(host, _) if host.ends_with(".service.local") => {
+                        Some(self.0.into())
+                    }
+                    _ => None,
+                }
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let gateway = ServiceGateway(listener.local_addr().unwrap());
+        let connector = TcpUdpConnector::new(None);
+        let mut rule = ConnectRule::any();
+        rule.deny(
+            RulePattern::Any,
+            RulePattern::Specif(25.into()),
+            RulePattern::Any,
+        );
+        let src = "192.168.0.2:12345".parse().unwrap();
+        let connect = |addr: Address| {
+            perform_command(
+                Command::Connect,
+                &connector,
+                &rule,
+                None,
+                Some(&gateway),
+                ResolvedCheck::default(),
+                src,
+                None,
+                addr,
+            )
+            .1
+        };
+
+        let (_conn, peer) = connect(Address::Domain("web.service.local".into(), 80)).unwrap();
+        assert_eq!(peer, gateway.0);
+        // denied requests are not rewritten
+        let denied = Address::Domain("mail.service.local".into(), 25);
+        let err = connect(denied.clone()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::connection_not_allowed(denied, L4Protocol::Tcp)
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn rule_outbound_bind() {
+        use crate::connector::TcpUdpConnector;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst = listener.local_addr().unwrap();
+        let mut connector = TcpUdpConnector::new(None);
+        connector.set_outbound_bind(Some(OutboundBind::from_addr([127, 0, 0, 2].into())));
+        let mut rule = ConnectRule::any();
+        rule.push(ConnectRuleEntry::Allow(
+            ConnectRulePattern::new(
+                RulePattern::Any,
+                RulePattern::Specif(dst.port().into()),
+                RulePattern::Specif(L4Protocol::Tcp),
+            )
+            .bound_to(OutboundBind::from_addr([127, 0, 0, 3].into())),
+        ));
+        let src = "192.168.0.2:12345".parse().unwrap();
+        let connect = |rule: &ConnectRule| {
+            let (conn, _) = perform_command(
+                Command::Connect,
+                &connector,
+                rule,
+                None,
+                None,
+                ResolvedCheck::default(),
+                src,
+                None,
+                dst.into(),
+            )
+            .1
+            .unwrap();
+            conn.local_addr().unwrap().ip()
+        };
+
+        assert_eq!(connect(&rule), IpAddr::from([127, 0, 0, 3]));
+        // bound as configured to the connector unless the rule binds
+        assert_eq!(connect(&ConnectRule::any()), IpAddr::from([127, 0, 0, 2]));
+    }
 }
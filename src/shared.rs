@@ -0,0 +1,117 @@
+//! Resources shared by servers
+//!
+//! Servers listening on different addresses (e.g. one for each network interface)
+//! may share the connector with its DNS cache, `ServerConfig::global_rate_limit`,
+//! `ServerConfig::connection_rate_limit`, clients banned by `ServerConfig::client_ban`
+//! and the cumulative metrics by a [`SharedContext`],
+//! instead of keeping them for each server.
+//!
+//! ```no_run
+//! use gatekeeper::shared::SharedContext;
+//! use gatekeeper::{Server, ServerConfig};
+//!
+//! let config = ServerConfig::default();
+//! let shared = SharedContext::new(&config);
+//! let servers = ["192.168.0.1:1080", "10.0.0.1:1080"].map(|addr| {
+//!     let mut config = config.clone();
+//!     config.set_server_addr(addr.parse().unwrap());
+//!     Server::with_shared_context(config, &shared)
+//! });
+//! ```
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::client_ban::{BannedClient, ClientBans};
+use crate::config::ServerConfig;
+use crate::connection_limiter::ConnectionLimiter;
+use crate::connector::TcpUdpConnector;
+use crate::metrics::{Counters, Metrics};
+use crate::model::IpAddr;
+use crate::relay::Bandwidth;
+
+/// Resources of servers cloned from the same context
+///
+/// `max_sessions` of the configuration and of connect rules are still counted for each server.
+#[derive(Debug, Clone)]
+pub struct SharedContext<C = TcpUdpConnector> {
+    pub(crate) connector: C,
+    /// bandwidth shared by all sessions
+    pub(crate) bandwidth: Bandwidth,
+    /// rate limit of connections from each client
+    connection_limiter: Option<Arc<Mutex<ConnectionLimiter>>>,
+    /// clients failing repeatedly
+    client_bans: Option<Arc<Mutex<ClientBans>>>,
+    /// cumulative counters for metrics
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl SharedContext {
+    /// Context of the connector, `global_rate_limit` and `connection_rate_limit` of `config`
+    pub fn new(config: &ServerConfig) -> Self {
+        Self::with_connector(config, TcpUdpConnector::from_config(config))
+    }
+}
+
+impl<C> SharedContext<C> {
+    /// Context of `connector`, `global_rate_limit` and `connection_rate_limit` of `config`
+    pub fn with_connector(config: &ServerConfig, connector: C) -> Self {
+        Self {
+            connector,
+            bandwidth: Bandwidth::new(config.global_rate_limit),
+            connection_limiter: config
+                .connection_rate_limit
+                .map(|rate| Arc::new(Mutex::new(ConnectionLimiter::new(rate)))),
+            client_bans: config
+                .client_ban
+                .map(|ban| Arc::new(Mutex::new(ClientBans::new(ban)))),
+            counters: Arc::default(),
+        }
+    }
+
+    pub fn connector(&self) -> &C {
+        &self.connector
+    }
+
+    /// Cumulative metrics of all servers sharing the context, without running sessions
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.counters().snapshot(Default::default());
+        metrics.banned_clients = self.banned_clients();
+        metrics
+    }
+
+    pub(crate) fn counters(&self) -> MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// `false` if the client exceeds `ServerConfig::connection_rate_limit`
+    pub(crate) fn check_connection_rate(&self, ip: IpAddr) -> bool {
+        match &self.connection_limiter {
+            Some(limiter) => limiter
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .check(ip),
+            None => true,
+        }
+    }
+
+    fn client_bans(&self) -> Option<MutexGuard<'_, ClientBans>> {
+        let bans = self.client_bans.as_ref()?;
+        Some(bans.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// the client is banned by `ServerConfig::client_ban`
+    pub(crate) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.client_bans()
+            .is_some_and(|mut bans| bans.is_banned(ip))
+    }
+
+    /// Count a failed attempt of the client, returns `true` if it is banned by this attempt
+    pub(crate) fn fail(&self, ip: IpAddr) -> bool {
+        self.client_bans().is_some_and(|mut bans| bans.fail(ip))
+    }
+
+    pub(crate) fn banned_clients(&self) -> Vec<BannedClient> {
+        self.client_bans()
+            .map(|bans| bans.banned_clients())
+            .unwrap_or_default()
+    }
+}
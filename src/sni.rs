@@ -0,0 +1,171 @@
+//! Server Name Indication of TLS
+//!
+//! The first TLS record sent by a client (ClientHello) is read to find the server name,
+//! so that domain rules can be applied to connections requested by an ip address.
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Content type of handshake records
+const HANDSHAKE: u8 = 22;
+/// Handshake type of ClientHello
+const CLIENT_HELLO: u8 = 1;
+/// Extension type of server_name
+const SERVER_NAME: u16 = 0;
+/// Name type of host_name
+const HOST_NAME: u8 = 0;
+
+/// Connections to this port are inspected
+pub const HTTPS_PORT: u16 = 443;
+
+/// Give up reading the ClientHello after this duration
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the first TLS record from `strm`
+///
+/// If the stream does not start with a handshake record, only the bytes read so far are returned.
+/// Read timeouts of `strm` are retried until `timeout`.
+pub fn read_record<R: io::Read>(mut strm: R, timeout: Duration) -> io::Result<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut record = vec![0; 5];
+    read_full(&mut strm, &mut record, deadline)?;
+    if record[0] != HANDSHAKE {
+        return Ok(record);
+    }
+    let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+    record.resize(5 + len, 0);
+    read_full(&mut strm, &mut record[5..], deadline)?;
+    Ok(record)
+}
+
+fn read_full<R: io::Read>(strm: &mut R, mut buf: &mut [u8], deadline: Instant) -> io::Result<()> {
+    use io::ErrorKind as K;
+    while !buf.is_empty() {
+        match strm.read(buf) {
+            Ok(0) => return Err(K::UnexpectedEof.into()),
+            Ok(size) => buf = &mut buf[size..],
+            Err(err) if err.kind() == K::WouldBlock || err.kind() == K::TimedOut => {
+                if deadline <= Instant::now() {
+                    return Err(err);
+                }
+            }
+            Err(err) if err.kind() == K::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Byte reader of TLS structures
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    /// vector prefixed by its length of `len_size` bytes
+    fn vec(&mut self, len_size: usize) -> Option<Parser<'a>> {
+        let len = match len_size {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?,
+        };
+        self.take(len).map(Parser)
+    }
+}
+
+/// Host name in the ClientHello `record`
+///
+/// Returns `None` if the record is not a ClientHello, or it has no server_name extension.
+/// A ClientHello fragmented into multiple records is not supported.
+pub fn server_name(record: &[u8]) -> Option<String> {
+    let mut rec = Parser(record);
+    if rec.u8()? != HANDSHAKE {
+        return None;
+    }
+    rec.take(2)?; // legacy_record_version
+    let mut hs = rec.vec(2)?;
+    if hs.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = hs.vec(3)?;
+    hello.take(2 + 32)?; // legacy_version, random
+    hello.vec(1)?; // legacy_session_id
+    hello.vec(2)?; // cipher_suites
+    hello.vec(1)?; // legacy_compression_methods
+    let mut exts = hello.vec(2)?;
+    while let Some(ext_type) = exts.u16() {
+        let mut ext = exts.vec(2)?;
+        if ext_type != SERVER_NAME {
+            continue;
+        }
+        let mut names = ext.vec(2)?;
+        while let Some(name_type) = names.u8() {
+            let name = names.vec(2)?;
+            if name_type == HOST_NAME {
+                return String::from_utf8(name.0.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// ClientHello record with the server_name extension of `name`
+    pub fn client_hello(name: &str) -> Vec<u8> {
+        let vec = |len_size: usize, body: &[u8]| {
+            let mut v = body.len().to_be_bytes()[8 - len_size..].to_vec();
+            v.extend_from_slice(body);
+            v
+        };
+        let mut sni = vec![0, 0]; // server_name
+        sni.extend(vec(
+            2,
+            &vec(2, &[&[HOST_NAME][..], &vec(2, name.as_bytes())].concat()),
+        ));
+        let mut exts = vec![0xff, 0x01, 0, 1, 0]; // renegotiation_info
+        exts.extend(sni);
+        let mut hello = vec![3, 3];
+        hello.extend([0; 32]);
+        hello.extend(vec(1, &[]));
+        hello.extend(vec(2, &[0x13, 0x01]));
+        hello.extend(vec(1, &[0]));
+        hello.extend(vec(2, &exts));
+        let hs = [&[CLIENT_HELLO][..], &vec(3, &hello)].concat();
+        [&[HANDSHAKE, 3, 1][..], &vec(2, &hs)].concat()
+    }
+
+    #[test]
+    fn parse_server_name() {
+        let record = client_hello("example.com");
+        assert_eq!(server_name(&record).as_deref(), Some("example.com"));
+        assert_eq!(
+            read_record(&record[..], READ_TIMEOUT).unwrap(),
+            record.as_slice()
+        );
+        assert_eq!(server_name(&record[..record.len() - 1]), None);
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), None);
+        assert_eq!(read_record(&b"GET / "[..], READ_TIMEOUT).unwrap(), b"GET /");
+    }
+}
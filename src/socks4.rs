@@ -0,0 +1,135 @@
+//! SOCKS Protocol Version 4 and 4a Messages
+//!
+//! Only requests from clients are parsed, and replies are emitted.
+//! The version field of a request is expected to be consumed for detecting the protocol.
+use std::io;
+use std::net::Ipv4Addr;
+
+use crate::model::{Address, Command, ConnectResult, Error, ErrorKind};
+
+pub const SOCKS4_VERSION: u8 = 4;
+
+/// Version of replies
+const REPLY_VERSION: u8 = 0;
+const REQUEST_GRANTED: u8 = 90;
+const REQUEST_REJECTED: u8 = 91;
+
+/// Maximum length of USERID and domain name fields (except the terminating NUL)
+const MAX_FIELD_LEN: usize = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub command: Command,
+    pub connect_to: Address,
+    pub user_id: Vec<u8>,
+}
+
+/// Receive a request following the version field
+pub fn recv_request<R: io::Read>(mut strm: R) -> Result<Request, Error> {
+    let mut buf = [0u8; 7];
+    strm.read_exact(&mut buf)?;
+    let command = match buf[0] {
+        1 => Command::Connect,
+        2 => Command::Bind,
+        cd => {
+            return Err(
+                ErrorKind::message_fmt(format_args!("socks4: unknown command: {}", cd)).into(),
+            )
+        }
+    };
+    let port = u16::from_be_bytes([buf[1], buf[2]]);
+    let ip = Ipv4Addr::new(buf[3], buf[4], buf[5], buf[6]);
+    let user_id = read_nul_terminated(&mut strm)?;
+    // SOCKS4a: 0.0.0.x (x != 0) is followed by the domain name
+    let connect_to = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => {
+            let domain = read_nul_terminated(&mut strm)?;
+            let domain = String::from_utf8(domain).map_err(|err| {
+                ErrorKind::message_fmt(format_args!("socks4a: domain name: {}", err))
+            })?;
+            Address::Domain(domain, port)
+        }
+        _ => Address::IpAddr(ip.into(), port),
+    };
+    Ok(Request {
+        command,
+        connect_to,
+        user_id,
+    })
+}
+
+fn read_nul_terminated<R: io::Read>(mut strm: R) -> Result<Vec<u8>, Error> {
+    let mut field = vec![];
+    loop {
+        let mut c = [0u8];
+        strm.read_exact(&mut c)?;
+        if c[0] == 0 {
+            return Ok(field);
+        }
+        if field.len() >= MAX_FIELD_LEN {
+            return Err(ErrorKind::message_fmt(format_args!("socks4: too long field")).into());
+        }
+        field.push(c[0]);
+    }
+}
+
+/// Send a reply
+///
+/// SOCKS4 has no detailed error codes, all errors are replied as rejected.
+pub fn send_reply<W: io::Write>(mut strm: W, result: ConnectResult) -> Result<(), Error> {
+    let cd = match result {
+        Ok(()) => REQUEST_GRANTED,
+        Err(_) => REQUEST_REJECTED,
+    };
+    // DSTPORT and DSTIP are ignored for CONNECT
+    strm.write_all(&[REPLY_VERSION, cd, 0, 0, 0, 0, 0, 0])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::ConnectError;
+
+    #[test]
+    fn socks4_request() {
+        let msg = [1, 0, 80, 192, 168, 0, 1, b'u', b's', b'e', b'r', 0];
+        assert_eq!(
+            recv_request(&msg[..]).unwrap(),
+            Request {
+                command: Command::Connect,
+                connect_to: "192.168.0.1:80"
+                    .parse::<std::net::SocketAddr>()
+                    .unwrap()
+                    .into(),
+                user_id: b"user".to_vec(),
+            }
+        );
+
+        let mut msg = vec![1, 1, 187, 0, 0, 0, 1, 0];
+        msg.extend_from_slice(b"example.com\0");
+        assert_eq!(
+            recv_request(&msg[..]).unwrap(),
+            Request {
+                command: Command::Connect,
+                connect_to: Address::Domain("example.com".to_owned(), 443),
+                user_id: vec![],
+            }
+        );
+
+        // missing NUL
+        let mut msg = vec![1, 0, 80, 192, 168, 0, 1];
+        msg.extend_from_slice(&[b'a'; 300]);
+        assert!(recv_request(&msg[..]).is_err());
+    }
+
+    #[test]
+    fn socks4_reply() {
+        let mut buf = vec![];
+        send_reply(&mut buf, Ok(())).unwrap();
+        assert_eq!(buf, [0, 90, 0, 0, 0, 0, 0, 0]);
+        let mut buf = vec![];
+        send_reply(&mut buf, Err(ConnectError::ConnectionRefused)).unwrap();
+        assert_eq!(buf, [0, 91, 0, 0, 0, 0, 0, 0]);
+    }
+}
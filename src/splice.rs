@@ -0,0 +1,138 @@
+//! Zero-copy relay between sockets by `splice(2)`
+//!
+//! Data read from the source socket is moved into a pipe and then into the destination socket,
+//! without being copied to userspace.
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use nix::fcntl::{splice, OFlag, SpliceFFlags};
+use nix::unistd::pipe2;
+
+/// Maximum bytes moved by a `splice` call
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// One direction of a relay between 2 sockets
+#[derive(Debug)]
+pub struct Splice {
+    src: OwnedFd,
+    dst: OwnedFd,
+    /// read end of the pipe
+    pipe_rd: OwnedFd,
+    /// write end of the pipe
+    pipe_wr: OwnedFd,
+    /// bytes in the pipe not yet written to `dst`
+    pending: usize,
+    deadline: Option<Instant>,
+    counter: Arc<AtomicU64>,
+}
+
+impl Splice {
+    /// `src` and `dst` are duplicated, so the caller may close them
+    pub fn new(
+        src: RawFd,
+        dst: RawFd,
+        deadline: Option<Instant>,
+        counter: Arc<AtomicU64>,
+    ) -> io::Result<Self> {
+        // safety: the caller owns the sockets while this function is running
+        let (src, dst) = unsafe {
+            (
+                BorrowedFd::borrow_raw(src).try_clone_to_owned()?,
+                BorrowedFd::borrow_raw(dst).try_clone_to_owned()?,
+            )
+        };
+        let (rd, wr) = pipe2(OFlag::O_CLOEXEC)?;
+        // safety: the pipe is created just now and owned by nobody else
+        let (pipe_rd, pipe_wr) = unsafe { (OwnedFd::from_raw_fd(rd), OwnedFd::from_raw_fd(wr)) };
+        Ok(Self {
+            src,
+            dst,
+            pipe_rd,
+            pipe_wr,
+            pending: 0,
+            deadline,
+            counter,
+        })
+    }
+
+    /// Relay a chunk of data
+    ///
+    /// Returns the number of bytes written to `dst`, and `0` means the end of `src`.
+    /// Read timeouts of `src` are reported as `WouldBlock` like `io::Read`,
+    /// and `TimedOut` after the deadline.
+    pub fn copy(&mut self) -> io::Result<u64> {
+        if matches!(self.deadline, Some(deadline) if deadline <= Instant::now()) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if self.pending == 0 {
+            self.pending = splice(
+                self.src.as_raw_fd(),
+                None,
+                self.pipe_wr.as_raw_fd(),
+                None,
+                CHUNK_SIZE,
+                SpliceFFlags::SPLICE_F_MOVE,
+            )?;
+            if self.pending == 0 {
+                return Ok(0);
+            }
+        }
+        let mut written = 0;
+        while self.pending > 0 {
+            let size = splice(
+                self.pipe_rd.as_raw_fd(),
+                None,
+                self.dst.as_raw_fd(),
+                None,
+                self.pending,
+                SpliceFFlags::SPLICE_F_MOVE,
+            )?;
+            self.pending -= size;
+            written += size as u64;
+            self.counter.fetch_add(size as u64, Ordering::Relaxed);
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn splice_sockets() {
+        let (mut src, src_peer) = socket_pair();
+        let (dst_peer, mut dst) = socket_pair();
+        let counter = Arc::new(AtomicU64::new(0));
+        let mut relay = Splice::new(
+            src_peer.as_raw_fd(),
+            dst_peer.as_raw_fd(),
+            None,
+            counter.clone(),
+        )
+        .unwrap();
+        drop((src_peer, dst_peer));
+
+        src.write_all(b"hello").unwrap();
+        assert_eq!(relay.copy().unwrap(), 5);
+        let mut buf = [0; 5];
+        dst.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(counter.load(Ordering::Relaxed), 5);
+
+        drop(src);
+        assert_eq!(relay.copy().unwrap(), 0);
+    }
+}
@@ -1,52 +1,188 @@
+#[cfg(unix)]
 use std::convert::TryInto;
 use std::io;
+#[cfg(unix)]
 use std::mem;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::net::{SocketAddrV4, SocketAddrV6};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::time::Duration;
+#[cfg(any(not(unix), test))]
+use std::{thread, time::Instant};
 
+#[cfg(unix)]
 use nix::sys::time::{TimeVal, TimeValLike};
 
+/// Interval to retry accepting while no connection arrives (without `select`)
+#[cfg(any(not(unix), test))]
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 pub trait TcpListenerExt {
     fn accept_timeout(&self, timeout: Option<Duration>) -> io::Result<(TcpStream, SocketAddr)>;
 }
 
+#[cfg(unix)]
 impl TcpListenerExt for TcpListener {
     /// accept(2) with timeout
     ///
     /// * `timeout`
     ///   Timeout for _accept_. If the value is `None`, wait connection indefinitely.
     fn accept_timeout(&self, timeout: Option<Duration>) -> io::Result<(TcpStream, SocketAddr)> {
-        use nix::sys::select::*;
-
         let fd = self.as_raw_fd();
+        wait_acceptable(fd, None, timeout)?;
+        accept_fd(fd)
+    }
+}
 
-        let mut tm = timeout.map(dur_to_timeval::<TimeVal>).transpose()?;
+/// accept(2) on `listener` unless `wakeup` is woken up first
+///
+/// Returns `None` if woken up, without waking up periodically if `timeout` is `None`.
+#[cfg(unix)]
+pub(crate) fn accept_or_wake(
+    listener: &TcpListener,
+    wakeup: &Wakeup,
+    timeout: Option<Duration>,
+) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+    let fd = listener.as_raw_fd();
+    match wait_acceptable(fd, Some(wakeup), timeout)? {
+        Ready::Acceptable => accept_fd(fd).map(Some),
+        Ready::Woken => Ok(None),
+    }
+}
 
-        let mut fds = FdSet::new();
-        fds.insert(fd);
-        let r = select(None, &mut fds, None, None, &mut tm).map_err(io::Error::from)?;
-        if r == 0 {
-            return Err(io::Error::new(io::ErrorKind::TimedOut, "select accept"));
+#[cfg(unix)]
+fn accept_fd(fd: RawFd) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&storage) as libc::socklen_t;
+    unsafe {
+        let accepted = libc::accept(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len);
+        if accepted < 0 {
+            return Err(io::Error::last_os_error());
         }
-        assert!(r == 1);
-        assert!(fds.contains(fd));
-
-        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
-        let mut len = mem::size_of_val(&storage) as libc::socklen_t;
-        unsafe {
-            let accepted =
-                libc::accept(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len);
-            if accepted < 0 {
-                return Err(io::Error::last_os_error());
+        let addr = sockaddr_to_addr(&storage, len as usize)?;
+        Ok((TcpStream::from_raw_fd(accepted), addr))
+    }
+}
+
+#[cfg(not(unix))]
+impl TcpListenerExt for TcpListener {
+    /// accept with timeout
+    ///
+    /// * `timeout`
+    ///   Timeout for _accept_. If the value is `None`, wait connection indefinitely.
+    fn accept_timeout(&self, timeout: Option<Duration>) -> io::Result<(TcpStream, SocketAddr)> {
+        match timeout {
+            Some(timeout) => accept_polling(self, timeout),
+            None => self.accept(),
+        }
+    }
+}
+
+/// Accept a connection by polling the non-blocking `listener` until `timeout`
+///
+/// This is portable but less responsive than `select`, so used only where it is not available.
+#[cfg(any(not(unix), test))]
+fn accept_polling(
+    listener: &TcpListener,
+    timeout: Duration,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let deadline = Instant::now() + timeout;
+    listener.set_nonblocking(true)?;
+    let result = loop {
+        match listener.accept() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let now = Instant::now();
+                if deadline <= now {
+                    break Err(io::Error::new(io::ErrorKind::TimedOut, "poll accept"));
+                }
+                thread::sleep(ACCEPT_POLL_INTERVAL.min(deadline - now));
             }
-            let addr = sockaddr_to_addr(&storage, len as usize)?;
-            Ok((TcpStream::from_raw_fd(accepted), addr))
+            result => break result,
+        }
+    };
+    listener.set_nonblocking(false)?;
+    let (strm, addr) = result?;
+    // accepted sockets inherit the non-blocking mode on some platforms (e.g. Windows)
+    strm.set_nonblocking(false)?;
+    Ok((strm, addr))
+}
+
+/// Self-pipe waking up threads waiting in [`wait_acceptable`] (e.g. acceptors to terminate)
+///
+/// The pipe is never drained, so every thread waiting on it returns once woken up.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct Wakeup {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+#[cfg(unix)]
+impl Wakeup {
+    pub fn new() -> io::Result<Self> {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+
+        let (read, write) = nix::unistd::pipe().map_err(io::Error::from)?;
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) };
+        for fd in [&read, &write] {
+            fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+                .map_err(io::Error::from)?;
+        }
+        // waking up never blocks, even if the pipe is full
+        fcntl(write.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(io::Error::from)?;
+        Ok(Self { read, write })
+    }
+
+    pub fn wake(&self) {
+        // a full pipe is already readable
+        nix::unistd::write(self.write.as_raw_fd(), &[1]).ok();
+    }
+}
+
+/// What [`wait_acceptable`] waited for
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ready {
+    Acceptable,
+    Woken,
+}
+
+/// Wait until a connection arrives at the listening socket `fd`, or `wakeup` is woken up
+///
+/// Returns `TimedOut` error if neither happened within `timeout`.
+#[cfg(unix)]
+pub(crate) fn wait_acceptable(
+    fd: RawFd,
+    wakeup: Option<&Wakeup>,
+    timeout: Option<Duration>,
+) -> io::Result<Ready> {
+    use nix::sys::select::*;
+
+    let mut tm = timeout.map(dur_to_timeval::<TimeVal>).transpose()?;
+
+    let wake_fd = wakeup.map(|wakeup| wakeup.read.as_raw_fd());
+    let mut fds = FdSet::new();
+    fds.insert(fd);
+    if let Some(wake_fd) = wake_fd {
+        fds.insert(wake_fd);
+    }
+    let r = select(None, &mut fds, None, None, &mut tm).map_err(io::Error::from)?;
+    if r == 0 {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "select accept"));
+    }
+    match wake_fd {
+        Some(wake_fd) if fds.contains(wake_fd) => Ok(Ready::Woken),
+        _ => {
+            assert!(fds.contains(fd));
+            Ok(Ready::Acceptable)
         }
     }
 }
 
 /// Convert Duration to timeval in microseconds
+#[cfg(unix)]
 fn dur_to_timeval<T: TimeValLike>(dur: Duration) -> io::Result<T> {
     dur.as_micros()
         .try_into()
@@ -67,6 +203,7 @@ fn dur_to_timeval<T: TimeValLike>(dur: Duration) -> io::Result<T> {
 /// * `len`
 ///   The sizeof `storage` in bytes.
 ///   This should larger than or equals to the size of the *actual* type of `storage`.
+#[cfg(unix)]
 fn sockaddr_to_addr(storage: &libc::sockaddr_storage, len: usize) -> io::Result<SocketAddr> {
     match storage.ss_family as libc::c_int {
         libc::AF_INET => {
@@ -95,3 +232,60 @@ fn sockaddr_to_addr(storage: &libc::sockaddr_storage, len: usize) -> io::Result<
         )),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accept_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_millis(50);
+        for accept in [TcpListener::accept_timeout, accept_polling_opt] {
+            let err = accept(&listener, Some(timeout)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+            let client = TcpStream::connect(addr).unwrap();
+            let (_strm, peer) = accept(&listener, Some(timeout)).unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wake_accept() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let wakeup = Wakeup::new().unwrap();
+        let timeout = Some(Duration::from_millis(50));
+        let err = accept_or_wake(&listener, &wakeup, timeout).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_strm, peer) = accept_or_wake(&listener, &wakeup, None).unwrap().unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        // every waiting thread is woken up, and returns at once afterwards
+        let wakeup = std::sync::Arc::new(wakeup);
+        let listener = std::sync::Arc::new(listener);
+        let waiters = (0..2)
+            .map(|_| {
+                let (listener, wakeup) = (listener.clone(), wakeup.clone());
+                thread::spawn(move || accept_or_wake(&listener, &wakeup, None).unwrap())
+            })
+            .collect::<Vec<_>>();
+        thread::sleep(Duration::from_millis(50));
+        wakeup.wake();
+        for waiter in waiters {
+            assert!(waiter.join().unwrap().is_none());
+        }
+        assert!(accept_or_wake(&listener, &wakeup, None).unwrap().is_none());
+    }
+
+    fn accept_polling_opt(
+        listener: &TcpListener,
+        timeout: Option<Duration>,
+    ) -> io::Result<(TcpStream, SocketAddr)> {
+        accept_polling(listener, timeout.unwrap())
+    }
+}
@@ -0,0 +1,339 @@
+//! Utilities to test applications built on gatekeeper (`test-util` feature)
+//!
+//! Servers run with in-memory streams instead of sockets:
+//! [`DummyBinder`] accepts a [`BufferStream`] holding the bytes sent by a client,
+//! and [`BufferConnector`] connects requested addresses to given streams.
+//! This allows to test `AuthService`, `Connector` or `ConnectPolicy` implementations
+//! against the session machinery without opening ports.
+//!
+//! ```
+//! use std::borrow::Cow;
+//! use std::sync::mpsc;
+//! use std::thread;
+//! use std::time::Duration;
+//! use gatekeeper::test_util::{BufferConnector, BufferStream, DummyBinder};
+//! use gatekeeper::{Address, Server, ServerCommand, ServerConfig};
+//!
+//! // NoAuth, then CONNECT 192.0.2.1:80
+//! let request = vec![5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 0, 80];
+//! let client = BufferStream::with_buffer(Cow::from(request), Cow::from(vec![]));
+//! let binder = DummyBinder::new(client.clone(), "127.0.0.1:5000".parse().unwrap());
+//! let dst: Address = "192.0.2.1:80".parse().unwrap();
+//! let connector: BufferConnector<BufferStream> =
+//!     [(dst, Ok(BufferStream::new()))].into_iter().collect();
+//!
+//! let (tx_done, _rx_done) = mpsc::sync_channel(1);
+//! let (mut server, tx) = Server::with_binder(ServerConfig::default(), binder, tx_done, connector);
+//! let th = thread::spawn(move || server.serve());
+//! thread::sleep(Duration::from_millis(500));
+//! tx.send(ServerCommand::Terminate).unwrap();
+//! th.join().unwrap().unwrap();
+//! // method selection and a successful reply
+//! assert_eq!(&client.wr_buff().get_ref()[..3], &[5, 0, 5]);
+//! ```
+//!
+//! [`measure_relay`] runs a [`Relay`] between in-memory streams and reports its throughput,
+//! which is used by the benchmarks under `benches/`.
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::iter::FromIterator;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::acceptor::Binder;
+use crate::byte_stream::ByteStream;
+use crate::connector::{Connector, TcpStreamListener};
+use crate::model::{Address, ConnectError, Error, ErrorKind, L4Protocol};
+use crate::pkt_stream::UdpPktStream;
+use crate::relay::Relay;
+
+/// Stream reading bytes from `rd_buff`, and writing bytes into `wr_buff`
+///
+/// Clones share the buffers, so that a clone given to a server can be inspected by the test.
+#[derive(Debug, Clone)]
+pub struct BufferStream {
+    pub rd_buff: Arc<Mutex<io::Cursor<Vec<u8>>>>,
+    pub wr_buff: Arc<Mutex<io::Cursor<Vec<u8>>>>,
+}
+
+impl BufferStream {
+    pub fn new() -> Self {
+        BufferStream::with_buffer(vec![].into(), vec![].into())
+    }
+
+    pub fn with_buffer(rd: Cow<[u8]>, wr: Cow<[u8]>) -> Self {
+        Self {
+            rd_buff: Arc::new(Mutex::new(io::Cursor::new(rd.into_owned()))),
+            wr_buff: Arc::new(Mutex::new(io::Cursor::new(wr.into_owned()))),
+        }
+    }
+
+    pub fn rd_buff(&self) -> MutexGuard<'_, io::Cursor<Vec<u8>>> {
+        self.rd_buff.lock().unwrap()
+    }
+
+    pub fn wr_buff(&self) -> MutexGuard<'_, io::Cursor<Vec<u8>>> {
+        self.wr_buff.lock().unwrap()
+    }
+}
+
+impl Default for BufferStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl io::Read for BufferStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rd_buff.lock().unwrap().read(buf)
+    }
+}
+
+impl io::Write for BufferStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wr_buff.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.wr_buff.lock().unwrap().flush()
+    }
+}
+
+impl ByteStream for BufferStream {
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        let rd = Self {
+            rd_buff: self.rd_buff.clone(),
+            wr_buff: self.wr_buff.clone(),
+        };
+        let wr = Self {
+            rd_buff: self.rd_buff.clone(),
+            wr_buff: self.wr_buff.clone(),
+        };
+        Ok((Box::new(rd), Box::new(wr)))
+    }
+}
+
+/// Stream reading chunks yielded by `iter`, and writing bytes into `wr_buff`
+#[derive(Debug, Clone)]
+pub struct IterBuffer<T> {
+    pub iter: T,
+    pub wr_buff: Arc<Mutex<io::Cursor<Vec<u8>>>>,
+}
+
+impl<T> IterBuffer<T>
+where
+    T: Iterator<Item = Vec<u8>>,
+{
+    pub fn new(iter: T, wr_buff: io::Cursor<Vec<u8>>) -> Self {
+        Self {
+            iter,
+            wr_buff: Arc::new(Mutex::new(wr_buff)),
+        }
+    }
+}
+
+impl<T> io::Read for IterBuffer<T>
+where
+    T: Iterator<Item = Vec<u8>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(chunk) = self.iter.next() {
+            (&chunk[..]).read(buf)
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+impl<T> io::Write for IterBuffer<T>
+where
+    T: Iterator<Item = Vec<u8>>,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        log::debug!("IterBuffer::write({})", String::from_utf8_lossy(buf));
+        self.wr_buff.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.wr_buff.lock().unwrap().flush()
+    }
+}
+
+impl<T> ByteStream for IterBuffer<T>
+where
+    T: fmt::Debug + Iterator<Item = Vec<u8>> + Clone + Send + 'static,
+{
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        let rd = Box::new(self.clone()) as Box<dyn io::Read + Send>;
+        let wr = Box::new(self.clone()) as Box<dyn io::Write + Send>;
+        Ok((rd, wr))
+    }
+}
+
+/// Connector connecting each address to the given stream, or failing with the given error
+///
+/// UDP ASSOCIATE and BIND commands are not supported.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BufferConnector<S> {
+    pub strms: BTreeMap<Address, Result<S, ConnectError>>,
+}
+
+impl<S> FromIterator<(Address, Result<S, ConnectError>)> for BufferConnector<S> {
+    fn from_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = (Address, Result<S, ConnectError>)>,
+    {
+        Self {
+            strms: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<S> BufferConnector<S> {
+    pub fn stream(&self, addr: &Address) -> &S {
+        self.strms[addr].as_ref().unwrap()
+    }
+}
+
+impl<S> Connector for BufferConnector<S>
+where
+    S: ByteStream + Clone,
+{
+    type B = S;
+    type P = UdpPktStream;
+    type L = TcpStreamListener;
+    fn connect_byte_stream(&self, addr: Address) -> Result<(Self::B, SocketAddr), Error> {
+        log::debug!("connect_byte_stream: {:?}", &addr);
+        match &self.strms[&addr] {
+            Ok(strm) => {
+                use Address::*;
+                let peer = match addr {
+                    IpAddr(peer, port) => SocketAddr::new(peer, port),
+                    ScopedV6(peer) => peer.into(),
+                    Domain(_, port) => format!("192.168.1.1:{}", port).parse().unwrap(),
+                };
+                Ok((strm.clone(), peer))
+            }
+            Err(err) => {
+                use Address::*;
+                use ConnectError::*;
+                use L4Protocol::*;
+                let kind = match err {
+                    NetworkUnreachable => match addr {
+                        Domain(domain, port) => ErrorKind::DomainNotResolved { domain, port },
+                        IpAddr(ipaddr, port) => ErrorKind::HostUnreachable {
+                            host: ipaddr.to_string(),
+                            port,
+                        },
+                        ScopedV6(addr) => ErrorKind::HostUnreachable {
+                            host: addr.ip().to_string(),
+                            port: addr.port(),
+                        },
+                    },
+                    HostUnreachable => {
+                        let port = addr.port();
+                        let host = match addr {
+                            Domain(domain, _) => domain,
+                            IpAddr(ipaddr, _) => ipaddr.to_string(),
+                            ScopedV6(addr) => addr.ip().to_string(),
+                        };
+                        ErrorKind::HostUnreachable { host, port }
+                    }
+                    ConnectionNotAllowed => ErrorKind::connection_not_allowed(addr, Tcp),
+                    ConnectionRefused => ErrorKind::connection_refused(addr, Tcp),
+                    _ => ErrorKind::io(&io::Error::new(io::ErrorKind::Other, err.clone())),
+                };
+                Err(kind.into())
+            }
+        }
+    }
+    fn bind_pkt_stream(&self, _addr: SocketAddr) -> Result<Self::P, Error> {
+        unimplemented!("BufferConnector::bind_pkt_stream")
+    }
+    fn listen_byte_stream(&self, _addr: SocketAddr) -> Result<Self::L, Error> {
+        unimplemented!("BufferConnector::listen_byte_stream")
+    }
+}
+
+/// Binder accepting a single client connected by `stream`
+#[derive(Debug, Clone)]
+pub struct DummyBinder {
+    pub stream: BufferStream,
+    pub src_addr: SocketAddr,
+}
+
+impl DummyBinder {
+    pub fn new(stream: BufferStream, src_addr: SocketAddr) -> Self {
+        Self { stream, src_addr }
+    }
+}
+
+impl Binder for DummyBinder {
+    type Stream = BufferStream;
+    type Iter = std::iter::Once<(Self::Stream, SocketAddr)>;
+    fn bind(&self, addr: SocketAddr) -> Result<Self::Iter, Error> {
+        log::debug!("bind: {}", addr);
+        Ok(std::iter::once((self.stream.clone(), self.src_addr)))
+    }
+}
+
+/// Bytes relayed by [`measure_relay`] and the time taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayThroughput {
+    /// bytes relayed in both directions
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl RelayThroughput {
+    /// megabytes (10^6 bytes) relayed per second
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / 1e6 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for RelayThroughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {:?} ({:.1} MB/s)",
+            self.bytes,
+            self.elapsed,
+            self.mb_per_sec()
+        )
+    }
+}
+
+/// Relay `upload` bytes from a client and `download` bytes from a server by `relay`
+///
+/// Both streams are in memory, so that the result measures the relay itself,
+/// until each of them reaches the end.
+pub fn measure_relay(
+    relay: &Relay,
+    upload: usize,
+    download: usize,
+) -> Result<RelayThroughput, Error> {
+    let client = BufferStream::with_buffer(vec![0; upload].into(), vec![].into());
+    let server = BufferStream::with_buffer(vec![0; download].into(), vec![].into());
+    let start = Instant::now();
+    let handle = relay.start(
+        client.clone(),
+        "192.0.2.1:5000".parse().unwrap(),
+        server.clone(),
+        "192.0.2.2:80".parse().unwrap(),
+    )?;
+    let traffic = handle.traffic();
+    handle
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+    let elapsed = start.elapsed();
+    assert_eq!(server.wr_buff().get_ref().len(), upload);
+    assert_eq!(client.wr_buff().get_ref().len(), download);
+    Ok(RelayThroughput {
+        bytes: traffic.upload() + traffic.download(),
+        elapsed,
+    })
+}
@@ -0,0 +1,536 @@
+//! SOCKS over TLS
+//!
+//! Connections from clients are wrapped by [`TlsStream`] before the SOCKS5 handshake.
+//! The TLS handshake is performed by the session on the first read.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use gatekeeper::{tls, ServerConfig};
+//! # fn main() -> Result<(), gatekeeper::model::Error> {
+//! let tls_config = tls::load_server_config(Path::new("server.crt"), Path::new("server.key"))?;
+//! let (mut server, _tx) = tls::with_tls(ServerConfig::default(), tls_config);
+//! server.serve().ok();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With `load_server_config_with_client_auth`, clients are authenticated by their certificates
+//! issued by the given CAs. The common name of the certificate identifies the client
+//! as `user` of connect rules with the `cert:` prefix (e.g. `cert:device-01`),
+//! unless the SOCKS auth method authenticates another user.
+//! An `AuthService` checks it by `AuthService::authorize_peer`.
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::*;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConnection};
+
+use crate::acceptor::{Binder, TcpAcceptor, TcpBinder};
+use crate::byte_stream::ByteStream;
+use crate::config::ServerConfig;
+use crate::connector::TcpUdpConnector;
+use crate::model::{Error, ErrorKind};
+use crate::server::Server;
+use crate::server_command::ServerCommand;
+
+/// Load a certificate chain and a private key in PEM format
+pub fn load_server_config(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<Arc<rustls::ServerConfig>, Error> {
+    build_server_config(cert_path, key_path, None)
+}
+
+/// Load a certificate chain and a private key in PEM format,
+/// and verify certificates of clients by the CA certificates in `client_ca_path`
+///
+/// If `required` is `false`, clients without a certificate are also accepted.
+pub fn load_server_config_with_client_auth(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: &Path,
+    required: bool,
+) -> Result<Arc<rustls::ServerConfig>, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(client_ca_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| pem_error(client_ca_path, err))?
+    {
+        roots.add(cert).map_err(|err| {
+            ErrorKind::message_fmt(format_args!("{}: {}", client_ca_path.display(), err))
+        })?;
+    }
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+    let builder = if required {
+        builder
+    } else {
+        builder.allow_unauthenticated()
+    };
+    let verifier = builder
+        .build()
+        .map_err(|err| ErrorKind::message_fmt(format_args!("tls config: {}", err)))?;
+    build_server_config(cert_path, key_path, Some(verifier))
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn build_server_config(
+    cert_path: &Path,
+    key_path: &Path,
+    verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> Result<Arc<rustls::ServerConfig>, Error> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| pem_error(cert_path, err))?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| pem_error(key_path, err))?;
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| {
+            match verifier {
+                Some(verifier) => builder.with_client_cert_verifier(verifier),
+                None => builder.with_no_client_auth(),
+            }
+            .with_single_cert(certs, key)
+        })
+        .map_err(|err| ErrorKind::message_fmt(format_args!("tls config: {}", err)))?;
+    Ok(Arc::new(config))
+}
+
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> Error {
+    ErrorKind::message_fmt(format_args!("{}: {}", path.display(), err)).into()
+}
+
+/// Server accepting SOCKS over TLS
+pub fn with_tls(
+    config: ServerConfig,
+    tls_config: Arc<rustls::ServerConfig>,
+) -> (
+    Server<TlsStream, TlsBinder, TcpUdpConnector>,
+    mpsc::Sender<ServerCommand<TlsStream>>,
+) {
+    let (tx_done, rx_done) = mpsc::sync_channel(config.acceptor_count());
+    let mut tcp = TcpBinder::new(
+        config.client_rw_timeout,
+        Arc::new(Mutex::new(rx_done)),
+        config.accept_timeout,
+    );
+    tcp.set_tcp_keepalive(config.tcp_keepalive);
+    let binder = TlsBinder::new(tcp, tls_config);
+    let connector = TcpUdpConnector::from_config(&config);
+    Server::with_binder(config, binder, tx_done, connector)
+}
+
+/// TLS stream on a connection from a client
+///
+/// Cloned streams share the TLS session,
+/// which is locked only while records are processed, not while waiting for them.
+#[derive(Debug)]
+pub struct TlsStream {
+    conn: Arc<Mutex<ServerConnection>>,
+    sock: TcpStream,
+}
+
+impl TlsStream {
+    pub fn new(config: Arc<rustls::ServerConfig>, sock: TcpStream) -> Result<Self, Error> {
+        let conn = ServerConnection::new(config)
+            .map_err(|err| ErrorKind::message_fmt(format_args!("tls: {}", err)))?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            sock,
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            conn: self.conn.clone(),
+            sock: self.sock.try_clone()?,
+        })
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, ServerConnection>> {
+        self.conn
+            .lock()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+    }
+
+    /// send pending TLS records
+    fn write_tls(conn: &mut ServerConnection, mut sock: &TcpStream) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut sock)?;
+        }
+        Ok(())
+    }
+
+    /// receive TLS records arrived
+    fn read_tls(&self) -> io::Result<()> {
+        // block without the lock until records arrive
+        let eof = self.sock.peek(&mut [0])? == 0;
+        let mut conn = self.lock()?;
+        if eof {
+            conn.read_tls(&mut io::empty())?;
+        } else {
+            conn.read_tls(&mut &self.sock)?;
+        }
+        conn.process_new_packets()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut conn = self.lock()?;
+                match conn.reader().read(buf) {
+                    Ok(size) => return Ok(size),
+                    // closed without close_notify
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err),
+                }
+                // handshake messages
+                Self::write_tls(&mut conn, &self.sock)?;
+            }
+            self.read_tls()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.lock()?;
+        let size = conn.writer().write(buf)?;
+        Self::write_tls(&mut conn, &self.sock)?;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.lock()?;
+        conn.writer().flush()?;
+        Self::write_tls(&mut conn, &self.sock)
+    }
+}
+
+impl ByteStream for TlsStream {
+    #[allow(clippy::type_complexity)]
+    fn split(&self) -> Result<(Box<dyn io::Read + Send>, Box<dyn io::Write + Send>), Error> {
+        Ok((Box::new(self.try_clone()?), Box::new(self.try_clone()?)))
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.sock.read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    /// common name of the verified client certificate, after the TLS handshake
+    fn peer_identity(&self) -> Option<String> {
+        let conn = self.conn.lock().ok()?;
+        common_name(conn.peer_certificates()?.first()?)
+    }
+}
+
+/// DER tags
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const IA5_STRING: u8 = 0x16;
+/// `[0] EXPLICIT Version` of TBSCertificate
+const VERSION: u8 = 0xa0;
+/// OID of commonName (2.5.4.3)
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Byte reader of DER encoded structures
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    /// tag and contents of the next element
+    fn next(&mut self) -> Option<(u8, Der<'a>)> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&len, mut rest) = rest.split_first()?;
+        let len = if len < 0x80 {
+            len as usize
+        } else {
+            // long form
+            let size = (len & 0x7f) as usize;
+            if size == 0 || size > 4 || rest.len() < size {
+                return None;
+            }
+            let (bytes, body) = rest.split_at(size);
+            rest = body;
+            bytes.iter().fold(0, |len, b| len << 8 | *b as usize)
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (body, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some((tag, Der(body)))
+    }
+
+    /// contents of the next element of `tag`
+    fn expect(&mut self, tag: u8) -> Option<Der<'a>> {
+        match self.next()? {
+            (t, body) if t == tag => Some(body),
+            _ => None,
+        }
+    }
+}
+
+/// Common name in the subject of the DER encoded certificate `cert`
+fn common_name(cert: &[u8]) -> Option<String> {
+    let mut tbs = Der(cert).expect(SEQUENCE)?.expect(SEQUENCE)?;
+    if tbs.0.first() == Some(&VERSION) {
+        tbs.next()?;
+    }
+    // serialNumber, signature, issuer, validity
+    for _ in 0..4 {
+        tbs.next()?;
+    }
+    let mut subject = tbs.expect(SEQUENCE)?;
+    while let Some(mut rdn) = subject.expect(SET) {
+        while let Some(mut attr) = rdn.expect(SEQUENCE) {
+            if attr.expect(OBJECT_IDENTIFIER)?.0 != COMMON_NAME {
+                continue;
+            }
+            return match attr.next()? {
+                (UTF8_STRING | PRINTABLE_STRING | IA5_STRING, value) => {
+                    String::from_utf8(value.0.to_vec()).ok()
+                }
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+/// Wrap accepted connections with TLS
+pub struct TlsAcceptor {
+    inner: TcpAcceptor,
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl Iterator for TlsAcceptor {
+    type Item = (TlsStream, SocketAddr);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (sock, addr) = self.inner.next()?;
+            match TlsStream::new(self.config.clone(), sock) {
+                Ok(strm) => return Some((strm, addr)),
+                Err(err) => error!("tls error: {}: {}", addr, err),
+            }
+        }
+    }
+}
+
+pub struct TlsBinder {
+    inner: TcpBinder,
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsBinder {
+    pub fn new(inner: TcpBinder, config: Arc<rustls::ServerConfig>) -> Self {
+        Self { inner, config }
+    }
+}
+
+impl Binder for TlsBinder {
+    type Stream = TlsStream;
+    type Iter = TlsAcceptor;
+    fn bind(&self, addr: SocketAddr) -> Result<Self::Iter, Error> {
+        Ok(TlsAcceptor {
+            inner: self.inner.bind(addr)?,
+            config: self.config.clone(),
+        })
+    }
+
+    fn wake_acceptors(&self) {
+        self.inner.wake_acceptors()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn server_config() -> Arc<rustls::ServerConfig> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        load_server_config(&dir.join("localhost.crt"), &dir.join("localhost.key")).unwrap()
+    }
+
+    fn connect(sock: TcpStream) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+        connect_with(sock, false)
+    }
+
+    /// connect with the certificate of `device-01` issued by `ca.crt` if `client_auth`
+    fn connect_with(
+        sock: TcpStream,
+        client_auth: bool,
+    ) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(dir.join("localhost.crt")).unwrap())
+            .unwrap();
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+        let config = if client_auth {
+            let certs = vec![CertificateDer::from_pem_file(dir.join("client.crt")).unwrap()];
+            let key = PrivateKeyDer::from_pem_file(dir.join("client.key")).unwrap();
+            builder.with_client_auth_cert(certs, key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let conn = rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap())
+            .unwrap();
+        rustls::StreamOwned::new(conn, sock)
+    }
+
+    #[test]
+    fn split_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = server_config();
+        let th = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let strm = TlsStream::new(config, sock).unwrap();
+            let (mut rd, mut wr) = strm.split().unwrap();
+            let mut buf = [0; 5];
+            rd.read_exact(&mut buf).unwrap();
+            wr.write_all(&buf).unwrap();
+            // wait for the client to close
+            assert_eq!(rd.read(&mut buf).unwrap(), 0);
+        });
+
+        let mut client = connect(TcpStream::connect(addr).unwrap());
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        client.conn.send_close_notify();
+        client.flush().unwrap();
+        th.join().unwrap();
+    }
+
+    #[test]
+    fn certificate_common_name() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        let cn = |name: &str| common_name(&CertificateDer::from_pem_file(dir.join(name)).unwrap());
+        assert_eq!(cn("client.crt").as_deref(), Some("device-01"));
+        assert_eq!(cn("localhost.crt").as_deref(), Some("localhost"));
+        assert_eq!(cn("ca.crt").as_deref(), Some("gatekeeper test CA"));
+        assert_eq!(common_name(&[0x30, 0x82, 0x01]), None);
+    }
+
+    #[test]
+    fn client_auth() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        let load = |required| {
+            load_server_config_with_client_auth(
+                &dir.join("localhost.crt"),
+                &dir.join("localhost.key"),
+                &dir.join("ca.crt"),
+                required,
+            )
+            .unwrap()
+        };
+        // returns the identity of the client, or the error of the handshake
+        let accept = |config: Arc<rustls::ServerConfig>, client_auth: bool| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let th = thread::spawn(move || {
+                let (sock, _) = listener.accept().unwrap();
+                let mut strm = TlsStream::new(config, sock).unwrap();
+                let mut buf = [0; 5];
+                strm.read_exact(&mut buf).map(|_| strm.peer_identity())
+            });
+            let mut client = connect_with(TcpStream::connect(addr).unwrap(), client_auth);
+            // the server may close the connection on the handshake
+            client.write_all(b"hello").ok();
+            client.flush().ok();
+            th.join().unwrap()
+        };
+
+        assert_eq!(
+            accept(load(true), true).unwrap().as_deref(),
+            Some("device-01")
+        );
+        assert!(accept(load(true), false).is_err());
+        assert_eq!(accept(load(false), false).unwrap(), None);
+        assert_eq!(accept(server_config(), false).unwrap(), None);
+    }
+
+    #[test]
+    fn socks_over_tls() {
+        use crate::model;
+        use crate::rw_socks_stream as socks;
+
+        let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut strm, _) = echo.accept().unwrap();
+            let mut buf = [0; 5];
+            strm.read_exact(&mut buf).unwrap();
+            strm.write_all(&buf).unwrap();
+        });
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        let mut config = ServerConfig::default();
+        config.set_server_addr(addr);
+        let (mut server, tx) = with_tls(config, server_config());
+        let server_th = thread::spawn(move || server.serve().unwrap());
+
+        let mut client = loop {
+            match TcpStream::connect(addr) {
+                Ok(sock) => break connect(sock),
+                Err(_) => thread::sleep(std::time::Duration::from_millis(100)),
+            }
+        };
+        socks::test::write_method_candidates(
+            &mut client,
+            model::MethodCandidates::new(&[model::Method::NoAuth]),
+        )
+        .unwrap();
+        socks::test::write_connect_request(
+            &mut client,
+            model::ConnectRequest::connect_to(echo_addr),
+        )
+        .unwrap();
+        socks::test::read_method_selection(&mut client).unwrap();
+        let reply = socks::test::read_connect_reply(&mut client).unwrap();
+        assert_eq!(reply.connect_result, Ok(()));
+
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        tx.send(ServerCommand::Terminate).unwrap();
+        server_th.join().unwrap();
+    }
+}